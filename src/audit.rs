@@ -0,0 +1,208 @@
+use anyhow::Result;
+use log::error;
+use serde::Serialize;
+use sqlx::{prelude::FromRow, SqlitePool};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// One event in the life of a request
+#[derive(Clone, Debug, PartialEq, FromRow, Serialize)]
+pub struct AuditEntry {
+    pub request_id: String,
+    /// `submitted` or `finished`
+    pub event: String,
+    /// RFC 3339 time (UTC) when the event happened, not when it was written
+    pub timestamp: String,
+    /// Who submitted the request: `key:<client name>` for an API key's client (see
+    /// `Settings::api_keys`), or else the client's IP
+    pub client: Option<String>,
+    pub cohort_id: Option<i32>,
+    pub phenotype_definition: Option<String>,
+    /// The submitted request as JSON, so it can be rerun exactly
+    pub request_body: Option<String>,
+    /// Terminal status (`done` or `error`) of a finished request
+    pub status: Option<String>,
+    pub message: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn submitted(
+        request_id: Uuid,
+        client: Option<String>,
+        cohort_id: i32,
+        phenotype_definition: String,
+        request_body: String,
+    ) -> Self {
+        Self {
+            request_id: request_id.to_string(),
+            event: "submitted".to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            client,
+            cohort_id: Some(cohort_id),
+            phenotype_definition: Some(phenotype_definition),
+            request_body: Some(request_body),
+            status: None,
+            message: None,
+        }
+    }
+
+    /// A request that finished successfully, or failed with `error`
+    pub fn finished(request_id: Uuid, error: Option<String>) -> Self {
+        let status = match error {
+            None => "done",
+            Some(_) => "error",
+        };
+        Self {
+            request_id: request_id.to_string(),
+            event: "finished".to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            client: None,
+            cohort_id: None,
+            phenotype_definition: None,
+            request_body: None,
+            status: Some(status.to_string()),
+            message: error,
+        }
+    }
+}
+
+/// Append-only log of request events in the `audit_log` table. Entries are written by a
+/// background task, so recording one never waits on the database.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: mpsc::UnboundedSender<AuditEntry>,
+}
+
+impl AuditLog {
+    /// Create the table if needed and start the writer task, which runs on the current
+    /// tokio runtime until every `AuditLog` handle is dropped
+    pub async fn start(db: SqlitePool) -> Result<Self> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY,
+                request_id TEXT NOT NULL,
+                event TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                client TEXT,
+                cohort_id INTEGER,
+                phenotype_definition TEXT,
+                status TEXT,
+                message TEXT,
+                request_body TEXT
+            );
+            CREATE INDEX IF NOT EXISTS audit_log_request_id ON audit_log (request_id);",
+        )
+        .execute(&db)
+        .await?;
+        // Tables created before request bodies were recorded don't have their column
+        let has_request_body =
+            sqlx::query("SELECT 1 FROM pragma_table_info('audit_log') WHERE name = 'request_body'")
+                .fetch_optional(&db)
+                .await?
+                .is_some();
+        if !has_request_body {
+            sqlx::query("ALTER TABLE audit_log ADD COLUMN request_body TEXT")
+                .execute(&db)
+                .await?;
+        }
+        let (sender, mut receiver) = mpsc::unbounded_channel::<AuditEntry>();
+        tokio::spawn(async move {
+            while let Some(entry) = receiver.recv().await {
+                if let Err(err) = insert_audit_entry(&db, &entry).await {
+                    error!(
+                        "Failed to write audit log entry for {}: {}",
+                        entry.request_id, err
+                    );
+                }
+            }
+        });
+        Ok(Self { sender })
+    }
+
+    /// Queue an entry to be written. This doesn't block, so it can be called from both
+    /// handlers and worker threads.
+    pub fn record(&self, entry: AuditEntry) {
+        if let Err(err) = self.sender.send(entry) {
+            error!(
+                "Audit log writer has stopped, dropping entry for {}",
+                err.0.request_id
+            );
+        }
+    }
+}
+
+async fn insert_audit_entry(db: &SqlitePool, entry: &AuditEntry) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO audit_log
+        (request_id, event, timestamp, client, cohort_id, phenotype_definition, request_body,
+        status, message)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+    )
+    .bind(&entry.request_id)
+    .bind(&entry.event)
+    .bind(&entry.timestamp)
+    .bind(&entry.client)
+    .bind(entry.cohort_id)
+    .bind(&entry.phenotype_definition)
+    .bind(&entry.request_body)
+    .bind(&entry.status)
+    .bind(&entry.message)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Every recorded event for a request, oldest first
+pub async fn fetch_audit_history(
+    db: &SqlitePool,
+    request_id: &Uuid,
+) -> Result<Vec<AuditEntry>, sqlx::Error> {
+    sqlx::query_as::<_, AuditEntry>(
+        "SELECT request_id, event, timestamp, client, cohort_id, phenotype_definition,
+        request_body, status, message FROM audit_log WHERE request_id = $1 ORDER BY id",
+    )
+    .bind(request_id.to_string())
+    .fetch_all(db)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_audit_history() {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let audit_log = AuditLog::start(db.clone()).await.unwrap();
+        let request_id = Uuid::new_v4();
+        audit_log.record(AuditEntry::submitted(
+            request_id,
+            Some("127.0.0.1".to_string()),
+            1,
+            "\"a\"".to_string(),
+            "{}".to_string(),
+        ));
+        audit_log.record(AuditEntry::finished(request_id, Some("failed".to_string())));
+        audit_log.record(AuditEntry::finished(Uuid::new_v4(), None));
+        // Entries are written in the background, so wait for them to arrive
+        let mut history = Vec::new();
+        for _ in 0..100 {
+            history = fetch_audit_history(&db, &request_id).await.unwrap();
+            if history.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].event, "submitted");
+        assert_eq!(history[0].client.as_deref(), Some("127.0.0.1"));
+        assert_eq!(history[0].request_body.as_deref(), Some("{}"));
+        assert_eq!(history[1].status.as_deref(), Some("error"));
+        assert_eq!(history[1].message.as_deref(), Some("failed"));
+    }
+}
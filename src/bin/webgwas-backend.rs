@@ -1,34 +1,85 @@
 use anyhow::{anyhow, Context, Result};
 use axum::{
-    extract::{ConnectInfo, Path, Query, State},
+    body::Body,
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post, put},
-    Json, Router,
+    Extension, Json, Router,
 };
-use itertools::izip;
+use axum_server::tls_rustls::RustlsConfig;
+use faer::Col;
+use futures_util::stream;
 use log::{error, info};
-use phenotype_definitions::{apply_phenotype_definition, validate_phenotype_definition};
+use opentelemetry::{
+    trace::{TraceError, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace::TracerProvider, Resource};
+use phenotype_definitions::{
+    apply_phenotype_definition, check_definition_size, missing_features, operator_is_disabled,
+    parse_checked_definition, resolve_feature_index, validate_parsed_definition,
+    validate_phenotype_definition, PhenotypeError,
+};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
 use std::sync::Arc;
-use std::{net::SocketAddr, thread};
-use tower_http::{compression::CompressionLayer, cors::CorsLayer, trace::TraceLayer};
+use std::time::{Duration, Instant};
+use std::{
+    net::{IpAddr, SocketAddr},
+    thread,
+};
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, NotForContentType, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    cors::CorsLayer,
+    trace::TraceLayer,
+};
 use tracing::info_span;
 use tracing_appender::rolling;
 use tracing_subscriber::Layer;
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid;
 
-use webgwas_backend::AppState;
+use webgwas_backend::utils::{sanitize_label, sha256_hex, subsample_indices, vec_to_col};
+use webgwas_backend::{
+    audit::{fetch_audit_history, AuditEntry},
+    conditional::conditional_json,
+    count_features, fetch_features, fetch_features_page,
+    igwas::{projection_to_parquet, validate_plot_columns},
+    library::{fetch_definition, list_definitions, save_definition, SavedDefinition},
+    regression::{compute_rsquared, cosine_similarity},
+    AppState, CachedProjection, IdempotencyCheck,
+};
 use webgwas_backend::{config::Settings, models::PhenotypeSummaryRequest};
-use webgwas_backend::{errors::WebGWASError, worker::worker_loop};
+use webgwas_backend::{
+    errors::{ErrorCode, WebGWASError},
+    extract::{ValidJson, ValidQuery},
+    worker::{
+        check_definition_features, download_file_name, get_or_compute_projection,
+        resolve_num_covariates, worker_loop, ExcludedFeatures,
+    },
+};
 use webgwas_backend::{models::PvaluesQuery, phenotype_definitions};
 use webgwas_backend::{
     models::{
-        ApproximatePhenotypeValues, CohortResponse, FeatureResponse, GetFeaturesRequest,
-        PhenotypeFitQuality, PhenotypeSummary, PvaluesResponse, ValidPhenotypeResponse,
-        WebGWASRequest, WebGWASRequestId, WebGWASResponse, WebGWASResult, WebGWASResultStatus,
+        ApproximatePhenotypeValues, CohortData, CohortResponse, CohortSummary,
+        CompareProjectionsRequest, CompareProjectionsResponse, CovarianceRequest,
+        CovarianceResponse, DefinitionListQuery, DefinitionSyntax, FeatureHistogram,
+        FeatureListFormat, GetFeaturesRequest, HealthResponse, HistogramQuery, Node, Operator,
+        Operators, PhenotypeDivergence, PhenotypeSummary, PreloadRequest, PreloadResponse,
+        ProjectionRequest, ProjectionVarianceResponse, PvaluesResponse, RequestListEntry,
+        RequestListQuery, RequestListResponse, SaveDefinitionRequest, StatusTimestamps,
+        UnavailableCohort, ValidPhenotypeResponse, ValidateAllCohortsRequest,
+        ValidatePhenotypeQuery, WebGWASRequest, WebGWASRequestId, WebGWASResponse, WebGWASResult,
+        WebGWASResultStatus,
     },
-    render_results::load_pvalues,
+    render_results::{compute_histogram, load_pvalues},
 };
-use webgwas_backend::{regression::regress_left_inverse_vec, utils::vec_to_col};
 
 #[tokio::main]
 async fn main() {
@@ -38,200 +89,752 @@ async fn main() {
 
     // Configure tracing/logging
     let appender = rolling::daily(&settings.log_path, "webgwas");
-    let subscriber = tracing_subscriber::registry().with(
-        fmt::layer()
-            .json()
-            .flatten_event(true)
-            .with_writer(appender)
-            .with_thread_ids(false)
-            .with_target(false)
-            .with_level(true)
-            .with_timer(fmt::time::ChronoUtc::rfc_3339())
-            .with_span_events(fmt::format::FmtSpan::CLOSE)
-            .with_filter(tracing_subscriber::EnvFilter::from_default_env()),
-    );
+    let tracer_provider = settings
+        .otlp_endpoint
+        .as_deref()
+        .map(otlp_tracer_provider)
+        .transpose()
+        .context("Failed to set up OTLP exporter")
+        .unwrap();
+    let otlp_layer = tracer_provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("webgwas-backend"))
+            .with_filter(tracing_subscriber::EnvFilter::from_default_env())
+    });
+    let subscriber = tracing_subscriber::registry()
+        .with(
+            fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_writer(appender)
+                .with_thread_ids(false)
+                .with_target(false)
+                .with_level(true)
+                .with_timer(fmt::time::ChronoUtc::rfc_3339())
+                .with_span_events(fmt::format::FmtSpan::CLOSE)
+                .with_filter(tracing_subscriber::EnvFilter::from_default_env()),
+        )
+        .with(otlp_layer);
     subscriber.init();
 
     // Trace layer for the http server
-    let trace_layer = TraceLayer::new_for_http().make_span_with(|request: &http::Request<_>| {
-        let ip = get_client_ip(request);
-        tracing::info_span!(
-            "API request",
-            method = %request.method(),
-            uri = %request.uri(),
-            version = ?request.version(),
-            client_ip = %ip,
-        )
-    });
+    let trusted_proxies = settings.trusted_proxies.clone();
+    let trace_layer =
+        TraceLayer::new_for_http().make_span_with(move |request: &http::Request<_>| {
+            let ip = get_client_ip(request, &trusted_proxies);
+            tracing::info_span!(
+                "API request",
+                method = %request.method(),
+                uri = %request.uri(),
+                version = ?request.version(),
+                client_ip = %ip,
+            )
+        });
 
     let state = {
         let _span = tracing::info_span!("Initializing state").entered();
         Arc::new(AppState::new(settings).await.unwrap())
     };
 
-    let worker_state = state.clone();
-    thread::spawn(move || {
-        worker_loop(worker_state);
-    });
+    // Each worker pops from the shared queue under its lock, so no request is handled twice
+    for _ in 0..state.settings.num_workers.max(1) {
+        let worker_state = state.clone();
+        thread::spawn(move || {
+            worker_loop(worker_state);
+        });
+    }
 
-    let app = Router::new()
+    // Compress large responses for clients that accept it, skipping small ones where the
+    // overhead isn't worth it and result zips, which are already compressed
+    let compression_layer = CompressionLayer::new()
+        .gzip(true)
+        .deflate(true)
+        .br(true)
+        .zstd(true)
+        .compress_when(
+            DefaultPredicate::new()
+                .and(SizeAbove::new(state.settings.compression_min_size))
+                .and(NotForContentType::const_new("application/zip")),
+        );
+
+    let tls_paths = state
+        .settings
+        .tls_paths()
+        .unwrap()
+        .map(|(cert, key)| (cert.to_string(), key.to_string()));
+
+    // When API keys are configured, endpoints that submit work or are for admins always
+    // require one, and the read-only endpoints only if `require_api_key_for_reads` is set
+    let api_key_layer = middleware::from_fn_with_state(state.clone(), require_api_key);
+    let read_routes = Router::new()
         .route("/api/cohorts", get(get_cohorts))
+        .route("/api/cohorts/:cohort_id/summary", get(get_cohort_summary))
+        .route(
+            "/api/cohorts/:cohort_id/correlation",
+            get(download_correlation),
+        )
         .route("/api/features", get(get_features))
+        .route(
+            "/api/features/:cohort_id/:code/histogram",
+            get(get_feature_histogram),
+        )
+        .route("/api/operators", get(get_operators))
         .route("/api/phenotype", put(validate_phenotype))
+        .route("/api/phenotypes/validate", post(validate_phenotypes))
+        .route(
+            "/api/phenotype/validate_all_cohorts",
+            post(validate_phenotype_all_cohorts),
+        )
         .route("/api/phenotype_summary", post(get_phenotype_summary))
-        .route("/api/igwas", post(post_igwas))
+        .route("/api/projection_variance", post(get_projection_variance))
+        .route("/api/projection.parquet", post(download_projection))
+        .route("/api/compare", post(compare_projections))
+        .route("/api/covariance", post(get_covariance))
+        .route("/api/definitions", get(get_saved_definitions))
+        .route("/api/definitions/:definition_id", get(get_saved_definition))
         .route("/api/igwas/results/:request_id", get(get_igwas_results))
         .route(
             "/api/igwas/results/pvalues/:request_id",
             get(get_igwas_pvalues),
         )
+        .route("/api/download/:request_id", get(download_result));
+    let read_routes = match state.settings.require_api_key_for_reads {
+        true => read_routes.route_layer(api_key_layer.clone()),
+        false => read_routes,
+    };
+    // The API key layer is added last so that it runs before rate limiting
+    let protected_routes = Router::new()
+        .route(
+            "/api/igwas",
+            post(post_igwas).route_layer(middleware::from_fn_with_state(state.clone(), rate_limit)),
+        )
+        .route("/api/preload", post(preload_cohorts))
+        .route("/api/cohorts/:cohort_id/reload", post(reload_cohort))
+        .route("/api/definitions", post(post_saved_definition))
+        .route("/api/requests", get(list_requests))
+        .route(
+            "/api/requests/:request_id/rerun",
+            post(rerun_request)
+                .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit)),
+        )
+        .route_layer(api_key_layer);
+
+    // Health checks never need an API key, so that monitoring doesn't need one
+    let app = Router::new()
+        .route("/api/health", get(get_health))
+        .merge(read_routes)
+        .merge(protected_routes)
         .layer(trace_layer)
         .layer(CorsLayer::permissive())
-        .layer(CompressionLayer::new().gzip(true).deflate(true).br(true).zstd(true))
+        .layer(compression_layer)
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls_paths {
+        Some((cert_path, key_path)) => {
+            let tls_config = RustlsConfig::from_pem_file(&cert_path, &key_path)
+                .await
+                .context(anyhow!(
+                    "Failed to load TLS certificate {} and key {}",
+                    cert_path,
+                    key_path
+                ))
+                .unwrap();
+            info!("Serving HTTPS on {}", ADDRESS);
+            let handle = axum_server::Handle::new();
+            let shutdown_handle = handle.clone();
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                shutdown_handle.graceful_shutdown(Some(SHUTDOWN_GRACE_PERIOD));
+            });
+            axum_server::bind_rustls(ADDRESS.parse().unwrap(), tls_config)
+                .handle(handle)
+                .serve(service)
+                .await
+                .unwrap();
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(ADDRESS).await.unwrap();
+            axum::serve(listener, service)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .unwrap();
+        }
+    }
+    if let Some(provider) = tracer_provider {
+        // Flush any spans that haven't been exported yet
+        provider.shutdown().unwrap();
+    }
 }
 
+/// Longest an HTTPS connection is waited on to finish after a shutdown signal
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Resolve on Ctrl+C or SIGTERM, so that the server stops accepting connections and
+/// returns, letting `main` flush its spans
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down");
+}
+
+/// Batch exporter sending spans to an OTLP collector over gRPC. Must be called within
+/// the tokio runtime, which runs the exports.
+fn otlp_tracer_provider(endpoint: &str) -> Result<TracerProvider, TraceError> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::Config::default().with_resource(
+            Resource::new([KeyValue::new("service.name", "webgwas-backend")]),
+        ))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)
+}
+
+/// Address the server listens on
+const ADDRESS: &str = "0.0.0.0:8000";
+
 /// Get all cohorts
-async fn get_cohorts(State(state): State<Arc<AppState>>) -> Json<Vec<CohortResponse>> {
+async fn get_cohorts(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<CohortResponse>>, WebGWASError> {
     let result = sqlx::query_as::<_, CohortResponse>("SELECT id, name FROM cohort")
         .fetch_all(&state.db)
         .await
-        .context("Failed to fetch cohorts")
-        .unwrap();
-    Json(result)
+        .context("Failed to fetch cohorts")?
+        .into_iter()
+        .filter(|cohort| state.cohort_allowed(cohort.id))
+        .collect();
+    Ok(Json(result))
+}
+
+fn cohort_not_allowed(cohort_id: i32) -> anyhow::Error {
+    anyhow!("Cohort {} isn't available on this server", cohort_id)
+}
+
+/// Reject requests for cohorts the settings don't expose
+fn check_cohort_allowed(state: &AppState, cohort_id: i32) -> Result<(), WebGWASError> {
+    match state.cohort_allowed(cohort_id) {
+        true => Ok(()),
+        false => Err(WebGWASError::new(
+            ErrorCode::Forbidden,
+            cohort_not_allowed(cohort_id),
+        )),
+    }
+}
+
+/// Report whether every cohort is available, listing those that failed to load
+async fn get_health(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
+    let mut unavailable_cohorts = state
+        .cohort_load_errors
+        .lock()
+        .unwrap()
+        .iter()
+        // Cohorts the settings don't expose aren't listed, so their names don't leak
+        .filter(|(cohort_id, _)| state.cohort_allowed(**cohort_id))
+        .map(|(cohort_id, error)| UnavailableCohort {
+            cohort_id: *cohort_id,
+            error: error.clone(),
+        })
+        .collect::<Vec<UnavailableCohort>>();
+    unavailable_cohorts.sort_by_key(|cohort| cohort.cohort_id);
+    let status = match unavailable_cohorts.is_empty() {
+        true => "ok",
+        false => "degraded",
+    };
+    Json(HealthResponse {
+        status: status.to_string(),
+        unavailable_cohorts,
+    })
+}
+
+/// A cohort's data, loading it if this is its first use. Errors with `COHORT_NOT_FOUND`
+/// for a cohort that doesn't exist, `COHORT_UNAVAILABLE` for one that failed to load, and
+/// `FORBIDDEN` for one the settings don't expose.
+async fn get_cohort_data(
+    state: &Arc<AppState>,
+    cohort_id: i32,
+) -> Result<Arc<CohortData>, WebGWASError> {
+    check_cohort_allowed(state, cohort_id)?;
+    let loading_state = state.clone();
+    tokio::task::spawn_blocking(move || loading_state.cohort_data(cohort_id))
+        .await?
+        .map_err(|err| match state.cohort_load_error(cohort_id) {
+            Some(_) => WebGWASError::new(ErrorCode::CohortUnavailable, err),
+            None => err.into(),
+        })?
+        .ok_or_else(|| {
+            WebGWASError::new(
+                ErrorCode::CohortNotFound,
+                anyhow!("Cohort {} not found", cohort_id),
+            )
+        })
+}
+
+/// Get feature counts and sample sizes for a cohort
+async fn get_cohort_summary(
+    State(state): State<Arc<AppState>>,
+    Path(cohort_id): Path<i32>,
+) -> Result<Json<CohortSummary>, WebGWASError> {
+    let cohort_info = get_cohort_data(&state, cohort_id).await?;
+    let features = fetch_features(&state.db, cohort_id).await?;
+    Ok(Json(CohortSummary::new(cohort_id, &cohort_info, &features)))
 }
 
-/// Get all features for a given cohort
+/// Features read per query when streaming the feature list
+const FEATURE_STREAM_CHUNK_SIZE: usize = 1000;
+
+/// Get the features of a cohort, optionally a page at a time. The total number of features
+/// is sent in the `X-Total-Count` header, so clients know how many pages there are.
 async fn get_features(
-    Query(request): Query<GetFeaturesRequest>,
+    ValidQuery(request): ValidQuery<GetFeaturesRequest>,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<Vec<FeatureResponse>>, WebGWASError> {
-    let result = sqlx::query_as::<_, FeatureResponse>(
-        "SELECT code, name, type as node_type, sample_size
-        FROM feature WHERE cohort_id = $1
-        ORDER BY sample_size DESC",
-    )
-    .bind(request.cohort_id)
-    .fetch_all(&state.db)
-    .await;
+) -> Result<Response, WebGWASError> {
+    check_cohort_allowed(&state, request.cohort_id)?;
+    let total = count_features(&state.db, request.cohort_id)
+        .await
+        .context("Failed to count features")?;
+    let offset = request.offset.unwrap_or(0);
+    let mut response = match request.format {
+        FeatureListFormat::Json => {
+            let result = fetch_features_page(&state.db, request.cohort_id, offset, request.limit)
+                .await
+                .context("Failed to fetch features")?;
+            Json(result).into_response()
+        }
+        FeatureListFormat::Ndjson => {
+            // Read in chunks, so only one chunk is in memory at a time
+            let db = state.db.clone();
+            let cohort_id = request.cohort_id;
+            let lines = stream::try_unfold((offset, request.limit), move |(offset, remaining)| {
+                let db = db.clone();
+                async move {
+                    let chunk_size = remaining.map_or(FEATURE_STREAM_CHUNK_SIZE, |remaining| {
+                        remaining.min(FEATURE_STREAM_CHUNK_SIZE)
+                    });
+                    if chunk_size == 0 {
+                        return Ok(None);
+                    }
+                    let features =
+                        fetch_features_page(&db, cohort_id, offset, Some(chunk_size)).await?;
+                    if features.is_empty() {
+                        return Ok(None);
+                    }
+                    let n_features = features.len();
+                    let mut chunk = String::new();
+                    for feature in features {
+                        chunk += &serde_json::to_string(&feature)?;
+                        chunk.push('\n');
+                    }
+                    let remaining = remaining.map(|remaining| remaining - n_features);
+                    Ok::<_, anyhow::Error>(Some((chunk, (offset + n_features, remaining))))
+                }
+            });
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/x-ndjson")
+                .body(Body::from_stream(lines))?
+        }
+    };
+    response
+        .headers_mut()
+        .insert("X-Total-Count", HeaderValue::from(total));
+    Ok(response)
+}
+
+/// Get the covariance between features of a cohort
+async fn get_covariance(
+    State(state): State<Arc<AppState>>,
+    ValidJson(request): ValidJson<CovarianceRequest>,
+) -> Result<Json<CovarianceResponse>, WebGWASError> {
+    let cohort_info = get_cohort_data(&state, request.cohort_id).await?;
+    let covariance = cohort_info
+        .covariance_submatrix(&request.codes)
+        .map_err(|err| WebGWASError::new(ErrorCode::UnknownFeature, err))?;
+    Ok(Json(CovarianceResponse {
+        cohort_id: request.cohort_id,
+        codes: request.codes,
+        covariance,
+    }))
+}
+
+/// Download a cohort's full feature correlation matrix as CSV, labeled by feature code.
+/// It's streamed a row at a time, since for large cohorts it's too big to build at once.
+async fn download_correlation(
+    State(state): State<Arc<AppState>>,
+    Path(cohort_id): Path<i32>,
+) -> Result<Response, WebGWASError> {
+    let cohort_info = get_cohort_data(&state, cohort_id).await?;
+    let file_name = format!("{}_correlation.csv", cohort_info.cohort.normalized_name);
+    let lines = cohort_info
+        .correlation_csv_lines()
+        .map(Ok::<String, Infallible>);
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/csv")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .body(Body::from_stream(stream::iter(lines)))?)
+}
 
-    match result {
-        Ok(result) => Ok(Json(result)),
+/// Load cohorts into memory ahead of the requests that need them
+async fn preload_cohorts(
+    State(state): State<Arc<AppState>>,
+    ValidJson(request): ValidJson<PreloadRequest>,
+) -> Json<Vec<PreloadResponse>> {
+    let mut results = Vec::new();
+    for cohort_id in request.cohort_ids {
+        let result = match state.preload_cohort(cohort_id).await {
+            Ok(true) => PreloadResponse {
+                cohort_id,
+                is_loaded: true,
+                message: "Cohort loaded".to_string(),
+            },
+            Ok(false) => PreloadResponse {
+                cohort_id,
+                is_loaded: true,
+                message: "Cohort was already loaded".to_string(),
+            },
+            Err(err) => {
+                error!("Failed to preload cohort {}: {}", cohort_id, err);
+                PreloadResponse {
+                    cohort_id,
+                    is_loaded: false,
+                    message: format!("Failed to load cohort: {}", err),
+                }
+            }
+        };
+        results.push(result);
+    }
+    Json(results)
+}
+
+/// Load a cohort's data from disk again after its files change, without a restart
+async fn reload_cohort(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(cohort_id): Path<i32>,
+) -> Result<Json<PreloadResponse>, WebGWASError> {
+    check_admin(&state, &headers)?;
+    let response = match state.reload_cohort(cohort_id).await {
+        Ok(()) => PreloadResponse {
+            cohort_id,
+            is_loaded: true,
+            message: "Cohort reloaded".to_string(),
+        },
         Err(err) => {
-            error!("Failed to fetch features: {}", err);
-            Err(err.into())
+            error!("Failed to reload cohort {}: {}", cohort_id, err);
+            PreloadResponse {
+                cohort_id,
+                is_loaded: false,
+                message: format!("Failed to reload cohort: {}", err),
+            }
         }
-    }
+    };
+    Ok(Json(response))
+}
+
+/// Default number of bins in a feature histogram
+const DEFAULT_HISTOGRAM_BINS: usize = 50;
+/// Maximum number of bins a client can request
+const MAX_HISTOGRAM_BINS: usize = 1000;
+
+/// Get the distribution of a single feature in a cohort
+async fn get_feature_histogram(
+    State(state): State<Arc<AppState>>,
+    Path((cohort_id, code)): Path<(i32, String)>,
+    ValidQuery(query): ValidQuery<HistogramQuery>,
+) -> Result<Json<FeatureHistogram>, WebGWASError> {
+    check_cohort_allowed(&state, cohort_id)?;
+    let node_type = state
+        .knowledge_base
+        .lock()
+        .unwrap()
+        .find_field(cohort_id, &code)
+        .map(|field| field.node_type)
+        .ok_or_else(|| {
+            WebGWASError::new(
+                ErrorCode::UnknownFeature,
+                anyhow!("Unknown field {} in cohort {}", code, cohort_id),
+            )
+        })?;
+    let cohort_info = get_cohort_data(&state, cohort_id).await?;
+    let index = resolve_feature_index(&code, &cohort_info.feature_names, &cohort_info.aliases)
+        .ok_or_else(|| {
+            WebGWASError::new(
+                ErrorCode::UnknownFeature,
+                anyhow!("Field {} not found in cohort data", code),
+            )
+        })?;
+    let values = cohort_info
+        .features
+        .col(index)
+        .iter()
+        .copied()
+        .collect::<Vec<f32>>();
+    let n_bins = query
+        .bins
+        .unwrap_or(DEFAULT_HISTOGRAM_BINS)
+        .clamp(1, MAX_HISTOGRAM_BINS);
+    let (bin_edges, counts, n_missing) = compute_histogram(&values, node_type, n_bins);
+    Ok(Json(FeatureHistogram {
+        cohort_id,
+        code,
+        node_type,
+        bin_edges,
+        counts,
+        n_missing,
+    }))
 }
 
+/// Get the operators that definitions can use, leaving out disabled ones
+async fn get_operators(State(state): State<Arc<AppState>>) -> Json<Vec<Operator>> {
+    Json(
+        Operators::all()
+            .iter()
+            .filter(|op| !operator_is_disabled(op, &state.settings.disabled_operators))
+            .map(|op| op.value())
+            .collect(),
+    )
+}
+
+/// Maximum number of phenotype definitions accepted by a single batch validation
+const MAX_BATCH_VALIDATION_SIZE: usize = 100;
+
 /// Validate a phenotype definition
 async fn validate_phenotype(
     State(state): State<Arc<AppState>>,
-    Json(request): Json<WebGWASRequest>,
-) -> Json<ValidPhenotypeResponse> {
-    let result = match validate_phenotype_definition(
+    ValidQuery(query): ValidQuery<ValidatePhenotypeQuery>,
+    ValidJson(request): ValidJson<WebGWASRequest>,
+) -> Result<Json<ValidPhenotypeResponse>, WebGWASError> {
+    check_request_definition_size(&state, &request.phenotype_definition)?;
+    let include_ast = query.include_ast.unwrap_or(false);
+    Ok(Json(check_phenotype_definition(
+        &state,
+        request,
+        include_ast,
+    )))
+}
+
+/// Validate a batch of phenotype definitions, returning one response per definition in
+/// the order they were given
+async fn validate_phenotypes(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(query): ValidQuery<ValidatePhenotypeQuery>,
+    ValidJson(requests): ValidJson<Vec<WebGWASRequest>>,
+) -> Result<Json<Vec<ValidPhenotypeResponse>>, WebGWASError> {
+    if requests.len() > MAX_BATCH_VALIDATION_SIZE {
+        return Err(WebGWASError::new(
+            ErrorCode::BatchTooLarge,
+            anyhow!(
+                "Batch of {} phenotype definitions exceeds the limit of {}",
+                requests.len(),
+                MAX_BATCH_VALIDATION_SIZE
+            ),
+        ));
+    }
+    for request in &requests {
+        check_request_definition_size(&state, &request.phenotype_definition)?;
+    }
+    let include_ast = query.include_ast.unwrap_or(false);
+    let results = requests
+        .into_iter()
+        .map(|request| check_phenotype_definition(&state, request, include_ast))
+        .collect::<Vec<ValidPhenotypeResponse>>();
+    Ok(Json(results))
+}
+
+fn check_phenotype_definition(
+    state: &AppState,
+    request: WebGWASRequest,
+    include_ast: bool,
+) -> ValidPhenotypeResponse {
+    let validation = validate_phenotype_definition(
         request.cohort_id,
         &request.phenotype_definition,
-        &state.knowledge_base,
-    ) {
-        Ok(_) => ValidPhenotypeResponse {
-            is_valid: true,
-            message: "Phenotype definition is valid".to_string(),
-            phenotype_definition: request.phenotype_definition,
-        },
-        Err(err) => ValidPhenotypeResponse {
-            is_valid: false,
-            message: format!("Phenotype definition is invalid: {}", err),
-            phenotype_definition: request.phenotype_definition,
-        },
-    };
-    Json(result)
+        request.syntax,
+        &state.knowledge_base.lock().unwrap(),
+        &state.settings.disabled_operators,
+    );
+    match validation {
+        Ok(definition) => {
+            valid_phenotype_response(request.phenotype_definition, definition, include_ast)
+        }
+        Err(err) => invalid_phenotype_response(request.phenotype_definition, &err),
+    }
+}
+
+fn valid_phenotype_response(
+    phenotype_definition: String,
+    definition: Vec<Node>,
+    include_ast: bool,
+) -> ValidPhenotypeResponse {
+    ValidPhenotypeResponse {
+        is_valid: true,
+        message: "Phenotype definition is valid".to_string(),
+        phenotype_definition,
+        ast: include_ast.then_some(definition),
+        error_position: None,
+        error_token: None,
+        missing_features: None,
+    }
 }
 
+fn invalid_phenotype_response(
+    phenotype_definition: String,
+    err: &anyhow::Error,
+) -> ValidPhenotypeResponse {
+    let parse_error = err.downcast_ref::<PhenotypeError>();
+    ValidPhenotypeResponse {
+        is_valid: false,
+        message: format!("Phenotype definition is invalid: {}", err),
+        phenotype_definition,
+        ast: None,
+        error_position: parse_error.map(|err| err.position),
+        error_token: parse_error.map(|err| err.token.clone()),
+        missing_features: None,
+    }
+}
+
+/// Validate a phenotype definition against every cohort this server exposes, by cohort
+/// id. The definition is parsed once, then only its features are resolved per cohort.
+async fn validate_phenotype_all_cohorts(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(query): ValidQuery<ValidatePhenotypeQuery>,
+    ValidJson(request): ValidJson<ValidateAllCohortsRequest>,
+) -> Result<Json<BTreeMap<i32, ValidPhenotypeResponse>>, WebGWASError> {
+    check_request_definition_size(&state, &request.phenotype_definition)?;
+    let include_ast = query.include_ast.unwrap_or(false);
+    let definition = request.phenotype_definition;
+    let parsed = parse_checked_definition(
+        &definition,
+        request.syntax,
+        &state.settings.disabled_operators,
+    );
+    let kb = state.knowledge_base.lock().unwrap();
+    let results = state
+        .cohorts
+        .keys()
+        .copied()
+        .filter(|&cohort_id| state.cohort_allowed(cohort_id))
+        .map(|cohort_id| {
+            let parsed = match &parsed {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    return (
+                        cohort_id,
+                        invalid_phenotype_response(definition.clone(), err),
+                    )
+                }
+            };
+            let missing = missing_features(cohort_id, &parsed.nodes, &kb);
+            let response = if !missing.is_empty() {
+                ValidPhenotypeResponse {
+                    missing_features: Some(missing.clone()),
+                    ..invalid_phenotype_response(
+                        definition.clone(),
+                        &anyhow!("Fields not in cohort {}: {}", cohort_id, missing.join(", ")),
+                    )
+                }
+            } else {
+                match validate_parsed_definition(cohort_id, parsed, &kb) {
+                    Ok(valid_nodes) => {
+                        valid_phenotype_response(definition.clone(), valid_nodes, include_ast)
+                    }
+                    Err(err) => invalid_phenotype_response(definition.clone(), &err),
+                }
+            };
+            (cohort_id, response)
+        })
+        .collect();
+    Ok(Json(results))
+}
+
+/// Seed used to subsample the summary when the request doesn't give one
+const DEFAULT_SUMMARY_SEED: u64 = 0;
+
 async fn get_phenotype_summary(
     State(state): State<Arc<AppState>>,
-    Json(request): Json<PhenotypeSummaryRequest>,
+    ValidJson(request): ValidJson<PhenotypeSummaryRequest>,
 ) -> Result<Json<PhenotypeSummary>, WebGWASError> {
     // TODO: Figure out how to reduce memory usage here
     // TODO: Reduce the amount of code duplication here
     // 1. Validate the phenotype definition
-    let definition = match validate_phenotype_definition(
+    let definition = validate_request_definition(
+        &state,
         request.cohort_id,
         &request.phenotype_definition,
-        &state.knowledge_base,
-    ) {
-        Ok(definition) => definition,
-        Err(err) => {
-            return Err(anyhow!("Failed to validate phenotype definition: {}", err).into());
-        }
-    };
+        request.syntax,
+    )?;
     // 2. Apply the phenotype definition
-    let cohort_info = {
-        let binding = state.cohort_id_to_data.lock().unwrap();
-        binding.get(&request.cohort_id).unwrap().clone()
-    };
+    let cohort_info = get_cohort_data(&state, request.cohort_id).await?;
+    check_definition_features(&definition, &cohort_info).map_err(excluded_features_error)?;
     let phenotype = apply_phenotype_definition(
         &definition,
         &cohort_info.feature_names,
         &cohort_info.features,
+        &cohort_info.aliases,
     )
     .context(anyhow!("Failed to apply phenotype definition"))?;
     let phenotype_col = vec_to_col(&phenotype);
 
-    // 3. Regress the phenotype against the features
-    let (beta, intercept) = {
-        let _span = info_span!("regress_left_inverse_vec").entered();
-        let mut beta = regress_left_inverse_vec(&phenotype_col, &cohort_info.left_inverse);
-        let intercept = beta.read(beta.nrows() - 1);
-        beta.truncate(beta.nrows() - 1);
-        (beta, intercept)
-    };
+    // 3. Regress the phenotype against the features, reusing a cached projection if possible
+    let cached_projection = get_or_compute_projection(
+        &state,
+        request.cohort_id,
+        &definition,
+        &request.projection_options,
+        &cohort_info,
+    )
+    .map_err(projection_error)?;
     let phenotype_pred = {
         let _span = info_span!("polars_to_faer_f32").entered();
-        let intercept_col = vec_to_col(&vec![intercept; phenotype_col.nrows()]);
-        cohort_info.features.as_ref() * beta + intercept_col
+        predict_phenotype(&cohort_info, &cached_projection)
     };
 
-    let phenotype_values = izip!(phenotype.iter(), phenotype_pred.iter())
-        .map(|(x, y)| {
+    let sample_indices = subsample_indices(
+        phenotype_pred.nrows(),
+        request.n_samples.unwrap_or(phenotype_pred.nrows()),
+        request.seed.unwrap_or(DEFAULT_SUMMARY_SEED),
+    );
+    let phenotype_values = sample_indices
+        .iter()
+        .map(|&i| {
             Some(ApproximatePhenotypeValues {
-                true_value: *x,
-                approx_value: *y,
+                true_value: *phenotype.get(i)?,
+                approx_value: *phenotype_pred.get(i),
             })
         })
-        .take(request.n_samples.unwrap_or(phenotype_pred.nrows()))
         .collect::<Option<Vec<ApproximatePhenotypeValues>>>()
         .context("Failed to calculate phenotype values")?;
 
     // 4. Calculate the fit quality
     let rsquared = {
         let _span = info_span!("compute_rsquared").entered();
-        let rss = (&phenotype_pred - &phenotype_col)
-            .iter()
-            .map(|x| x.powi(2))
-            .sum::<f32>();
-        let mean = phenotype_col.sum() / phenotype_col.nrows() as f32;
-        let tss = phenotype_col
-            .iter()
-            .map(|x| (x - mean).powi(2))
-            .sum::<f32>();
-
-        1.0 - (rss / tss) // This will be NaN if tss is zero
+        compute_rsquared(&phenotype_col, &phenotype_pred)
     };
-    let fit_quality_reference = state
-        .fit_quality_reference
-        .iter()
-        .take(request.n_samples.unwrap_or(phenotype_pred.nrows()))
-        .cloned()
-        .collect::<Vec<PhenotypeFitQuality>>();
+    let divergence = PhenotypeDivergence::new(&phenotype_values);
+    let fit_quality_reference =
+        request.fit_quality_reference(&state.fit_quality_reference, phenotype_pred.nrows());
 
     Ok(Json(PhenotypeSummary {
         phenotype_definition: request.phenotype_definition,
@@ -239,69 +842,495 @@ async fn get_phenotype_summary(
         phenotype_values,
         fit_quality_reference,
         rsquared,
+        divergence,
+    }))
+}
+
+/// The phenotype approximated by its projection onto the cohort features
+fn predict_phenotype(cohort_info: &CohortData, cached_projection: &CachedProjection) -> Col<f32> {
+    let intercept_col = vec_to_col(&vec![
+        cached_projection.intercept;
+        cohort_info.features.nrows()
+    ]);
+    cohort_info.features.as_ref() * &cached_projection.projection.feature_coefficient
+        + intercept_col
+}
+
+/// Get how much of a phenotype the cohort features capture, without running the GWAS. See
+/// `ProjectionVarianceResponse` for what the numbers mean.
+async fn get_projection_variance(
+    State(state): State<Arc<AppState>>,
+    ValidJson(request): ValidJson<ProjectionRequest>,
+) -> Result<Json<ProjectionVarianceResponse>, WebGWASError> {
+    let definition = validate_request_definition(
+        &state,
+        request.cohort_id,
+        &request.phenotype_definition,
+        request.syntax,
+    )?;
+    let cohort_info = get_cohort_data(&state, request.cohort_id).await?;
+    check_definition_features(&definition, &cohort_info).map_err(excluded_features_error)?;
+    let phenotype = apply_phenotype_definition(
+        &definition,
+        &cohort_info.feature_names,
+        &cohort_info.features,
+        &cohort_info.aliases,
+    )
+    .context(anyhow!("Failed to apply phenotype definition"))?;
+    let cached_projection = get_or_compute_projection(
+        &state,
+        request.cohort_id,
+        &definition,
+        &request.projection_options,
+        &cohort_info,
+    )
+    .map_err(projection_error)?;
+    let rsquared = compute_rsquared(
+        &vec_to_col(&phenotype),
+        &predict_phenotype(&cohort_info, &cached_projection),
+    );
+    let total_feature_variance = cohort_info.total_feature_variance();
+    Ok(Json(ProjectionVarianceResponse {
+        phenotype_definition: request.phenotype_definition,
+        cohort_id: request.cohort_id,
+        projection_variance: cached_projection.projection_variance,
+        total_feature_variance,
+        variance_ratio: cached_projection.projection_variance / total_feature_variance,
+        rsquared,
     }))
 }
 
+/// Compare how two phenotypes project onto a cohort's features, without running either GWAS
+async fn compare_projections(
+    State(state): State<Arc<AppState>>,
+    ValidJson(request): ValidJson<CompareProjectionsRequest>,
+) -> Result<Json<CompareProjectionsResponse>, WebGWASError> {
+    let definition_a = validate_request_definition(
+        &state,
+        request.cohort_id,
+        &request.phenotype_definition_a,
+        request.syntax,
+    )?;
+    let definition_b = validate_request_definition(
+        &state,
+        request.cohort_id,
+        &request.phenotype_definition_b,
+        request.syntax,
+    )?;
+    let cohort_info = get_cohort_data(&state, request.cohort_id).await?;
+    let projection = |definition: &[Node]| {
+        get_or_compute_projection(
+            &state,
+            request.cohort_id,
+            definition,
+            &request.projection_options,
+            &cohort_info,
+        )
+    };
+    let projection_a = projection(&definition_a).map_err(projection_error)?;
+    let projection_b = projection(&definition_b).map_err(projection_error)?;
+    Ok(Json(CompareProjectionsResponse {
+        cohort_id: request.cohort_id,
+        // Both are standardized to the cohort's features, so their coefficients line up
+        cosine_similarity: cosine_similarity(
+            &projection_a.projection.feature_coefficient,
+            &projection_b.projection.feature_coefficient,
+        ),
+        projection_variance_a: projection_a.projection_variance,
+        projection_variance_b: projection_b.projection_variance,
+    }))
+}
+
+/// Check a request's phenotype definition against the size limit before parsing it, or a
+/// `DEFINITION_TOO_LARGE` error
+fn check_request_definition_size(
+    state: &AppState,
+    phenotype_definition: &str,
+) -> Result<(), WebGWASError> {
+    check_definition_size(phenotype_definition, state.settings.max_definition_bytes)
+        .map_err(|err| WebGWASError::new(ErrorCode::DefinitionTooLarge, err))
+}
+
+/// Validate a phenotype definition given in a request, or an `INVALID_PHENOTYPE` error
+fn validate_request_definition(
+    state: &AppState,
+    cohort_id: i32,
+    phenotype_definition: &str,
+    syntax: DefinitionSyntax,
+) -> Result<Vec<Node>, WebGWASError> {
+    check_cohort_allowed(state, cohort_id)?;
+    check_request_definition_size(state, phenotype_definition)?;
+    validate_phenotype_definition(
+        cohort_id,
+        phenotype_definition,
+        syntax,
+        &state.knowledge_base.lock().unwrap(),
+        &state.settings.disabled_operators,
+    )
+    .map_err(|err| {
+        WebGWASError::new(
+            ErrorCode::InvalidPhenotype,
+            anyhow!("Failed to validate phenotype definition: {}", err),
+        )
+    })
+}
+
+/// Report features of a definition that can't be projected as an invalid phenotype,
+/// listing each with its reason in the error's details
+fn excluded_features_error(excluded: ExcludedFeatures) -> WebGWASError {
+    let details = serde_json::json!({ "excluded_features": excluded.0 });
+    WebGWASError::new(ErrorCode::InvalidPhenotype, excluded).with_details(details)
+}
+
+/// Convert an error computing a projection, which is internal unless it's because of
+/// features that can't be projected
+fn projection_error(err: anyhow::Error) -> WebGWASError {
+    match err.downcast::<ExcludedFeatures>() {
+        Ok(excluded) => excluded_features_error(excluded),
+        Err(err) => err.into(),
+    }
+}
+
+/// Download a phenotype's projection coefficients as a parquet file, for applying the
+/// projection to other GWAS summary statistics. This is the same projection the indirect
+/// GWAS uses, with a coefficient (possibly zero) for every cohort feature, in the cohort's
+/// feature order.
+async fn download_projection(
+    State(state): State<Arc<AppState>>,
+    ValidJson(request): ValidJson<ProjectionRequest>,
+) -> Result<Response, WebGWASError> {
+    let definition = validate_request_definition(
+        &state,
+        request.cohort_id,
+        &request.phenotype_definition,
+        request.syntax,
+    )?;
+    let cohort_info = get_cohort_data(&state, request.cohort_id).await?;
+    let cached_projection = get_or_compute_projection(
+        &state,
+        request.cohort_id,
+        &definition,
+        &request.projection_options,
+        &cohort_info,
+    )
+    .map_err(projection_error)?;
+    let bytes = projection_to_parquet(&cached_projection.projection)?;
+    let file_name = format!("{}_projection.parquet", cohort_info.cohort.normalized_name);
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/vnd.apache.parquet")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .body(Body::from(bytes))?)
+}
+
+/// Longest `Idempotency-Key` header accepted
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Submit a request. Submissions with an `Idempotency-Key` header that the same client has
+/// used recently for the same body get the original response back instead of being
+/// submitted again. Reusing a key for a different body is an error.
 async fn post_igwas(
     State(state): State<Arc<AppState>>,
-    Json(request): Json<WebGWASRequest>,
-) -> Json<WebGWASResponse> {
+    Extension(ClientId(client)): Extension<ClientId>,
+    headers: HeaderMap,
+    ValidJson(request): ValidJson<WebGWASRequest>,
+) -> Result<Json<WebGWASResponse>, WebGWASError> {
+    let Some(key) = headers.get("Idempotency-Key") else {
+        return submit_request(&state, Some(client), request);
+    };
+    let key = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+        .ok_or_else(|| {
+            WebGWASError::new(
+                ErrorCode::InvalidRequest,
+                anyhow!(
+                    "Idempotency-Key must be 1 to {} visible ASCII characters",
+                    MAX_IDEMPOTENCY_KEY_LEN
+                ),
+            )
+        })?;
+    // Scoped to the client (an API key or IP), so keys can't replay another's submission
+    let body_hash = sha256_hex(serde_json::to_string(&request)?.as_bytes());
+    let now = chrono::Utc::now().timestamp();
+    let check = state
+        .idempotency_keys
+        .check(&client, key, &body_hash, now)
+        .await
+        .context("Failed to check the Idempotency-Key")?;
+    match check {
+        IdempotencyCheck::Submit(reservation) => {
+            let Json(response) = submit_request(&state, Some(client), request)?;
+            // The request is queued either way, so failing to store the key isn't an error
+            if let Err(err) = reservation.complete(&response, now).await {
+                error!("Failed to store Idempotency-Key: {:#}", err);
+            }
+            Ok(Json(response))
+        }
+        IdempotencyCheck::Replay(response) => Ok(Json(response)),
+        IdempotencyCheck::Mismatch => Err(WebGWASError::new(
+            ErrorCode::InvalidRequest,
+            anyhow!("Idempotency-Key was already used for a different request"),
+        )),
+        IdempotencyCheck::InProgress => Err(WebGWASError::new(
+            ErrorCode::IdempotencyKeyInUse,
+            anyhow!("A submission with this Idempotency-Key is still in progress"),
+        )),
+    }
+}
+
+/// Submit a previous request again as a new request, with exactly the body it was
+/// submitted with, as if it were resubmitted by hand
+async fn rerun_request(
+    State(state): State<Arc<AppState>>,
+    client: Option<Extension<ClientId>>,
+    Path(request_id): Path<Uuid>,
+) -> Result<Json<WebGWASResponse>, WebGWASError> {
+    let history = fetch_audit_history(&state.db, &request_id)
+        .await
+        .context("Failed to fetch request history")?;
+    let submitted = history
+        .into_iter()
+        .find(|entry| entry.event == "submitted")
+        .ok_or_else(|| request_not_found(request_id))?;
+    let request_body = submitted.request_body.ok_or_else(|| {
+        WebGWASError::new(
+            ErrorCode::InvalidRequest,
+            anyhow!(
+                "Request {} was submitted before request bodies were recorded, so it can't \
+                be rerun exactly",
+                request_id
+            ),
+        )
+        .with_request_id(request_id)
+    })?;
+    let request = serde_json::from_str::<WebGWASRequest>(&request_body)
+        .context("Failed to read the submitted request")?;
+    info!("Rerunning request {}", request_id);
+    submit_request(
+        &state,
+        client.map(|Extension(ClientId(client))| client),
+        request,
+    )
+}
+
+/// Validate a request and queue it, recording it in the audit log
+fn submit_request(
+    state: &AppState,
+    client: Option<String>,
+    request: WebGWASRequest,
+) -> Result<Json<WebGWASResponse>, WebGWASError> {
+    // Checked before anything else, so an oversized definition isn't even logged
+    check_request_definition_size(state, &request.phenotype_definition)?;
     let unique_id = Uuid::new_v4();
     tracing::info!(
-        request_id = %unique_id, cohort_id = %request.cohort_id, 
+        request_id = %unique_id, cohort_id = %request.cohort_id,
         phenotype = %request.phenotype_definition, "Received webgwas request");
-    match validate_phenotype_definition(
+    state.audit_log.record(AuditEntry::submitted(
+        unique_id,
+        client,
+        request.cohort_id,
+        request.phenotype_definition.clone(),
+        serde_json::to_string(&request)?,
+    ));
+    // Every rejection from here on also finishes the request's audit entry
+    let reject = |code: ErrorCode, err: anyhow::Error| {
+        state
+            .audit_log
+            .record(AuditEntry::finished(unique_id, Some(err.to_string())));
+        WebGWASError::new(code, err).with_request_id(unique_id)
+    };
+    if !state.cohort_allowed(request.cohort_id) {
+        let err = cohort_not_allowed(request.cohort_id);
+        return Err(reject(ErrorCode::Forbidden, err));
+    }
+    let validation = validate_phenotype_definition(
         request.cohort_id,
         &request.phenotype_definition,
-        &state.knowledge_base,
-    ) {
+        request.syntax,
+        &state.knowledge_base.lock().unwrap(),
+        &state.settings.disabled_operators,
+    );
+    match validation {
         Ok(definition) => {
+            if let Some(load_error) = state.cohort_load_error(request.cohort_id) {
+                let err = anyhow!(
+                    "Cohort {} is unavailable: {}",
+                    request.cohort_id,
+                    load_error
+                );
+                return Err(reject(ErrorCode::CohortUnavailable, err));
+            }
+            let cohort_info = state
+                .cohort_id_to_data
+                .lock()
+                .unwrap()
+                .get(&request.cohort_id)
+                .cloned();
+            if let Some(cohort_info) = &cohort_info {
+                let plot_columns = match request.plot_data {
+                    true => cohort_info
+                        .gwas
+                        .slice(0, 0)
+                        .and_then(|gwas| validate_plot_columns(&gwas)),
+                    false => Ok(()),
+                };
+                if let Err(err) = plot_columns {
+                    let err = anyhow!("Plot data isn't available for this cohort: {}", err);
+                    return Err(reject(ErrorCode::InvalidRequest, err));
+                }
+                if let Err(err) = resolve_num_covariates(
+                    request.num_covar,
+                    cohort_info.num_covar,
+                    cohort_info.features.nrows(),
+                ) {
+                    let err = anyhow!("Invalid number of covariates: {}", err);
+                    return Err(reject(ErrorCode::InvalidCovariates, err));
+                }
+            }
+            if let Some(bucket) = &request.destination_bucket {
+                if !state.settings.destination_allowed(bucket) {
+                    let err = anyhow!("Results can't be delivered to bucket {}", bucket);
+                    return Err(reject(ErrorCode::Forbidden, err));
+                }
+            }
+            if let Some(threshold) = request.p_threshold {
+                if !(threshold > 0.0 && threshold <= 1.0) {
+                    let err = anyhow!("P-value threshold must be in (0, 1], got {}", threshold);
+                    return Err(reject(ErrorCode::InvalidRequest, err));
+                }
+            }
             let result = WebGWASResult {
                 request_id: unique_id,
                 status: WebGWASResultStatus::Queued,
                 error_msg: None,
                 url: None,
+                progress: None,
+                content_length: None,
+                checksum: None,
+                lambda_gc: None,
+                resolved_definition: Some(definition.clone()),
                 local_result_file: None,
+                s3_key: None,
+                destination: None,
+                timestamps: StatusTimestamps::queued(),
             };
             state.results.lock().unwrap().insert(result);
 
             // Build the processed request
-            let request = WebGWASRequestId {
-                id: unique_id,
-                phenotype_definition: definition,
-                cohort_id: request.cohort_id,
-            };
+            let n_variants = cohort_info
+                .map(|cohort_data| cohort_data.gwas.height())
+                .unwrap_or(0);
+            let mut queued_request = WebGWASRequestId::new(
+                unique_id,
+                definition,
+                request.cohort_id,
+                request.projection_options,
+                request.num_covar,
+                n_variants,
+            );
+            queued_request.label = request.label.as_deref().and_then(sanitize_label);
+            queued_request.output_format = request.output_format;
+            queued_request.pvalue_adjustment = request.pvalue_adjustment;
+            queued_request.p_threshold = request.p_threshold;
+            queued_request.destination_bucket = request.destination_bucket;
+            queued_request.plot_data = request.plot_data;
+            let estimated_wait = state
+                .request_durations
+                .estimate_wait(state.queue.len(), state.settings.num_workers);
             // Put the request in the queue
-            state.queue.lock().unwrap().push(request);
+            state.queue.push(queued_request);
             // Return the request id
-            Json(WebGWASResponse {
+            Ok(Json(WebGWASResponse {
                 request_id: unique_id,
                 status: WebGWASResultStatus::Queued,
                 message: None,
-            })
+                estimated_wait_secs: estimated_wait.map(|wait| wait.as_secs_f32()),
+            }))
+        }
+        Err(err) => {
+            let err = anyhow!("Failed to validate phenotype definition: {}", err);
+            Err(reject(ErrorCode::InvalidPhenotype, err))
         }
-        Err(err) => Json(WebGWASResponse {
-            request_id: unique_id,
-            status: WebGWASResultStatus::Error,
-            message: Some(format!("Failed to validate phenotype definition: {}", err)),
-        }),
     }
 }
 
+/// Status of a request. Unless `status_etags` is off, polls that send the last `ETag` in
+/// `If-None-Match` get 304 Not Modified until the status changes.
 async fn get_igwas_results(
     State(state): State<Arc<AppState>>,
     Path(request_id): Path<Uuid>,
-) -> Json<WebGWASResult> {
-    match state.results.lock().unwrap().get(&request_id) {
-        Some(result) => Json(result.clone()),
-        None => Json(WebGWASResult {
-            request_id,
-            status: WebGWASResultStatus::Error,
-            error_msg: Some(format!("No result found for request {}", request_id)),
-            url: None,
-            local_result_file: None,
-        }),
+    headers: HeaderMap,
+) -> Result<Response, WebGWASError> {
+    let result = state
+        .results
+        .lock()
+        .unwrap()
+        .get(&request_id)
+        .cloned()
+        .ok_or_else(|| request_not_found(request_id))?;
+    match state.settings.status_etags {
+        true => Ok(conditional_json(
+            &result,
+            result.timestamps.latest(),
+            &headers,
+        )?),
+        false => Ok(Json(result).into_response()),
+    }
+}
+
+/// Stream a result zip from S3 through the server, for clients that can't follow the
+/// presigned URL. The zip is named after the phenotype definition.
+async fn download_result(
+    State(state): State<Arc<AppState>>,
+    Path(request_id): Path<Uuid>,
+) -> Result<Response, WebGWASError> {
+    let (key, file_name) = match state.results.lock().unwrap().get(&request_id) {
+        Some(result) => match (&result.status, &result.s3_key) {
+            (WebGWASResultStatus::Done, Some(key)) => (
+                key.clone(),
+                download_file_name(&request_id, result.resolved_definition.as_deref()),
+            ),
+            _ => {
+                return Err(WebGWASError::new(
+                    ErrorCode::ResultNotAvailable,
+                    anyhow!("No uploaded result for request {}", request_id),
+                )
+                .with_request_id(request_id))
+            }
+        },
+        None => return Err(request_not_found(request_id)),
+    };
+    let object = state
+        .s3_client
+        .get_object()
+        .bucket(&state.settings.s3_bucket)
+        .key(&key)
+        .send()
+        .await
+        .context("Failed to fetch result from S3")
+        .map_err(|err| WebGWASError::from(err).with_request_id(request_id))?;
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/zip")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        );
+    if let Some(content_length) = object.content_length {
+        response = response.header(header::CONTENT_LENGTH, content_length);
     }
+    Ok(response.body(Body::new(object.body.into_inner()))?)
+}
+
+fn request_not_found(request_id: Uuid) -> WebGWASError {
+    WebGWASError::new(
+        ErrorCode::RequestNotFound,
+        anyhow!("No result found for request {}", request_id),
+    )
+    .with_request_id(request_id)
 }
 
 #[axum::debug_handler]
@@ -309,52 +1338,277 @@ async fn get_igwas_pvalues(
     State(state): State<Arc<AppState>>,
     Path(request_id): Path<Uuid>,
     Query(query): Query<PvaluesQuery>,
-) -> Json<PvaluesResponse> {
-    match state.results.lock().unwrap().get(&request_id) {
-        Some(results) => match &results.local_result_file {
-            Some(path) => match load_pvalues(path.to_path_buf(), query.min_neg_log_p) {
-                Ok(result) => Json(PvaluesResponse {
-                    request_id,
-                    status: WebGWASResultStatus::Done,
-                    error_msg: None,
-                    pvalues: Some(result.pvalues),
-                    chromosome_positions: Some(result.chromosome_positions),
-                }),
-                Err(err) => Json(PvaluesResponse {
-                    request_id,
-                    status: WebGWASResultStatus::Error,
-                    error_msg: Some(format!("Failed to load p-values: {:#}", err)),
-                    pvalues: None,
-                    chromosome_positions: None,
-                }),
-            },
-            None => Json(PvaluesResponse {
-                request_id,
-                status: WebGWASResultStatus::Error,
-                error_msg: Some(format!("No local file found for request {}", request_id)),
-                pvalues: None,
-                chromosome_positions: None,
-            }),
-        },
-        None => Json(PvaluesResponse {
-            request_id,
-            status: WebGWASResultStatus::Error,
-            error_msg: Some(format!("No result found for request {}", request_id)),
-            pvalues: None,
-            chromosome_positions: None,
-        }),
+) -> Result<Json<PvaluesResponse>, WebGWASError> {
+    let path = match state.results.lock().unwrap().get(&request_id) {
+        Some(results) => results.local_result_file.clone().ok_or_else(|| {
+            WebGWASError::new(
+                ErrorCode::ResultNotAvailable,
+                anyhow!("No local file found for request {}", request_id),
+            )
+            .with_request_id(request_id)
+        })?,
+        None => return Err(request_not_found(request_id)),
+    };
+    let result = load_pvalues(path, query.min_neg_log_p)
+        .context("Failed to load p-values")
+        .map_err(|err| WebGWASError::from(err).with_request_id(request_id))?;
+    Ok(Json(PvaluesResponse {
+        request_id,
+        status: WebGWASResultStatus::Done,
+        error_msg: None,
+        pvalues: Some(result.pvalues),
+        chromosome_positions: Some(result.chromosome_positions),
+    }))
+}
+
+/// Reject requests without the configured admin token. Admin endpoints are disabled
+/// entirely when no token is configured.
+fn check_admin(state: &AppState, headers: &HeaderMap) -> Result<(), WebGWASError> {
+    let token = headers.get("X-Admin-Token").and_then(|hv| hv.to_str().ok());
+    match &state.settings.admin_token {
+        Some(expected) if token == Some(expected.as_str()) => Ok(()),
+        Some(_) => Err(WebGWASError::new(
+            ErrorCode::Forbidden,
+            anyhow!("Invalid admin token"),
+        )),
+        None => Err(WebGWASError::new(
+            ErrorCode::AdminDisabled,
+            anyhow!("Admin endpoints are disabled"),
+        )),
+    }
+}
+
+/// Longest name a saved definition can have, in bytes
+const MAX_DEFINITION_NAME_BYTES: usize = 255;
+
+/// Save a phenotype definition to the library, once it's validated against its cohort
+async fn post_saved_definition(
+    State(state): State<Arc<AppState>>,
+    ValidJson(request): ValidJson<SaveDefinitionRequest>,
+) -> Result<Json<SavedDefinition>, WebGWASError> {
+    let name = request.name.trim();
+    if name.is_empty() || name.len() > MAX_DEFINITION_NAME_BYTES {
+        return Err(WebGWASError::new(
+            ErrorCode::InvalidRequest,
+            anyhow!(
+                "Definition names must be 1 to {} bytes",
+                MAX_DEFINITION_NAME_BYTES
+            ),
+        ));
     }
+    // Saved definitions are submitted again as they are, so they're always postfix
+    validate_request_definition(
+        &state,
+        request.cohort_id,
+        &request.phenotype_definition,
+        DefinitionSyntax::Postfix,
+    )?;
+    let saved = save_definition(
+        &state.db,
+        name,
+        request.cohort_id,
+        &request.phenotype_definition,
+    )
+    .await
+    .map_err(|err| match err.as_database_error() {
+        Some(db_err) if db_err.is_unique_violation() => WebGWASError::new(
+            ErrorCode::DefinitionNameTaken,
+            anyhow!(
+                "Cohort {} already has a definition named {}",
+                request.cohort_id,
+                name
+            ),
+        ),
+        _ => anyhow::Error::from(err)
+            .context("Failed to save definition")
+            .into(),
+    })?;
+    info!(
+        "Saved definition {} for cohort {}",
+        saved.id, saved.cohort_id
+    );
+    Ok(Json(saved))
 }
 
-fn get_client_ip<T>(request: &axum::http::Request<T>) -> String {
+/// List the saved definitions of the cohorts this server exposes
+async fn get_saved_definitions(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(query): ValidQuery<DefinitionListQuery>,
+) -> Result<Json<Vec<SavedDefinition>>, WebGWASError> {
+    if let Some(cohort_id) = query.cohort_id {
+        check_cohort_allowed(&state, cohort_id)?;
+    }
+    let definitions = list_definitions(&state.db, query.cohort_id)
+        .await
+        .context("Failed to list definitions")?
+        .into_iter()
+        .filter(|definition| state.cohort_allowed(definition.cohort_id))
+        .collect();
+    Ok(Json(definitions))
+}
+
+/// Get a saved definition, whose cohort and definition can be submitted as a request
+async fn get_saved_definition(
+    State(state): State<Arc<AppState>>,
+    Path(definition_id): Path<i64>,
+) -> Result<Json<SavedDefinition>, WebGWASError> {
+    let definition = fetch_definition(&state.db, definition_id)
+        .await
+        .context("Failed to fetch definition")?
+        .ok_or_else(|| {
+            WebGWASError::new(
+                ErrorCode::DefinitionNotFound,
+                anyhow!("No saved definition {}", definition_id),
+            )
+        })?;
+    check_cohort_allowed(&state, definition.cohort_id)?;
+    Ok(Json(definition))
+}
+
+/// Default and maximum number of requests listed per page
+const DEFAULT_REQUEST_LIST_LIMIT: usize = 100;
+const MAX_REQUEST_LIST_LIMIT: usize = 1000;
+
+/// List queued requests in the order they'll run, followed by the running ones
+async fn list_requests(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    ValidQuery(query): ValidQuery<RequestListQuery>,
+) -> Result<Json<RequestListResponse>, WebGWASError> {
+    check_admin(&state, &headers)?;
+    let entries = state.queue.snapshot();
+    let total = entries.len();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_REQUEST_LIST_LIMIT)
+        .clamp(1, MAX_REQUEST_LIST_LIMIT);
+    let results = state.results.lock().unwrap();
+    let requests = entries
+        .into_iter()
+        .skip(query.offset.unwrap_or(0))
+        .take(limit)
+        .map(|entry| RequestListEntry {
+            request_id: entry.id,
+            status: results.peek(&entry.id).map(|result| result.status.clone()),
+            cohort_id: entry.cohort_id,
+            elapsed_seconds: entry.request_time.elapsed().as_secs_f32(),
+            queue_position: entry.queue_position,
+        })
+        .collect();
+    Ok(Json(RequestListResponse { total, requests }))
+}
+
+/// Who made a request: the client name of their API key if it's one of the configured keys,
+/// and their IP otherwise. Unrecognized keys are ignored, since they're free to rotate.
+#[derive(Clone)]
+struct ClientId(String);
+
+/// The API key sent in the `Authorization: Bearer` or `X-API-Key` header, if any
+fn request_api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| headers.get("X-API-Key").and_then(|hv| hv.to_str().ok()))
+}
+
+/// Reject requests without one of the configured API keys, doing nothing when none are
+/// configured. Passes the key's `ClientId` on as an extension.
+async fn require_api_key(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if state.settings.api_keys.is_empty() {
+        return next.run(request).await;
+    }
+    let client = request_api_key(request.headers())
+        .and_then(|key| state.settings.api_key_client(key))
+        .map(|client| format!("key:{}", client));
+    match client {
+        Some(client) => {
+            request.extensions_mut().insert(ClientId(client));
+            next.run(request).await
+        }
+        None => {
+            info!("Rejected a request without a valid API key");
+            (
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                WebGWASError::new(
+                    ErrorCode::Unauthorized,
+                    anyhow!("A valid API key is required"),
+                ),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Reject submissions from clients that have used up their rate limit. Passes the client's
+/// `ClientId` on to the handler as an extension, unless `require_api_key` already has.
+async fn rate_limit(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let client = match request.extensions().get::<ClientId>() {
+        Some(ClientId(client)) => client.clone(),
+        None => request_api_key(request.headers())
+            .and_then(|key| state.settings.api_key_client(key))
+            .map(|client| format!("key:{}", client))
+            .unwrap_or_else(|| get_client_ip(&request, &state.settings.trusted_proxies)),
+    };
+    match state.rate_limiter.check(&client, Instant::now()) {
+        Ok(()) => {
+            request.extensions_mut().insert(ClientId(client));
+            next.run(request).await
+        }
+        Err(retry_after) => {
+            info!("Rate limited a submission");
+            let retry_after = retry_after.as_secs_f64().ceil() as u64;
+            (
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                WebGWASError::new(
+                    ErrorCode::RateLimited,
+                    anyhow!("Too many requests, please try again later"),
+                ),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// The client's IP address. Anyone can set forwarding headers, so they're only read on
+/// connections from `trusted_proxies`, and other connections are identified by their own
+/// address. Behind trusted proxies, the client is the rightmost `X-Forwarded-For` hop
+/// that isn't one of them, since the hops to its left are whatever the client sent.
+fn get_client_ip<T>(request: &axum::http::Request<T>, trusted_proxies: &[IpAddr]) -> String {
+    let Some(peer) = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ci| ci.0.ip())
+    else {
+        return "unknown".to_string();
+    };
+    if !trusted_proxies.contains(&peer) {
+        return peer.to_string();
+    }
+
     // Try to get the IP from the X-Forwarded-For header
-    if let Some(ip) = request
+    let hops = request
         .headers()
-        .get("X-Forwarded-For")
-        .and_then(|hv| hv.to_str().ok())
-        .and_then(|s| s.split(',').next())
-    {
-        return ip.trim().to_string();
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|hv| hv.to_str().ok())
+        .flat_map(|s| s.split(','))
+        .map(|hop| hop.trim())
+        .collect::<Vec<&str>>();
+    let untrusted = |hop: &str| {
+        hop.parse::<IpAddr>()
+            .map_or(true, |ip| !trusted_proxies.contains(&ip))
+    };
+    if let Some(ip) = hops.into_iter().rev().find(|hop| untrusted(hop)) {
+        return ip.to_string();
     }
 
     // If X-Forwarded-For is not available, try X-Real-IP
@@ -366,12 +1620,267 @@ fn get_client_ip<T>(request: &axum::http::Request<T>) -> String {
         return ip.trim().to_string();
     }
     info!("No X-Forwarded-For or X-Real-IP header found, falling back to direct connection IP");
-    info!("Headers: {:#?}", request.headers());
 
     // If neither header is available, fall back to the direct connection IP
-    request
-        .extensions()
-        .get::<ConnectInfo<SocketAddr>>()
-        .map(|ci| ci.0.ip().to_string())
-        .unwrap_or_else(|| "unknown".to_string())
+    peer.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use webgwas_backend::audit::AuditLog;
+    use webgwas_backend::models::{Feature, NodeType, OutputFormat, PvalueAdjustment};
+    use webgwas_backend::phenotype_definitions::{format_phenotype_definition, KnowledgeBase};
+    use webgwas_backend::{
+        IdempotencyKeys, ProjectionCache, RateLimiter, RequestDurations, RequestQueue,
+        ResultsCache, ThreadBudget,
+    };
+
+    /// App state with `settings.toml` plus `extra_settings`, and a knowledge base of real
+    /// features in cohort 1, but no cohort data. The database is in memory.
+    async fn test_state(extra_settings: &str, codes: &[&str]) -> AppState {
+        let contents = format!(
+            "{}\n{}",
+            include_str!("../../settings.toml"),
+            extra_settings
+        );
+        let settings = toml::from_str::<Settings>(&contents).unwrap();
+        // Each connection to an in-memory database has its own database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let features = codes
+            .iter()
+            .enumerate()
+            .map(|(i, code)| Feature {
+                id: i as i32,
+                code: code.to_string(),
+                name: code.to_string(),
+                node_type: NodeType::Real,
+                sample_size: 10,
+                cohort_id: 1,
+            })
+            .collect();
+        let s3_config = aws_sdk_s3::Config::builder()
+            .behavior_version_latest()
+            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+            .build();
+        AppState {
+            root_directory: std::env::temp_dir(),
+            db: db.clone(),
+            s3_client: aws_sdk_s3::Client::from_conf(s3_config),
+            knowledge_base: Arc::new(Mutex::new(KnowledgeBase::new(features))),
+            cohorts: HashMap::new(),
+            cohort_id_to_data: Arc::new(Mutex::new(HashMap::new())),
+            cohort_load_errors: Arc::new(Mutex::new(HashMap::new())),
+            fit_quality_reference: Arc::new(Vec::new()),
+            queue: Arc::new(RequestQueue::default()),
+            results: Arc::new(Mutex::new(ResultsCache::new(settings.cache_capacity))),
+            projections: Arc::new(Mutex::new(ProjectionCache::new(
+                settings.projection_cache_capacity,
+            ))),
+            thread_budget: Arc::new(ThreadBudget::new(1)),
+            rate_limiter: Arc::new(RateLimiter::new(
+                settings.rate_limit_burst,
+                settings.rate_limit_per_minute,
+            )),
+            idempotency_keys: Arc::new(
+                IdempotencyKeys::new(
+                    db.clone(),
+                    Duration::from_secs(settings.idempotency_key_ttl_secs),
+                )
+                .await
+                .unwrap(),
+            ),
+            request_durations: Arc::new(RequestDurations::default()),
+            audit_log: AuditLog::start(db).await.unwrap(),
+            runtime: tokio::runtime::Handle::current(),
+            settings,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_submit_infix_definition() {
+        let state = test_state("", &["sbp", "dbp"]).await;
+        let submit = |body: serde_json::Value| {
+            let request = serde_json::from_value::<WebGWASRequest>(body).unwrap();
+            submit_request(&state, None, request).map(|_| ())
+        };
+        submit(serde_json::json!({
+            "phenotype_definition": "(sbp - dbp) / 2 > 100",
+            "syntax": "infix",
+            "cohort_id": 1,
+        }))
+        .unwrap();
+        submit(serde_json::json!({
+            "phenotype_definition": r#""sbp" "dbp" `SUB` <REAL:2> `DIV` <REAL:100> `GT`"#,
+            "cohort_id": 1,
+        }))
+        .unwrap();
+        let infix = state.queue.pop();
+        let postfix = state.queue.pop();
+        assert_eq!(
+            format_phenotype_definition(&infix.phenotype_definition),
+            format_phenotype_definition(&postfix.phenotype_definition)
+        );
+
+        // Without the syntax, the infix definition is read as postfix
+        let err = submit(serde_json::json!({
+            "phenotype_definition": "(sbp - dbp) / 2 > 100",
+            "cohort_id": 1,
+        }))
+        .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidPhenotype);
+    }
+
+    #[tokio::test]
+    async fn test_definition_too_large() {
+        let state = Arc::new(test_state("max_definition_bytes = 16", &["sbp"]).await);
+        let request = || {
+            serde_json::from_value::<WebGWASRequest>(serde_json::json!({
+                "phenotype_definition": r#""sbp" "sbp" `ADD` "sbp" `ADD`"#,
+                "cohort_id": 1,
+            }))
+            .unwrap()
+        };
+        let check = |err: WebGWASError| async move {
+            let response = err.into_response();
+            assert_eq!(response.status(), http::StatusCode::PAYLOAD_TOO_LARGE);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+            assert_eq!(body["code"], "DEFINITION_TOO_LARGE");
+        };
+        check(submit_request(&state, None, request()).err().unwrap()).await;
+        assert!(state.queue.is_empty());
+        let query = ValidatePhenotypeQuery { include_ast: None };
+        let err = validate_phenotype(
+            State(state.clone()),
+            ValidQuery(query),
+            ValidJson(request()),
+        )
+        .await
+        .err()
+        .unwrap();
+        check(err).await;
+    }
+
+    #[tokio::test]
+    async fn test_submit_disallowed_cohort() {
+        let submit = |state: &AppState| {
+            let request = serde_json::from_value::<WebGWASRequest>(serde_json::json!({
+                "phenotype_definition": r#""sbp" `ROOT`"#,
+                "cohort_id": 1,
+            }))
+            .unwrap();
+            submit_request(state, None, request).map(|_| ())
+        };
+        let state = test_state("allowed_cohorts = [2]", &["sbp"]).await;
+        let err = submit(&state).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Forbidden);
+        assert!(state.queue.is_empty());
+        let state = test_state("allowed_cohorts = [1]", &["sbp"]).await;
+        submit(&state).unwrap();
+        assert_eq!(state.queue.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_health_hides_disallowed_cohorts() {
+        let state = Arc::new(test_state("allowed_cohorts = [2]", &[]).await);
+        state.cohort_load_errors.lock().unwrap().extend([
+            (1, "Failed to read secret_cohort".to_string()),
+            (2, "Failed to read public_cohort".to_string()),
+        ]);
+        let Json(health) = get_health(State(state)).await;
+        assert_eq!(health.status, "degraded");
+        assert_eq!(health.unavailable_cohorts.len(), 1);
+        assert_eq!(health.unavailable_cohorts[0].cohort_id, 2);
+    }
+
+    #[tokio::test]
+    async fn test_rerun_replays_request() {
+        let state = Arc::new(test_state("", &["sbp", "dbp"]).await);
+        let request = serde_json::from_value::<WebGWASRequest>(serde_json::json!({
+            "phenotype_definition": "sbp - dbp",
+            "syntax": "infix",
+            "cohort_id": 1,
+            "num_covar": 2,
+            "standardize_features": true,
+            "label": "pulse pressure",
+            "output_format": "arrow",
+            "pvalue_adjustment": "benjamini_hochberg",
+            "p_threshold": 1e-5,
+            "plot_data": true,
+        }))
+        .unwrap();
+        let Json(submitted) = submit_request(&state, None, request).unwrap();
+        let original = state.queue.pop();
+        assert_eq!(original.id, submitted.request_id);
+        // Audit entries are written in the background, so wait for the submission
+        for _ in 0..100 {
+            if !fetch_audit_history(&state.db, &original.id)
+                .await
+                .unwrap()
+                .is_empty()
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let Json(rerun_response) = rerun_request(State(state.clone()), None, Path(original.id))
+            .await
+            .unwrap();
+        let rerun = state.queue.pop();
+        assert_eq!(rerun.id, rerun_response.request_id);
+        assert_ne!(rerun.id, original.id);
+        assert_eq!(
+            format_phenotype_definition(&rerun.phenotype_definition),
+            format_phenotype_definition(&original.phenotype_definition)
+        );
+        assert!(rerun.projection_options.standardize_features);
+        assert_eq!(rerun.num_covar, Some(2));
+        assert_eq!(rerun.label.as_deref(), Some("pulse_pressure"));
+        assert_eq!(rerun.output_format, OutputFormat::Arrow);
+        assert_eq!(
+            rerun.pvalue_adjustment,
+            Some(PvalueAdjustment::BenjaminiHochberg)
+        );
+        assert_eq!(rerun.p_threshold, Some(1e-5));
+        assert!(rerun.plot_data);
+    }
+
+    #[test]
+    fn test_get_client_ip() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let request = |peer: &str, forwarded_for: Option<&str>| {
+            let mut builder = http::Request::builder();
+            if let Some(forwarded_for) = forwarded_for {
+                builder = builder.header("X-Forwarded-For", forwarded_for);
+            }
+            let mut request = builder.body(()).unwrap();
+            let peer = SocketAddr::new(peer.parse().unwrap(), 443);
+            request.extensions_mut().insert(ConnectInfo(peer));
+            request
+        };
+        // Forwarding headers from clients themselves are ignored
+        let direct = request("203.0.113.7", Some("198.51.100.1"));
+        assert_eq!(get_client_ip(&direct, &[]), "203.0.113.7");
+        assert_eq!(get_client_ip(&direct, &[proxy]), "203.0.113.7");
+        // Behind a proxy, hops the client added to the left of its own are skipped
+        let proxied = request("10.0.0.1", Some("198.51.100.1, 203.0.113.7"));
+        assert_eq!(get_client_ip(&proxied, &[proxy]), "203.0.113.7");
+        assert_eq!(get_client_ip(&proxied, &[]), "10.0.0.1");
+        let chained = request("10.0.0.1", Some("203.0.113.7, 10.0.0.1"));
+        assert_eq!(get_client_ip(&chained, &[proxy]), "203.0.113.7");
+        assert_eq!(
+            get_client_ip(&request("10.0.0.1", None), &[proxy]),
+            "10.0.0.1"
+        );
+    }
 }
@@ -25,7 +25,7 @@ use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::fmt::time::ChronoLocal;
 
 use webgwas_backend::models::NodeType;
-use webgwas_backend::regression::compute_weighted_ridge_pseudoinverse;
+use webgwas_backend::regression::{compute_weighted_ridge_pseudoinverse, RIDGE_LAMBDA};
 use webgwas_backend::{
     models::Cohort,
     regression::{compute_covariance, residualize_covariates, transpose_vec_vec},
@@ -226,6 +226,11 @@ pub struct PhenoOptions {
     /// Min sample size
     #[arg(long = "min-sample-size")]
     min_sample_size: Option<usize>,
+
+    /// Anonymize the covariates with the phenotypes and write them to covariates.parquet,
+    /// so requests can residualize on them (samples are then also grouped by covariates)
+    #[arg(long = "include-covariates", default_value_t = false)]
+    include_covariates: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -638,11 +643,23 @@ impl LocalAppState {
                 Some(n) => n,
                 None => data.y_phenotypes.nrows(),
             };
+            // Covariates are anonymized in the same rows, so they stay aligned with the
+            // phenotype centroids. The trailing intercept column is left out.
+            let n_covariates = match pheno_options.include_covariates {
+                true => data.covariate_names.len(),
+                false => 0,
+            };
             let raw_phenotypes = data
                 .y_phenotypes
                 .row_iter()
+                .zip(data.x_covariates.row_iter())
                 .take(n_samples)
-                .map(|x| x.iter().copied().collect::<Vec<f32>>())
+                .map(|(y, x)| {
+                    y.iter()
+                        .chain(x.iter().take(n_covariates))
+                        .copied()
+                        .collect::<Vec<f32>>()
+                })
                 .collect::<Vec<Vec<f32>>>();
             mdav(raw_phenotypes, pheno_options.k_anonymity)?
         };
@@ -651,10 +668,26 @@ impl LocalAppState {
             mdav_result.n_occurrences.len(),
             "MDAV result has different lengths"
         );
+        let n_phenotypes = data.phenotype_names.len();
+        let (phenotype_centroids, covariate_centroids): (Vec<Vec<f32>>, Vec<Vec<f32>>) =
+            mdav_result
+                .centroids
+                .into_iter()
+                .map(|mut centroid| {
+                    let covariates = centroid.split_off(n_phenotypes);
+                    (centroid, covariates)
+                })
+                .unzip();
+        if pheno_options.include_covariates {
+            let _span = info_span!("Writing anonymized covariates").entered();
+            let mut covariates_df = vec_vec_to_polars(covariate_centroids, &data.covariate_names)?;
+            let covariates_path = self.cohort_directory.join("covariates.parquet");
+            write_parquet(&mut covariates_df, &covariates_path)?;
+        }
         let mut anonymized_phenotypes_df = {
             let _span = info_span!("Processing anonymized phenotypes").entered();
             let mut anonymized_phenotypes_df =
-                vec_vec_to_polars(mdav_result.centroids, &data.phenotype_names)?;
+                vec_vec_to_polars(phenotype_centroids, &data.phenotype_names)?;
             info!(
                 "Anonymized phenotypes shape {:?}",
                 anonymized_phenotypes_df.shape()
@@ -690,9 +723,17 @@ impl LocalAppState {
             let mut count_col = vec_to_col(&mdav_result.n_occurrences);
             let min = mdav_result.n_occurrences.iter().min().unwrap();
             count_col.iter_mut().for_each(|x| *x = { *x } / *min as f32);
-            compute_weighted_ridge_pseudoinverse(&anonymized_phenotype_mat, &count_col, 1.0)
-                .transpose()
-                .to_owned()
+            // The server refits on subsets of the rows with the same weights
+            let mut weights_df = df!("weight" => count_col.iter().copied().collect::<Vec<f32>>())?;
+            let weights_path = self.cohort_directory.join("sample_weights.parquet");
+            write_parquet(&mut weights_df, &weights_path)?;
+            compute_weighted_ridge_pseudoinverse(
+                &anonymized_phenotype_mat,
+                &count_col,
+                RIDGE_LAMBDA,
+            )
+            .transpose()
+            .to_owned()
         };
         let mut column_names = data.phenotype_names.clone();
         column_names.push("intercept".to_string());
@@ -1071,6 +1112,7 @@ pub struct PhenotypesCovariates {
     pub y_phenotypes: Mat<f32>,
     pub x_covariates: Mat<f32>,
     pub phenotype_names: Vec<String>,
+    pub covariate_names: Vec<String>,
 }
 
 pub fn read_phenotypes_covariates(
@@ -1142,6 +1184,7 @@ pub fn read_phenotypes_covariates(
         y_phenotypes: y,
         x_covariates: x,
         phenotype_names: pheno_cols.to_vec(),
+        covariate_names: covar_cols.iter().map(|x| x.to_string()).collect(),
     })
 }
 
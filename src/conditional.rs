@@ -0,0 +1,118 @@
+use anyhow::Result;
+use axum::{
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LAST_MODIFIED},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::utils::sha256_hex;
+
+/// Strong entity tag of a JSON body, which changes whenever any of its content does
+pub fn etag(body: &[u8]) -> String {
+    format!("\"{}\"", &sha256_hex(body)[..32])
+}
+
+/// Whether an `If-None-Match` header value (a list of entity tags, or `*`) matches the
+/// tag. Tags are compared weakly, as the header requires, so `W/` prefixes are ignored.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Serialize a frequently polled value as JSON with an `ETag`, or respond 304 Not
+/// Modified without a body if the request's `If-None-Match` already has that tag.
+/// `Last-Modified` is only informational: it has second precision, so it can miss
+/// changes that the tag catches (e.g. progress), and `If-Modified-Since` isn't checked.
+pub fn conditional_json<T: Serialize>(
+    value: &T,
+    last_modified: Option<DateTime<Utc>>,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let body = serde_json::to_vec(value)?;
+    let etag = etag(&body);
+    let not_modified = headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| etag_matches(value, &etag));
+    let mut response = match not_modified {
+        true => StatusCode::NOT_MODIFIED.into_response(),
+        false => ([(CONTENT_TYPE, "application/json")], body).into_response(),
+    };
+    let response_headers = response.headers_mut();
+    response_headers.insert(ETAG, HeaderValue::from_str(&etag)?);
+    // Caches may store the status, but must check it's current before each use
+    response_headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    if let Some(last_modified) = last_modified {
+        let http_date = last_modified
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        response_headers.insert(LAST_MODIFIED, HeaderValue::from_str(&http_date)?);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{StatusTimestamps, WebGWASResult, WebGWASResultStatus};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"x\", W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abcd\"", "\"abc\""));
+    }
+
+    #[test]
+    fn test_conditional_json_polling() {
+        let mut result = WebGWASResult {
+            request_id: Uuid::nil(),
+            status: WebGWASResultStatus::Queued,
+            error_msg: None,
+            url: None,
+            progress: None,
+            content_length: None,
+            checksum: None,
+            lambda_gc: None,
+            resolved_definition: None,
+            local_result_file: None,
+            s3_key: None,
+            destination: None,
+            timestamps: StatusTimestamps::queued(),
+        };
+        let poll = |result: &WebGWASResult, etag: Option<&HeaderValue>| {
+            let mut headers = HeaderMap::new();
+            if let Some(etag) = etag {
+                headers.insert(IF_NONE_MATCH, etag.clone());
+            }
+            conditional_json(result, result.timestamps.latest(), &headers).unwrap()
+        };
+        let first = poll(&result, None);
+        assert_eq!(first.status(), StatusCode::OK);
+        assert!(first.headers().contains_key(LAST_MODIFIED));
+        let etag = first.headers().get(ETAG).unwrap().clone();
+        for _ in 0..2 {
+            let response = poll(&result, Some(&etag));
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(response.headers().get(ETAG), Some(&etag));
+        }
+
+        result.status = WebGWASResultStatus::Uploading;
+        let changed = poll(&result, Some(&etag));
+        assert_eq!(changed.status(), StatusCode::OK);
+        let new_etag = changed.headers().get(ETAG).unwrap().clone();
+        assert_ne!(new_etag, etag);
+        assert_eq!(
+            poll(&result, Some(&new_etag)).status(),
+            StatusCode::NOT_MODIFIED
+        );
+    }
+}
@@ -1,5 +1,11 @@
-use anyhow::Result;
+use std::{collections::HashMap, net::IpAddr, ops::RangeInclusive, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Result};
 use serde::Deserialize;
+use uuid::Uuid;
+use zip::{write::SimpleFileOptions, CompressionMethod};
+
+use crate::models::Operators;
 
 #[derive(Deserialize, Debug)]
 pub enum LogLevel {
@@ -13,20 +19,389 @@ pub enum LogLevel {
     Error,
 }
 
+/// How files are compressed in result zips
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ZipCompression {
+    Stored,
+    #[default]
+    Deflated,
+    Zstd,
+}
+
+impl ZipCompression {
+    pub fn method(&self) -> CompressionMethod {
+        match self {
+            ZipCompression::Stored => CompressionMethod::Stored,
+            ZipCompression::Deflated => CompressionMethod::Deflated,
+            ZipCompression::Zstd => CompressionMethod::Zstd,
+        }
+    }
+
+    /// Levels accepted by the method, or `None` if it doesn't take a level
+    fn level_range(&self) -> Option<RangeInclusive<i64>> {
+        match self {
+            ZipCompression::Stored => None,
+            ZipCompression::Deflated => Some(0..=9),
+            ZipCompression::Zstd => Some(-7..=22),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Settings {
     pub cache_capacity: usize,
+    #[serde(default = "default_projection_cache_capacity")]
+    pub projection_cache_capacity: usize,
+    /// Region of `s3_bucket`, which presigned result URLs point at
     pub s3_region: String,
+    /// Base URL of an S3-compatible store (e.g. MinIO at `http://localhost:9000`) to use
+    /// instead of AWS
+    pub s3_endpoint: Option<String>,
     pub s3_bucket: String,
     pub s3_result_path: String,
+    /// Upload each cohort's results under its own prefix inside `s3_result_path`, named
+    /// after the cohort's normalized name
+    #[serde(default)]
+    pub s3_prefix_by_cohort: bool,
+    /// Result prefixes inside `s3_result_path` for specific cohorts, keyed by normalized
+    /// cohort name. These apply even when `s3_prefix_by_cohort` is off.
+    #[serde(default)]
+    pub s3_cohort_prefixes: HashMap<String, String>,
+    /// Buckets that requests may have their results delivered to instead of `s3_bucket`.
+    /// Each must let this server's credentials write to it (e.g. with a bucket policy).
+    /// Requests can't choose a destination when this is empty.
+    #[serde(default)]
+    pub destination_buckets: Vec<String>,
+    /// Cohorts this server exposes, by id or normalized name. Other cohorts are left out of
+    /// the cohort listing and requests for them are forbidden. All cohorts are exposed when
+    /// this is empty.
+    #[serde(default)]
+    pub allowed_cohorts: Vec<AllowedCohort>,
     pub log_path: String,
+    /// OTLP (gRPC) collector to export tracing spans to, e.g. `http://localhost:4317`.
+    /// Spans are only written to the log when this isn't set.
+    pub otlp_endpoint: Option<String>,
     pub dry_run: bool,
+    /// Number of worker threads processing the request queue
+    #[serde(default = "default_num_workers")]
+    pub num_workers: usize,
+    /// Threads used by each GWAS computation, clamped to the available parallelism
+    #[serde(default = "default_igwas_threads")]
+    pub igwas_threads: usize,
+    /// Responses smaller than this many bytes are sent uncompressed
+    #[serde(default = "default_compression_min_size")]
+    pub compression_min_size: u16,
+    /// Fail requests whose result file or plot data file would be larger than this many
+    /// bytes, rather than filling the disk
+    pub max_result_bytes: Option<u64>,
+    /// Read GWAS data from disk in chunks while computing rather than keeping it in memory,
+    /// trading slower requests for much lower memory use
+    #[serde(default)]
+    pub stream_gwas: bool,
+    /// Token required in the `X-Admin-Token` header by admin endpoints, which are
+    /// disabled when this isn't set
+    pub admin_token: Option<String>,
+    /// API keys keyed by the name of the client they belong to. When any are set,
+    /// submission and admin endpoints require one in an `Authorization: Bearer` or
+    /// `X-API-Key` header. Without any, the server is unauthenticated.
+    #[serde(default)]
+    pub api_keys: HashMap<String, String>,
+    /// Also require an API key for read-only endpoints (e.g. listing cohorts), which are
+    /// otherwise public
+    #[serde(default)]
+    pub require_api_key_for_reads: bool,
+    /// Longest phenotype definition accepted, in bytes. Longer definitions are rejected
+    /// before they're parsed.
+    #[serde(default = "default_max_definition_bytes")]
+    pub max_definition_bytes: usize,
+    /// Names of operators that phenotype definitions may not use (e.g. `div`)
+    #[serde(default)]
+    pub disabled_operators: Vec<String>,
+    /// Compression method for result zips (`stored`, `deflated`, or `zstd`)
+    #[serde(default)]
+    pub zip_compression: ZipCompression,
+    /// Compression level for result zips, from 0 to 9 for `deflated` and -7 to 22 for
+    /// `zstd`. Higher levels make smaller zips more slowly, and the method's default level
+    /// is used when this isn't set.
+    #[serde(default)]
+    pub zip_compression_level: Option<i64>,
+    /// Submissions a client can make at once before being rate limited
+    #[serde(default = "default_rate_limit_burst")]
+    pub rate_limit_burst: u32,
+    /// Submissions per minute a client regains after using up its burst
+    #[serde(default = "default_rate_limit_per_minute")]
+    pub rate_limit_per_minute: u32,
+    /// Addresses of reverse proxies in front of the server, whose `X-Forwarded-For` and
+    /// `X-Real-IP` headers identify clients. Clients can set those headers themselves, so
+    /// they're ignored on connections from anywhere else.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Seconds a submission's `Idempotency-Key` is remembered, during which resubmitting
+    /// with the same key returns the original response instead of a new request
+    #[serde(default = "default_idempotency_key_ttl_secs")]
+    pub idempotency_key_ttl_secs: u64,
+    /// Tag request statuses with an `ETag`, so polls with a matching `If-None-Match` get
+    /// 304 Not Modified until the status changes
+    #[serde(default = "default_status_etags")]
+    pub status_etags: bool,
+    /// PEM certificate and private key for serving HTTPS directly. Both or neither must
+    /// be set, and without them the server uses plain HTTP.
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+}
+
+/// Entry of `allowed_cohorts`, matching a cohort by id or by normalized name
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum AllowedCohort {
+    Id(i32),
+    Name(String),
+}
+
+fn default_projection_cache_capacity() -> usize {
+    100
+}
+
+fn default_num_workers() -> usize {
+    1
+}
+
+fn default_igwas_threads() -> usize {
+    8
+}
+
+fn default_compression_min_size() -> u16 {
+    1024
+}
+
+fn default_rate_limit_burst() -> u32 {
+    5
+}
+
+fn default_rate_limit_per_minute() -> u32 {
+    10
+}
+
+fn default_max_definition_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_idempotency_key_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_status_etags() -> bool {
+    true
 }
 
 impl Settings {
     pub fn read_file(toml_path: &str) -> Result<Self> {
         let contents = std::fs::read_to_string(toml_path)?;
         let settings = toml::from_str::<Settings>(&contents)?;
+        settings.tls_paths()?;
+        for name in &settings.disabled_operators {
+            Operators::from_str(name).context(anyhow!("Invalid disabled operator {}", name))?;
+        }
+        settings.zip_file_options()?;
         Ok(settings)
     }
+
+    /// Options for files added to result zips, or an error if the compression level isn't
+    /// valid for the method
+    pub fn zip_file_options(&self) -> Result<SimpleFileOptions> {
+        if let Some(level) = self.zip_compression_level {
+            match self.zip_compression.level_range() {
+                Some(range) if range.contains(&level) => {}
+                Some(range) => bail!(
+                    "zip_compression_level must be between {} and {} for {:?}, got {}",
+                    range.start(),
+                    range.end(),
+                    self.zip_compression,
+                    level
+                ),
+                None => bail!(
+                    "zip_compression_level can't be set for {:?}",
+                    self.zip_compression
+                ),
+            }
+        }
+        Ok(SimpleFileOptions::default()
+            .compression_method(self.zip_compression.method())
+            .compression_level(self.zip_compression_level)
+            .unix_permissions(0o644))
+    }
+
+    /// S3 key for a request's result zip, shared by the upload and the presigned URL
+    pub fn result_key(&self, cohort_name: &str, request_id: &Uuid) -> String {
+        match self.s3_cohort_prefixes.get(cohort_name) {
+            Some(prefix) => format!("{}/{}/{}.zip", self.s3_result_path, prefix, request_id),
+            None if self.s3_prefix_by_cohort => {
+                format!("{}/{}/{}.zip", self.s3_result_path, cohort_name, request_id)
+            }
+            None => format!("{}/{}.zip", self.s3_result_path, request_id),
+        }
+    }
+
+    /// Whether requests may deliver results to a bucket
+    pub fn destination_allowed(&self, bucket: &str) -> bool {
+        self.destination_buckets
+            .iter()
+            .any(|allowed| allowed == bucket)
+    }
+
+    /// Whether a cohort is exposed by this server, given its id and normalized name
+    pub fn cohort_allowed(&self, cohort_id: i32, normalized_name: Option<&str>) -> bool {
+        self.allowed_cohorts.is_empty()
+            || self.allowed_cohorts.iter().any(|allowed| match allowed {
+                AllowedCohort::Id(id) => *id == cohort_id,
+                AllowedCohort::Name(name) => Some(name.as_str()) == normalized_name,
+            })
+    }
+
+    /// Name of the client an API key belongs to, if it's a configured key
+    pub fn api_key_client(&self, key: &str) -> Option<&str> {
+        self.api_keys
+            .iter()
+            .find(|(_, expected)| expected.as_str() == key)
+            .map(|(client, _)| client.as_str())
+    }
+
+    /// Certificate and key paths if TLS is configured, or an error if only one is set
+    pub fn tls_paths(&self) -> Result<Option<(&str, &str)>> {
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            (Some(_), None) => bail!("tls_cert_path is set but tls_key_path is not"),
+            (None, Some(_)) => bail!("tls_key_path is set but tls_cert_path is not"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_settings(extra: &str) -> Settings {
+        let contents = format!("{}\n{}", include_str!("../settings.toml"), extra);
+        toml::from_str::<Settings>(&contents).unwrap()
+    }
+
+    #[test]
+    fn test_optional_settings_default() {
+        let optional = [
+            "projection_cache_capacity",
+            "s3_prefix_by_cohort",
+            "num_workers",
+            "igwas_threads",
+            "compression_min_size",
+            "stream_gwas",
+            "rate_limit_burst",
+            "rate_limit_per_minute",
+        ];
+        let contents = include_str!("../settings.toml")
+            .lines()
+            .filter(|line| !optional.iter().any(|name| line.starts_with(name)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let settings = toml::from_str::<Settings>(&contents).unwrap();
+        assert!(!settings.stream_gwas);
+        assert!(!settings.s3_prefix_by_cohort);
+        // The other defaults are the values in the example settings
+        let example = toml::from_str::<Settings>(include_str!("../settings.toml")).unwrap();
+        assert_eq!(
+            settings.projection_cache_capacity,
+            example.projection_cache_capacity
+        );
+        assert_eq!(settings.num_workers, example.num_workers);
+        assert_eq!(settings.igwas_threads, example.igwas_threads);
+        assert_eq!(settings.compression_min_size, example.compression_min_size);
+        assert_eq!(settings.rate_limit_burst, example.rate_limit_burst);
+        assert_eq!(
+            settings.rate_limit_per_minute,
+            example.rate_limit_per_minute
+        );
+    }
+
+    #[test]
+    fn test_tls_paths() {
+        assert_eq!(parse_settings("").tls_paths().unwrap(), None);
+        let settings = parse_settings("tls_cert_path = \"cert.pem\"\ntls_key_path = \"key.pem\"");
+        assert_eq!(settings.tls_paths().unwrap(), Some(("cert.pem", "key.pem")));
+        assert!(parse_settings("tls_cert_path = \"cert.pem\"")
+            .tls_paths()
+            .is_err());
+    }
+
+    #[test]
+    fn test_api_key_client() {
+        assert!(parse_settings("").api_keys.is_empty());
+        let settings = parse_settings("[api_keys]\nlab = \"secret\"\nother = \"key2\"");
+        assert_eq!(settings.api_key_client("secret"), Some("lab"));
+        assert_eq!(settings.api_key_client("key2"), Some("other"));
+        assert_eq!(settings.api_key_client("lab"), None);
+        assert_eq!(settings.api_key_client(""), None);
+    }
+
+    #[test]
+    fn test_zip_file_options() {
+        assert!(parse_settings("").zip_file_options().is_ok());
+        let settings = parse_settings("zip_compression = \"zstd\"\nzip_compression_level = 19");
+        assert_eq!(settings.zip_compression, ZipCompression::Zstd);
+        assert!(settings.zip_file_options().is_ok());
+        let err = parse_settings("zip_compression_level = 10")
+            .zip_file_options()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "zip_compression_level must be between 0 and 9 for Deflated, got 10"
+        );
+        assert!(
+            parse_settings("zip_compression = \"stored\"\nzip_compression_level = 1")
+                .zip_file_options()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_destination_allowed() {
+        assert!(!parse_settings("").destination_allowed("webgwas"));
+        let settings = parse_settings("destination_buckets = [\"lab-results\"]");
+        assert!(settings.destination_allowed("lab-results"));
+        assert!(!settings.destination_allowed("lab"));
+    }
+
+    #[test]
+    fn test_cohort_allowed() {
+        assert!(parse_settings("").cohort_allowed(1, Some("ukb")));
+        let settings = parse_settings("allowed_cohorts = [2, \"ukb\"]");
+        assert_eq!(
+            settings.allowed_cohorts,
+            vec![AllowedCohort::Id(2), AllowedCohort::Name("ukb".to_string())]
+        );
+        assert!(settings.cohort_allowed(1, Some("ukb")));
+        assert!(settings.cohort_allowed(2, Some("other")));
+        assert!(!settings.cohort_allowed(3, Some("other")));
+        assert!(!settings.cohort_allowed(3, None));
+    }
+
+    #[test]
+    fn test_result_key() {
+        let id = Uuid::nil();
+        let settings = parse_settings("[s3_cohort_prefixes]\nukb = \"biobank\"");
+        assert_eq!(
+            settings.result_key("other", &id),
+            format!("results/{}.zip", id)
+        );
+        assert_eq!(
+            settings.result_key("ukb", &id),
+            format!("results/biobank/{}.zip", id)
+        );
+        let mut settings = parse_settings("");
+        settings.s3_prefix_by_cohort = true;
+        assert_eq!(
+            settings.result_key("other", &id),
+            format!("results/other/{}.zip", id)
+        );
+    }
 }
@@ -1,17 +1,143 @@
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
+use log::error;
+use serde::Serialize;
+use uuid::Uuid;
 
-pub struct WebGWASError(anyhow::Error);
+/// Stable, machine-readable reason for an error response
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The request body or query string doesn't have the expected shape
+    InvalidRequest,
+    InvalidPhenotype,
+    InvalidCovariates,
+    UnknownFeature,
+    CohortNotFound,
+    /// The cohort exists, but its data failed to load
+    CohortUnavailable,
+    RequestNotFound,
+    DefinitionNotFound,
+    /// The cohort already has a saved definition with the same name
+    DefinitionNameTaken,
+    /// The request exists, but its results aren't available (yet or anymore)
+    ResultNotAvailable,
+    BatchTooLarge,
+    /// A phenotype definition is longer than the server accepts
+    DefinitionTooLarge,
+    /// The request body is larger than the server accepts
+    PayloadTooLarge,
+    /// The request body isn't JSON, going by its `Content-Type`
+    UnsupportedMediaType,
+    /// A submission with the same `Idempotency-Key` is still running
+    IdempotencyKeyInUse,
+    RateLimited,
+    /// A valid API key is required but wasn't given
+    Unauthorized,
+    Forbidden,
+    AdminDisabled,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::InvalidPhenotype | ErrorCode::InvalidCovariates => StatusCode::BAD_REQUEST,
+            ErrorCode::UnknownFeature
+            | ErrorCode::CohortNotFound
+            | ErrorCode::RequestNotFound
+            | ErrorCode::DefinitionNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::CohortUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::ResultNotAvailable
+            | ErrorCode::DefinitionNameTaken
+            | ErrorCode::IdempotencyKeyInUse => StatusCode::CONFLICT,
+            ErrorCode::BatchTooLarge
+            | ErrorCode::DefinitionTooLarge
+            | ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            // Don't reveal that admin endpoints exist when they're turned off
+            ErrorCode::AdminDisabled => StatusCode::NOT_FOUND,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// JSON body of every error response
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub code: ErrorCode,
+    /// The request the error is about, for errors tied to a submitted request
+    pub request_id: Option<Uuid>,
+    /// Path to the offending field of the request (e.g. `cohort_id`), when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    /// Structured detail about the error, with a shape that depends on its code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl IntoResponse for ErrorResponse {
+    fn into_response(self) -> Response {
+        (self.code.status(), Json(self)).into_response()
+    }
+}
+
+/// Error returned by handlers. Errors converted with `?` are internal errors, while
+/// expected failures should be built with `WebGWASError::new` and a specific code.
+#[derive(Debug)]
+pub struct WebGWASError {
+    code: ErrorCode,
+    error: anyhow::Error,
+    request_id: Option<Uuid>,
+    details: Option<serde_json::Value>,
+}
+
+impl WebGWASError {
+    pub fn new(code: ErrorCode, error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            code,
+            error: error.into(),
+            request_id: None,
+            details: None,
+        }
+    }
+
+    pub fn with_request_id(mut self, request_id: Uuid) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+}
 
 impl IntoResponse for WebGWASError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        if self.code == ErrorCode::Internal {
+            error!("Internal error: {:#}", self.error);
+        }
+        ErrorResponse {
+            error: format!("{:#}", self.error),
+            code: self.code,
+            request_id: self.request_id,
+            field: None,
+            details: self.details,
+        }
+        .into_response()
     }
 }
 
@@ -22,6 +148,42 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        Self::new(ErrorCode::Internal, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn test_error_envelope() {
+        let request_id = Uuid::new_v4();
+        let response = WebGWASError::new(ErrorCode::CohortNotFound, anyhow!("Cohort 1"))
+            .with_request_id(request_id)
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "Cohort 1",
+                "code": "COHORT_NOT_FOUND",
+                "request_id": request_id,
+            })
+        );
+    }
+
+    #[test]
+    fn test_question_mark_is_internal() {
+        fn fails() -> Result<(), WebGWASError> {
+            Err::<(), _>(anyhow!("oops"))?;
+            Ok(())
+        }
+        assert_eq!(fails().unwrap_err().code(), ErrorCode::Internal);
     }
 }
@@ -0,0 +1,271 @@
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::errors::{ErrorCode, ErrorResponse};
+
+/// Error returned when a request body or query doesn't match the expected shape
+#[derive(Debug, Serialize)]
+pub struct SubmissionError {
+    /// Path to the offending field (e.g. `cohort_id`), when it can be determined
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub message: String,
+    /// Status of the response, which is 422 except when the body can't be read at all
+    #[serde(skip)]
+    pub status: StatusCode,
+}
+
+impl SubmissionError {
+    fn from_path_error<E: std::fmt::Display>(err: serde_path_to_error::Error<E>) -> Self {
+        let path = err.path().to_string();
+        let message = err.inner().to_string();
+        Self {
+            field: offending_field(&path, &message),
+            message,
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    /// Error for a body that axum rejected before it could be deserialized, keeping the
+    /// rejection's status (e.g. 413 for a body over the size limit)
+    fn from_rejection(status: StatusCode, message: String) -> Self {
+        Self {
+            field: None,
+            message,
+            status,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self.status {
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorCode::UnsupportedMediaType,
+            _ => ErrorCode::InvalidRequest,
+        }
+    }
+}
+
+impl IntoResponse for SubmissionError {
+    fn into_response(self) -> Response {
+        let status = self.status;
+        let mut response = ErrorResponse {
+            code: self.code(),
+            error: self.message,
+            request_id: None,
+            field: self.field,
+            details: None,
+        }
+        .into_response();
+        *response.status_mut() = status;
+        response
+    }
+}
+
+/// Find the field responsible for a deserialization error. Missing fields are reported
+/// at their parent's path, so the name is taken from the message instead.
+fn offending_field(path: &str, message: &str) -> Option<String> {
+    if let Some(rest) = message.strip_prefix("missing field `") {
+        let name = rest.split('`').next()?;
+        return match path {
+            "." => Some(name.to_string()),
+            _ => Some(format!("{}.{}", path, name)),
+        };
+    }
+    match path {
+        "." => None,
+        _ => Some(path.to_string()),
+    }
+}
+
+/// Whether the request declares a JSON body (`application/json` or `application/*+json`),
+/// which axum's `Json` extractor also requires
+fn has_json_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match essence.strip_prefix("application/") {
+        Some(subtype) => subtype == "json" || subtype.ends_with("+json"),
+        None => false,
+    }
+}
+
+/// JSON body extractor that rejects malformed bodies with a 422 naming the bad field.
+/// Bodies without a JSON `Content-Type` get a 415, and bodies over the size limit a 413.
+pub struct ValidJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ValidJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = SubmissionError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !has_json_content_type(req.headers()) {
+            return Err(SubmissionError::from_rejection(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected request with `Content-Type: application/json`".to_string(),
+            ));
+        }
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|err| SubmissionError::from_rejection(err.status(), err.body_text()))?;
+        let deserializer = &mut serde_json::Deserializer::from_slice(&bytes);
+        let value = serde_path_to_error::deserialize(deserializer)
+            .map_err(SubmissionError::from_path_error)?;
+        Ok(ValidJson(value))
+    }
+}
+
+/// Query string extractor that rejects malformed queries with a 422 naming the bad field
+pub struct ValidQuery<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for ValidQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = SubmissionError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let deserializer =
+            serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+        let value = serde_path_to_error::deserialize(deserializer)
+            .map_err(SubmissionError::from_path_error)?;
+        Ok(ValidQuery(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{GetFeaturesRequest, PhenotypeSummaryRequest, WebGWASRequest};
+    use axum::body::Body;
+
+    async fn parse_body<T: DeserializeOwned>(body: &'static str) -> Result<T, SubmissionError> {
+        let request = Request::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        ValidJson::<T>::from_request(request, &())
+            .await
+            .map(|ValidJson(value)| value)
+    }
+
+    #[tokio::test]
+    async fn test_valid_json() {
+        let request =
+            parse_body::<WebGWASRequest>(r#"{"phenotype_definition": "\"a\"", "cohort_id": 1}"#)
+                .await
+                .unwrap();
+        assert_eq!(request.cohort_id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_missing_field() {
+        let err = parse_body::<WebGWASRequest>(r#"{"phenotype_definition": "\"a\""}"#)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.field.as_deref(), Some("cohort_id"));
+    }
+
+    #[tokio::test]
+    async fn test_wrong_type() {
+        let err = parse_body::<PhenotypeSummaryRequest>(
+            r#"{"phenotype_definition": "\"a\"", "cohort_id": 1, "n_samples": "many"}"#,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.field.as_deref(), Some("n_samples"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_json() {
+        let err = parse_body::<WebGWASRequest>("not json")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.field, None);
+        assert_eq!(
+            err.into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_content_type() {
+        let parse = |content_type: Option<&'static str>| async move {
+            let mut builder = Request::builder();
+            if let Some(content_type) = content_type {
+                builder = builder.header(header::CONTENT_TYPE, content_type);
+            }
+            let request = builder.body(Body::from(r#"{"cohort_id": 1}"#)).unwrap();
+            ValidJson::<GetFeaturesRequest>::from_request(request, &())
+                .await
+                .map(|_| ())
+        };
+        assert!(parse(Some("application/json; charset=utf-8")).await.is_ok());
+        assert!(parse(Some("application/cloudevents+json")).await.is_ok());
+        for content_type in [None, Some("text/json"), Some("text/plain")] {
+            let err = parse(content_type).await.unwrap_err();
+            assert_eq!(err.code(), ErrorCode::UnsupportedMediaType);
+            assert_eq!(
+                err.into_response().status(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large() {
+        // Over axum's default limit of 2 MB
+        let padding = "a".repeat(3 * 1024 * 1024);
+        let request = Request::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(
+                r#"{{"cohort_id": 1, "pad": "{}"}}"#,
+                padding
+            )))
+            .unwrap();
+        let err = ValidJson::<GetFeaturesRequest>::from_request(request, &())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), ErrorCode::PayloadTooLarge);
+        assert_eq!(err.into_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_query_wrong_type() {
+        let request = Request::builder()
+            .uri("/api/features?cohort_id=abc")
+            .body(Body::empty())
+            .unwrap();
+        let (mut parts, _) = request.into_parts();
+        let err = ValidQuery::<GetFeaturesRequest>::from_request_parts(&mut parts, &())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.field.as_deref(), Some("cohort_id"));
+    }
+}
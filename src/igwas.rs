@@ -1,15 +1,22 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use faer::Col;
 use faer_ext::polars::polars_to_faer_f32;
 use itertools::izip;
-use log::debug;
+use log::{debug, warn};
 use polars::prelude::*;
-use statrs::distribution::{ContinuousCDF, StudentsT};
-use std::{fs::File, path::Path};
+use serde::Deserialize;
+use statrs::distribution::{ChiSquared, ContinuousCDF, StudentsT};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
 
-use crate::utils::{slice_after_excl, slice_before, slice_before_excl};
+use crate::models::{scan_cohort_file, PvalueAdjustment};
+use crate::utils::{load_optional_toml, slice_after_excl, slice_before, slice_before_excl};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Projection {
     pub feature_id: Vec<String>,
     pub feature_coefficient: Col<f32>,
@@ -111,6 +118,17 @@ impl Projection {
     }
 }
 
+/// Write a projection as parquet, with `feature_id` and `coefficient` columns
+pub fn projection_to_parquet(projection: &Projection) -> Result<Vec<u8>> {
+    let mut df = df!(
+        "feature_id" => &projection.feature_id,
+        "coefficient" => projection.feature_coefficient.iter().copied().collect::<Vec<f32>>(),
+    )?;
+    let mut bytes = Vec::new();
+    ParquetWriter::new(&mut bytes).finish(&mut df)?;
+    Ok(bytes)
+}
+
 pub fn indirect_std_error(
     projection_variance: f32,
     indirect_genotype_variance: f32,
@@ -160,7 +178,12 @@ pub struct ResultStats {
     pub std_error: Vec<f32>,
     pub t_stat: Vec<f32>,
     pub neg_log_p_value: Vec<f32>,
+    /// Samples used in each variant's test, written as the `sample_size` column. This comes
+    /// from the variant's degrees of freedom in the cohort GWAS, so variants with more
+    /// missing genotypes have a smaller sample size.
     pub sample_size: Vec<i32>,
+    /// Number of variants whose statistics weren't finite (see `compute_batch_results`)
+    pub n_failed: usize,
 }
 
 pub struct FeatureStats {
@@ -170,6 +193,258 @@ pub struct FeatureStats {
     pub degrees_of_freedom: Int32Chunked,
 }
 
+/// Columns every GWAS dataframe must contain, along with their expected types
+fn required_gwas_columns() -> [(&'static str, DataType); 5] {
+    [
+        ("variant_id", DataType::String),
+        ("a1", DataType::String),
+        ("a2", DataType::String),
+        ("degrees_of_freedom", DataType::Int32),
+        ("genotype_partial_variance", DataType::Float32),
+    ]
+}
+
+/// Names of the required GWAS columns in a cohort's GWAS file, read from an optional
+/// `gwas_columns.toml` in the cohort's directory for files that don't use the usual names.
+/// Columns are renamed to the usual names as they're read.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GwasColumns {
+    pub variant_id: String,
+    pub a1: String,
+    pub a2: String,
+    pub degrees_of_freedom: String,
+    pub genotype_partial_variance: String,
+    /// Only needed for plot data (see `validate_plot_columns`)
+    pub chromosome: String,
+    pub position: String,
+}
+
+impl Default for GwasColumns {
+    fn default() -> Self {
+        Self {
+            variant_id: "variant_id".to_string(),
+            a1: "a1".to_string(),
+            a2: "a2".to_string(),
+            degrees_of_freedom: "degrees_of_freedom".to_string(),
+            genotype_partial_variance: "genotype_partial_variance".to_string(),
+            chromosome: "chromosome".to_string(),
+            position: "position".to_string(),
+        }
+    }
+}
+
+impl GwasColumns {
+    /// Read a cohort's GWAS column names, which are the usual names for a cohort without
+    /// the file
+    pub fn load(cohort_root: &Path) -> Result<Self> {
+        load_optional_toml(&cohort_root.join("gwas_columns.toml"))
+    }
+
+    /// Pairs of the usual name and this file's name, in `required_gwas_columns` order
+    fn names(&self) -> [(&'static str, &str); 5] {
+        [
+            ("variant_id", &self.variant_id),
+            ("a1", &self.a1),
+            ("a2", &self.a2),
+            ("degrees_of_freedom", &self.degrees_of_freedom),
+            ("genotype_partial_variance", &self.genotype_partial_variance),
+        ]
+    }
+
+    /// Pairs of the usual name and this file's name for columns the file may not have
+    fn optional_names(&self) -> [(&'static str, &str); 2] {
+        [
+            ("chromosome", &self.chromosome),
+            ("position", &self.position),
+        ]
+    }
+
+    /// Rename a dataframe's columns from this file's names to the usual names. Errors
+    /// listing every required column the dataframe doesn't have, by this file's name.
+    pub fn to_canonical(&self, mut gwas_df: DataFrame) -> Result<DataFrame> {
+        let schema = gwas_df.schema();
+        let missing = self
+            .names()
+            .iter()
+            .filter(|(_, name)| schema.get(name).is_none())
+            .map(|(_, name)| name.to_string())
+            .collect::<Vec<String>>();
+        if !missing.is_empty() {
+            bail!("GWAS data is missing columns: {}", missing.join(", "));
+        }
+        let optional = self
+            .optional_names()
+            .into_iter()
+            .filter(|(_, name)| schema.get(name).is_some());
+        for (canonical, name) in self.names().into_iter().chain(optional) {
+            if canonical != name {
+                gwas_df.rename(name, canonical.into()).context(anyhow!(
+                    "Failed to rename GWAS column {} to {}",
+                    name,
+                    canonical
+                ))?;
+            }
+        }
+        Ok(gwas_df)
+    }
+}
+
+/// Check that a GWAS dataframe has the columns needed by `compute_batch_stats`
+pub fn validate_gwas_columns(gwas_df: &DataFrame) -> Result<()> {
+    let schema = gwas_df.schema();
+    let missing = required_gwas_columns()
+        .iter()
+        .filter(|(name, _)| schema.get(name).is_none())
+        .map(|(name, _)| name.to_string())
+        .collect::<Vec<String>>();
+    if !missing.is_empty() {
+        bail!("GWAS data is missing columns: {}", missing.join(", "));
+    }
+    for (name, dtype) in required_gwas_columns() {
+        let actual = schema.get(name).unwrap();
+        if *actual != dtype {
+            bail!(
+                "GWAS column {} has type {}, expected {}",
+                name,
+                actual,
+                dtype
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Columns of the plot data, in order, which are kept from the results
+const PLOT_DATA_COLUMNS: [&str; 3] = ["chromosome", "position", "neg_log_p_value"];
+
+/// Check that a GWAS dataframe has the variant positions needed for plot data
+pub fn validate_plot_columns(gwas_df: &DataFrame) -> Result<()> {
+    let schema = gwas_df.schema();
+    let missing = ["chromosome", "position"]
+        .into_iter()
+        .filter(|name| schema.get(name).is_none())
+        .collect::<Vec<&str>>();
+    if !missing.is_empty() {
+        bail!(
+            "GWAS data is missing columns needed for plot data: {}",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Names of the columns in results computed from a GWAS dataframe with the given columns,
+/// including the adjusted p-value columns that could be added
+pub fn result_column_names(gwas_columns: &[String]) -> Vec<String> {
+    let mut names = slice_before_excl(gwas_columns, &"degrees_of_freedom".to_string());
+    names.extend(
+        [
+            "beta",
+            "std_error",
+            "t_stat",
+            "neg_log_p_value",
+            "sample_size",
+        ]
+        .into_iter()
+        .chain(
+            [
+                PvalueAdjustment::Bonferroni,
+                PvalueAdjustment::BenjaminiHochberg,
+            ]
+            .map(|method| method.column_name()),
+        )
+        .map(|x| x.to_string()),
+    );
+    names
+}
+
+/// A cohort's variant annotations (e.g. gene and consequence), which stay on disk between
+/// runs. Each run that writes them reads the file once (see `Annotations::read`).
+pub struct Annotations {
+    path: PathBuf,
+}
+
+impl Annotations {
+    /// Refer to the annotations file at `path`, checking that it can be joined into results
+    /// with the given columns: it needs a string `variant_id` column with at most one row
+    /// per variant, and no other column may share a name with a result column. Only the
+    /// `variant_id` column is read.
+    pub fn load(path: &Path, result_columns: &[String]) -> Result<Self> {
+        let schema = scan_cohort_file(path)?.collect_schema()?;
+        let dtype = schema
+            .get("variant_id")
+            .context("Annotations are missing the variant_id column")?;
+        if *dtype != DataType::String {
+            bail!(
+                "Annotation column variant_id has type {}, expected {}",
+                dtype,
+                DataType::String
+            );
+        }
+        let colliding = schema
+            .iter_names()
+            .filter(|name| name.as_str() != "variant_id")
+            .filter(|name| result_columns.iter().any(|x| x == name.as_str()))
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>();
+        if !colliding.is_empty() {
+            bail!(
+                "Annotation columns have the same names as result columns: {}",
+                colliding.join(", ")
+            );
+        }
+        let variant_ids = scan_cohort_file(path)?
+            .select([col("variant_id")])
+            .collect()?;
+        let variant_id = variant_ids.column("variant_id")?;
+        if variant_id.as_materialized_series().n_unique()? != variant_ids.height() {
+            bail!("Annotations have more than one row for some variants");
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Read every annotation, keyed by variant, so that each chunk of results looks up its
+    /// own rows rather than scanning the file again
+    pub fn read(&self) -> Result<VariantAnnotations> {
+        let df = scan_cohort_file(&self.path)?.collect()?;
+        let rows = df
+            .column("variant_id")?
+            .str()?
+            .iter()
+            .enumerate()
+            .filter_map(|(i, id)| id.map(|id| (id.to_string(), i as IdxSize)))
+            .collect();
+        Ok(VariantAnnotations {
+            df: df.drop("variant_id")?,
+            rows,
+        })
+    }
+}
+
+/// Annotations read into memory for one run (see `Annotations::read`)
+pub struct VariantAnnotations {
+    /// Every annotation column except `variant_id`
+    df: DataFrame,
+    /// Row of `df` for each annotated variant
+    rows: HashMap<String, IdxSize>,
+}
+
+impl VariantAnnotations {
+    /// The annotation columns of the variants in `variant_id`, in its order, with nulls for
+    /// variants that aren't annotated
+    pub fn for_variants(&self, variant_id: &Column) -> Result<DataFrame> {
+        let indices = variant_id
+            .str()?
+            .iter()
+            .map(|id| id.and_then(|id| self.rows.get(id).copied()))
+            .collect::<IdxCa>();
+        Ok(self.df.take(&indices)?)
+    }
+}
+
 pub fn compute_batch_stats(df: &DataFrame, projection: &mut Projection) -> Result<RunningStats> {
     let columns = df
         .get_column_names()
@@ -247,12 +522,16 @@ pub fn compute_batch_stats(df: &DataFrame, projection: &mut Projection) -> Resul
     })
 }
 
+/// Compute indirect summary statistics. Degenerate variants (e.g. with a negative
+/// residual variance or zero genotype variance) don't stop the rest from being computed.
+/// Instead, their standard error, t statistic, and p-value are NaN, and they're counted
+/// in `n_failed`.
 pub fn compute_batch_results(
     running_stats: RunningStats,
     projection_variance: f32,
     n_covariates: usize,
 ) -> Result<ResultStats> {
-    let std_error: Vec<f32> = izip!(
+    let mut std_error: Vec<f32> = izip!(
         &running_stats.beta,
         &running_stats.genotype_variance,
         &running_stats.degrees_of_freedom
@@ -267,19 +546,40 @@ pub fn compute_batch_results(
     })
     .collect();
 
-    let t_stat: Vec<f32> = running_stats
+    let mut t_stat: Vec<f32> = running_stats
         .beta
         .iter()
         .zip(std_error.iter())
         .map(|(beta, std_error)| beta / std_error)
         .collect();
 
-    let neg_log_p_value: Vec<f32> = t_stat
+    let mut neg_log_p_value: Vec<f32> = t_stat
         .iter()
         .zip(running_stats.degrees_of_freedom.iter())
         .map(|(&t_stat, &dof)| compute_neg_log_pvalue(t_stat, dof))
         .collect();
 
+    // An infinite -log10 p-value is a p-value too small for f64, so it isn't a failure
+    let mut n_failed = 0;
+    for (beta, std_error, t_stat, neg_log_p_value) in izip!(
+        &running_stats.beta,
+        &mut std_error,
+        &mut t_stat,
+        &mut neg_log_p_value
+    ) {
+        if !beta.is_finite()
+            || !std_error.is_finite()
+            || !t_stat.is_finite()
+            || neg_log_p_value.is_nan()
+        {
+            *std_error = f32::NAN;
+            *t_stat = f32::NAN;
+            *neg_log_p_value = f32::NAN;
+            n_failed += 1;
+        }
+    }
+
+    // The feature GWAS fit an intercept, the genotype, and the covariates
     let sample_size: Vec<i32> = running_stats
         .degrees_of_freedom
         .iter()
@@ -296,6 +596,7 @@ pub fn compute_batch_results(
         t_stat,
         neg_log_p_value,
         sample_size,
+        n_failed,
     })
 }
 
@@ -317,45 +618,491 @@ pub fn results_to_dataframe(result_stats: ResultStats) -> Result<DataFrame> {
     Ok(df)
 }
 
-pub fn write_dataframe(
-    df: &mut DataFrame,
-    path: &Path,
-    n_threads: usize,
-    compress: bool,
-) -> Result<()> {
-    let file = File::create(path)?;
-    if compress {
-        let compressed_writer = zstd::Encoder::new(file, 3)?.auto_finish();
-        CsvWriter::new(compressed_writer)
-            .with_separator(b'\t')
-            .n_threads(n_threads)
-            .finish(df)?;
-    } else {
-        CsvWriter::new(file)
-            .with_separator(b'\t')
-            .n_threads(n_threads)
-            .finish(df)?;
+/// Where and how to write the results of an indirect GWAS
+pub struct ResultsOutput<'a> {
+    /// The format of the results is chosen by the extension of the path
+    pub path: &'a Path,
+    pub n_threads: usize,
+    /// Abort if the results, or the plot data, would be larger than this many bytes
+    pub max_bytes: Option<u64>,
+    /// Add a column of -log10 p-values adjusted with this method
+    pub pvalue_adjustment: Option<PvalueAdjustment>,
+    /// Only write variants with a p-value below this threshold
+    pub p_threshold: Option<f32>,
+    /// Variant annotations to add to the results, matched by `variant_id`. Variants without
+    /// annotations get nulls.
+    pub annotations: Option<&'a Annotations>,
+    /// Also write plot data (see `PLOT_DATA_COLUMNS`) to this path as parquet, with a row
+    /// per row of the results, which needs the GWAS data to have variant positions
+    pub plot_data_path: Option<&'a Path>,
+}
+
+/// Writer that refuses to write more than `limit` bytes in total
+struct SizeLimitedWriter<W> {
+    inner: W,
+    limit: Option<u64>,
+    written: u64,
+    exceeded: bool,
+}
+
+impl SizeLimitedWriter<BufWriter<File>> {
+    fn create(path: &Path, limit: Option<u64>) -> Result<Self> {
+        Ok(Self {
+            inner: BufWriter::new(File::create(path)?),
+            limit,
+            written: 0,
+            exceeded: false,
+        })
+    }
+}
+
+impl<W: Write> Write for SizeLimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(limit) = self.limit {
+            if self.written + buf.len() as u64 > limit {
+                self.exceeded = true;
+                return Err(std::io::Error::other("maximum result size exceeded"));
+            }
+        }
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write results in the format matching the file extension: Arrow IPC for `.arrow`, and
+/// tab-separated text otherwise, with plot data if it's requested. Batches are computed
+/// and written one at a time, so only one is held in memory, and they must share a schema.
+/// Returns the number of variants written. If computing or writing a batch fails,
+/// including by either file exceeding the size limit, the partial files are removed.
+pub fn write_results<I>(batches: I, output: &ResultsOutput) -> Result<usize>
+where
+    I: IntoIterator<Item = Result<DataFrame>>,
+{
+    let mut writer = SizeLimitedWriter::create(output.path, output.max_bytes)?;
+    let mut plot_writer = None;
+    let mut n_written = 0;
+    let result = write_results_batches(
+        batches,
+        output,
+        &mut writer,
+        &mut plot_writer,
+        &mut n_written,
+    )
+    .and_then(|()| Ok(writer.flush()?));
+    if let Err(err) = result {
+        // Failing to clean up shouldn't hide why writing failed
+        for path in std::iter::once(output.path).chain(output.plot_data_path) {
+            if let Err(remove_err) = std::fs::remove_file(path) {
+                if remove_err.kind() != std::io::ErrorKind::NotFound {
+                    warn!(
+                        "Failed to remove partial results file {}: {}",
+                        path.display(),
+                        remove_err
+                    );
+                }
+            }
+        }
+        if writer.exceeded {
+            bail!(
+                "Results exceed the maximum size of {} bytes (aborted after writing {} bytes \
+                for the first {} variants)",
+                output.max_bytes.unwrap_or_default(),
+                writer.written,
+                n_written
+            );
+        }
+        if let Some(plot_writer) = plot_writer.filter(|writer| writer.exceeded) {
+            bail!(
+                "Plot data exceed the maximum size of {} bytes (aborted after writing {} bytes \
+                for the first {} variants)",
+                output.max_bytes.unwrap_or_default(),
+                plot_writer.written,
+                n_written
+            );
+        }
+        return Err(err);
+    }
+    Ok(n_written)
+}
+
+/// Batched writer for the format of the results file
+enum ResultsBatchWriter<W: Write> {
+    Ipc(polars::io::ipc::BatchedWriter<W>),
+    Csv(polars::io::csv::write::BatchedWriter<W>),
+}
+
+impl<W: Write> ResultsBatchWriter<W> {
+    fn new(output: &ResultsOutput, writer: W, schema: &Schema) -> Result<Self> {
+        Ok(match output.path.extension().and_then(|x| x.to_str()) {
+            Some("arrow") => Self::Ipc(IpcWriter::new(writer).batched(schema)?),
+            _ => Self::Csv(
+                CsvWriter::new(writer)
+                    .with_separator(b'\t')
+                    .n_threads(output.n_threads)
+                    .batched(schema)?,
+            ),
+        })
+    }
+
+    fn write_batch(&mut self, df: &DataFrame) -> PolarsResult<()> {
+        match self {
+            Self::Ipc(writer) => writer.write_batch(df),
+            Self::Csv(writer) => writer.write_batch(df),
+        }
+    }
+
+    fn finish(&mut self) -> PolarsResult<()> {
+        match self {
+            Self::Ipc(writer) => writer.finish(),
+            Self::Csv(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Write each batch of results, and its plot data, counting the variants in each batch
+/// that was written completely. The plot data file is created in `plot_file`, with the
+/// same size limit as the results.
+fn write_results_batches<I, W>(
+    batches: I,
+    output: &ResultsOutput,
+    writer: W,
+    plot_file: &mut Option<SizeLimitedWriter<BufWriter<File>>>,
+    n_written: &mut usize,
+) -> Result<()>
+where
+    I: IntoIterator<Item = Result<DataFrame>>,
+    W: Write,
+{
+    let mut batches = batches.into_iter();
+    // The writers need a schema up front, which is taken from the first batch
+    let first = batches.next().context("No results computed")??;
+    let mut results_writer = ResultsBatchWriter::new(output, writer, &first.schema())?;
+    if let Some(path) = output.plot_data_path {
+        *plot_file = Some(SizeLimitedWriter::create(path, output.max_bytes)?);
+    }
+    let mut plot_writer = match plot_file.as_mut() {
+        Some(plot_file) => Some(
+            ParquetWriter::new(plot_file).batched(&first.select(PLOT_DATA_COLUMNS)?.schema())?,
+        ),
+        None => None,
+    };
+    for batch in std::iter::once(Ok(first)).chain(batches) {
+        let mut batch = batch?;
+        // Batched writers need the columns' chunks to line up
+        batch.align_chunks();
+        results_writer.write_batch(&batch)?;
+        if let Some(plot_writer) = plot_writer.as_mut() {
+            plot_writer.write_batch(&batch.select(PLOT_DATA_COLUMNS)?)?;
+        }
+        *n_written += batch.height();
+    }
+    results_writer.finish()?;
+    if let Some(plot_writer) = plot_writer {
+        plot_writer.finish()?;
+    }
+    if let Some(plot_file) = plot_file.as_mut() {
+        plot_file.flush()?;
     }
     Ok(())
 }
 
-pub fn run_igwas_df_impl(
-    gwas_df: &DataFrame,
+/// Summary of an indirect GWAS run
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IgwasSummary {
+    /// Number of variants tested
+    pub n_tested: usize,
+    /// Number of variants dropped because they were missing GWAS statistics
+    pub n_dropped: usize,
+    /// Number of tested variants whose statistics couldn't be computed, which are in the
+    /// results with NaN p-values
+    pub n_failed: usize,
+    /// Genomic inflation factor, absent when no variant has a p-value
+    pub lambda_gc: Option<f32>,
+    /// Method of the adjusted p-value column, if one was added
+    pub pvalue_adjustment: Option<PvalueAdjustment>,
+    /// Threshold the written variants were filtered by, if any
+    pub p_threshold: Option<f32>,
+    /// Number of variants below `p_threshold`, which are the only ones written
+    pub n_below_threshold: Option<usize>,
+}
+
+/// Compute the genomic inflation factor (lambda GC), the median chi-square statistic
+/// divided by its expected median under the null. The chi-square statistic is a
+/// decreasing function of the p-value, so its median is that of the median p-value.
+/// NaN p-values are ignored, and `None` is returned if there are none left.
+pub fn compute_lambda_gc(neg_log_p_values: &[f32]) -> Option<f32> {
+    let mut pvalues = neg_log_p_values
+        .iter()
+        .filter(|x| !x.is_nan())
+        .map(|x| 10_f64.powf(-*x as f64))
+        .collect::<Vec<f64>>();
+    if pvalues.is_empty() {
+        return None;
+    }
+    pvalues.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = pvalues.len() / 2;
+    let median_p = if pvalues.len() % 2 == 0 {
+        (pvalues[mid - 1] + pvalues[mid]) / 2.0
+    } else {
+        pvalues[mid]
+    };
+    let chi_squared = ChiSquared::new(1.0).unwrap();
+    let lambda = chi_squared.inverse_cdf(1.0 - median_p) / chi_squared.inverse_cdf(0.5);
+    Some(lambda as f32)
+}
+
+/// Adjust -log10 p-values for multiple testing, returning -log10 adjusted p-values. Working
+/// on the -log10 scale means the p-values of strong hits can't underflow to 0. NaN p-values
+/// stay NaN and don't count towards the number of tests. Adjusted p-values are capped at
+/// 1, so the results are at least 0.
+pub fn adjust_pvalues(neg_log_p_values: &[f32], method: PvalueAdjustment) -> Vec<f32> {
+    let n_tests = neg_log_p_values.iter().filter(|x| !x.is_nan()).count() as f64;
+    let mut adjusted = vec![f32::NAN; neg_log_p_values.len()];
+    match method {
+        PvalueAdjustment::Bonferroni => {
+            let log_n_tests = n_tests.log10();
+            for (adjusted, x) in adjusted.iter_mut().zip(neg_log_p_values.iter()) {
+                // `max` would turn NaN into 0
+                if !x.is_nan() {
+                    *adjusted = (*x as f64 - log_n_tests).max(0.0) as f32;
+                }
+            }
+        }
+        PvalueAdjustment::BenjaminiHochberg => {
+            // Going from the largest p-value down, each adjusted p-value is the smallest
+            // p * n / rank among those at least as large, keeping them monotone in p
+            let mut order = (0..neg_log_p_values.len())
+                .filter(|&i| !neg_log_p_values[i].is_nan())
+                .collect::<Vec<usize>>();
+            order.sort_by(|&a, &b| neg_log_p_values[b].total_cmp(&neg_log_p_values[a]));
+            let mut running_max = 0.0_f64;
+            for (rank, &i) in order.iter().enumerate().rev() {
+                let scale = (n_tests / (rank + 1) as f64).log10();
+                running_max = running_max.max(neg_log_p_values[i] as f64 - scale);
+                adjusted[i] = running_max as f32;
+            }
+        }
+    }
+    adjusted
+}
+
+/// Drop variants missing degrees of freedom, genotype variance, or any feature beta, since
+/// these are needed to compute indirect summary statistics
+pub fn drop_incomplete_variants(gwas_df: &DataFrame) -> Result<(DataFrame, usize)> {
+    let columns = gwas_df
+        .get_column_names()
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>();
+    let info_and_ids = slice_before_excl(&columns, &"degrees_of_freedom".to_string());
+    let stat_columns = columns
+        .iter()
+        .filter(|x| !info_and_ids.contains(x))
+        .cloned()
+        .collect::<Vec<String>>();
+    let has_nulls = stat_columns.iter().any(|x| {
+        gwas_df
+            .column(x)
+            .map(|c| c.null_count() > 0)
+            .unwrap_or(false)
+    });
+    if !has_nulls {
+        return Ok((gwas_df.clone(), 0));
+    }
+    let complete_df = gwas_df.drop_nulls(Some(&stat_columns))?;
+    let n_dropped = gwas_df.height() - complete_df.height();
+    Ok((complete_df, n_dropped))
+}
+
+/// A cohort's GWAS summary statistics, either held in memory or read from disk a chunk at
+/// a time, so that peak memory is one chunk per running request rather than the whole file
+pub enum GwasData {
+    /// Data with the usual column names (see `GwasColumns::to_canonical`)
+    InMemory(DataFrame),
+    OnDisk {
+        path: PathBuf,
+        n_variants: usize,
+        columns: GwasColumns,
+    },
+}
+
+impl GwasData {
+    /// Refer to the GWAS file at `path`, only reading enough of it to count the variants.
+    /// Its columns are renamed with `columns` whenever a chunk is read.
+    pub fn on_disk(path: &Path, columns: GwasColumns) -> Result<Self> {
+        let n_variants = scan_cohort_file(path)?
+            .select([len()])
+            .collect()?
+            .column("len")?
+            .cast(&DataType::UInt64)?
+            .u64()?
+            .get(0)
+            .context("Failed to count GWAS variants")?;
+        Ok(Self::OnDisk {
+            path: path.to_path_buf(),
+            n_variants: n_variants as usize,
+            columns,
+        })
+    }
+
+    /// Number of variants
+    pub fn height(&self) -> usize {
+        match self {
+            GwasData::InMemory(df) => df.height(),
+            GwasData::OnDisk { n_variants, .. } => *n_variants,
+        }
+    }
+
+    /// The `length` variants starting at `offset`, or fewer at the end of the data
+    pub fn slice(&self, offset: usize, length: usize) -> Result<DataFrame> {
+        match self {
+            GwasData::InMemory(df) => Ok(df.slice(offset as i64, length)),
+            GwasData::OnDisk { path, columns, .. } => columns.to_canonical(
+                scan_cohort_file(path)?
+                    .slice(offset as i64, length as IdxSize)
+                    .collect()?,
+            ),
+        }
+    }
+}
+
+/// Number of variants processed between progress updates
+pub const VARIANT_CHUNK_SIZE: usize = 100_000;
+
+/// Results of the chunk of variants starting at `offset`, with the number of variants
+/// dropped for missing statistics and the number whose statistics couldn't be computed
+fn compute_chunk_results(
+    gwas: &GwasData,
+    offset: usize,
     projection: &mut Projection,
     projection_variance: f32,
     n_covariates: usize,
-    output_path: &Path,
-    n_threads: usize,
-) -> Result<()> {
+) -> Result<(DataFrame, usize, usize)> {
+    let (chunk_df, n_dropped) = drop_incomplete_variants(&gwas.slice(offset, VARIANT_CHUNK_SIZE)?)?;
     debug!("Computing batch stats");
-    let running_stats = compute_batch_stats(gwas_df, projection)?;
+    let running_stats = compute_batch_stats(&chunk_df, projection)?;
     debug!("Computing batch results");
     let result_stats = compute_batch_results(running_stats, projection_variance, n_covariates)?;
+    let n_failed = result_stats.n_failed;
     debug!("Converting results to dataframe");
-    let mut results_df = results_to_dataframe(result_stats)?;
+    Ok((results_to_dataframe(result_stats)?, n_dropped, n_failed))
+}
+
+/// The -log10 p-values of a chunk of results, with NaN for missing values
+fn neg_log_p_values(results_df: &DataFrame) -> Result<Vec<f32>> {
+    Ok(results_df
+        .column("neg_log_p_value")?
+        .f32()?
+        .iter()
+        .map(|x| x.unwrap_or(f32::NAN))
+        .collect())
+}
+
+/// Run indirect GWAS over the variants in chunks, reporting the fraction of work done to
+/// `progress_callback` after each chunk. Only one chunk of `gwas`, and of the results, is
+/// held in memory at a time: each is written as soon as it's computed.
+pub fn run_igwas_df_impl<F>(
+    gwas: &GwasData,
+    projection: &mut Projection,
+    projection_variance: f32,
+    n_covariates: usize,
+    output: &ResultsOutput,
+    progress_callback: F,
+) -> Result<IgwasSummary>
+where
+    F: Fn(f32),
+{
+    let n_variants = gwas.height();
+    let offsets = (0..n_variants.max(1)).step_by(VARIANT_CHUNK_SIZE);
+    if output.plot_data_path.is_some() {
+        // Checked first, so a GWAS can't be computed only to fail at the end
+        validate_plot_columns(&gwas.slice(0, 0)?)?;
+    }
+    let n_passes = if output.pvalue_adjustment.is_some() {
+        2
+    } else {
+        1
+    };
+    let report_progress = |pass: usize, offset: usize| {
+        let n_done = pass * n_variants + (offset + VARIANT_CHUNK_SIZE).min(n_variants);
+        progress_callback(if n_variants == 0 {
+            1.0
+        } else {
+            n_done as f32 / (n_passes * n_variants) as f32
+        });
+    };
+    // Adjustment needs the p-values of every variant before any results are written, so
+    // they're computed in a first pass that keeps only the p-values, and the results are
+    // computed again in a second pass that writes them
+    let adjusted = match output.pvalue_adjustment {
+        Some(method) => {
+            let mut all_neg_log_p_values = Vec::with_capacity(n_variants);
+            for offset in offsets.clone() {
+                let (chunk_results_df, _, _) = compute_chunk_results(
+                    gwas,
+                    offset,
+                    projection,
+                    projection_variance,
+                    n_covariates,
+                )?;
+                all_neg_log_p_values.extend(neg_log_p_values(&chunk_results_df)?);
+                report_progress(0, offset);
+            }
+            Some((method, adjust_pvalues(&all_neg_log_p_values, method)))
+        }
+        None => None,
+    };
+    // Filtered after adjustment and lambda GC, which need the p-values of every variant.
+    // Failed variants have NaN p-values, so they never pass.
+    let min_neg_log_p_value = output.p_threshold.map(|threshold| -threshold.log10());
+    let annotations = output.annotations.map(Annotations::read).transpose()?;
+    let mut all_neg_log_p_values = Vec::with_capacity(n_variants);
+    let mut n_dropped = 0;
+    let mut n_failed = 0;
+    let chunks = offsets.map(|offset| {
+        let (mut chunk_results_df, n_chunk_dropped, n_chunk_failed) =
+            compute_chunk_results(gwas, offset, projection, projection_variance, n_covariates)?;
+        n_dropped += n_chunk_dropped;
+        n_failed += n_chunk_failed;
+        let chunk_neg_log_p_values = neg_log_p_values(&chunk_results_df)?;
+        if let Some((method, adjusted)) = &adjusted {
+            let start = all_neg_log_p_values.len();
+            let chunk_adjusted = &adjusted[start..start + chunk_results_df.height()];
+            chunk_results_df
+                .with_column(Column::new(method.column_name().into(), chunk_adjusted))?;
+        }
+        if let Some(min_neg_log_p_value) = min_neg_log_p_value {
+            let mask = chunk_neg_log_p_values
+                .iter()
+                .map(|x| *x > min_neg_log_p_value)
+                .collect::<BooleanChunked>();
+            chunk_results_df = chunk_results_df.filter(&mask)?;
+        }
+        if let Some(annotations) = &annotations {
+            let chunk_annotations =
+                annotations.for_variants(chunk_results_df.column("variant_id")?)?;
+            chunk_results_df = chunk_results_df.hstack(chunk_annotations.get_columns())?;
+        }
+        all_neg_log_p_values.extend(chunk_neg_log_p_values);
+        report_progress(n_passes - 1, offset);
+        Ok(chunk_results_df)
+    });
     debug!("Writing results");
-    write_dataframe(&mut results_df, output_path, n_threads, false)?;
-    Ok(())
+    let n_written = write_results(chunks, output)?;
+    Ok(IgwasSummary {
+        n_tested: all_neg_log_p_values.len(),
+        n_dropped,
+        n_failed,
+        lambda_gc: compute_lambda_gc(&all_neg_log_p_values),
+        pvalue_adjustment: output.pvalue_adjustment,
+        p_threshold: output.p_threshold,
+        n_below_threshold: output.p_threshold.map(|_| n_written),
+    })
 }
 
 pub fn compute_neg_log_pvalue(t_statistic: f32, degrees_of_freedom: i32) -> f32 {
@@ -377,3 +1124,534 @@ pub fn compute_neg_log_pvalue(t_statistic: f32, degrees_of_freedom: i32) -> f32
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gwas_fixture() -> DataFrame {
+        df!(
+            "variant_id" => ["1:1:A:G", "1:2:C:T"],
+            "a1" => ["A", "C"],
+            "a2" => ["G", "T"],
+            "degrees_of_freedom" => [100_i32, 100],
+            "genotype_partial_variance" => [0.5_f32, 0.25],
+            "feature" => [0.1_f32, -0.2],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate_gwas_columns() {
+        assert!(validate_gwas_columns(&gwas_fixture()).is_ok());
+    }
+
+    #[test]
+    fn test_validate_gwas_columns_renamed() {
+        let mut df = gwas_fixture();
+        df.rename("variant_id", "ID".into()).unwrap();
+        let err = validate_gwas_columns(&df).unwrap_err();
+        assert!(err.to_string().contains("variant_id"));
+    }
+
+    #[test]
+    fn test_validate_gwas_columns_wrong_type() {
+        let mut df = gwas_fixture();
+        df.with_column(Column::new("degrees_of_freedom".into(), [100.0_f32, 100.0]))
+            .unwrap();
+        assert!(validate_gwas_columns(&df).is_err());
+    }
+
+    #[test]
+    fn test_compute_lambda_gc() {
+        // Uniform p-values are what's expected under the null
+        let neg_log_p_values = (1..1000)
+            .map(|i| -(i as f32 / 1000.0).log10())
+            .collect::<Vec<f32>>();
+        let lambda = compute_lambda_gc(&neg_log_p_values).unwrap();
+        assert!((lambda - 1.0).abs() < 1e-3);
+        assert_eq!(compute_lambda_gc(&[f32::NAN, f32::NAN]), None);
+    }
+
+    #[test]
+    fn test_adjust_pvalues() {
+        // p = 0.01, 0.04, NaN, 0.03, so three tests
+        let neg_log_p_values = [2.0, -(0.04_f32.log10()), f32::NAN, -(0.03_f32.log10())];
+        let bonferroni = adjust_pvalues(&neg_log_p_values, PvalueAdjustment::Bonferroni);
+        let bh = adjust_pvalues(&neg_log_p_values, PvalueAdjustment::BenjaminiHochberg);
+        for (result, expected) in [
+            (bonferroni, [0.03_f32, 0.12, f32::NAN, 0.09]),
+            (bh, [0.03, 0.04, f32::NAN, 0.04]),
+        ] {
+            assert!(result[2].is_nan());
+            for i in [0, 1, 3] {
+                let p = 10_f32.powf(-result[i]);
+                assert!((p - expected[i]).abs() < 1e-5, "{:?}", result);
+            }
+        }
+        let large = adjust_pvalues(&[0.0, 0.0], PvalueAdjustment::Bonferroni);
+        assert_eq!(large, vec![0.0, 0.0]);
+        // p = 1e-400 is far below the smallest f32 (or f64), but its adjustment isn't lost
+        for method in [
+            PvalueAdjustment::Bonferroni,
+            PvalueAdjustment::BenjaminiHochberg,
+        ] {
+            let strong = adjust_pvalues(&[400.0, 1.0], method);
+            assert!(
+                (strong[0] - (400.0 - 2_f32.log10())).abs() < 1e-3,
+                "{:?}",
+                strong
+            );
+        }
+    }
+
+    #[test]
+    fn test_drop_incomplete_variants() {
+        let mut df = gwas_fixture();
+        df.with_column(Column::new("feature".into(), [Some(0.1_f32), None]))
+            .unwrap();
+        let (complete_df, n_dropped) = drop_incomplete_variants(&df).unwrap();
+        assert_eq!(complete_df.height(), 1);
+        assert_eq!(n_dropped, 1);
+        let (complete_df, n_dropped) = drop_incomplete_variants(&gwas_fixture()).unwrap();
+        assert_eq!(complete_df.height(), 2);
+        assert_eq!(n_dropped, 0);
+    }
+
+    #[test]
+    fn test_gwas_data_on_disk() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("gwas.parquet");
+        let mut df = gwas_fixture();
+        ParquetWriter::new(File::create(&path).unwrap())
+            .finish(&mut df)
+            .unwrap();
+        let on_disk = GwasData::on_disk(&path, GwasColumns::default()).unwrap();
+        let in_memory = GwasData::InMemory(df);
+        assert_eq!(on_disk.height(), 2);
+        assert!(on_disk
+            .slice(1, 5)
+            .unwrap()
+            .equals(&in_memory.slice(1, 5).unwrap()));
+
+        let run = |gwas: &GwasData, name: &str| {
+            let mut projection =
+                Projection::new(vec!["feature".to_string()], faer::col![2.0]).unwrap();
+            let output_path = dir.join(name);
+            let output = results_output(&output_path);
+            let summary =
+                run_igwas_df_impl(gwas, &mut projection, 1.0, 0, &output, |_| {}).unwrap();
+            (summary, std::fs::read_to_string(output_path).unwrap())
+        };
+        assert_eq!(run(&on_disk, "a.tsv"), run(&in_memory, "b.tsv"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_degenerate_variants_fail_alone() {
+        // The second variant has no genotype variance, and the third a beta too large for
+        // the projection variance, so its residual variance is negative
+        let gwas = GwasData::InMemory(
+            df!(
+                "variant_id" => ["1:1:A:G", "1:2:C:T", "1:3:G:A", "1:4:T:C"],
+                "a1" => ["A", "C", "G", "T"],
+                "a2" => ["G", "T", "A", "C"],
+                "degrees_of_freedom" => [100_i32, 100, 100, 100],
+                "genotype_partial_variance" => [0.5_f32, 0.0, 0.5, 0.25],
+                "feature" => [0.1_f32, 0.1, 10.0, -0.2],
+            )
+            .unwrap(),
+        );
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("results.arrow");
+        let output = results_output(&path);
+        let mut projection = Projection::new(vec!["feature".to_string()], faer::col![1.0]).unwrap();
+        let summary = run_igwas_df_impl(&gwas, &mut projection, 1.0, 0, &output, |_| {}).unwrap();
+        assert_eq!(summary.n_tested, 4);
+        assert_eq!(summary.n_failed, 2);
+        let results = IpcReader::new(File::open(&path).unwrap()).finish().unwrap();
+        let neg_log_p_values = results
+            .column("neg_log_p_value")
+            .unwrap()
+            .f32()
+            .unwrap()
+            .iter()
+            .map(|x| x.unwrap())
+            .collect::<Vec<f32>>();
+        assert!(neg_log_p_values[1].is_nan() && neg_log_p_values[2].is_nan());
+        assert!(neg_log_p_values[0].is_finite() && neg_log_p_values[3].is_finite());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_annotations() {
+        let gwas = GwasData::InMemory(gwas_fixture());
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let run = |annotations: Option<&Annotations>| {
+            let path = dir.join("results.arrow");
+            let output = ResultsOutput {
+                annotations,
+                ..results_output(&path)
+            };
+            let mut projection =
+                Projection::new(vec!["feature".to_string()], faer::col![1.0]).unwrap();
+            run_igwas_df_impl(&gwas, &mut projection, 1.0, 0, &output, |_| {}).unwrap();
+            IpcReader::new(File::open(&path).unwrap()).finish().unwrap()
+        };
+        // Without annotations, the results only have the usual columns
+        let plain = run(None);
+        assert_eq!(plain.width(), 8);
+
+        // Only the second variant is annotated, and the annotations are in another order
+        let annotations_path = dir.join("annotations.parquet");
+        let mut annotations_df = df!(
+            "variant_id" => ["1:9:A:T", "1:2:C:T"],
+            "gene" => ["GENE1", "GENE2"],
+        )
+        .unwrap();
+        ParquetWriter::new(File::create(&annotations_path).unwrap())
+            .finish(&mut annotations_df)
+            .unwrap();
+        let annotations = Annotations::load(&annotations_path, &column_names(&plain)).unwrap();
+        let annotated = run(Some(&annotations));
+        assert_eq!(annotated.height(), 2);
+        assert!(annotated.drop("gene").unwrap().equals_missing(&plain));
+        let genes = annotated
+            .column("gene")
+            .unwrap()
+            .str()
+            .unwrap()
+            .iter()
+            .map(|x| x.map(|x| x.to_string()))
+            .collect::<Vec<Option<String>>>();
+        assert_eq!(genes, vec![None, Some("GENE2".to_string())]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Output of plain results to `path`, on one thread. Tests override the fields they need.
+    fn results_output(path: &Path) -> ResultsOutput<'_> {
+        ResultsOutput {
+            path,
+            n_threads: 1,
+            max_bytes: None,
+            pvalue_adjustment: None,
+            p_threshold: None,
+            annotations: None,
+            plot_data_path: None,
+        }
+    }
+
+    fn column_names(df: &DataFrame) -> Vec<String> {
+        df.get_column_names()
+            .iter()
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_load_annotations() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("annotations.parquet");
+        let result_columns = result_column_names(&column_names(&gwas_fixture()));
+        let load = |mut df: DataFrame| {
+            ParquetWriter::new(File::create(&path).unwrap())
+                .finish(&mut df)
+                .unwrap();
+            Annotations::load(&path, &result_columns).map(|_| ())
+        };
+        let valid = df!(
+            "variant_id" => ["1:1:A:G", "1:2:C:T"],
+            "gene" => ["GENE1", "GENE2"],
+        )
+        .unwrap();
+        assert!(load(valid).is_ok());
+        let duplicated = df!(
+            "variant_id" => ["1:1:A:G", "1:1:A:G"],
+            "gene" => ["GENE1", "GENE2"],
+        )
+        .unwrap();
+        assert!(load(duplicated).is_err());
+        let unkeyed = df!("gene" => ["GENE1"]).unwrap();
+        assert!(load(unkeyed).is_err());
+        // These would otherwise be joined in as beta_right and a1_right
+        let colliding = df!(
+            "variant_id" => ["1:1:A:G"],
+            "beta" => [0.5_f32],
+            "a1" => ["A"],
+        )
+        .unwrap();
+        let err = load(colliding).unwrap_err();
+        assert!(err.to_string().ends_with("beta, a1"), "{}", err);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_plot_data() {
+        let mut df = gwas_fixture();
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("results.arrow");
+        let plot_data_path = dir.join("plot_data.parquet");
+        let output = ResultsOutput {
+            plot_data_path: Some(&plot_data_path),
+            ..results_output(&path)
+        };
+        let mut projection = Projection::new(vec!["feature".to_string()], faer::col![1.0]).unwrap();
+        let err = run_igwas_df_impl(
+            &GwasData::InMemory(df.clone()),
+            &mut projection,
+            1.0,
+            0,
+            &output,
+            |_| {},
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("chromosome, position"));
+        assert!(!path.exists());
+
+        // Positions are info columns, between the alleles and the degrees of freedom
+        df.insert_column(3, Column::new("chromosome".into(), ["1", "1"]))
+            .unwrap();
+        df.insert_column(4, Column::new("position".into(), [1_i64, 2]))
+            .unwrap();
+        run_igwas_df_impl(
+            &GwasData::InMemory(df.clone()),
+            &mut projection,
+            1.0,
+            0,
+            &output,
+            |_| {},
+        )
+        .unwrap();
+        let results = IpcReader::new(File::open(&path).unwrap()).finish().unwrap();
+        let plot_data = ParquetReader::new(File::open(&plot_data_path).unwrap())
+            .finish()
+            .unwrap();
+        assert_eq!(plot_data.height(), results.height());
+        assert_eq!(plot_data.get_column_names(), PLOT_DATA_COLUMNS);
+        assert!(plot_data
+            .column("neg_log_p_value")
+            .unwrap()
+            .equals(results.column("neg_log_p_value").unwrap()));
+
+        // The plot data has the same size limit as the results. Text results of two
+        // variants are smaller than the parquet plot data's metadata.
+        let text_path = dir.join("results.tsv");
+        let limited = ResultsOutput {
+            path: &text_path,
+            max_bytes: Some(1000),
+            ..output
+        };
+        let err = run_igwas_df_impl(
+            &GwasData::InMemory(df),
+            &mut projection,
+            1.0,
+            0,
+            &limited,
+            |_| {},
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Plot data exceed the maximum size"));
+        assert!(!text_path.exists());
+        assert!(!plot_data_path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_p_threshold() {
+        // The first variant is strongly associated, the second isn't, and the third has no
+        // genotype variance, so its p-value is NaN
+        let gwas = GwasData::InMemory(
+            df!(
+                "variant_id" => ["1:1:A:G", "1:2:C:T", "1:3:G:A"],
+                "a1" => ["A", "C", "G"],
+                "a2" => ["G", "T", "A"],
+                "degrees_of_freedom" => [1000_i32, 1000, 1000],
+                "genotype_partial_variance" => [0.5_f32, 0.5, 0.0],
+                "feature" => [0.3_f32, 0.01, 0.1],
+            )
+            .unwrap(),
+        );
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("results.arrow");
+        let output = ResultsOutput {
+            p_threshold: Some(5e-8),
+            ..results_output(&path)
+        };
+        let mut projection = Projection::new(vec!["feature".to_string()], faer::col![1.0]).unwrap();
+        let summary = run_igwas_df_impl(&gwas, &mut projection, 1.0, 0, &output, |_| {}).unwrap();
+        assert_eq!(summary.n_tested, 3);
+        assert_eq!(summary.p_threshold, Some(5e-8));
+        assert_eq!(summary.n_below_threshold, Some(1));
+        // Lambda GC still uses every variant
+        assert!(summary.lambda_gc.is_some());
+        let results = IpcReader::new(File::open(&path).unwrap()).finish().unwrap();
+        let variant_ids = results
+            .column("variant_id")
+            .unwrap()
+            .str()
+            .unwrap()
+            .iter()
+            .map(|x| x.unwrap().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(variant_ids, vec!["1:1:A:G"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_projection_to_parquet() {
+        let mut projection = Projection::new(
+            vec!["b".to_string(), "a".to_string()],
+            faer::col![2.0, -1.0],
+        )
+        .unwrap();
+        projection.standardize(&["a".to_string(), "b".to_string(), "c".to_string()]);
+        let bytes = projection_to_parquet(&projection).unwrap();
+        let df = ParquetReader::new(std::io::Cursor::new(bytes))
+            .finish()
+            .unwrap();
+        let expected = df!(
+            "feature_id" => ["a", "b", "c"],
+            "coefficient" => [-1.0_f32, 2.0, 0.0],
+        )
+        .unwrap();
+        assert!(df.equals(&expected));
+    }
+
+    #[test]
+    fn test_per_variant_sample_size() {
+        let mut df = gwas_fixture();
+        df.with_column(Column::new("degrees_of_freedom".into(), [100_i32, 90]))
+            .unwrap();
+        let mut projection = Projection::new(vec!["feature".to_string()], faer::col![1.0]).unwrap();
+        let running_stats = compute_batch_stats(&df, &mut projection).unwrap();
+        let results =
+            results_to_dataframe(compute_batch_results(running_stats, 1.0, 3).unwrap()).unwrap();
+        let sample_size = results
+            .column("sample_size")
+            .unwrap()
+            .i32()
+            .unwrap()
+            .iter()
+            .map(|x| x.unwrap())
+            .collect::<Vec<i32>>();
+        assert_eq!(sample_size, vec![105, 95]);
+    }
+
+    #[test]
+    fn test_write_results_arrow_round_trip() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("results.arrow");
+        let df = df!(
+            "variant_id" => ["1:1:A:G", "1:2:C:T"],
+            "beta" => [0.1_f32 + f32::EPSILON, -1.234_567_9e-30],
+            "info" => [std::f64::consts::PI, 1e-300],
+        )
+        .unwrap();
+        let output = results_output(&path);
+        write_results([Ok(df.clone())], &output).unwrap();
+        let loaded = IpcReader::new(File::open(&path).unwrap()).finish().unwrap();
+        assert!(loaded.equals(&df));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_results_max_bytes() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("results.tsv");
+        let mut output = ResultsOutput {
+            max_bytes: Some(10),
+            ..results_output(&path)
+        };
+        let err = write_results([Ok(gwas_fixture())], &output).unwrap_err();
+        assert!(err.to_string().contains("maximum size of 10 bytes"));
+        assert!(err.to_string().contains("for the first 0 variants"));
+        assert!(!path.exists());
+        output.max_bytes = Some(1000);
+        assert_eq!(write_results([Ok(gwas_fixture())], &output).unwrap(), 2);
+        assert!(path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_results_batches() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("results.arrow");
+        let output = results_output(&path);
+        let batches = [
+            Ok(gwas_fixture().slice(0, 1)),
+            Ok(gwas_fixture().slice(1, 1)),
+        ];
+        assert_eq!(write_results(batches, &output).unwrap(), 2);
+        let loaded = IpcReader::new(File::open(&path).unwrap()).finish().unwrap();
+        assert!(loaded.equals(&gwas_fixture()));
+
+        // A batch failing to compute aborts the write, after earlier batches were written
+        let batches = [Ok(gwas_fixture()), Err(anyhow!("Failed batch"))];
+        let err = write_results(batches, &output).unwrap_err();
+        assert_eq!(err.to_string(), "Failed batch");
+        assert!(!path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Check that results are written as they're computed, so peak memory doesn't grow
+    /// with the number of variants. Only works on Linux. Run with
+    /// `cargo test --release measure_streaming_memory -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn measure_streaming_memory() {
+        let read_status_kb = |field: &str| -> usize {
+            let status = std::fs::read_to_string("/proc/self/status").unwrap();
+            let line = status.lines().find(|x| x.starts_with(field)).unwrap();
+            line.split_whitespace().nth(1).unwrap().parse().unwrap()
+        };
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let gwas_path = dir.join("gwas.parquet");
+        let n_variants = 20 * VARIANT_CHUNK_SIZE;
+        {
+            let mut df = df!(
+                "variant_id" => (0..n_variants).map(|i| format!("1:{}:A:G", i)).collect::<Vec<_>>(),
+                "a1" => vec!["A"; n_variants],
+                "a2" => vec!["G"; n_variants],
+                "degrees_of_freedom" => vec![100_i32; n_variants],
+                "genotype_partial_variance" => vec![0.5_f32; n_variants],
+                "feature" => (0..n_variants).map(|i| (i % 100) as f32 / 1000.0).collect::<Vec<_>>(),
+            )
+            .unwrap();
+            ParquetWriter::new(File::create(&gwas_path).unwrap())
+                .finish(&mut df)
+                .unwrap();
+        }
+        let gwas = GwasData::on_disk(&gwas_path, GwasColumns::default()).unwrap();
+        let path = dir.join("results.tsv");
+        let output = ResultsOutput {
+            pvalue_adjustment: Some(PvalueAdjustment::BenjaminiHochberg),
+            ..results_output(&path)
+        };
+        let mut projection = Projection::new(vec!["feature".to_string()], faer::col![1.0]).unwrap();
+        // Reset the peak resident set size to the current one
+        std::fs::write("/proc/self/clear_refs", "5").unwrap();
+        let baseline_kb = read_status_kb("VmRSS:");
+        let summary = run_igwas_df_impl(&gwas, &mut projection, 1.0, 0, &output, |_| {}).unwrap();
+        let peak_increase_kb = read_status_kb("VmHWM:") - baseline_kb;
+        let results_kb = std::fs::metadata(&path).unwrap().len() as usize / 1024;
+        println!(
+            "{} variants: peak RSS increase {} kB, results {} kB",
+            summary.n_tested, peak_increase_kb, results_kb
+        );
+        assert_eq!(summary.n_tested, n_variants);
+        assert!(peak_increase_kb < results_kb / 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,548 @@
+//! Parser for phenotype definitions written as infix expressions, e.g.
+//! `(sbp - dbp) / 2 > 100`, as an alternative to the postfix node list.
+//!
+//! From lowest to highest precedence, the syntax is
+//! - `|` (OR), then `^` (XOR), then `&` (AND)
+//! - `>`, `>=`, `<`, `<=`, `==`, which can't be chained
+//! - `+`, `-`, then `*`, `/`
+//! - unary `-` (NEG) and `!` (NOT)
+//! - parentheses, function calls, feature codes, and constants
+//!
+//! Every other operator is called by name, e.g. `clamp(age, 40, 70)` or
+//! `sum_features(a, b, c)`. Feature codes are bare identifiers, or quoted (`"21001-0.0"`)
+//! when they aren't valid identifiers or clash with `true` and `false`, which are boolean
+//! constants. Numbers are real constants.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::models::{Constant, NodeType, Operators, ParsingNode};
+use crate::phenotype_definitions::{ParsedDefinition, PhenotypeError, TokenSpan};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f32),
+    Identifier(String),
+    Quoted(String),
+    Symbol(&'static str),
+    LeftParen,
+    RightParen,
+    Comma,
+    End,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "'{}'", value),
+            Token::Identifier(name) => write!(f, "'{}'", name),
+            Token::Quoted(code) => write!(f, "'\"{}\"'", code),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+            Token::LeftParen => write!(f, "'('"),
+            Token::RightParen => write!(f, "')'"),
+            Token::Comma => write!(f, "','"),
+            Token::End => write!(f, "end of expression"),
+        }
+    }
+}
+
+/// Symbols, with two-character ones first so they take priority over their prefixes
+const SYMBOLS: [&str; 13] = [
+    ">=", "<=", "==", ">", "<", "+", "-", "*", "/", "&", "|", "^", "!",
+];
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Split an expression into tokens, each with its span
+fn tokenize(expression: &str) -> Result<Vec<(Token, TokenSpan)>, PhenotypeError> {
+    let chars = expression.chars().collect::<Vec<char>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let position = i + 1;
+        let span = |end: usize| TokenSpan::new(position, &chars[i..end].iter().collect::<String>());
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let token = match c {
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            ',' => Token::Comma,
+            '"' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&c| c == '"')
+                    .ok_or_else(|| {
+                        PhenotypeError::new(&span(chars.len()), "Unterminated quoted feature")
+                    })?;
+                let code = chars[i + 1..i + 1 + end].iter().collect::<String>();
+                if code.is_empty() {
+                    return Err(PhenotypeError::new(&span(i + 2), "Empty quoted feature"));
+                }
+                tokens.push((Token::Quoted(code), span(i + end + 2)));
+                i += end + 2;
+                continue;
+            }
+            c if c.is_ascii_digit()
+                || (c == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit)) =>
+            {
+                let mut end = i;
+                while end < chars.len() && (chars[end].is_ascii_digit() || chars[end] == '.') {
+                    end += 1;
+                }
+                // Exponent, as in 1e-3
+                if end < chars.len() && (chars[end] == 'e' || chars[end] == 'E') {
+                    let mut exponent_end = end + 1;
+                    if exponent_end < chars.len() && matches!(chars[exponent_end], '+' | '-') {
+                        exponent_end += 1;
+                    }
+                    if chars.get(exponent_end).is_some_and(char::is_ascii_digit) {
+                        end = exponent_end;
+                        while end < chars.len() && chars[end].is_ascii_digit() {
+                            end += 1;
+                        }
+                    }
+                }
+                let text = chars[i..end].iter().collect::<String>();
+                if chars.get(end).is_some_and(|&c| is_identifier_char(c)) {
+                    let span = span(end + 1);
+                    let message = format!(
+                        "Invalid number '{}' (quote feature codes that start with a digit)",
+                        span.token
+                    );
+                    return Err(PhenotypeError::new(&span, message));
+                }
+                let value = text.parse::<f32>().map_err(|_| {
+                    PhenotypeError::new(&span(end), format!("Invalid number '{}'", text))
+                })?;
+                tokens.push((Token::Number(value), span(end)));
+                i = end;
+                continue;
+            }
+            c if is_identifier_char(c) => {
+                let mut end = i;
+                while end < chars.len() && is_identifier_char(chars[end]) {
+                    end += 1;
+                }
+                let name = chars[i..end].iter().collect::<String>();
+                tokens.push((Token::Identifier(name), span(end)));
+                i = end;
+                continue;
+            }
+            _ => {
+                let symbol = SYMBOLS
+                    .iter()
+                    .find(|symbol| {
+                        symbol
+                            .chars()
+                            .enumerate()
+                            .all(|(j, s)| chars.get(i + j) == Some(&s))
+                    })
+                    .ok_or_else(|| {
+                        PhenotypeError::new(&span(i + 1), format!("Unexpected character '{}'", c))
+                    })?;
+                tokens.push((Token::Symbol(symbol), span(i + symbol.len())));
+                i += symbol.len();
+                continue;
+            }
+        };
+        tokens.push((token, span(i + 1)));
+        i += 1;
+    }
+    tokens.push((Token::End, TokenSpan::new(chars.len() + 1, "")));
+    Ok(tokens)
+}
+
+/// Deepest nesting of parentheses, calls and unary operators the parser accepts. Each
+/// level recurses through every precedence level, so deep enough expressions would
+/// overflow the stack (a call level takes about 8 KB in debug builds).
+pub const MAX_NESTING_DEPTH: usize = 128;
+
+/// Recursive descent parser that emits nodes in postfix order as it goes, each with the
+/// span of the token it came from
+struct Parser {
+    tokens: Vec<(Token, TokenSpan)>,
+    next: usize,
+    depth: usize,
+    parsed: ParsedDefinition,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].0
+    }
+
+    fn span(&self) -> TokenSpan {
+        self.tokens[self.next].1.clone()
+    }
+
+    fn push(&mut self, node: ParsingNode, span: TokenSpan) {
+        self.parsed.nodes.push(node);
+        self.parsed.spans.push(span);
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.next].0.clone();
+        if token != Token::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, PhenotypeError> {
+        Err(PhenotypeError::new(&self.span(), message))
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), PhenotypeError> {
+        if *self.peek() != expected {
+            return self.error(format!("Expected {}, found {}", expected, self.peek()));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    /// Run `parse` one nesting level deeper, failing past `MAX_NESTING_DEPTH`
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<(), PhenotypeError>,
+    ) -> Result<(), PhenotypeError> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            return self.error(format!(
+                "Expression is nested more than {} levels deep",
+                MAX_NESTING_DEPTH
+            ));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Parse a left-associative chain of binary operators, one precedence level above
+    /// `operand`
+    fn binary(
+        &mut self,
+        operators: &[(&str, Operators)],
+        operand: fn(&mut Self) -> Result<(), PhenotypeError>,
+    ) -> Result<(), PhenotypeError> {
+        operand(self)?;
+        while let Token::Symbol(symbol) = self.peek() {
+            let Some((_, op)) = operators.iter().find(|(s, _)| s == symbol) else {
+                break;
+            };
+            let op = *op;
+            let span = self.span();
+            self.advance();
+            operand(self)?;
+            self.push(ParsingNode::Operator(op), span);
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self) -> Result<(), PhenotypeError> {
+        self.nested(|parser| parser.binary(&[("|", Operators::Or)], Self::xor))
+    }
+
+    fn xor(&mut self) -> Result<(), PhenotypeError> {
+        self.binary(&[("^", Operators::Xor)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<(), PhenotypeError> {
+        self.binary(&[("&", Operators::And)], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<(), PhenotypeError> {
+        const COMPARISONS: [(&str, Operators); 5] = [
+            (">", Operators::Gt),
+            (">=", Operators::Ge),
+            ("<", Operators::Lt),
+            ("<=", Operators::Le),
+            ("==", Operators::Eq),
+        ];
+        let is_comparison = |token: &Token| match token {
+            Token::Symbol(symbol) => COMPARISONS.iter().find(|(s, _)| s == symbol).map(|c| c.1),
+            _ => None,
+        };
+        self.additive()?;
+        if let Some(op) = is_comparison(self.peek()) {
+            let span = self.span();
+            self.advance();
+            self.additive()?;
+            self.push(ParsingNode::Operator(op), span);
+            if is_comparison(self.peek()).is_some() {
+                return self.error(
+                    "Comparisons can't be chained, combine them with '&' instead".to_string(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn additive(&mut self) -> Result<(), PhenotypeError> {
+        self.binary(
+            &[("+", Operators::Add), ("-", Operators::Sub)],
+            Self::multiplicative,
+        )
+    }
+
+    fn multiplicative(&mut self) -> Result<(), PhenotypeError> {
+        self.binary(&[("*", Operators::Mul), ("/", Operators::Div)], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<(), PhenotypeError> {
+        let span = self.span();
+        match self.peek() {
+            Token::Symbol("-") => {
+                self.advance();
+                // Negative numbers are constants, since some operators (e.g. clamp bounds)
+                // only accept constants
+                if let Token::Number(value) = *self.peek() {
+                    let number = self.span();
+                    self.advance();
+                    let span = TokenSpan::new(span.position, &format!("-{}", number.token));
+                    self.push_constant(-value, NodeType::Real, span);
+                    return Ok(());
+                }
+                self.nested(Self::unary)?;
+                self.push(ParsingNode::Operator(Operators::Neg), span);
+                Ok(())
+            }
+            Token::Symbol("!") => {
+                self.advance();
+                self.nested(Self::unary)?;
+                self.push(ParsingNode::Operator(Operators::Not), span);
+                Ok(())
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn push_constant(&mut self, value: f32, node_type: NodeType, span: TokenSpan) {
+        self.push(ParsingNode::Constant(Constant { value, node_type }), span);
+    }
+
+    fn primary(&mut self) -> Result<(), PhenotypeError> {
+        let span = self.span();
+        match self.advance() {
+            Token::Number(value) => self.push_constant(value, NodeType::Real, span),
+            Token::Quoted(code) => self.push(ParsingNode::Feature(code), span),
+            Token::LeftParen => {
+                self.expression()?;
+                self.expect(Token::RightParen)?;
+            }
+            Token::Identifier(name) if *self.peek() == Token::LeftParen => {
+                self.call(&name, span)?;
+            }
+            Token::Identifier(name) => match name.as_str() {
+                "true" => self.push_constant(1.0, NodeType::Bool, span),
+                "false" => self.push_constant(0.0, NodeType::Bool, span),
+                _ => self.push(ParsingNode::Feature(name), span),
+            },
+            token => {
+                return Err(PhenotypeError::new(
+                    &span,
+                    format!("Expected a value, found {}", token),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse the arguments of a call to the operator `name`, whose name has the span
+    /// `span`. Variadic operators take any positive number of arguments, and their count
+    /// constant takes the name's span too.
+    fn call(&mut self, name: &str, span: TokenSpan) -> Result<(), PhenotypeError> {
+        let op = Operators::from_str(name)
+            .map_err(|_| PhenotypeError::new(&span, format!("Unknown operator '{}'", name)))?;
+        self.expect(Token::LeftParen)?;
+        let mut n_arguments = 0;
+        if *self.peek() != Token::RightParen {
+            loop {
+                self.expression()?;
+                n_arguments += 1;
+                if *self.peek() != Token::Comma {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect(Token::RightParen)?;
+        if op.is_variadic() {
+            if n_arguments == 0 {
+                return Err(PhenotypeError::new(
+                    &span,
+                    format!("{} expects at least 1 argument", op),
+                ));
+            }
+            self.push_constant(n_arguments as f32, NodeType::Real, span.clone());
+        } else {
+            let arity = op.value().arity as usize;
+            if n_arguments != arity {
+                return Err(PhenotypeError::new(
+                    &span,
+                    format!(
+                        "Operator {} expects {} arguments, got {}",
+                        op, arity, n_arguments
+                    ),
+                ));
+            }
+        }
+        self.push(ParsingNode::Operator(op), span);
+        Ok(())
+    }
+}
+
+/// Parse an infix expression into nodes in postfix order, the same nodes that
+/// `parse_definition` gives for the equivalent postfix definition. Operators point at
+/// their symbol or name.
+pub fn parse_infix_definition(expression: &str) -> Result<ParsedDefinition, PhenotypeError> {
+    let mut parser = Parser {
+        tokens: tokenize(expression)?,
+        next: 0,
+        depth: 0,
+        parsed: ParsedDefinition::default(),
+    };
+    parser.expression()?;
+    if *parser.peek() != Token::End {
+        return parser.error(format!("Unexpected {}", parser.peek()));
+    }
+    Ok(parser.parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::testing::test_field;
+    use crate::models::Node;
+    use crate::phenotype_definitions::{
+        format_phenotype_definition, format_string_definition, parse_definition, validate_nodes,
+        KnowledgeBase,
+    };
+
+    fn format_infix(expression: &str) -> String {
+        let kb = KnowledgeBase::new(
+            ["sbp", "dbp", "age", "21001-0.0"]
+                .into_iter()
+                .map(test_field)
+                .collect(),
+        );
+        let nodes = parse_infix_definition(expression).unwrap().nodes;
+        let valid_nodes: Vec<Node> = validate_nodes(1, &nodes, &kb).unwrap();
+        format_phenotype_definition(&valid_nodes)
+    }
+
+    #[test]
+    fn test_parse_infix_round_trip() {
+        assert_eq!(
+            format_infix("(sbp - dbp) / 2 > 100"),
+            "GT(DIV(SUB('sbp' [sbp], 'dbp' [dbp]), `2`), `100`)"
+        );
+        // Multiplication binds tighter than addition, and both are left-associative
+        assert_eq!(
+            format_infix("sbp + dbp * 2 - age"),
+            "SUB(ADD('sbp' [sbp], MUL('dbp' [dbp], `2`)), 'age' [age])"
+        );
+        assert_eq!(
+            format_infix(r#"age >= 40 & !(sbp < 120) | "21001-0.0" > 30"#),
+            "OR(AND(GE('age' [age], `40`), NOT(LT('sbp' [sbp], `120`))), \
+             GT('21001-0.0' [21001-0.0], `30`))"
+        );
+        assert_eq!(
+            format_infix("clamp(-sbp, -1.5e2, 0) + sum_features(sbp, dbp, age)"),
+            "ADD(CLAMP(NEG('sbp' [sbp]), `-150`, `0`), \
+             SUM_FEATURES('sbp' [sbp], 'dbp' [dbp], 'age' [age], `3`))"
+        );
+        // Infix parses to the same nodes as the postfix definition
+        let nodes = parse_infix_definition("is_missing(sbp) == true")
+            .unwrap()
+            .nodes;
+        let postfix = format_string_definition(&nodes);
+        assert_eq!(postfix, r#""sbp" `IS_MISSING` <BOOL:T> `EQ`"#);
+        let reparsed = parse_definition(&postfix).unwrap().nodes;
+        assert_eq!(format_string_definition(&reparsed), postfix);
+    }
+
+    #[test]
+    fn test_parse_infix_errors() {
+        let error = |expression: &str| parse_infix_definition(expression).unwrap_err();
+        assert_eq!(
+            error("(sbp - dbp"),
+            PhenotypeError {
+                position: 11,
+                token: String::new(),
+                message: "Expected ')', found end of expression".to_string(),
+            }
+        );
+        assert_eq!(
+            error("sbp + * 2").to_string(),
+            "Expected a value, found '*' at position 7"
+        );
+        assert_eq!(
+            error("sbp $ 2").to_string(),
+            "Unexpected character '$' at position 5"
+        );
+        assert_eq!(
+            error("sbp dbp").to_string(),
+            "Unexpected 'dbp' at position 5"
+        );
+        assert_eq!(
+            error("foo(sbp)").to_string(),
+            "Unknown operator 'foo' at position 1"
+        );
+        assert_eq!(
+            error("1 + clamp(sbp, 0)").to_string(),
+            "Operator CLAMP expects 3 arguments, got 2 at position 5"
+        );
+        let err = error("0 < age < 10");
+        assert_eq!((err.position, err.token.as_str()), (9, "<"));
+        assert_eq!(error(r#""sbp"#).message, "Unterminated quoted feature");
+        let err = error("2abc > 1");
+        assert_eq!((err.position, err.token.as_str()), (1, "2a"));
+        assert_eq!(error("1 + clamp(sbp, 0)").token, "clamp");
+    }
+
+    #[test]
+    fn test_parse_infix_nesting_limit() {
+        let parenthesized = |depth: usize| "(".repeat(depth) + "sbp" + &")".repeat(depth);
+        assert!(parse_infix_definition(&parenthesized(MAX_NESTING_DEPTH - 1)).is_ok());
+        let err = parse_infix_definition(&parenthesized(100_000)).unwrap_err();
+        assert_eq!(
+            err.message,
+            format!(
+                "Expression is nested more than {} levels deep",
+                MAX_NESTING_DEPTH
+            )
+        );
+        let err = parse_infix_definition(&("-!".repeat(50_000) + "sbp")).unwrap_err();
+        assert!(err.message.starts_with("Expression is nested"));
+        assert!(parse_infix_definition(&("!".repeat(100) + "sbp")).is_ok());
+        // Calls are the deepest recursion per level
+        let calls = |depth: usize| "not(".repeat(depth) + "sbp" + &")".repeat(depth);
+        assert!(parse_infix_definition(&calls(MAX_NESTING_DEPTH - 1)).is_ok());
+        assert!(parse_infix_definition(&calls(100_000)).is_err());
+    }
+
+    #[test]
+    fn test_parse_infix_spans() {
+        let parsed = parse_infix_definition(r#"-2 * "21001-0.0" >= 1e1"#).unwrap();
+        let spans = parsed
+            .spans
+            .iter()
+            .map(|span| (span.position, span.token.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![
+                (1, "-2"),
+                (6, "\"21001-0.0\""),
+                (4, "*"),
+                (21, "1e1"),
+                (18, ">=")
+            ]
+        );
+    }
+}
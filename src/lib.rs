@@ -1,7 +1,7 @@
-use anyhow::{anyhow, Context, Result};
-use aws_config::Region;
+use anyhow::{anyhow, bail, Context, Result};
+use aws_config::{Region, SdkConfig};
 use aws_sdk_s3::Client;
-use log::info;
+use log::{error, info};
 use models::Cohort;
 use phenotype_definitions::KnowledgeBase;
 use polars::io::parquet::read::ParquetReader;
@@ -10,15 +10,21 @@ use sqlx::sqlite::SqlitePoolOptions;
 use sqlx::SqlitePool;
 use std::path::{Path, PathBuf};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     fs::File,
-    sync::{Arc, Mutex},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 
+pub mod audit;
+pub mod conditional;
 pub mod config;
 pub mod errors;
+pub mod extract;
 pub mod igwas;
+pub mod infix;
+pub mod library;
 pub mod models;
 pub mod phenotype_definitions;
 pub mod regression;
@@ -26,19 +32,38 @@ pub mod render_results;
 pub mod utils;
 pub mod worker;
 
+use crate::audit::AuditLog;
 use crate::config::Settings;
-use crate::models::{CohortData, Feature, PhenotypeFitQuality, WebGWASRequestId, WebGWASResult};
+use crate::igwas::Projection;
+use crate::models::{
+    CohortData, CohortMeta, Feature, FeatureResponse, Node, PhenotypeFitQuality, ProjectionOptions,
+    WebGWASRequestId, WebGWASResponse, WebGWASResult,
+};
+use crate::phenotype_definitions::hash_phenotype_definition;
 
 pub struct AppState {
     pub root_directory: PathBuf,
     pub settings: Settings,
     pub db: SqlitePool,
     pub s3_client: aws_sdk_s3::Client,
-    pub knowledge_base: KnowledgeBase,
+    pub knowledge_base: Arc<Mutex<KnowledgeBase>>,
+    /// Metadata of every cohort, loaded at startup
+    pub cohorts: HashMap<i32, Arc<CohortMeta>>,
+    /// Data of the cohorts loaded so far (see `AppState::cohort_data`)
     pub cohort_id_to_data: Arc<Mutex<HashMap<i32, Arc<CohortData>>>>,
+    /// Why each cohort that failed to load is unavailable
+    pub cohort_load_errors: Arc<Mutex<HashMap<i32, String>>>,
     pub fit_quality_reference: Arc<Vec<PhenotypeFitQuality>>,
-    pub queue: Arc<Mutex<Vec<WebGWASRequestId>>>,
+    pub queue: Arc<RequestQueue>,
     pub results: Arc<Mutex<ResultsCache>>,
+    pub projections: Arc<Mutex<ProjectionCache>>,
+    pub thread_budget: Arc<ThreadBudget>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub idempotency_keys: Arc<IdempotencyKeys>,
+    pub request_durations: Arc<RequestDurations>,
+    pub audit_log: AuditLog,
+    /// The server's runtime, for the worker threads' async work (e.g. uploads)
+    pub runtime: tokio::runtime::Handle,
 }
 
 impl AppState {
@@ -55,7 +80,7 @@ impl AppState {
             }
             info!("Results directory cleared");
         }
-        std::fs::create_dir_all(root.join("results"))?;
+        ensure_results_directory(&root)?;
         let db_path = root.join("webgwas.db").display().to_string();
         let db = SqlitePoolOptions::new()
             .max_connections(20)
@@ -72,21 +97,18 @@ impl AppState {
             .execute(&db)
             .await?;
 
-        let cohort_id_to_data = sqlx::query_as::<_, Cohort>("SELECT * FROM cohort")
+        let audit_log = AuditLog::start(db.clone())
+            .await
+            .context("Failed to start audit log")?;
+        library::create_definitions_table(&db)
+            .await
+            .context("Failed to create the definitions library table")?;
+
+        let cohorts = sqlx::query_as::<_, Cohort>("SELECT * FROM cohort")
             .fetch_all(&db)
             .await
-            .context("Failed to fetch cohorts")?
-            .into_iter()
-            .map(|cohort| -> Result<CohortData> { CohortData::load(cohort, &root) })
-            .collect::<Result<Vec<CohortData>>>()?
-            .into_iter()
-            .map(|cohort_data| {
-                (
-                    cohort_data.cohort.id.expect("Cohort ID is missing"),
-                    Arc::new(cohort_data),
-                )
-            })
-            .collect::<HashMap<i32, Arc<CohortData>>>();
+            .context("Failed to fetch cohorts")?;
+        let (cohorts, cohort_load_errors) = load_cohort_metas(cohorts, &root);
 
         let fields = sqlx::query_as::<_, Feature>(
             "SELECT id, code, name, type as node_type, sample_size, cohort_id FROM feature",
@@ -95,11 +117,17 @@ impl AppState {
         .await
         .context("Failed to fetch features")
         .unwrap();
-        let kb = KnowledgeBase::new(fields);
+        let mut kb = KnowledgeBase::new(fields);
+        for (cohort_id, meta) in cohorts.iter() {
+            kb.add_aliases(*cohort_id, &meta.aliases);
+        }
 
         let region = Region::new(settings.s3_region.clone());
         let shared_config = aws_config::from_env().region(region).load().await;
-        let s3_client = Client::new(&shared_config);
+        let s3_client = Client::from_conf(s3_client_config(
+            &shared_config,
+            settings.s3_endpoint.as_deref(),
+        ));
 
         let fit_quality_path = root.join("fit_quality.parquet");
         let fit_quality_file = File::open(&fit_quality_path).context(anyhow!(
@@ -122,21 +150,569 @@ impl AppState {
             .context("Failed to load fit quality reference")?;
 
         let results = Arc::new(Mutex::new(ResultsCache::new(settings.cache_capacity)));
+        let projections = Arc::new(Mutex::new(ProjectionCache::new(
+            settings.projection_cache_capacity,
+        )));
+        let rate_limiter = Arc::new(RateLimiter::new(
+            settings.rate_limit_burst,
+            settings.rate_limit_per_minute,
+        ));
+
+        let idempotency_keys = Arc::new(
+            IdempotencyKeys::new(
+                db.clone(),
+                Duration::from_secs(settings.idempotency_key_ttl_secs),
+            )
+            .await
+            .context("Failed to create the idempotency key table")?,
+        );
 
         let state = AppState {
             root_directory: root,
             settings,
             db,
             s3_client,
-            knowledge_base: kb,
-            cohort_id_to_data: Arc::new(Mutex::new(cohort_id_to_data)),
+            knowledge_base: Arc::new(Mutex::new(kb)),
+            cohorts,
+            cohort_id_to_data: Arc::new(Mutex::new(HashMap::new())),
+            cohort_load_errors: Arc::new(Mutex::new(cohort_load_errors)),
             fit_quality_reference: Arc::new(fit_quality_reference),
-            queue: Arc::new(Mutex::new(Vec::new())),
+            queue: Arc::new(RequestQueue::default()),
             results,
+            projections,
+            thread_budget: Arc::new(ThreadBudget::new(available_threads())),
+            rate_limiter,
+            idempotency_keys,
+            request_durations: Arc::new(RequestDurations::default()),
+            audit_log,
+            runtime: tokio::runtime::Handle::current(),
         };
         info!("Finished initializing app state");
         Ok(state)
     }
+
+    /// A cohort's data, loading it on first use, or `None` for a cohort that doesn't exist.
+    /// Loading reads large files, so async code should call this on a blocking thread.
+    /// A cohort that fails to load is marked unavailable rather than loaded again.
+    pub fn cohort_data(&self, cohort_id: i32) -> Result<Option<Arc<CohortData>>> {
+        if let Some(cohort_data) = self.cohort_id_to_data.lock().unwrap().get(&cohort_id) {
+            return Ok(Some(cohort_data.clone()));
+        }
+        if let Some(load_error) = self.cohort_load_error(cohort_id) {
+            bail!("Cohort {} is unavailable: {}", cohort_id, load_error);
+        }
+        let Some(meta) = self.cohorts.get(&cohort_id) else {
+            return Ok(None);
+        };
+        let generation = self.projections.lock().unwrap().generation(cohort_id);
+        let cohort_data = match CohortData::load(
+            meta.as_ref().clone(),
+            &self.root_directory,
+            self.settings.stream_gwas,
+        ) {
+            Ok(cohort_data) => CohortData {
+                generation,
+                ..cohort_data
+            },
+            Err(err) => {
+                let load_error = format!("{:#}", err);
+                error!("Failed to load cohort {}: {}", cohort_id, load_error);
+                self.cohort_load_errors
+                    .lock()
+                    .unwrap()
+                    .insert(cohort_id, load_error.clone());
+                bail!("Cohort {} is unavailable: {}", cohort_id, load_error);
+            }
+        };
+        // Concurrent first requests may each load the cohort, but only one copy is kept
+        let cohort_data = self
+            .cohort_id_to_data
+            .lock()
+            .unwrap()
+            .entry(cohort_id)
+            .or_insert(Arc::new(cohort_data))
+            .clone();
+        Ok(Some(cohort_data))
+    }
+
+    /// Whether the settings expose a cohort through the API (see `Settings::allowed_cohorts`)
+    pub fn cohort_allowed(&self, cohort_id: i32) -> bool {
+        let normalized_name = self
+            .cohorts
+            .get(&cohort_id)
+            .map(|meta| meta.cohort.normalized_name.as_str());
+        self.settings.cohort_allowed(cohort_id, normalized_name)
+    }
+
+    /// Why a cohort is unavailable, if it failed to load
+    pub fn cohort_load_error(&self, cohort_id: i32) -> Option<String> {
+        self.cohort_load_errors
+            .lock()
+            .unwrap()
+            .get(&cohort_id)
+            .cloned()
+    }
+
+    /// Load a cohort into memory if it isn't already, returning whether it was loaded now.
+    /// An already-loaded cohort is left as is rather than reloaded, while one that failed
+    /// to load is tried again, e.g. after its files are fixed. Only cohorts whose metadata
+    /// loaded at startup can be loaded.
+    pub async fn preload_cohort(self: &Arc<Self>, cohort_id: i32) -> Result<bool> {
+        if self
+            .cohort_id_to_data
+            .lock()
+            .unwrap()
+            .contains_key(&cohort_id)
+        {
+            return Ok(false);
+        }
+        if !self.cohorts.contains_key(&cohort_id) {
+            if let Some(load_error) = self.cohort_load_error(cohort_id) {
+                bail!("Cohort {} is unavailable: {}", cohort_id, load_error);
+            }
+            bail!("Cohort {} not found", cohort_id);
+        }
+        self.cohort_load_errors.lock().unwrap().remove(&cohort_id);
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || state.cohort_data(cohort_id)).await??;
+        Ok(true)
+    }
+
+    /// Load a cohort's data from disk again, e.g. after its files are updated, and drop the
+    /// projections computed from the old data. Requests already using the old data finish
+    /// with it, but their projections aren't cached.
+    pub async fn reload_cohort(self: &Arc<Self>, cohort_id: i32) -> Result<()> {
+        if !self.cohorts.contains_key(&cohort_id) {
+            bail!("Cohort {} not found", cohort_id);
+        }
+        // Invalidated before the new data is loaded, so that it's loaded in a new generation
+        self.projections
+            .lock()
+            .unwrap()
+            .invalidate_cohort(cohort_id);
+        self.cohort_id_to_data.lock().unwrap().remove(&cohort_id);
+        self.cohort_load_errors.lock().unwrap().remove(&cohort_id);
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || state.cohort_data(cohort_id)).await??;
+        Ok(())
+    }
+}
+
+/// Load the metadata of each cohort. A cohort whose metadata fails to load is left out
+/// and its error returned instead, so that one corrupt cohort doesn't stop the server.
+pub fn load_cohort_metas(
+    cohorts: Vec<Cohort>,
+    root: &Path,
+) -> (HashMap<i32, Arc<CohortMeta>>, HashMap<i32, String>) {
+    let mut metas = HashMap::new();
+    let mut load_errors = HashMap::new();
+    for cohort in cohorts {
+        let cohort_id = cohort.id.expect("Cohort ID is missing");
+        match CohortMeta::load(cohort, root) {
+            Ok(meta) => {
+                metas.insert(cohort_id, Arc::new(meta));
+            }
+            Err(err) => {
+                let load_error = format!("{:#}", err);
+                error!("Failed to load cohort {}: {}", cohort_id, load_error);
+                load_errors.insert(cohort_id, load_error);
+            }
+        }
+    }
+    (metas, load_errors)
+}
+
+/// Create the `results` directory under `root` if it doesn't exist, returning its path.
+/// Workers write results and metadata there, so a missing directory fails every request.
+pub fn ensure_results_directory(root: &Path) -> Result<PathBuf> {
+    let results_directory = root.join("results");
+    std::fs::create_dir_all(&results_directory).context(anyhow!(
+        "Failed to create results directory at {}",
+        results_directory.display()
+    ))?;
+    Ok(results_directory)
+}
+
+/// S3 client configuration, sending requests to `endpoint` instead of AWS when it's given.
+/// S3-compatible stores (e.g. MinIO) generally don't serve buckets as subdomains, so an
+/// overridden endpoint uses path-style addressing.
+pub fn s3_client_config(shared_config: &SdkConfig, endpoint: Option<&str>) -> aws_sdk_s3::Config {
+    let mut builder = aws_sdk_s3::config::Builder::from(shared_config);
+    if let Some(endpoint) = endpoint {
+        builder = builder.endpoint_url(endpoint).force_path_style(true);
+    }
+    builder.build()
+}
+
+/// Fetch the features of a cohort, most-measured first. Ties are broken by code so that
+/// the ordering is the same on every call.
+pub async fn fetch_features(
+    db: &SqlitePool,
+    cohort_id: i32,
+) -> Result<Vec<FeatureResponse>, sqlx::Error> {
+    fetch_features_page(db, cohort_id, 0, None).await
+}
+
+/// Fetch up to `limit` features of a cohort (or all of them), skipping the first `offset`,
+/// in the same order as `fetch_features`. Codes are unique within a cohort, so the order is
+/// total and pages never overlap or skip a feature.
+pub async fn fetch_features_page(
+    db: &SqlitePool,
+    cohort_id: i32,
+    offset: usize,
+    limit: Option<usize>,
+) -> Result<Vec<FeatureResponse>, sqlx::Error> {
+    // A negative limit is no limit in SQLite
+    let limit = limit.map_or(-1, |limit| limit as i64);
+    sqlx::query_as::<_, FeatureResponse>(
+        "SELECT code, name, type as node_type, sample_size
+        FROM feature WHERE cohort_id = $1
+        ORDER BY sample_size DESC, code ASC
+        LIMIT $2 OFFSET $3",
+    )
+    .bind(cohort_id)
+    .bind(limit)
+    .bind(offset as i64)
+    .fetch_all(db)
+    .await
+}
+
+/// Number of features of a cohort
+pub async fn count_features(db: &SqlitePool, cohort_id: i32) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT COUNT(*) FROM feature WHERE cohort_id = $1")
+        .bind(cohort_id)
+        .fetch_one(db)
+        .await
+}
+
+/// Queue of pending requests that lets workers block until a request arrives.
+/// Requests are popped cheapest first (by `WebGWASRequestId::cost`), with ties going
+/// to the earliest `request_time`. Popped requests are tracked as running until the
+/// worker calls `finish`.
+#[derive(Default)]
+pub struct RequestQueue {
+    requests: Mutex<Vec<WebGWASRequestId>>,
+    running: Mutex<Vec<QueueEntry>>,
+    available: Condvar,
+}
+
+/// A queued or running request
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueEntry {
+    pub id: Uuid,
+    pub cohort_id: i32,
+    pub request_time: Instant,
+    /// Zero-based position in the queue, absent once a worker has taken the request
+    pub queue_position: Option<usize>,
+}
+
+impl RequestQueue {
+    /// Add a request and wake one waiting worker
+    pub fn push(&self, request: WebGWASRequestId) {
+        self.requests.lock().unwrap().push(request);
+        self.available.notify_one();
+    }
+
+    /// Take the cheapest request, blocking until one is available
+    pub fn pop(&self) -> WebGWASRequestId {
+        let mut requests = self.requests.lock().unwrap();
+        loop {
+            let next = requests
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, request)| (request.cost, request.request_time))
+                .map(|(index, _)| index);
+            if let Some(index) = next {
+                let request = requests.swap_remove(index);
+                self.running.lock().unwrap().push(QueueEntry {
+                    id: request.id,
+                    cohort_id: request.cohort_id,
+                    request_time: request.request_time,
+                    queue_position: None,
+                });
+                return request;
+            }
+            requests = self.available.wait(requests).unwrap();
+        }
+    }
+
+    /// Number of queued and running requests
+    pub fn len(&self) -> usize {
+        let requests = self.requests.lock().unwrap();
+        requests.len() + self.running.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stop tracking a popped request as running
+    pub fn finish(&self, id: &Uuid) {
+        self.running.lock().unwrap().retain(|entry| entry.id != *id);
+    }
+
+    /// Every queued request in the order they'll be popped, followed by the running ones.
+    /// Both are read under the queue lock, so a request is never missed or listed twice.
+    pub fn snapshot(&self) -> Vec<QueueEntry> {
+        let requests = self.requests.lock().unwrap();
+        let mut queued = requests.iter().collect::<Vec<&WebGWASRequestId>>();
+        queued.sort_by_key(|request| (request.cost, request.request_time));
+        let mut entries = queued
+            .iter()
+            .enumerate()
+            .map(|(position, request)| QueueEntry {
+                id: request.id,
+                cohort_id: request.cohort_id,
+                request_time: request.request_time,
+                queue_position: Some(position),
+            })
+            .collect::<Vec<QueueEntry>>();
+        entries.extend(self.running.lock().unwrap().iter().cloned());
+        entries
+    }
+}
+
+/// Number of recent requests averaged by `RequestDurations`
+pub const REQUEST_DURATION_HISTORY: usize = 20;
+
+/// How long recently completed requests took, for estimating how long new ones will wait
+#[derive(Default)]
+pub struct RequestDurations {
+    durations: Mutex<VecDeque<Duration>>,
+}
+
+impl RequestDurations {
+    /// Record a completed request, forgetting the oldest once the history is full
+    pub fn record(&self, duration: Duration) {
+        let mut durations = self.durations.lock().unwrap();
+        if durations.len() == REQUEST_DURATION_HISTORY {
+            durations.pop_front();
+        }
+        durations.push_back(duration);
+    }
+
+    /// Mean duration of the recent requests, if there are any
+    pub fn average(&self) -> Option<Duration> {
+        let durations = self.durations.lock().unwrap();
+        if durations.is_empty() {
+            return None;
+        }
+        Some(durations.iter().sum::<Duration>() / durations.len() as u32)
+    }
+
+    /// Approximate time until a request submitted now is done, assuming the `n_ahead`
+    /// requests already queued or running are shared evenly between the workers and each
+    /// takes the average duration. Cheaper requests are run first, so this is only a guide.
+    pub fn estimate_wait(&self, n_ahead: usize, n_workers: usize) -> Option<Duration> {
+        let rounds = n_ahead as f64 / n_workers.max(1) as f64 + 1.0;
+        self.average().map(|average| average.mul_f64(rounds))
+    }
+}
+
+/// Number of threads the machine can run in parallel
+pub fn available_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Threads shared by concurrent GWAS computations, so that several workers together
+/// don't run more threads than the machine has
+pub struct ThreadBudget {
+    total: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl ThreadBudget {
+    pub fn new(total: usize) -> Self {
+        let total = total.max(1);
+        Self {
+            total,
+            available: Mutex::new(total),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Take `n` threads (at most the whole budget), blocking until they are free.
+    /// The threads are returned when the permit is dropped.
+    pub fn acquire(&self, n: usize) -> ThreadPermit<'_> {
+        let n = n.clamp(1, self.total);
+        let mut available = self.available.lock().unwrap();
+        while *available < n {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= n;
+        ThreadPermit { budget: self, n }
+    }
+}
+
+pub struct ThreadPermit<'a> {
+    budget: &'a ThreadBudget,
+    pub n: usize,
+}
+
+impl Drop for ThreadPermit<'_> {
+    fn drop(&mut self) {
+        *self.budget.available.lock().unwrap() += self.n;
+        self.budget.released.notify_all();
+    }
+}
+
+/// Token-bucket limit on submissions from each client. A client starts with `burst`
+/// tokens, spends one per submission, and regains `per_minute` tokens every minute.
+pub struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, per_minute: u32) -> Self {
+        Self {
+            burst: burst.max(1) as f64,
+            per_second: per_minute.max(1) as f64 / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Spend a token for `client`, or return how long until one becomes available
+    pub fn check(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let refill = |tokens: f64, last: Instant| {
+            let elapsed = now.saturating_duration_since(last).as_secs_f64();
+            (tokens + elapsed * self.per_second).min(self.burst)
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        // A client whose bucket has refilled is no different from a new one
+        buckets.retain(|_, (tokens, last)| refill(*tokens, *last) < self.burst);
+        let (tokens, last) = buckets
+            .entry(client.to_string())
+            .or_insert((self.burst, now));
+        let available = refill(*tokens, *last);
+        if available < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - available) / self.per_second));
+        }
+        *tokens = available - 1.0;
+        *last = now;
+        Ok(())
+    }
+}
+
+/// Submissions made with an `Idempotency-Key`, so that retrying a submission within `ttl`
+/// returns the original response rather than submitting it again. Keys are scoped to the
+/// client that used them. They're stored in the `idempotency_key` table with a hash of the
+/// request body, so they survive restarts and can't be reused for a different request.
+pub struct IdempotencyKeys {
+    db: SqlitePool,
+    ttl: Duration,
+    /// Keys whose submission is running, which aren't in the table yet
+    in_flight: Mutex<HashSet<(String, String)>>,
+}
+
+/// What to do with a submission made with an `Idempotency-Key`
+pub enum IdempotencyCheck<'a> {
+    /// The key is new, so submit, then `complete` the reservation
+    Submit(IdempotencyReservation<'a>),
+    /// The key was used for the same body, and this was the response
+    Replay(WebGWASResponse),
+    /// The key was used for a different body
+    Mismatch,
+    /// A submission with the key is still running
+    InProgress,
+}
+
+impl IdempotencyKeys {
+    /// Create the table if needed
+    pub async fn new(db: SqlitePool, ttl: Duration) -> Result<Self> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS idempotency_key (
+                client TEXT NOT NULL,
+                key TEXT NOT NULL,
+                body_hash TEXT NOT NULL,
+                response TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (client, key)
+            );",
+        )
+        .execute(&db)
+        .await?;
+        Ok(Self {
+            db,
+            ttl,
+            in_flight: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Check `client`'s `key` for a submission whose body hashes to `body_hash`, at `now`
+    /// (Unix seconds). Keys are forgotten once they're `ttl` old. No lock is held while the
+    /// caller submits: the key is reserved instead, so concurrent retries can't both submit.
+    pub async fn check(
+        &self,
+        client: &str,
+        key: &str,
+        body_hash: &str,
+        now: i64,
+    ) -> Result<IdempotencyCheck<'_>> {
+        let key = (client.to_string(), key.to_string());
+        // Reserved before looking the key up, so that a submission finishing in between
+        // has already stored its response
+        if !self.in_flight.lock().unwrap().insert(key.clone()) {
+            return Ok(IdempotencyCheck::InProgress);
+        }
+        let reservation = IdempotencyReservation {
+            keys: self,
+            key,
+            body_hash: body_hash.to_string(),
+        };
+        sqlx::query("DELETE FROM idempotency_key WHERE created_at <= $1")
+            .bind(now - self.ttl.as_secs() as i64)
+            .execute(&self.db)
+            .await?;
+        let stored = sqlx::query_as::<_, (String, String)>(
+            "SELECT body_hash, response FROM idempotency_key WHERE client = $1 AND key = $2",
+        )
+        .bind(&reservation.key.0)
+        .bind(&reservation.key.1)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(match stored {
+            Some((stored_hash, _)) if stored_hash != body_hash => IdempotencyCheck::Mismatch,
+            Some((_, response)) => IdempotencyCheck::Replay(serde_json::from_str(&response)?),
+            None => IdempotencyCheck::Submit(reservation),
+        })
+    }
+}
+
+/// A key reserved for a submission, which is released when this is dropped. Failed
+/// submissions aren't `complete`d, so they can be retried with the same key.
+pub struct IdempotencyReservation<'a> {
+    keys: &'a IdempotencyKeys,
+    key: (String, String),
+    body_hash: String,
+}
+
+impl IdempotencyReservation<'_> {
+    /// Store the submission's response, made at `now` (Unix seconds), for retries
+    pub async fn complete(self, response: &WebGWASResponse, now: i64) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO idempotency_key (client, key, body_hash, response, created_at)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&self.key.0)
+        .bind(&self.key.1)
+        .bind(&self.body_hash)
+        .bind(serde_json::to_string(response)?)
+        .bind(now)
+        .execute(&self.keys.db)
+        .await?;
+        Ok(())
+    }
+}
+
+impl Drop for IdempotencyReservation<'_> {
+    fn drop(&mut self) {
+        self.keys.in_flight.lock().unwrap().remove(&self.key);
+    }
 }
 
 pub struct ResultsCache {
@@ -166,7 +742,472 @@ impl ResultsCache {
         self.id_to_result.get(id)
     }
 
+    /// Look up a result without marking it as recently used
+    pub fn peek(&self, id: &Uuid) -> Option<&WebGWASResult> {
+        self.id_to_result.peek(id)
+    }
+
     pub fn get_mut(&mut self, id: &Uuid) -> Option<&mut WebGWASResult> {
         self.id_to_result.get_mut(id)
     }
 }
+
+/// A phenotype's projection onto the cohort features, standardized to the cohort's
+/// feature order, along with the quantities derived from the same fit
+pub struct CachedProjection {
+    pub projection: Projection,
+    pub intercept: f32,
+    pub projection_variance: f32,
+    /// Samples missing the phenotype, which the options' missing policy dropped or imputed
+    pub n_missing: usize,
+}
+
+/// Projections keyed by cohort, generation, and phenotype definition hash, so that
+/// summarizing a phenotype and then running its GWAS only computes the projection once
+pub struct ProjectionCache {
+    key_to_projection: hashlru::Cache<(i32, u64, u64, ProjectionOptions), Arc<CachedProjection>>,
+    /// Number of times each cohort has been invalidated. Cohort data records the
+    /// generation it was loaded in, so projections of data loaded before an invalidation
+    /// are never returned or stored.
+    cohort_generations: HashMap<i32, u64>,
+}
+
+impl ProjectionCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            key_to_projection: hashlru::Cache::new(capacity),
+            cohort_generations: HashMap::new(),
+        }
+    }
+
+    /// The cohort's current generation, which data loaded now belongs to
+    pub fn generation(&self, cohort_id: i32) -> u64 {
+        self.cohort_generations
+            .get(&cohort_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn get(
+        &mut self,
+        cohort_id: i32,
+        generation: u64,
+        definition: &[Node],
+        options: &ProjectionOptions,
+    ) -> Option<Arc<CachedProjection>> {
+        let key = (
+            cohort_id,
+            generation,
+            hash_phenotype_definition(definition),
+            options.clone(),
+        );
+        self.key_to_projection.get(&key).cloned()
+    }
+
+    /// Cache a projection computed from data of the given generation, unless the cohort
+    /// has been invalidated since
+    pub fn insert(
+        &mut self,
+        cohort_id: i32,
+        generation: u64,
+        definition: &[Node],
+        options: &ProjectionOptions,
+        projection: Arc<CachedProjection>,
+    ) {
+        if generation != self.generation(cohort_id) {
+            return;
+        }
+        let key = (
+            cohort_id,
+            generation,
+            hash_phenotype_definition(definition),
+            options.clone(),
+        );
+        self.key_to_projection.insert(key, projection);
+    }
+
+    /// Drop all projections for a cohort and start its next generation, which must happen
+    /// whenever its data is reloaded
+    pub fn invalidate_cohort(&mut self, cohort_id: i32) {
+        *self.cohort_generations.entry(cohort_id).or_insert(0) += 1;
+        let keys = self
+            .key_to_projection
+            .keys()
+            .filter(|(id, _, _, _)| *id == cohort_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.key_to_projection.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_config::BehaviorVersion;
+    use aws_sdk_s3::config::{Credentials, SharedCredentialsProvider};
+    use aws_sdk_s3::presigning::PresigningConfig;
+    use std::thread;
+
+    async fn presigned_url(endpoint: Option<&str>) -> String {
+        let shared_config = SdkConfig::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("eu-central-1"))
+            .credentials_provider(SharedCredentialsProvider::new(Credentials::for_tests()))
+            .build();
+        Client::from_conf(s3_client_config(&shared_config, endpoint))
+            .get_object()
+            .bucket("webgwas")
+            .key("results/abc.zip")
+            .presigned(PresigningConfig::expires_in(Duration::from_secs(60)).unwrap())
+            .await
+            .unwrap()
+            .uri()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_s3_client_config() {
+        let url = presigned_url(None).await;
+        assert!(
+            url.starts_with("https://webgwas.s3.eu-central-1.amazonaws.com/results/abc.zip?"),
+            "{url}"
+        );
+        let url = presigned_url(Some("http://localhost:9000")).await;
+        assert!(
+            url.starts_with("http://localhost:9000/webgwas/results/abc.zip?"),
+            "{url}"
+        );
+        assert!(url.contains("eu-central-1"), "{url}");
+    }
+
+    #[test]
+    fn test_ensure_results_directory() {
+        let root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&root).unwrap();
+        let results_directory = ensure_results_directory(&root).unwrap();
+        assert_eq!(results_directory, root.join("results"));
+        assert!(results_directory.is_dir());
+        // Already existing is fine
+        ensure_results_directory(&root).unwrap();
+
+        // A file in the way can't be replaced by a directory
+        let blocked_root = root.join("blocked");
+        std::fs::create_dir_all(&blocked_root).unwrap();
+        File::create(blocked_root.join("results")).unwrap();
+        let err = ensure_results_directory(&blocked_root).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Failed to create results directory"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_request_queue_pop_blocks_until_push() {
+        let queue = Arc::new(RequestQueue::default());
+        let worker_queue = queue.clone();
+        let handle = thread::spawn(move || worker_queue.pop().id);
+        let id = Uuid::new_v4();
+        queue.push(WebGWASRequestId::new(
+            id,
+            Vec::new(),
+            0,
+            Default::default(),
+            None,
+            10,
+        ));
+        assert_eq!(handle.join().unwrap(), id);
+    }
+
+    #[test]
+    fn test_request_queue_snapshot() {
+        let queue = RequestQueue::default();
+        let request = |cost| {
+            WebGWASRequestId::new(
+                Uuid::new_v4(),
+                Vec::new(),
+                0,
+                Default::default(),
+                None,
+                cost,
+            )
+        };
+        let (running, expensive, cheap) = (request(1), request(1000), request(10));
+        let (running_id, expensive_id, cheap_id) = (running.id, expensive.id, cheap.id);
+        queue.push(running);
+        assert_eq!(queue.pop().id, running_id);
+        queue.push(expensive);
+        queue.push(cheap);
+        let positions = queue
+            .snapshot()
+            .iter()
+            .map(|entry| (entry.id, entry.queue_position))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            positions,
+            vec![
+                (cheap_id, Some(0)),
+                (expensive_id, Some(1)),
+                (running_id, None)
+            ]
+        );
+        queue.finish(&running_id);
+        assert_eq!(queue.snapshot().len(), 2);
+    }
+
+    #[test]
+    fn test_request_queue_pops_cheapest_first() {
+        let queue = RequestQueue::default();
+        let expensive = WebGWASRequestId::new(
+            Uuid::new_v4(),
+            Vec::new(),
+            0,
+            Default::default(),
+            None,
+            1000,
+        );
+        let cheap_first =
+            WebGWASRequestId::new(Uuid::new_v4(), Vec::new(), 0, Default::default(), None, 10);
+        let cheap_second =
+            WebGWASRequestId::new(Uuid::new_v4(), Vec::new(), 0, Default::default(), None, 10);
+        let expected = [cheap_first.id, cheap_second.id, expensive.id];
+        queue.push(expensive);
+        queue.push(cheap_first);
+        queue.push(cheap_second);
+        let popped = (0..3).map(|_| queue.pop().id).collect::<Vec<Uuid>>();
+        assert_eq!(popped, expected);
+    }
+
+    async fn feature_db() -> SqlitePool {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::query(
+            "CREATE TABLE feature (code TEXT, name TEXT, type TEXT, sample_size INTEGER, cohort_id INTEGER)",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        for (code, sample_size) in [("c", 10), ("a", 10), ("d", 20), ("b", 10)] {
+            sqlx::query("INSERT INTO feature VALUES ($1, $1, 'REAL', $2, 1)")
+                .bind(code)
+                .bind(sample_size)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+        db
+    }
+
+    fn codes(features: Vec<FeatureResponse>) -> Vec<String> {
+        features.into_iter().map(|x| x.code).collect()
+    }
+
+    #[tokio::test]
+    async fn test_fetch_features_stable_order() {
+        let db = feature_db().await;
+        let first = codes(fetch_features(&db, 1).await.unwrap());
+        let second = codes(fetch_features(&db, 1).await.unwrap());
+        assert_eq!(first, vec!["d", "a", "b", "c"]);
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_features_pages() {
+        let db = feature_db().await;
+        assert_eq!(count_features(&db, 1).await.unwrap(), 4);
+        let mut pages = Vec::new();
+        for offset in (0..5).step_by(3) {
+            pages.extend(codes(
+                fetch_features_page(&db, 1, offset, Some(3)).await.unwrap(),
+            ));
+        }
+        assert_eq!(pages, codes(fetch_features(&db, 1).await.unwrap()));
+        assert!(fetch_features_page(&db, 1, 4, Some(3))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_thread_budget_blocks_until_released() {
+        let budget = Arc::new(ThreadBudget::new(4));
+        let permit = budget.acquire(3);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let worker_budget = budget.clone();
+        let handle = thread::spawn(move || {
+            let permit = worker_budget.acquire(2);
+            sender.send(permit.n).unwrap();
+        });
+        let timeout = std::time::Duration::from_millis(50);
+        assert!(receiver.recv_timeout(timeout).is_err());
+        drop(permit);
+        assert_eq!(receiver.recv().unwrap(), 2);
+        handle.join().unwrap();
+        // Requests beyond the budget are clamped to it rather than blocking forever
+        assert_eq!(budget.acquire(100).n, 4);
+    }
+
+    #[test]
+    fn test_projection_cache_invalidate_cohort() {
+        let definition = vec![Node::Operator(models::Operators::Root)];
+        let cached = Arc::new(CachedProjection {
+            projection: Projection::new(vec!["a".to_string()], faer::col![1.0]).unwrap(),
+            intercept: 0.0,
+            projection_variance: 1.0,
+            n_missing: 0,
+        });
+        let options = ProjectionOptions::default();
+        let mut cache = ProjectionCache::new(10);
+        cache.insert(1, 0, &definition, &options, cached.clone());
+        cache.insert(2, 0, &definition, &options, cached.clone());
+        assert!(cache.get(1, 0, &definition, &options).is_some());
+        let standardized = ProjectionOptions {
+            standardize_features: true,
+            ..Default::default()
+        };
+        assert!(cache.get(1, 0, &definition, &standardized).is_none());
+        cache.invalidate_cohort(1);
+        assert_eq!(cache.generation(1), 1);
+        assert_eq!(cache.generation(2), 0);
+        assert!(cache.get(1, 0, &definition, &options).is_none());
+        assert!(cache.get(2, 0, &definition, &options).is_some());
+
+        // A projection of data loaded before the reload finishes after it, and isn't kept
+        cache.insert(1, 0, &definition, &options, cached.clone());
+        assert!(cache.get(1, 0, &definition, &options).is_none());
+        assert!(cache.get(1, 1, &definition, &options).is_none());
+        cache.insert(1, 1, &definition, &options, cached);
+        assert!(cache.get(1, 1, &definition, &options).is_some());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2, 60);
+        let start = Instant::now();
+        assert!(limiter.check("a", start).is_ok());
+        assert!(limiter.check("a", start).is_ok());
+        let retry_after = limiter.check("a", start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+        // Other clients have their own buckets
+        assert!(limiter.check("b", start).is_ok());
+        // One token is regained per second
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check("a", later).is_ok());
+        assert!(limiter.check("a", later).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_keys() {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let keys = IdempotencyKeys::new(db.clone(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        /// The id of the request submitted or replayed with the key, if either was allowed
+        async fn submit(
+            keys: &IdempotencyKeys,
+            client: &str,
+            key: &str,
+            body_hash: &str,
+            now: i64,
+        ) -> Option<Uuid> {
+            match keys.check(client, key, body_hash, now).await.unwrap() {
+                IdempotencyCheck::Submit(reservation) => {
+                    let response = WebGWASResponse {
+                        request_id: Uuid::new_v4(),
+                        status: models::WebGWASResultStatus::Queued,
+                        message: None,
+                        estimated_wait_secs: None,
+                    };
+                    reservation.complete(&response, now).await.unwrap();
+                    Some(response.request_id)
+                }
+                IdempotencyCheck::Replay(response) => Some(response.request_id),
+                _ => None,
+            }
+        }
+        let first = submit(&keys, "a", "k", "body", 0).await.unwrap();
+        assert_eq!(submit(&keys, "a", "k", "body", 0).await, Some(first));
+        // Keys are scoped to the client that used them
+        assert_ne!(submit(&keys, "b", "k", "body", 0).await, Some(first));
+        // A key can't be reused for a different request
+        assert!(matches!(
+            keys.check("a", "k", "other", 0).await.unwrap(),
+            IdempotencyCheck::Mismatch
+        ));
+        // A retry while the first submission runs doesn't submit again, and failed
+        // submissions aren't remembered
+        let IdempotencyCheck::Submit(reservation) = keys.check("a", "f", "body", 0).await.unwrap()
+        else {
+            panic!("Expected a new key");
+        };
+        assert!(matches!(
+            keys.check("a", "f", "body", 0).await.unwrap(),
+            IdempotencyCheck::InProgress
+        ));
+        drop(reservation);
+        assert!(matches!(
+            keys.check("a", "f", "body", 0).await.unwrap(),
+            IdempotencyCheck::Submit(_)
+        ));
+        // Keys are kept across restarts, but forgotten after the TTL
+        let keys = IdempotencyKeys::new(db, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(submit(&keys, "a", "k", "body", 59).await, Some(first));
+        assert_ne!(submit(&keys, "a", "k", "body", 60).await, Some(first));
+    }
+
+    #[test]
+    fn test_request_durations() {
+        let durations = RequestDurations::default();
+        assert_eq!(durations.estimate_wait(3, 2), None);
+        durations.record(Duration::from_secs(10));
+        durations.record(Duration::from_secs(20));
+        assert_eq!(durations.average(), Some(Duration::from_secs(15)));
+        // Four ahead on two workers is two rounds, then this request's own
+        assert_eq!(durations.estimate_wait(4, 2), Some(Duration::from_secs(45)));
+        for _ in 0..REQUEST_DURATION_HISTORY {
+            durations.record(Duration::from_secs(1));
+        }
+        assert_eq!(durations.average(), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_load_cohort_metas_isolates_corrupt_cohort() {
+        let root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let cohorts = ["good", "corrupt"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                std::fs::create_dir_all(root.join("cohorts").join(name)).unwrap();
+                Cohort {
+                    id: Some(i as i32 + 1),
+                    name: name.to_string(),
+                    normalized_name: name.to_string(),
+                    num_covar: None,
+                }
+            })
+            .collect::<Vec<Cohort>>();
+        // A parquet file cut short, as if its upload was interrupted
+        let mut aliases = df!("alias" => ["bmi"], "code" => ["21001"]).unwrap();
+        let mut bytes = Vec::new();
+        ParquetWriter::new(&mut bytes).finish(&mut aliases).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        let corrupt_path = root.join("cohorts").join("corrupt").join("aliases.parquet");
+        std::fs::write(corrupt_path, bytes).unwrap();
+
+        let (metas, load_errors) = load_cohort_metas(cohorts, &root);
+        assert_eq!(metas.keys().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(load_errors.keys().collect::<Vec<_>>(), vec![&2]);
+        assert!(load_errors[&2].contains("Failed to read aliases file"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
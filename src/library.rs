@@ -0,0 +1,126 @@
+use serde::Serialize;
+use sqlx::{prelude::FromRow, SqlitePool};
+
+/// A phenotype definition saved under a name, with what's needed to submit it again
+#[derive(Clone, Debug, PartialEq, FromRow, Serialize)]
+pub struct SavedDefinition {
+    pub id: i64,
+    pub name: String,
+    pub cohort_id: i32,
+    pub phenotype_definition: String,
+    /// RFC 3339 time (UTC) when the definition was saved
+    pub created_at: String,
+}
+
+/// Create the `saved_definition` table if needed. Names are unique within a cohort.
+pub async fn create_definitions_table(db: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS saved_definition (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            cohort_id INTEGER NOT NULL,
+            phenotype_definition TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            UNIQUE (cohort_id, name)
+        );",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Save a definition, which fails with a unique violation if the cohort already has one
+/// with the same name
+pub async fn save_definition(
+    db: &SqlitePool,
+    name: &str,
+    cohort_id: i32,
+    phenotype_definition: &str,
+) -> Result<SavedDefinition, sqlx::Error> {
+    let created_at = chrono::Utc::now().to_rfc3339();
+    let id = sqlx::query(
+        "INSERT INTO saved_definition (name, cohort_id, phenotype_definition, created_at)
+        VALUES ($1, $2, $3, $4)",
+    )
+    .bind(name)
+    .bind(cohort_id)
+    .bind(phenotype_definition)
+    .bind(&created_at)
+    .execute(db)
+    .await?
+    .last_insert_rowid();
+    Ok(SavedDefinition {
+        id,
+        name: name.to_string(),
+        cohort_id,
+        phenotype_definition: phenotype_definition.to_string(),
+        created_at,
+    })
+}
+
+/// Saved definitions, optionally only those of one cohort, ordered by cohort and name
+pub async fn list_definitions(
+    db: &SqlitePool,
+    cohort_id: Option<i32>,
+) -> Result<Vec<SavedDefinition>, sqlx::Error> {
+    sqlx::query_as::<_, SavedDefinition>(
+        "SELECT id, name, cohort_id, phenotype_definition, created_at FROM saved_definition
+        WHERE $1 IS NULL OR cohort_id = $1 ORDER BY cohort_id, name",
+    )
+    .bind(cohort_id)
+    .fetch_all(db)
+    .await
+}
+
+pub async fn fetch_definition(
+    db: &SqlitePool,
+    id: i64,
+) -> Result<Option<SavedDefinition>, sqlx::Error> {
+    sqlx::query_as::<_, SavedDefinition>(
+        "SELECT id, name, cohort_id, phenotype_definition, created_at FROM saved_definition
+        WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(db)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_saved_definitions() {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        create_definitions_table(&db).await.unwrap();
+        let bmi = save_definition(&db, "bmi", 1, "\"21001\"").await.unwrap();
+        save_definition(&db, "age", 1, "\"21022\"").await.unwrap();
+        save_definition(&db, "bmi", 2, "\"bmi\"").await.unwrap();
+        // Names are unique within a cohort only
+        let err = save_definition(&db, "bmi", 1, "\"other\"")
+            .await
+            .unwrap_err();
+        assert!(err
+            .as_database_error()
+            .is_some_and(|err| err.is_unique_violation()));
+
+        let names = |definitions: Vec<SavedDefinition>| {
+            definitions
+                .into_iter()
+                .map(|definition| (definition.cohort_id, definition.name))
+                .collect::<Vec<(i32, String)>>()
+        };
+        assert_eq!(
+            names(list_definitions(&db, Some(1)).await.unwrap()),
+            vec![(1, "age".to_string()), (1, "bmi".to_string())]
+        );
+        assert_eq!(list_definitions(&db, None).await.unwrap().len(), 3);
+        assert_eq!(fetch_definition(&db, bmi.id).await.unwrap(), Some(bmi));
+        assert_eq!(fetch_definition(&db, 100).await.unwrap(), None);
+    }
+}
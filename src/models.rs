@@ -1,16 +1,28 @@
 use anyhow::{anyhow, bail, Context, Result};
-use faer::Mat;
+use chrono::{DateTime, Utc};
+use faer::{Col, Mat};
 use faer_ext::polars::polars_to_faer_f32;
+use itertools::Itertools;
+use log::warn;
 use polars::prelude::*;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
+use std::collections::HashMap;
 use std::fs::File;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
 use std::{fmt::Display, path::Path};
 use tracing::info_span;
 use uuid::Uuid;
 
+use crate::igwas::{
+    result_column_names, validate_gwas_columns, Annotations, GwasColumns, GwasData, IgwasSummary,
+};
+use crate::phenotype_definitions::{format_phenotype_definition, resolve_feature_index};
+use crate::utils::load_optional_toml;
+
 #[derive(Serialize, FromRow, Debug)]
 pub struct CohortResponse {
     pub id: i32,
@@ -25,7 +37,7 @@ pub struct Cohort {
     pub num_covar: Option<i32>,
 }
 
-#[derive(Serialize, PartialEq, Clone, Copy, Debug, Deserialize, sqlx::Type)]
+#[derive(Serialize, PartialEq, Eq, Hash, Clone, Copy, Debug, Deserialize, sqlx::Type)]
 #[sqlx(rename_all = "UPPERCASE")]
 pub enum NodeType {
     #[serde(rename = "BOOL")]
@@ -87,6 +99,31 @@ pub struct ValidPhenotypeResponse {
     pub is_valid: bool,
     pub message: String,
     pub phenotype_definition: String,
+    /// Parsed definition in postfix order, included when requested with `include_ast`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ast: Option<Vec<Node>>,
+    /// 1-based character position of the offending token, for invalid definitions that
+    /// failed to parse or whose operators have the wrong number or type of operands
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_position: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_token: Option<String>,
+    /// Feature codes the cohort doesn't have, when validating against every cohort
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing_features: Option<Vec<String>>,
+}
+
+/// A phenotype definition to validate against every cohort
+#[derive(Deserialize)]
+pub struct ValidateAllCohortsRequest {
+    pub phenotype_definition: String,
+    #[serde(default)]
+    pub syntax: DefinitionSyntax,
+}
+
+#[derive(Deserialize)]
+pub struct ValidatePhenotypeQuery {
+    pub include_ast: Option<bool>,
 }
 
 pub struct ValidPhenotype {
@@ -96,26 +133,289 @@ pub struct ValidPhenotype {
     pub valid_nodes: Vec<Node>,
 }
 
+/// Options that change how a phenotype is projected onto the cohort features
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct ProjectionOptions {
+    /// Z-score standardize feature columns before the regression
+    #[serde(default)]
+    pub standardize_features: bool,
+    /// Project onto only these feature codes rather than every feature of the cohort
+    #[serde(default)]
+    pub feature_subset: Option<Vec<String>>,
+    /// How samples missing the phenotype are handled when fitting the projection
+    #[serde(default)]
+    pub missing_policy: MissingPolicy,
+    /// Regress the phenotype on the cohort's covariates and project the residuals instead,
+    /// for cohorts registered with their covariates. Unset uses the cohort's default (see
+    /// `CohortDefaults`).
+    #[serde(default)]
+    pub residualize_covariates: Option<bool>,
+}
+
+/// How samples with a missing (NaN) phenotype value are handled when fitting the projection.
+/// Missing values include samples excluded by the definition, e.g. by `CASE_CONTROL`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingPolicy {
+    /// Fit on the complete cases only, so the fit uses fewer samples
+    #[default]
+    DropMissing,
+    /// Replace missing values with the mean of the others, so every sample is used but the
+    /// phenotype's variance shrinks towards the mean
+    MeanImpute,
+}
+
+impl Display for MissingPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MissingPolicy::DropMissing => write!(f, "dropped (complete cases only)"),
+            MissingPolicy::MeanImpute => write!(f, "mean-imputed"),
+        }
+    }
+}
+
+/// Samples missing the phenotype in a request, and how they were handled
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MissingPhenotypeSummary {
+    pub policy: MissingPolicy,
+    pub n_missing: usize,
+}
+
 #[derive(Deserialize, sqlx::Type)]
 pub struct PhenotypeSummaryRequest {
     pub phenotype_definition: String,
+    #[serde(default)]
+    pub syntax: DefinitionSyntax,
     pub cohort_id: i32,
+    /// Number of randomly chosen samples to return, or all samples if absent
     pub n_samples: Option<usize>,
+    /// Seed for choosing the samples, so that repeated requests return the same ones
+    pub seed: Option<u64>,
+    /// Number of fit quality reference points to return. Defaults to the number of samples
+    /// returned.
+    pub n_fit_quality_points: Option<usize>,
+    #[serde(flatten)]
+    pub projection_options: ProjectionOptions,
 }
 
-#[derive(Deserialize, sqlx::Type)]
+impl PhenotypeSummaryRequest {
+    /// The requested number of points of the fit quality reference, in their stored order.
+    /// By default, there are as many as samples returned, out of `n_cohort_samples`.
+    pub fn fit_quality_reference(
+        &self,
+        reference: &[PhenotypeFitQuality],
+        n_cohort_samples: usize,
+    ) -> Vec<PhenotypeFitQuality> {
+        let n_points = self
+            .n_fit_quality_points
+            .or(self.n_samples)
+            .unwrap_or(n_cohort_samples);
+        reference.iter().take(n_points).cloned().collect()
+    }
+}
+
+/// A phenotype to project onto a cohort's features, without running the GWAS
+#[derive(Deserialize)]
+pub struct ProjectionRequest {
+    pub phenotype_definition: String,
+    #[serde(default)]
+    pub syntax: DefinitionSyntax,
+    pub cohort_id: i32,
+    #[serde(flatten)]
+    pub projection_options: ProjectionOptions,
+}
+
+/// Two phenotypes to project onto the same cohort's features and compare
+#[derive(Deserialize)]
+pub struct CompareProjectionsRequest {
+    pub phenotype_definition_a: String,
+    pub phenotype_definition_b: String,
+    /// Syntax of both definitions
+    #[serde(default)]
+    pub syntax: DefinitionSyntax,
+    pub cohort_id: i32,
+    #[serde(flatten)]
+    pub projection_options: ProjectionOptions,
+}
+
+/// How similarly two phenotypes project onto a cohort's features
+#[derive(Serialize)]
+pub struct CompareProjectionsResponse {
+    pub cohort_id: i32,
+    /// Cosine similarity of the two phenotypes' projection coefficients, from -1 (opposite)
+    /// to 1 (the same up to scale). It's `None` when either phenotype has all-zero
+    /// coefficients, e.g. a constant phenotype. Coefficients are on the features' own
+    /// scales, so features with large values count for less.
+    pub cosine_similarity: Option<f32>,
+    /// Variance of each projected phenotype (see `ProjectionVarianceResponse`)
+    pub projection_variance_a: f32,
+    pub projection_variance_b: f32,
+}
+
+/// How much of a phenotype is captured by its projection onto the cohort features.
+///
+/// These are rough signals of fit, not heritability estimates: they describe the features,
+/// not genetics, and say nothing about how much of the projected phenotype is heritable.
+#[derive(Serialize)]
+pub struct ProjectionVarianceResponse {
+    pub phenotype_definition: String,
+    pub cohort_id: i32,
+    /// Variance of the projected phenotype, `beta' C beta` for projection coefficients
+    /// `beta` and feature covariance `C`. This is the variance the indirect GWAS uses.
+    pub projection_variance: f32,
+    /// Sum of the variances of every feature (the trace of `C`)
+    pub total_feature_variance: f32,
+    /// `projection_variance / total_feature_variance`. This depends on the scale of the
+    /// features, since rescaling one changes its share of the total, so it's only
+    /// comparable between phenotypes in the same cohort.
+    pub variance_ratio: f32,
+    /// Fraction of the phenotype's variance explained by the projection, over samples that
+    /// aren't missing the phenotype. This is an in-sample fit, so it's optimistic when
+    /// there are many features relative to samples.
+    pub rsquared: f32,
+}
+
+#[derive(Deserialize, Serialize, sqlx::Type)]
 pub struct WebGWASRequest {
     pub phenotype_definition: String,
+    #[serde(default)]
+    pub syntax: DefinitionSyntax,
     pub cohort_id: i32,
+    /// Number of covariates to use instead of the cohort's, for sensitivity analyses
+    pub num_covar: Option<i32>,
+    #[serde(flatten)]
+    pub projection_options: ProjectionOptions,
+    /// Label used to name the files inside the result zip
+    pub label: Option<String>,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Multiple testing correction to add to the results as an extra column of -log10
+    /// adjusted p-values
+    pub pvalue_adjustment: Option<PvalueAdjustment>,
+    /// Only return variants with a p-value below this (e.g. 5e-8), in (0, 1]
+    pub p_threshold: Option<f32>,
+    /// Bucket to deliver the result zip to, instead of the results bucket and a presigned
+    /// URL. It must be one of the server's `destination_buckets`.
+    pub destination_bucket: Option<String>,
+    /// Also add each variant's chromosome, position, and -log10(p) to the result zip as
+    /// `plot_data.parquet`, for Manhattan plots. The cohort's GWAS needs variant positions.
+    #[serde(default)]
+    pub plot_data: bool,
+}
+
+/// Method for adjusting p-values for multiple testing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PvalueAdjustment {
+    /// Family-wise error rate control, multiplying each p-value by the number of variants
+    Bonferroni,
+    /// False discovery rate control, which needs every p-value at once
+    BenjaminiHochberg,
+}
+
+impl PvalueAdjustment {
+    /// Name of the column of -log10 adjusted p-values in the results
+    pub fn column_name(&self) -> &'static str {
+        match self {
+            PvalueAdjustment::Bonferroni => "neg_log_p_adj_bonferroni",
+            PvalueAdjustment::BenjaminiHochberg => "neg_log_p_adj_bh",
+        }
+    }
+}
+
+impl Display for PvalueAdjustment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PvalueAdjustment::Bonferroni => write!(f, "Bonferroni"),
+            PvalueAdjustment::BenjaminiHochberg => write!(f, "Benjamini-Hochberg"),
+        }
+    }
+}
+
+/// How a phenotype definition in a request is written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DefinitionSyntax {
+    /// Nodes in postfix order, e.g. `"sbp" "dbp" `SUB``
+    #[default]
+    Postfix,
+    /// An expression with infix operators, e.g. `sbp - dbp` (see `crate::infix`)
+    Infix,
+}
+
+/// File format of the results inside the result zip
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Tab-separated text
+    #[default]
+    Tsv,
+    /// Arrow IPC, which keeps the exact values and types of every column
+    Arrow,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Tsv => "tsv",
+            OutputFormat::Arrow => "arrow",
+        }
+    }
 }
 
 pub struct WebGWASRequestId {
     pub id: Uuid,
     pub phenotype_definition: Vec<Node>,
     pub cohort_id: i32,
+    pub projection_options: ProjectionOptions,
+    pub num_covar: Option<i32>,
+    pub cost: usize,
+    pub request_time: Instant,
+    /// Sanitized label for the files inside the result zip
+    pub label: Option<String>,
+    pub output_format: OutputFormat,
+    pub pvalue_adjustment: Option<PvalueAdjustment>,
+    pub p_threshold: Option<f32>,
+    pub destination_bucket: Option<String>,
+    pub plot_data: bool,
 }
 
-#[derive(Clone, Serialize)]
+impl WebGWASRequestId {
+    pub fn new(
+        id: Uuid,
+        phenotype_definition: Vec<Node>,
+        cohort_id: i32,
+        projection_options: ProjectionOptions,
+        num_covar: Option<i32>,
+        n_variants: usize,
+    ) -> Self {
+        let cost = estimate_request_cost(&phenotype_definition, n_variants);
+        Self {
+            id,
+            phenotype_definition,
+            cohort_id,
+            projection_options,
+            num_covar,
+            cost,
+            request_time: Instant::now(),
+            label: None,
+            output_format: OutputFormat::default(),
+            pvalue_adjustment: None,
+            p_threshold: None,
+            destination_bucket: None,
+            plot_data: false,
+        }
+    }
+}
+
+/// Estimate the relative cost of a request as the number of nodes in the phenotype
+/// definition times the number of variants in the cohort. Each node is one pass over
+/// the samples when applying the definition, and the GWAS step scales with the variants.
+pub fn estimate_request_cost(phenotype_definition: &[Node], n_variants: usize) -> usize {
+    phenotype_definition.len().max(1) * n_variants
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum WebGWASResultStatus {
     Queued,
@@ -124,12 +424,15 @@ pub enum WebGWASResultStatus {
     Error,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct WebGWASResponse {
     pub request_id: Uuid,
     pub status: WebGWASResultStatus,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<String>,
+    /// Rough estimate of the seconds until the result is ready, null when there is no
+    /// history to base it on
+    pub estimated_wait_secs: Option<f32>,
 }
 
 #[derive(Clone, Serialize)]
@@ -140,8 +443,75 @@ pub struct WebGWASResult {
     pub error_msg: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub url: Option<String>,
+    /// Fraction of variants processed so far, between 0 and 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<f32>,
+    /// Size of the uploaded result in bytes, absent when nothing was uploaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_length: Option<i64>,
+    /// Hex-encoded SHA-256 of the result zip, to verify downloads
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Genomic inflation factor of the results
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lambda_gc: Option<f32>,
+    /// The definition as resolved against the cohort's features when it was queued,
+    /// which is exactly what gets computed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_definition: Option<Vec<Node>>,
     #[serde(skip_serializing)]
     pub local_result_file: Option<PathBuf>,
+    /// Where the result zip was uploaded in the results bucket
+    #[serde(skip_serializing)]
+    pub s3_key: Option<String>,
+    /// Where the result zip was delivered, for requests with a destination bucket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<ResultDestination>,
+    #[serde(flatten)]
+    pub timestamps: StatusTimestamps,
+}
+
+/// Times when a request reached each stage, serialized as RFC 3339 (UTC), or null for
+/// stages it hasn't reached. The time between `queued_at` and `started_at` is spent
+/// waiting in the queue.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StatusTimestamps {
+    pub queued_at: Option<DateTime<Utc>>,
+    /// When a worker picked the request up and started computing
+    pub started_at: Option<DateTime<Utc>>,
+    pub uploading_at: Option<DateTime<Utc>>,
+    /// When the request finished, successfully or not
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+impl StatusTimestamps {
+    /// Timestamps of a request queued now
+    pub fn queued() -> Self {
+        Self {
+            queued_at: Some(Utc::now()),
+            ..Default::default()
+        }
+    }
+
+    /// When the request last reached a stage, the latest of its timestamps
+    pub fn latest(&self) -> Option<DateTime<Utc>> {
+        [
+            self.queued_at,
+            self.started_at,
+            self.uploading_at,
+            self.completed_at,
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+    }
+}
+
+/// Object a result zip was delivered to outside the results bucket
+#[derive(Clone, Debug, Serialize)]
+pub struct ResultDestination {
+    pub bucket: String,
+    pub key: String,
 }
 
 #[derive(Deserialize)]
@@ -171,7 +541,7 @@ pub struct PvaluesResult {
 pub struct Pvalue {
     #[serde(rename = "i")]
     pub index: i32,
-    #[serde(rename = "p", serialize_with = "round_to_decimals")]
+    #[serde(rename = "p", serialize_with = "round_to::<4, _>")]
     pub pvalue: f32,
     #[serde(rename = "c")]
     pub chromosome: String,
@@ -187,26 +557,33 @@ pub struct ChromosomePosition {
     pub midpoint: i32,
 }
 
-pub fn round_to_decimals<S>(value: &f32, serializer: S) -> Result<S::Ok, S::Error>
+/// Serialize a float truncated to `N` decimal places, for use with `serialize_with`
+/// (e.g. `serialize_with = "round_to::<6, _>"`)
+pub fn round_to<const N: u32, S>(value: &f32, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
-    serializer.serialize_f64((*value as f64 * 10000.0).trunc() / 10000.0)
+    let scale = 10_f64.powi(N as i32);
+    serializer.serialize_f64((*value as f64 * scale).trunc() / scale)
 }
 
 #[derive(Serialize)]
 pub struct ApproximatePhenotypeValues {
-    #[serde(rename = "t", serialize_with = "round_to_decimals")]
+    #[serde(rename = "t", serialize_with = "round_to::<4, _>")]
     pub true_value: f32,
-    #[serde(rename = "a", serialize_with = "round_to_decimals")]
+    #[serde(rename = "a", serialize_with = "round_to::<4, _>")]
     pub approx_value: f32,
 }
 
-#[derive(Clone, Serialize)]
+/// How well a reference phenotype was approximated by its projection (`p`, phenotype R²)
+/// and how well the GWAS of that approximation matched the phenotype's true GWAS (`g`,
+/// GWAS R²). Together, the points show how much a summary's `rsquared` says about the
+/// accuracy of its indirect GWAS.
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct PhenotypeFitQuality {
-    #[serde(rename = "p", serialize_with = "round_to_decimals")]
+    #[serde(rename = "p", serialize_with = "round_to::<4, _>")]
     pub phenotype_fit_quality: f32,
-    #[serde(rename = "g", serialize_with = "round_to_decimals")]
+    #[serde(rename = "g", serialize_with = "round_to::<4, _>")]
     pub gwas_fit_quality: f32,
 }
 
@@ -216,20 +593,71 @@ pub struct PhenotypeSummary {
     pub cohort_id: i32,
     pub phenotype_values: Vec<ApproximatePhenotypeValues>,
     pub fit_quality_reference: Vec<PhenotypeFitQuality>,
-    #[serde(serialize_with = "round_to_decimals")]
+    #[serde(serialize_with = "round_to::<4, _>")]
     pub rsquared: f32,
+    /// How far `phenotype_values` are from their approximations
+    pub divergence: PhenotypeDivergence,
 }
 
-#[derive(Clone, Debug)]
+/// How far a phenotype's linear approximation is from its true values. Samples missing
+/// the phenotype (NaN) are left out.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PhenotypeDivergence {
+    /// Pearson correlation of the true and approximate values, or `None` when either is
+    /// constant (e.g. every sample is a case), since the correlation is then undefined
+    pub correlation: Option<f32>,
+    pub mean_absolute_error: f32,
+    /// Fraction of samples where the true and approximate values are on different sides of
+    /// the true mean. For a boolean phenotype, this is how often the approximation puts a
+    /// case with the controls or the other way around.
+    pub sign_mismatch_fraction: f32,
+}
+
+impl PhenotypeDivergence {
+    pub fn new(values: &[ApproximatePhenotypeValues]) -> Self {
+        let values = values
+            .iter()
+            .filter(|x| !x.true_value.is_nan())
+            .map(|x| (x.true_value as f64, x.approx_value as f64))
+            .collect::<Vec<(f64, f64)>>();
+        let n = values.len() as f64;
+        let true_mean = values.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let approx_mean = values.iter().map(|(_, a)| a).sum::<f64>() / n;
+        let (mut covariance, mut true_variance, mut approx_variance) = (0.0, 0.0, 0.0);
+        let (mut absolute_error, mut n_mismatched) = (0.0, 0);
+        for (t, a) in values.iter() {
+            covariance += (t - true_mean) * (a - approx_mean);
+            true_variance += (t - true_mean).powi(2);
+            approx_variance += (a - approx_mean).powi(2);
+            absolute_error += (t - a).abs();
+            if (*t > true_mean) != (*a > true_mean) {
+                n_mismatched += 1;
+            }
+        }
+        let correlation = match true_variance > 0.0 && approx_variance > 0.0 {
+            true => Some((covariance / (true_variance * approx_variance).sqrt()) as f32),
+            false => None,
+        };
+        Self {
+            correlation,
+            mean_absolute_error: (absolute_error / n) as f32,
+            sign_mismatch_fraction: (n_mismatched as f64 / n) as f32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct Operator {
     pub id: i32,
     pub name: String,
+    /// Number of operands, or -1 for a variadic operator, whose last operand is a constant
+    /// count of the operands before it
     pub arity: i32,
     pub input_type: NodeType,
     pub output_type: NodeType,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct Constant {
     pub value: f32,
     pub node_type: NodeType,
@@ -264,37 +692,357 @@ impl FromStr for Constant {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Nodes serialize with a `kind` of `feature`, `operator`, or `constant` alongside
+/// the fields of the inner value
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum Node {
     Feature(Feature),
     Operator(Operators),
     Constant(Constant),
 }
 
+#[derive(Deserialize)]
+pub struct HistogramQuery {
+    pub bins: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeatureHistogram {
+    pub cohort_id: i32,
+    pub code: String,
+    pub node_type: NodeType,
+    /// Bin edges (one more than the counts), absent for boolean features
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bin_edges: Option<Vec<f32>>,
+    /// Count per bin, or the false and true counts for boolean features
+    pub counts: Vec<usize>,
+    pub n_missing: usize,
+}
+
+/// A phenotype definition to save to the definitions library under a name
+#[derive(Deserialize)]
+pub struct SaveDefinitionRequest {
+    pub name: String,
+    pub cohort_id: i32,
+    pub phenotype_definition: String,
+}
+
+#[derive(Deserialize)]
+pub struct DefinitionListQuery {
+    /// Only list the definitions saved for this cohort
+    pub cohort_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct PreloadRequest {
+    pub cohort_ids: Vec<i32>,
+}
+
+#[derive(Serialize)]
+pub struct PreloadResponse {
+    pub cohort_id: i32,
+    pub is_loaded: bool,
+    pub message: String,
+}
+
+/// A cohort that failed to load, and why
+#[derive(Serialize)]
+pub struct UnavailableCohort {
+    pub cohort_id: i32,
+    pub error: String,
+}
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    /// `ok`, or `degraded` when some cohorts are unavailable
+    pub status: String,
+    pub unavailable_cohorts: Vec<UnavailableCohort>,
+}
+
+#[derive(Deserialize)]
+pub struct RequestListQuery {
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct RequestListEntry {
+    pub request_id: Uuid,
+    /// Absent if the result has already been evicted from the results cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<WebGWASResultStatus>,
+    pub cohort_id: i32,
+    pub elapsed_seconds: f32,
+    /// Zero-based position in the queue, absent for requests already being processed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct RequestListResponse {
+    /// Number of queued and running requests, across all pages
+    pub total: usize,
+    pub requests: Vec<RequestListEntry>,
+}
+
 #[derive(Deserialize)]
 pub struct GetFeaturesRequest {
     pub cohort_id: i32,
+    /// Number of features to skip, for paging through them
+    pub offset: Option<usize>,
+    /// Maximum number of features to return, or all of them if absent
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub format: FeatureListFormat,
+}
+
+/// How the feature list is sent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeatureListFormat {
+    /// A single JSON array
+    #[default]
+    Json,
+    /// One JSON object per line, streamed as they're read so that neither end holds the
+    /// whole list
+    Ndjson,
+}
+
+#[derive(Deserialize)]
+pub struct CovarianceRequest {
+    pub cohort_id: i32,
+    pub codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct CovarianceResponse {
+    pub cohort_id: i32,
+    /// Labels of both the rows and columns of `covariance`
+    pub codes: Vec<String>,
+    pub covariance: Vec<Vec<f32>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CohortSummary {
+    pub cohort_id: i32,
+    pub name: String,
+    pub n_samples: usize,
+    pub num_covar: Option<i32>,
+    pub n_features: usize,
+    pub n_features_by_type: HashMap<NodeType, usize>,
+    /// Smallest and largest feature sample sizes, absent when there are no features
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_size_range: Option<(i32, i32)>,
+}
+
+impl CohortSummary {
+    pub fn new(cohort_id: i32, cohort_data: &CohortData, features: &[FeatureResponse]) -> Self {
+        let mut n_features_by_type = HashMap::new();
+        for feature in features {
+            *n_features_by_type.entry(feature.node_type).or_insert(0) += 1;
+        }
+        let sizes = features.iter().map(|feature| feature.sample_size);
+        let sample_size_range = sizes.clone().min().zip(sizes.max());
+        Self {
+            cohort_id,
+            name: cohort_data.cohort.name.clone(),
+            n_samples: cohort_data.features.nrows(),
+            num_covar: cohort_data.num_covar,
+            n_features: features.len(),
+            n_features_by_type,
+            sample_size_range,
+        }
+    }
+}
+
+/// Per-cohort conventions for requests, read from an optional `defaults.toml` in the
+/// cohort's directory. Each applies only when a request doesn't set it, so the precedence
+/// is the request, then the cohort's default, then the server's behavior without one.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CohortDefaults {
+    /// Number of covariates, instead of the number the cohort was registered with
+    pub num_covar: Option<i32>,
+    /// Whether to residualize phenotypes on the cohort's covariates
+    pub residualize_covariates: Option<bool>,
+}
+
+impl CohortDefaults {
+    /// Read a cohort's defaults, which are all unset for a cohort without the file
+    pub fn load(cohort_root: &Path) -> Result<Self> {
+        load_optional_toml(&cohort_root.join("defaults.toml"))
+    }
+
+    /// The cohort's number of covariates for requests that don't give one: this default,
+    /// or else the number the cohort was registered with
+    pub fn num_covar(&self, registered: Option<i32>) -> Option<i32> {
+        self.num_covar.or(registered)
+    }
+
+    /// Whether to residualize: the request's choice, then this default, then not
+    pub fn residualize_covariates(&self, requested: Option<bool>) -> bool {
+        requested.or(self.residualize_covariates).unwrap_or(false)
+    }
+}
+
+/// The lightweight part of a cohort, loaded for every cohort at startup. Its features,
+/// GWAS, and matrices are only loaded into `CohortData` once a request needs them, so
+/// listing and validating against cohorts never waits on the heavy files.
+#[derive(Clone, Debug)]
+pub struct CohortMeta {
+    pub cohort: Cohort,
+    /// Map from a canonical feature code to this cohort's feature code
+    pub aliases: HashMap<String, String>,
+    pub defaults: CohortDefaults,
+}
+
+impl CohortMeta {
+    pub fn load(cohort: Cohort, root_directory: &Path) -> Result<CohortMeta> {
+        let cohort_root = root_directory.join("cohorts").join(&cohort.normalized_name);
+        // Aliases are optional, so a cohort without the file has none
+        let aliases_file_path = cohort_file_path(&cohort_root, "aliases");
+        let aliases = if aliases_file_path.exists() {
+            let aliases_df = read_cohort_file(&aliases_file_path).context(anyhow!(
+                "Failed to read aliases file for {}",
+                cohort_root.display()
+            ))?;
+            aliases_df
+                .column("alias")?
+                .str()?
+                .iter()
+                .zip(aliases_df.column("code")?.str()?.iter())
+                .map(|(alias, code)| Some((alias?.to_string(), code?.to_string())))
+                .collect::<Option<HashMap<String, String>>>()
+                .context("Failed to load feature aliases")?
+        } else {
+            HashMap::new()
+        };
+        let defaults = CohortDefaults::load(&cohort_root)?;
+        Ok(CohortMeta {
+            cohort,
+            aliases,
+            defaults,
+        })
+    }
 }
 
+/// A cohort's full data, loaded from its `CohortMeta` on first use
 pub struct CohortData {
     pub cohort: Cohort,
     pub feature_names: Vec<String>,
     pub features: Mat<f32>,
     pub left_inverse: Mat<f32>,
-    pub gwas_df: DataFrame,
+    /// Number of samples each row of `features` stands for, relative to the smallest row,
+    /// which `left_inverse` is weighted by
+    pub sample_weights: Col<f32>,
+    pub gwas: GwasData,
     pub covariance_matrix: Mat<f32>,
+    /// Map from a canonical feature code to this cohort's feature code
+    pub aliases: HashMap<String, String>,
+    /// Covariates of each sample, in the same rows as `features`, if the cohort has them
+    pub covariates: Option<Mat<f32>>,
+    /// Annotations of each variant, if the cohort has them
+    pub annotations: Option<Annotations>,
+    pub defaults: CohortDefaults,
+    /// Number of covariates for requests that don't give one (see `CohortDefaults::num_covar`)
+    pub num_covar: Option<i32>,
+    /// Generation of the cohort this data was loaded in (see `ProjectionCache::generation`)
+    pub generation: u64,
 }
 
 impl CohortData {
-    pub fn load(cohort: Cohort, root_directory: &Path) -> Result<CohortData> {
+    /// Rows and columns of the feature covariance matrix for the given codes, in order.
+    /// Errors listing every code that isn't a feature of this cohort.
+    pub fn covariance_submatrix(&self, codes: &[String]) -> Result<Vec<Vec<f32>>> {
+        let indices = self.feature_indices(codes)?;
+        let submatrix = indices
+            .iter()
+            .map(|&i| {
+                indices
+                    .iter()
+                    .map(|&j| self.covariance_matrix.read(i, j))
+                    .collect()
+            })
+            .collect();
+        Ok(submatrix)
+    }
+
+    /// Sum of the variances of every feature
+    pub fn total_feature_variance(&self) -> f32 {
+        (0..self.covariance_matrix.nrows())
+            .map(|i| self.covariance_matrix.read(i, i))
+            .sum()
+    }
+
+    /// Correlation of two features, normalizing their covariance by the variances on the
+    /// diagonal. A feature without variance has no correlation with anything (including
+    /// itself), so its correlations are NaN rather than infinite.
+    pub fn correlation(&self, i: usize, j: usize) -> f32 {
+        let scale = (self.covariance_matrix.read(i, i) * self.covariance_matrix.read(j, j)).sqrt();
+        if scale.is_nan() || scale == 0.0 {
+            return f32::NAN;
+        }
+        self.covariance_matrix.read(i, j) / scale
+    }
+
+    /// The feature correlation matrix as CSV lines, computed one at a time: a header of
+    /// feature codes, then a row per feature starting with its code
+    pub fn correlation_csv_lines(self: Arc<Self>) -> impl Iterator<Item = String> {
+        let n_features = self.feature_names.len();
+        (0..=n_features).map(move |line| match line {
+            0 => {
+                std::iter::once("feature")
+                    .chain(self.feature_names.iter().map(|code| code.as_str()))
+                    .map(csv_field)
+                    .join(",")
+                    + "\n"
+            }
+            _ => {
+                let i = line - 1;
+                std::iter::once(csv_field(&self.feature_names[i]))
+                    .chain((0..n_features).map(|j| self.correlation(i, j).to_string()))
+                    .join(",")
+                    + "\n"
+            }
+        })
+    }
+
+    /// Column indices of the given feature codes, in order. Errors listing every code that
+    /// isn't a feature of this cohort.
+    pub fn feature_indices(&self, codes: &[String]) -> Result<Vec<usize>> {
+        let indices = codes
+            .iter()
+            .map(|code| resolve_feature_index(code, &self.feature_names, &self.aliases))
+            .collect::<Vec<Option<usize>>>();
+        let unknown = codes
+            .iter()
+            .zip(indices.iter())
+            .filter(|(_, index)| index.is_none())
+            .map(|(code, _)| code.clone())
+            .collect::<Vec<String>>();
+        if !unknown.is_empty() {
+            bail!("Unknown fields: {}", unknown.join(", "));
+        }
+        Ok(indices.into_iter().flatten().collect())
+    }
+
+    /// Load a cohort's files. With `stream_gwas`, the GWAS file is only scanned here and
+    /// is read a chunk at a time whenever a GWAS is computed.
+    pub fn load(meta: CohortMeta, root_directory: &Path, stream_gwas: bool) -> Result<CohortData> {
+        let CohortMeta {
+            cohort,
+            aliases,
+            defaults,
+        } = meta;
+        let num_covar = defaults.num_covar(cohort.num_covar);
         let _span = info_span!("Load cohort: {}", cohort.name).entered();
         let cohort_root = root_directory.join("cohorts").join(&cohort.normalized_name);
-        let features_file_path = cohort_root.join("phenotypes.parquet");
-        let features_file = File::open(features_file_path).context(anyhow!(
-            "Failed to open phenotype data file for {}",
+        let features_file_path = cohort_file_path(&cohort_root, "phenotypes");
+        let features_df = read_cohort_file(&features_file_path).context(anyhow!(
+            "Failed to read phenotype data file for {}",
             cohort_root.display()
         ))?;
-        let features_df = ParquetReader::new(features_file).finish()?;
         let feature_names = features_df
             .get_column_names()
             .iter()
@@ -302,42 +1050,178 @@ impl CohortData {
             .collect::<Vec<String>>();
         let features = polars_to_faer_f32(features_df.lazy())?;
 
-        let left_inverse_file_path = cohort_root.join("phenotype_left_inverse.parquet");
-        let left_inverse_file = File::open(left_inverse_file_path).context(anyhow!(
-            "Failed to open left inverse file for {}",
+        let left_inverse_file_path = cohort_file_path(&cohort_root, "phenotype_left_inverse");
+        let left_inverse_df = read_cohort_file(&left_inverse_file_path).context(anyhow!(
+            "Failed to read left inverse file for {}",
             cohort_root.display()
         ))?;
-        let left_inverse_df = ParquetReader::new(left_inverse_file).finish()?;
         let left_inverse = polars_to_faer_f32(left_inverse_df.lazy())?
             .transpose()
             .to_owned();
 
-        let gwas_file_path = cohort_root.join("gwas.parquet");
-        let gwas_file = File::open(gwas_file_path).context(anyhow!(
-            "Failed to open GWAS file for {}",
-            cohort_root.display()
-        ))?;
-        let gwas_df = ParquetReader::new(gwas_file).finish()?;
+        // Cohorts registered before weights were saved are refit unweighted
+        let sample_weights_file_path = cohort_file_path(&cohort_root, "sample_weights");
+        let sample_weights = if sample_weights_file_path.exists() {
+            let sample_weights_df =
+                read_cohort_file(&sample_weights_file_path).context(anyhow!(
+                    "Failed to read sample weights file for {}",
+                    cohort_root.display()
+                ))?;
+            let sample_weights = polars_to_faer_f32(sample_weights_df.lazy())?;
+            if sample_weights.nrows() != features.nrows() || sample_weights.ncols() != 1 {
+                bail!(
+                    "Sample weights file for {} has shape {:?}, expected ({}, 1)",
+                    cohort_root.display(),
+                    sample_weights.shape(),
+                    features.nrows()
+                );
+            }
+            sample_weights.col(0).to_owned()
+        } else {
+            warn!(
+                "No sample weights for {}, so refits are unweighted",
+                cohort_root.display()
+            );
+            Col::from_fn(features.nrows(), |_| 1.0)
+        };
 
-        let covariance_matrix_file_path = cohort_root.join("covariance.parquet");
-        let covariance_matrix_file = File::open(covariance_matrix_file_path).context(anyhow!(
-            "Failed to open covariance matrix file for {}",
+        let gwas_file_path = cohort_file_path(&cohort_root, "gwas");
+        let gwas_columns = GwasColumns::load(&cohort_root)?;
+        let gwas = if stream_gwas {
+            GwasData::on_disk(&gwas_file_path, gwas_columns)
+        } else {
+            read_cohort_file(&gwas_file_path)
+                .and_then(|df| gwas_columns.to_canonical(df))
+                .map(GwasData::InMemory)
+        }
+        .context(anyhow!(
+            "Failed to read GWAS file for {}",
             cohort_root.display()
         ))?;
-        let covariance_matrix_df = ParquetReader::new(covariance_matrix_file).finish()?;
+        validate_gwas_columns(&gwas.slice(0, 1)?)
+            .context(anyhow!("Invalid GWAS file for {}", cohort_root.display()))?;
+
+        let covariance_matrix_file_path = cohort_file_path(&cohort_root, "covariance");
+        let covariance_matrix_df =
+            read_cohort_file(&covariance_matrix_file_path).context(anyhow!(
+                "Failed to read covariance matrix file for {}",
+                cohort_root.display()
+            ))?;
         let covariance_matrix = polars_to_faer_f32(covariance_matrix_df.lazy())?;
 
+        // Covariates are optional, and only needed to residualize phenotypes on them
+        let covariates_file_path = cohort_file_path(&cohort_root, "covariates");
+        let covariates = if covariates_file_path.exists() {
+            let covariates_df = read_cohort_file(&covariates_file_path).context(anyhow!(
+                "Failed to read covariates file for {}",
+                cohort_root.display()
+            ))?;
+            let covariates = polars_to_faer_f32(covariates_df.lazy())?;
+            if covariates.nrows() != features.nrows() {
+                bail!(
+                    "Covariates file for {} has {} rows, but there are {} samples",
+                    cohort_root.display(),
+                    covariates.nrows(),
+                    features.nrows()
+                );
+            }
+            // The registered number of covariates identifies the file's columns
+            if let Some(num_covar) = cohort.num_covar {
+                if covariates.ncols() != num_covar as usize {
+                    bail!(
+                        "Covariates file for {} has {} columns, but the cohort has {} covariates",
+                        cohort_root.display(),
+                        covariates.ncols(),
+                        num_covar
+                    );
+                }
+            }
+            Some(covariates)
+        } else {
+            None
+        };
+
+        // Annotations are optional, and only add columns to the results
+        let annotations_file_path = cohort_file_path(&cohort_root, "annotations");
+        let annotations = if annotations_file_path.exists() {
+            let gwas_columns = gwas
+                .slice(0, 0)?
+                .get_column_names()
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>();
+            let annotations =
+                Annotations::load(&annotations_file_path, &result_column_names(&gwas_columns))
+                    .context(anyhow!(
+                        "Invalid annotations file for {}",
+                        cohort_root.display()
+                    ))?;
+            Some(annotations)
+        } else {
+            None
+        };
+
         Ok(CohortData {
             cohort,
             feature_names,
             features,
             left_inverse,
-            gwas_df,
+            sample_weights,
+            gwas,
             covariance_matrix,
+            aliases,
+            covariates,
+            annotations,
+            num_covar,
+            defaults,
+            generation: 0,
         })
     }
 }
 
+/// Quote a CSV field if it contains a separator, quote, or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Locate a cohort file by name, accepting parquet or Arrow IPC (`.arrow`/`.feather`).
+/// Falls back to the parquet path so that missing files are reported with that name.
+pub fn cohort_file_path(cohort_root: &Path, stem: &str) -> PathBuf {
+    ["parquet", "arrow", "feather"]
+        .iter()
+        .map(|extension| cohort_root.join(format!("{}.{}", stem, extension)))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| cohort_root.join(format!("{}.parquet", stem)))
+}
+
+/// Lazily scan a dataframe using the scanner that matches the file extension, so that
+/// only the rows and columns that are needed get read
+pub fn scan_cohort_file(path: &Path) -> Result<LazyFrame> {
+    let lf = match path.extension().and_then(|x| x.to_str()) {
+        Some("parquet") => LazyFrame::scan_parquet(path, ScanArgsParquet::default())?,
+        Some("arrow") | Some("feather") => LazyFrame::scan_ipc(path, ScanArgsIpc::default())?,
+        _ => bail!("Unsupported file format for {}", path.display()),
+    };
+    Ok(lf)
+}
+
+/// Read a dataframe using the reader that matches the file extension. Compression is
+/// detected from the file itself, so parquet files may be uncompressed or use snappy,
+/// zstd, gzip, lz4, or brotli, and Arrow IPC files may be uncompressed or use zstd or lz4.
+pub fn read_cohort_file(path: &Path) -> Result<DataFrame> {
+    let file = File::open(path).context(anyhow!("Failed to open {}", path.display()))?;
+    let df = match path.extension().and_then(|x| x.to_str()) {
+        Some("parquet") => ParquetReader::new(file).finish()?,
+        Some("arrow") | Some("feather") => IpcReader::new(file).finish()?,
+        _ => bail!("Unsupported file format for {}", path.display()),
+    };
+    Ok(df)
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum Operators {
     Root,
@@ -353,6 +1237,20 @@ pub enum Operators {
     Lt,
     Le,
     Eq,
+    Xor,
+    Nand,
+    Clamp,
+    IsMissing,
+    Neg,
+    CaseControl,
+    InverseNormal,
+    Quantize,
+    SumFeatures,
+    ZScore,
+    SafeDiv,
+    CountTrue,
+    Threshold,
+    CountTrueStrict,
 }
 
 impl Display for Operators {
@@ -371,11 +1269,35 @@ impl Display for Operators {
             Operators::Lt => "LT",
             Operators::Le => "LE",
             Operators::Eq => "EQ",
+            Operators::Xor => "XOR",
+            Operators::Nand => "NAND",
+            Operators::Clamp => "CLAMP",
+            Operators::IsMissing => "IS_MISSING",
+            Operators::Neg => "NEG",
+            Operators::CaseControl => "CASE_CONTROL",
+            Operators::InverseNormal => "INVERSE_NORMAL",
+            Operators::Quantize => "QUANTIZE",
+            Operators::SumFeatures => "SUM_FEATURES",
+            Operators::ZScore => "Z_SCORE",
+            Operators::SafeDiv => "SAFE_DIV",
+            Operators::CountTrue => "COUNT_TRUE",
+            Operators::Threshold => "THRESHOLD",
+            Operators::CountTrueStrict => "COUNT_TRUE_STRICT",
         };
         write!(f, "{}", string)
     }
 }
 
+/// Operators serialize as their `Operator` description
+impl Serialize for Operators {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.value().serialize(serializer)
+    }
+}
+
 impl FromStr for Operators {
     type Err = anyhow::Error;
 
@@ -394,12 +1316,67 @@ impl FromStr for Operators {
             "LT" => Ok(Operators::Lt),
             "LE" => Ok(Operators::Le),
             "EQ" => Ok(Operators::Eq),
+            "XOR" => Ok(Operators::Xor),
+            "NAND" => Ok(Operators::Nand),
+            "CLAMP" => Ok(Operators::Clamp),
+            "IS_MISSING" => Ok(Operators::IsMissing),
+            "NEG" => Ok(Operators::Neg),
+            "CASE_CONTROL" => Ok(Operators::CaseControl),
+            "INVERSE_NORMAL" => Ok(Operators::InverseNormal),
+            "QUANTIZE" => Ok(Operators::Quantize),
+            "SUM_FEATURES" => Ok(Operators::SumFeatures),
+            "Z_SCORE" => Ok(Operators::ZScore),
+            "SAFE_DIV" => Ok(Operators::SafeDiv),
+            "COUNT_TRUE" => Ok(Operators::CountTrue),
+            "THRESHOLD" => Ok(Operators::Threshold),
+            "COUNT_TRUE_STRICT" => Ok(Operators::CountTrueStrict),
             _ => Err(anyhow!("Invalid operator: {}", s)),
         }
     }
 }
 
 impl Operators {
+    /// Every operator, in order of id. New variants must be added here to be listed.
+    pub fn all() -> &'static [Operators] {
+        &[
+            Operators::Root,
+            Operators::Add,
+            Operators::Sub,
+            Operators::Mul,
+            Operators::Div,
+            Operators::And,
+            Operators::Or,
+            Operators::Not,
+            Operators::Gt,
+            Operators::Ge,
+            Operators::Lt,
+            Operators::Le,
+            Operators::Eq,
+            Operators::Xor,
+            Operators::Nand,
+            Operators::Clamp,
+            Operators::IsMissing,
+            Operators::Neg,
+            Operators::CaseControl,
+            Operators::InverseNormal,
+            Operators::Quantize,
+            Operators::SumFeatures,
+            Operators::ZScore,
+            Operators::SafeDiv,
+            Operators::CountTrue,
+            Operators::Threshold,
+            Operators::CountTrueStrict,
+        ]
+    }
+
+    /// Whether the operator takes any number of operands, given by a constant count
+    pub fn is_variadic(&self) -> bool {
+        matches!(
+            self,
+            Operators::SumFeatures | Operators::CountTrue | Operators::CountTrueStrict
+        )
+    }
+
     pub fn value(&self) -> Operator {
         match self {
             Operators::Root => Operator {
@@ -493,11 +1470,109 @@ impl Operators {
                 input_type: NodeType::Any,
                 output_type: NodeType::Bool,
             },
+            Operators::Xor => Operator {
+                id: 13,
+                name: "xor".to_string(),
+                arity: 2,
+                input_type: NodeType::Bool,
+                output_type: NodeType::Bool,
+            },
+            Operators::Nand => Operator {
+                id: 14,
+                name: "nand".to_string(),
+                arity: 2,
+                input_type: NodeType::Bool,
+                output_type: NodeType::Bool,
+            },
+            Operators::Clamp => Operator {
+                id: 15,
+                name: "clamp".to_string(),
+                arity: 3,
+                input_type: NodeType::Real,
+                output_type: NodeType::Real,
+            },
+            Operators::IsMissing => Operator {
+                id: 16,
+                name: "is_missing".to_string(),
+                arity: 1,
+                input_type: NodeType::Any,
+                output_type: NodeType::Bool,
+            },
+            Operators::Neg => Operator {
+                id: 17,
+                name: "neg".to_string(),
+                arity: 1,
+                input_type: NodeType::Real,
+                output_type: NodeType::Real,
+            },
+            Operators::CaseControl => Operator {
+                id: 18,
+                name: "case_control".to_string(),
+                arity: 2,
+                input_type: NodeType::Bool,
+                output_type: NodeType::Bool,
+            },
+            Operators::InverseNormal => Operator {
+                id: 19,
+                name: "inverse_normal".to_string(),
+                arity: 1,
+                input_type: NodeType::Real,
+                output_type: NodeType::Real,
+            },
+            Operators::Quantize => Operator {
+                id: 20,
+                name: "quantize".to_string(),
+                arity: 2,
+                input_type: NodeType::Real,
+                output_type: NodeType::Real,
+            },
+            Operators::SumFeatures => Operator {
+                id: 21,
+                name: "sum_features".to_string(),
+                arity: -1,
+                input_type: NodeType::Any,
+                output_type: NodeType::Real,
+            },
+            Operators::ZScore => Operator {
+                id: 22,
+                name: "z_score".to_string(),
+                arity: 1,
+                input_type: NodeType::Real,
+                output_type: NodeType::Real,
+            },
+            Operators::SafeDiv => Operator {
+                id: 23,
+                name: "safe_div".to_string(),
+                arity: 2,
+                input_type: NodeType::Real,
+                output_type: NodeType::Real,
+            },
+            Operators::CountTrue => Operator {
+                id: 24,
+                name: "count_true".to_string(),
+                arity: -1,
+                input_type: NodeType::Bool,
+                output_type: NodeType::Real,
+            },
+            Operators::Threshold => Operator {
+                id: 25,
+                name: "threshold".to_string(),
+                arity: 2,
+                input_type: NodeType::Real,
+                output_type: NodeType::Bool,
+            },
+            Operators::CountTrueStrict => Operator {
+                id: 26,
+                name: "count_true_strict".to_string(),
+                arity: -1,
+                input_type: NodeType::Bool,
+                output_type: NodeType::Real,
+            },
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum ParsingNode {
     Feature(String),
     Operator(Operators),
@@ -525,23 +1600,59 @@ impl From<ParsingNode> for Node {
 pub struct RequestMetadata {
     pub request_id: Uuid,
     pub phenotype_definition: String,
+    /// The resolved definition that was computed, so the projection can be reproduced
+    /// even if the cohort's feature metadata changes later
+    pub resolved_definition: Vec<Node>,
     pub cohort_name: String,
     pub cohort_size: usize,
+    pub n_variants_tested: usize,
+    pub n_variants_dropped: usize,
+    pub n_variants_failed: usize,
+    pub missing_policy: MissingPolicy,
+    /// Samples missing the phenotype, out of `cohort_size`
+    pub n_samples_missing: usize,
+    pub lambda_gc: Option<f32>,
+    pub pvalue_adjustment: Option<PvalueAdjustment>,
+    /// Whether the phenotype was residualized on the cohort's covariates before projection
+    pub residualized_covariates: bool,
+    /// Only variants with a p-value below this were written to the results
+    pub p_threshold: Option<f32>,
+    pub n_variants_below_threshold: Option<usize>,
+    /// SHA-256 of the results file. The zip's own checksum can't be stored inside it, so
+    /// that one is reported in `WebGWASResult` instead.
+    pub results_checksum: String,
     pub webgwas_version: String,
 }
 
 impl RequestMetadata {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         request_id: Uuid,
-        phenotype_definition: String,
+        resolved_definition: &[Node],
         cohort_name: String,
         cohort_size: usize,
+        igwas_summary: &IgwasSummary,
+        missing_summary: MissingPhenotypeSummary,
+        residualized_covariates: bool,
+        results_checksum: String,
     ) -> Self {
         Self {
             request_id,
-            phenotype_definition,
+            phenotype_definition: format_phenotype_definition(resolved_definition),
+            resolved_definition: resolved_definition.to_vec(),
             cohort_name,
             cohort_size,
+            n_variants_tested: igwas_summary.n_tested,
+            n_variants_dropped: igwas_summary.n_dropped,
+            n_variants_failed: igwas_summary.n_failed,
+            missing_policy: missing_summary.policy,
+            n_samples_missing: missing_summary.n_missing,
+            lambda_gc: igwas_summary.lambda_gc,
+            pvalue_adjustment: igwas_summary.pvalue_adjustment,
+            residualized_covariates,
+            p_threshold: igwas_summary.p_threshold,
+            n_variants_below_threshold: igwas_summary.n_below_threshold,
+            results_checksum,
             webgwas_version: env!("CARGO_PKG_VERSION").to_string(),
         }
     }
@@ -549,18 +1660,424 @@ impl RequestMetadata {
 
 impl Display for RequestMetadata {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let resolved_definition =
+            serde_json::to_string(&self.resolved_definition).map_err(|_| std::fmt::Error)?;
+        // Only complete cases are used to fit the projection when missing samples are dropped
+        let n_samples_used = match self.missing_policy {
+            MissingPolicy::DropMissing => self.cohort_size - self.n_samples_missing,
+            MissingPolicy::MeanImpute => self.cohort_size,
+        };
+        let pvalue_adjustment = self.pvalue_adjustment.map_or("None".to_string(), |method| {
+            format!("{} ({})", method, method.column_name())
+        });
+        let p_threshold = match (self.p_threshold, self.n_variants_below_threshold) {
+            (Some(threshold), Some(n)) => format!("{} ({} variants written)", threshold, n),
+            _ => "None".to_string(),
+        };
         write!(
             f,
-            "Request ID: {}\nPhenotype definition: {}\nCohort name: {}\nCohort size: {}\nWebGWAS version: {}",
-            self.request_id, self.phenotype_definition, self.cohort_name, self.cohort_size, self.webgwas_version
+            "Request ID: {}\nPhenotype definition: {}\nResolved definition (JSON): {}\nCohort name: {}\nCohort size: {}\nSamples missing the phenotype: {} ({})\nSamples used to fit the projection: {}\nVariants tested: {}\nVariants dropped (missing GWAS statistics): {}\nVariants failed (non-finite statistics): {}\nGenomic inflation factor (lambda GC): {}\nP-value adjustment: {}\nResidualized on covariates: {}\nP-value threshold: {}\nResults SHA-256: {}\nWebGWAS version: {}",
+            self.request_id, self.phenotype_definition, resolved_definition, self.cohort_name, self.cohort_size, self.n_samples_missing, self.missing_policy, n_samples_used, self.n_variants_tested, self.n_variants_dropped, self.n_variants_failed,
+            self.lambda_gc.map_or("NA".to_string(), |x| x.to_string()), pvalue_adjustment, self.residualized_covariates, p_threshold, self.results_checksum, self.webgwas_version
         )
     }
 }
 
+/// Builders for the cohort data that tests need
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// Cohort 1, with these features and nothing else: its other matrices are zeros of
+    /// the right shapes, and it has no aliases, covariates, or GWAS. Tests set the fields
+    /// they need on the result.
+    pub(crate) fn test_cohort_data(feature_names: &[&str], features: Mat<f32>) -> CohortData {
+        let n_features = feature_names.len();
+        let n_samples = features.nrows();
+        CohortData {
+            cohort: Cohort {
+                id: Some(1),
+                name: "test".to_string(),
+                normalized_name: "test".to_string(),
+                num_covar: Some(0),
+            },
+            feature_names: feature_names.iter().map(|x| x.to_string()).collect(),
+            features,
+            left_inverse: Mat::zeros(n_features + 1, n_samples),
+            sample_weights: Col::from_fn(n_samples, |_| 1.0),
+            gwas: GwasData::InMemory(DataFrame::empty()),
+            covariance_matrix: Mat::zeros(n_features, n_features),
+            aliases: HashMap::new(),
+            covariates: None,
+            annotations: None,
+            defaults: Default::default(),
+            num_covar: Some(0),
+            generation: 0,
+        }
+    }
+
+    /// A real-valued feature of cohort 1
+    pub(crate) fn test_field(code: &str) -> Feature {
+        Feature {
+            id: 1,
+            code: code.to_string(),
+            name: code.to_string(),
+            node_type: NodeType::Real,
+            sample_size: 0,
+            cohort_id: 1,
+        }
+    }
+
+    /// `test_field` as a definition node
+    pub(crate) fn test_feature(code: &str) -> Node {
+        Node::Feature(test_field(code))
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::testing::test_cohort_data;
     use super::*;
 
+    #[test]
+    fn test_status_timestamps() {
+        let value = serde_json::to_value(StatusTimestamps::queued()).unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(value["queued_at"].as_str().unwrap()).is_ok());
+        for stage in ["started_at", "uploading_at", "completed_at"] {
+            assert!(value[stage].is_null());
+        }
+    }
+
+    #[test]
+    fn test_cohort_meta_loads_without_data() {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let cohort_root = root.join("cohorts").join("test");
+        std::fs::create_dir_all(&cohort_root).unwrap();
+        let cohort = Cohort {
+            id: Some(1),
+            name: "Test".to_string(),
+            normalized_name: "test".to_string(),
+            num_covar: None,
+        };
+        // Only the aliases are read, so the heavy files don't need to exist
+        let meta = CohortMeta::load(cohort.clone(), &root).unwrap();
+        assert!(meta.aliases.is_empty());
+        let mut aliases = df!("alias" => ["bmi"], "code" => ["21001"]).unwrap();
+        IpcWriter::new(File::create(cohort_root.join("aliases.arrow")).unwrap())
+            .finish(&mut aliases)
+            .unwrap();
+        let meta = CohortMeta::load(cohort, &root).unwrap();
+        assert_eq!(meta.aliases.get("bmi").map(String::as_str), Some("21001"));
+        assert!(CohortData::load(meta, &root, false).is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_load_cohort_with_gwas_columns() {
+        let root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let cohort_root = root.join("cohorts").join("test");
+        std::fs::create_dir_all(&cohort_root).unwrap();
+        let write = |name: &str, mut df: DataFrame| {
+            IpcWriter::new(File::create(cohort_root.join(format!("{}.arrow", name))).unwrap())
+                .finish(&mut df)
+                .unwrap();
+        };
+        write("phenotypes", df!("feature" => [1.0_f32, 2.0, 3.0]).unwrap());
+        write(
+            "phenotype_left_inverse",
+            df!("feature" => [0.1_f32, 0.2, 0.3]).unwrap(),
+        );
+        write("covariance", df!("feature" => [1.0_f32]).unwrap());
+        write(
+            "gwas",
+            df!(
+                "SNP" => ["1:1:A:G", "1:2:C:T"],
+                "a1" => ["A", "C"],
+                "a2" => ["G", "T"],
+                "degrees_of_freedom" => [100_i32, 100],
+                "genotype_partial_variance" => [0.5_f32, 0.25],
+                "feature" => [0.1_f32, -0.2],
+            )
+            .unwrap(),
+        );
+        let cohort = Cohort {
+            id: Some(1),
+            name: "Test".to_string(),
+            normalized_name: "test".to_string(),
+            num_covar: None,
+        };
+        let load = |stream_gwas: bool| {
+            let meta = CohortMeta::load(cohort.clone(), &root).unwrap();
+            CohortData::load(meta, &root, stream_gwas)
+        };
+        // Without the file, the usual names are expected, and the error names the column
+        let err = load(false).err().unwrap();
+        assert!(format!("{:#}", err).contains("missing columns: variant_id"));
+
+        std::fs::write(
+            cohort_root.join("gwas_columns.toml"),
+            "variant_id = \"SNP\"\n",
+        )
+        .unwrap();
+        for stream_gwas in [false, true] {
+            let cohort_data = load(stream_gwas).unwrap();
+            let gwas = cohort_data.gwas.slice(0, 2).unwrap();
+            assert_eq!(
+                gwas.column("variant_id").unwrap().str().unwrap().get(1),
+                Some("1:2:C:T")
+            );
+            assert!(gwas.column("SNP").is_err());
+        }
+
+        std::fs::write(
+            cohort_root.join("gwas_columns.toml"),
+            "variant_id = \"ID\"\n",
+        )
+        .unwrap();
+        let err = load(true).err().unwrap();
+        assert!(format!("{:#}", err).contains("missing columns: ID"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_cohort_defaults() {
+        let cohort_root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&cohort_root).unwrap();
+        // Without defaults, the request and then the registered cohort decide
+        let defaults = CohortDefaults::load(&cohort_root).unwrap();
+        assert_eq!(defaults, CohortDefaults::default());
+        assert_eq!(defaults.num_covar(Some(10)), Some(10));
+        assert!(!defaults.residualize_covariates(None));
+        assert!(defaults.residualize_covariates(Some(true)));
+
+        std::fs::write(
+            cohort_root.join("defaults.toml"),
+            "num_covar = 4\nresidualize_covariates = true\n",
+        )
+        .unwrap();
+        let defaults = CohortDefaults::load(&cohort_root).unwrap();
+        assert_eq!(defaults.num_covar(Some(10)), Some(4));
+        assert_eq!(
+            crate::worker::resolve_num_covariates(Some(3), defaults.num_covar(Some(10)), 100)
+                .unwrap(),
+            3
+        );
+        assert!(defaults.residualize_covariates(None));
+        assert!(!defaults.residualize_covariates(Some(false)));
+
+        std::fs::write(cohort_root.join("defaults.toml"), "num_covars = 4\n").unwrap();
+        assert!(CohortDefaults::load(&cohort_root).is_err());
+        std::fs::remove_dir_all(cohort_root).unwrap();
+    }
+
+    #[test]
+    fn test_correlation_csv_lines() {
+        let cohort_data = Arc::new(CohortData {
+            // The last feature is constant
+            covariance_matrix: faer::mat![[4.0, -1.0, 0.0], [-1.0, 1.0, 0.0], [0.0, 0.0, 0.0]],
+            ..test_cohort_data(&["a", "b,c", "d"], Mat::zeros(5, 3))
+        });
+        let lines = cohort_data.correlation_csv_lines().collect::<Vec<String>>();
+        assert_eq!(
+            lines,
+            vec![
+                "feature,a,\"b,c\",d\n",
+                "a,1,-0.5,NaN\n",
+                "\"b,c\",-0.5,1,NaN\n",
+                "d,NaN,NaN,NaN\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fit_quality_reference_points() {
+        let reference = (0..10)
+            .map(|i| PhenotypeFitQuality {
+                phenotype_fit_quality: i as f32 / 10.0,
+                gwas_fit_quality: i as f32 / 20.0,
+            })
+            .collect::<Vec<PhenotypeFitQuality>>();
+        let request = |json: serde_json::Value| {
+            serde_json::from_value::<PhenotypeSummaryRequest>(json).unwrap()
+        };
+        let small = request(serde_json::json!({
+            "phenotype_definition": "\"a\"",
+            "cohort_id": 1,
+            "n_samples": 5,
+            "n_fit_quality_points": 3,
+        }));
+        assert_eq!(small.fit_quality_reference(&reference, 100), reference[..3]);
+        // Without a count, it follows the number of samples, as it always has
+        let default = request(serde_json::json!({
+            "phenotype_definition": "\"a\"",
+            "cohort_id": 1,
+            "n_samples": 5,
+        }));
+        assert_eq!(default.fit_quality_reference(&reference, 100).len(), 5);
+        let all = request(serde_json::json!({"phenotype_definition": "\"a\"", "cohort_id": 1}));
+        assert_eq!(all.fit_quality_reference(&reference, 100), reference);
+        assert_eq!(all.fit_quality_reference(&reference, 4), reference[..4]);
+    }
+
+    #[test]
+    fn test_phenotype_divergence() {
+        let values = |pairs: &[(f32, f32)]| {
+            pairs
+                .iter()
+                .map(|&(true_value, approx_value)| ApproximatePhenotypeValues {
+                    true_value,
+                    approx_value,
+                })
+                .collect::<Vec<ApproximatePhenotypeValues>>()
+        };
+        // A boolean phenotype with one control approximated as a case, and a missing sample
+        let divergence = PhenotypeDivergence::new(&values(&[
+            (1.0, 0.9),
+            (0.0, 0.1),
+            (0.0, 0.7),
+            (1.0, 0.6),
+            (f32::NAN, 0.5),
+        ]));
+        assert!((divergence.correlation.unwrap() - 0.5937).abs() < 1e-4);
+        assert!((divergence.mean_absolute_error - 0.325).abs() < 1e-6);
+        assert_eq!(divergence.sign_mismatch_fraction, 0.25);
+        // Every sample is a case, so the correlation is undefined
+        let constant = PhenotypeDivergence::new(&values(&[(1.0, 0.8), (1.0, 1.2)]));
+        assert_eq!(constant.correlation, None);
+        assert!((constant.mean_absolute_error - 0.2).abs() < 1e-6);
+        assert_eq!(constant.sign_mismatch_fraction, 0.5);
+    }
+
+    #[test]
+    fn test_cohort_summary() {
+        let mut cohort_data = test_cohort_data(&["a", "b", "c"], Mat::zeros(5, 3));
+        cohort_data.num_covar = Some(2);
+        let feature = |code: &str, node_type, sample_size| FeatureResponse {
+            code: code.to_string(),
+            name: code.to_string(),
+            node_type,
+            sample_size,
+        };
+        let features = vec![
+            feature("a", NodeType::Bool, 5),
+            feature("b", NodeType::Real, 3),
+            feature("c", NodeType::Bool, 4),
+        ];
+        let summary = CohortSummary::new(1, &cohort_data, &features);
+        let value = serde_json::to_value(&summary).unwrap();
+        assert_eq!(value["n_samples"], 5);
+        assert_eq!(value["n_features"], 3);
+        assert_eq!(value["n_features_by_type"]["BOOL"], 2);
+        assert_eq!(value["n_features_by_type"]["REAL"], 1);
+        assert_eq!(value["sample_size_range"], serde_json::json!([3, 5]));
+        let empty = CohortSummary::new(1, &cohort_data, &[]);
+        assert!(empty.sample_size_range.is_none());
+    }
+
+    #[test]
+    fn test_covariance_submatrix() {
+        let cohort_data = CohortData {
+            covariance_matrix: faer::mat![[1.0, 0.1, 0.2], [0.1, 2.0, 0.3], [0.2, 0.3, 3.0]],
+            ..test_cohort_data(&["a", "b", "c"], Mat::zeros(0, 3))
+        };
+        let codes = vec!["c".to_string(), "a".to_string()];
+        assert_eq!(
+            cohort_data.covariance_submatrix(&codes).unwrap(),
+            vec![vec![3.0, 0.2], vec![0.2, 1.0]]
+        );
+        let codes = vec!["a".to_string(), "d".to_string()];
+        let err = cohort_data.covariance_submatrix(&codes).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown fields: d");
+    }
+
+    #[test]
+    fn test_metadata_records_resolved_definition() {
+        let definition = vec![
+            Node::Constant(Constant {
+                value: 1.0,
+                node_type: NodeType::Bool,
+            }),
+            Node::Operator(Operators::Not),
+        ];
+        let metadata = RequestMetadata::new(
+            Uuid::nil(),
+            &definition,
+            "test".to_string(),
+            10,
+            &IgwasSummary::default(),
+            MissingPhenotypeSummary::default(),
+            false,
+            "abc".to_string(),
+        );
+        let line = metadata
+            .to_string()
+            .lines()
+            .find_map(|line| line.strip_prefix("Resolved definition (JSON): "))
+            .map(|line| line.to_string())
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value, serde_json::to_value(&definition).unwrap());
+    }
+
+    #[test]
+    fn test_serialize_nodes() {
+        let nodes = vec![
+            Node::Operator(Operators::Not),
+            Node::Constant(Constant {
+                value: 1.0,
+                node_type: NodeType::Bool,
+            }),
+        ];
+        let value = serde_json::to_value(&nodes).unwrap();
+        assert_eq!(value[0]["kind"], "operator");
+        assert_eq!(value[0]["name"], "not");
+        assert_eq!(value[0]["arity"], 1);
+        assert_eq!(value[1]["kind"], "constant");
+        assert_eq!(value[1]["node_type"], "BOOL");
+    }
+
+    #[test]
+    fn test_round_to() {
+        #[derive(Serialize)]
+        struct Rounded {
+            #[serde(serialize_with = "round_to::<2, _>")]
+            coarse: f32,
+            #[serde(serialize_with = "round_to::<4, _>")]
+            default: f32,
+        }
+        let rounded = Rounded {
+            coarse: 0.123456,
+            default: 0.123456,
+        };
+        assert_eq!(
+            serde_json::to_string(&rounded).unwrap(),
+            r#"{"coarse":0.12,"default":0.1234}"#
+        );
+    }
+
+    #[test]
+    fn test_operators_all() {
+        for (i, op) in Operators::all().iter().enumerate() {
+            assert_eq!(op.value().id, i as i32);
+            let parsed = Operators::from_str(&op.to_string()).unwrap();
+            assert_eq!(parsed.value().id, op.value().id);
+        }
+    }
+
+    #[test]
+    fn test_read_cohort_file_ipc() {
+        let cohort_root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&cohort_root).unwrap();
+        let mut df = df!("feature" => [1.0_f32, 2.0]).unwrap();
+        let file = File::create(cohort_root.join("phenotypes.arrow")).unwrap();
+        IpcWriter::new(file).finish(&mut df).unwrap();
+        let path = cohort_file_path(&cohort_root, "phenotypes");
+        assert_eq!(path, cohort_root.join("phenotypes.arrow"));
+        let loaded = read_cohort_file(&path).unwrap();
+        assert!(loaded.equals(&df));
+        std::fs::remove_dir_all(cohort_root).unwrap();
+    }
+
     #[test]
     fn test_deserialize_node_type_bool() {
         let node_type = NodeType::from_str("BOOL").unwrap();
@@ -591,4 +2108,49 @@ mod tests {
         let result = Constant::from_str("<1.0:FOO>");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_read_compressed_cohort_files() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut df = df!(
+            "a" => [1.0_f32, 2.5, -3.0],
+            "b" => [Some(0.0_f32), None, Some(1.0)],
+        )
+        .unwrap();
+        let parquet_codecs = [
+            ("uncompressed", ParquetCompression::Uncompressed),
+            ("snappy", ParquetCompression::Snappy),
+            ("zstd", ParquetCompression::Zstd(None)),
+            ("gzip", ParquetCompression::Gzip(None)),
+            ("lz4", ParquetCompression::Lz4Raw),
+            ("brotli", ParquetCompression::Brotli(None)),
+        ];
+        for (name, compression) in parquet_codecs {
+            let path = dir.join(format!("{}.parquet", name));
+            ParquetWriter::new(File::create(&path).unwrap())
+                .with_compression(compression)
+                .finish(&mut df)
+                .unwrap();
+            assert!(read_cohort_file(&path).unwrap().equals_missing(&df));
+            let scanned = scan_cohort_file(&path).unwrap().collect().unwrap();
+            assert!(scanned.equals_missing(&df));
+        }
+        let ipc_codecs = [
+            ("uncompressed", None),
+            ("zstd", Some(IpcCompression::ZSTD)),
+            ("lz4", Some(IpcCompression::LZ4)),
+        ];
+        for (name, compression) in ipc_codecs {
+            let path = dir.join(format!("{}.arrow", name));
+            IpcWriter::new(File::create(&path).unwrap())
+                .with_compression(compression)
+                .finish(&mut df)
+                .unwrap();
+            assert!(read_cohort_file(&path).unwrap().equals_missing(&df));
+            let scanned = scan_cohort_file(&path).unwrap().collect().unwrap();
+            assert!(scanned.equals_missing(&df));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
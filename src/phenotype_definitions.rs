@@ -1,51 +1,218 @@
 use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Context, Result};
 use faer::Mat;
+use itertools::{izip, Itertools};
+use statrs::distribution::{ContinuousCDF, Normal};
 
-use crate::models::{Constant, Feature, Node, NodeType, Operators, ParsingNode};
+use crate::infix::parse_infix_definition;
+use crate::models::{Constant, DefinitionSyntax, Feature, Node, NodeType, Operators, ParsingNode};
 
-pub fn parse_string_definition(phenotype_definition: &str) -> Result<Vec<ParsingNode>> {
-    let mut nodes = Vec::new();
-    let mut current_node: ParsingNode;
-    for token in phenotype_definition.split_whitespace() {
-        match token.chars().next() {
+/// A malformed definition, at the 1-based character position of the offending token.
+/// Both syntaxes give these, for parse errors and for arity and type errors.
+#[derive(Debug, PartialEq)]
+pub struct PhenotypeError {
+    pub position: usize,
+    pub token: String,
+    pub message: String,
+}
+
+impl PhenotypeError {
+    pub fn new(span: &TokenSpan, message: impl Display) -> Self {
+        Self {
+            position: span.position,
+            token: span.token.clone(),
+            message: message.to_string(),
+        }
+    }
+
+    /// An error at the token of node `i`, or at the start of a definition without nodes
+    fn at_node(spans: &[TokenSpan], i: usize, message: impl Display) -> Self {
+        match spans.get(i) {
+            Some(span) => Self::new(span, message),
+            None => Self::new(&TokenSpan::new(1, ""), message),
+        }
+    }
+}
+
+impl Display for PhenotypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for PhenotypeError {}
+
+/// Where the token a node was parsed from starts (1-based, in characters), and its text
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenSpan {
+    pub position: usize,
+    pub token: String,
+}
+
+impl TokenSpan {
+    pub fn new(position: usize, token: &str) -> Self {
+        Self {
+            position,
+            token: token.to_string(),
+        }
+    }
+}
+
+/// Nodes of a definition in postfix order, with the span of the token each came from
+#[derive(Debug, Default)]
+pub struct ParsedDefinition {
+    pub nodes: Vec<ParsingNode>,
+    pub spans: Vec<TokenSpan>,
+}
+
+/// Split a definition on whitespace, keeping each token's 1-based character position
+fn tokens_with_positions(definition: &str) -> Vec<(&str, usize)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (char_index, (byte_index, c)) in definition.char_indices().enumerate() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some((byte_index, char_index + 1)),
+            (true, Some((token_start, position))) => {
+                tokens.push((&definition[token_start..byte_index], position));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some((token_start, position)) = start {
+        tokens.push((&definition[token_start..], position));
+    }
+    tokens
+}
+
+/// Parse a postfix definition into nodes, without checking its features against a cohort
+/// (see `validate_phenotype_definition`). Errors point at the token that couldn't be parsed.
+pub fn parse_definition(phenotype_definition: &str) -> Result<ParsedDefinition, PhenotypeError> {
+    let mut parsed = ParsedDefinition::default();
+    for (token, position) in tokens_with_positions(phenotype_definition) {
+        let span = TokenSpan::new(position, token);
+        let error = |message: String| PhenotypeError::new(&span, message);
+        let node = match token.chars().next() {
             Some('"') => {
-                if !token.ends_with('"') {
-                    bail!("Invalid field name {}", token);
+                if token.len() < 2 || !token.ends_with('"') {
+                    return Err(error(format!("Invalid field name {}", token)));
                 }
-                current_node =
-                    ParsingNode::Feature(token.get(1..token.len() - 1).unwrap().to_string());
-                nodes.push(current_node);
+                ParsingNode::Feature(token[1..token.len() - 1].to_string())
             }
             Some('`') => {
-                if !token.ends_with('`') {
-                    bail!("Invalid operator {}", token);
+                if token.len() < 2 || !token.ends_with('`') {
+                    return Err(error(format!("Invalid operator {}", token)));
                 }
-                let operator = Operators::from_str(token.get(1..token.len() - 1).unwrap())
-                    .context(anyhow!("Unknown operator {}", token))?;
-                current_node = ParsingNode::Operator(operator);
-                nodes.push(current_node);
+                let operator = Operators::from_str(&token[1..token.len() - 1])
+                    .map_err(|_| error(format!("Unknown operator {}", token)))?;
+                ParsingNode::Operator(operator)
             }
             Some('<') => {
                 if !token.ends_with('>') {
-                    bail!("Invalid constant {}", token);
+                    return Err(error(format!("Invalid constant {}", token)));
                 }
-                current_node = ParsingNode::Constant(Constant::from_str(token)?);
-                nodes.push(current_node);
+                let constant =
+                    Constant::from_str(token).map_err(|err| error(format!("{:#}", err)))?;
+                ParsingNode::Constant(constant)
             }
-            _ => {
-                bail!("Invalid token {}", token);
+            _ => return Err(error(format!("Invalid token {}", token))),
+        };
+        parsed.nodes.push(node);
+        parsed.spans.push(span);
+    }
+    Ok(parsed)
+}
+
+/// Write parsed nodes as a string definition, the inverse of `parse_definition`
+pub fn format_string_definition(nodes: &[ParsingNode]) -> String {
+    nodes
+        .iter()
+        .map(|node| match node {
+            ParsingNode::Feature(code) => format!("\"{}\"", code),
+            ParsingNode::Operator(op) => format!("`{}`", op),
+            ParsingNode::Constant(Constant {
+                value,
+                node_type: NodeType::Bool,
+            }) => format!("<BOOL:{}>", if *value == 0.0 { "F" } else { "T" }),
+            ParsingNode::Constant(constant) => {
+                format!("<{}:{}>", constant.node_type, constant.value)
+            }
+        })
+        .join(" ")
+}
+
+/// Check that every operator in a parsed definition has as many operands as its arity,
+/// and that the definition reduces to a single value. Errors point at the operator, or
+/// at the last value left over.
+pub fn check_arity(parsed: &ParsedDefinition) -> Result<(), PhenotypeError> {
+    let nodes = &parsed.nodes;
+    let error = |i: usize, message: String| PhenotypeError::at_node(&parsed.spans, i, message);
+    let mut depth = 0;
+    for (i, node) in nodes.iter().enumerate() {
+        match node {
+            ParsingNode::Feature(_) | ParsingNode::Constant(_) => depth += 1,
+            ParsingNode::Operator(op) => {
+                let previous_constant = match i.checked_sub(1).map(|j| &nodes[j]) {
+                    Some(ParsingNode::Constant(constant)) => Some(constant.value),
+                    _ => None,
+                };
+                let arity = operand_count(op, previous_constant)
+                    .map_err(|err| error(i, err.to_string()))?;
+                if depth < arity {
+                    return Err(error(
+                        i,
+                        format!(
+                            "Operator {} expects {} arguments, got {}",
+                            op.value().name,
+                            arity,
+                            depth
+                        ),
+                    ));
+                }
+                depth = depth - arity + 1;
             }
         }
     }
-    Ok(nodes)
+    if depth > 1 {
+        return Err(error(
+            nodes.len() - 1,
+            format!("Definition leaves {} values unconsumed", depth),
+        ));
+    }
+    Ok(())
+}
+
+/// Number of operands an operator takes. Variadic operators (`SUM_FEATURES`, `COUNT_TRUE`
+/// and `COUNT_TRUE_STRICT`) take a last operand that is a constant count `n` of the values
+/// before it, so they take `n + 1` operands. `previous_constant` is the value of the node
+/// right before the operator, if it's a constant, since constants are always leaves.
+pub fn operand_count(op: &Operators, previous_constant: Option<f32>) -> Result<usize> {
+    if !op.is_variadic() {
+        return Ok(op.value().arity as usize);
+    }
+    match previous_constant {
+        Some(n) if n >= 1.0 && n.fract() == 0.0 => Ok(n as usize + 1),
+        Some(n) => bail!("{} count must be a positive integer, got {}", op, n),
+        None => bail!("{} must be preceded by a constant count", op),
+    }
+}
+
+/// Value of the node before position `i` if it's a constant
+fn previous_constant(nodes: &[Node], i: usize) -> Option<f32> {
+    match i.checked_sub(1).map(|j| &nodes[j]) {
+        Some(Node::Constant(constant)) => Some(constant.value),
+        _ => None,
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct KnowledgeBase {
     cohort_id_code_to_field: HashMap<(i32, String), Feature>,
+    cohort_id_alias_to_code: HashMap<(i32, String), String>,
 }
 
 impl KnowledgeBase {
@@ -57,13 +224,53 @@ impl KnowledgeBase {
             .collect();
         Self {
             cohort_id_code_to_field,
+            cohort_id_alias_to_code: HashMap::new(),
         }
     }
 
+    /// Register a cohort's map from canonical feature codes to its own feature codes
+    pub fn add_aliases(&mut self, cohort_id: i32, aliases: &HashMap<String, String>) {
+        for (alias, code) in aliases {
+            self.cohort_id_alias_to_code
+                .insert((cohort_id, alias.clone()), code.clone());
+        }
+    }
+
+    /// Find a field by code, resolving aliases first and falling back to the literal code
     pub fn find_field(&self, cohort_id: i32, code: &str) -> Option<&Feature> {
+        if let Some(resolved) = self
+            .cohort_id_alias_to_code
+            .get(&(cohort_id, code.to_string()))
+        {
+            if let Some(field) = self
+                .cohort_id_code_to_field
+                .get(&(cohort_id, resolved.clone()))
+            {
+                return Some(field);
+            }
+        }
         self.cohort_id_code_to_field
             .get(&(cohort_id, code.to_string()))
     }
+
+    /// Whether a code is a field of some cohort other than `cohort_id`
+    pub fn in_other_cohort(&self, cohort_id: i32, code: &str) -> bool {
+        self.cohort_id_code_to_field
+            .keys()
+            .any(|(id, field_code)| *id != cohort_id && field_code == code)
+    }
+}
+
+/// Resolve a feature code to its column index, trying the alias map before the literal code
+pub fn resolve_feature_index(
+    code: &str,
+    names: &[String],
+    aliases: &HashMap<String, String>,
+) -> Option<usize> {
+    aliases
+        .get(code)
+        .and_then(|resolved| names.iter().position(|x| x == resolved))
+        .or_else(|| names.iter().position(|x| x == code))
 }
 
 pub fn validate_nodes(
@@ -72,15 +279,16 @@ pub fn validate_nodes(
     kb: &KnowledgeBase,
 ) -> Result<Vec<Node>> {
     let mut result = Vec::new();
+    let mut other_cohort_codes = Vec::new();
     for node in nodes {
         match node {
-            ParsingNode::Feature(field_code) => {
-                let field = kb
-                    .find_field(cohort_id, field_code)
-                    .ok_or(anyhow::anyhow!("Unknown field {}", field_code))?
-                    .clone();
-                result.push(Node::Feature(field));
-            }
+            ParsingNode::Feature(field_code) => match kb.find_field(cohort_id, field_code) {
+                Some(field) => result.push(Node::Feature(field.clone())),
+                None if kb.in_other_cohort(cohort_id, field_code) => {
+                    other_cohort_codes.push(field_code.clone());
+                }
+                None => bail!("Unknown field {}", field_code),
+            },
             ParsingNode::Operator(_) => {
                 result.push((*node).clone().into());
             }
@@ -90,68 +298,190 @@ pub fn validate_nodes(
             }
         }
     }
+    if !other_cohort_codes.is_empty() {
+        bail!(
+            "Fields not in cohort {}: {}",
+            cohort_id,
+            other_cohort_codes.join(", ")
+        );
+    }
     Ok(result)
 }
 
-pub fn type_check_nodes(nodes: &[Node]) -> Result<()> {
+/// A value on the type checking stack: a constant from the definition, or any other value
+/// of a known type
+#[derive(Debug)]
+enum TypedValue {
+    Constant(f32, NodeType),
+    Value(NodeType),
+}
+
+/// Check that every operator gets operands of its input type. Booleans are stored as 0.0
+/// and 1.0, so arithmetic (`ADD`, `SUB`, `MUL`, `DIV`) takes `Any` input and promotes
+/// them to those reals, always producing a `Real`. Other operators don't coerce: a `Real`
+/// input still rejects a boolean, and a `Bool` input rejects a real. `spans` are those of
+/// the parsed nodes the nodes were validated from, which errors point at.
+pub fn type_check_nodes(nodes: &[Node], spans: &[TokenSpan]) -> Result<(), PhenotypeError> {
+    let error = |i: usize, message: String| PhenotypeError::at_node(spans, i, message);
     let mut stack = Vec::new();
-    for node in nodes {
+    for (i, node) in nodes.iter().enumerate() {
         match node {
-            Node::Feature(_) | Node::Constant(_) => {
-                stack.push(node.clone());
+            Node::Feature(field) => stack.push(TypedValue::Value(field.node_type)),
+            Node::Constant(constant) => {
+                stack.push(TypedValue::Constant(constant.value, constant.node_type))
             }
             Node::Operator(op) => {
                 let operator_value = op.value();
-                for _ in 0..operator_value.arity {
-                    let top = stack.pop().ok_or(anyhow::anyhow!(
-                        "Operator {} expects {} arguments, got {}",
-                        operator_value.name,
-                        operator_value.arity,
-                        stack.len()
-                    ))?;
-                    match top {
-                        Node::Operator(_) => {
-                            bail!(
+                match op {
+                    Operators::Clamp => check_clamp_bounds(&nodes[..i]),
+                    Operators::Quantize => check_quantize_bins(&nodes[..i]),
+                    Operators::Threshold => check_threshold_cutoff(&nodes[..i]),
+                    _ => Ok(()),
+                }
+                .map_err(|err| error(i, err.to_string()))?;
+                let arity = operand_count(op, previous_constant(nodes, i))
+                    .map_err(|err| error(i, err.to_string()))?;
+                for j in 0..arity {
+                    let top = stack.pop().ok_or_else(|| {
+                        error(
+                            i,
+                            format!(
                                 "Operator {} expects {} arguments, got {}",
-                                operator_value.name,
-                                operator_value.arity,
-                                stack.len()
-                            )
-                        }
-                        Node::Feature(field) => {
-                            if field.node_type != operator_value.input_type
-                                && operator_value.input_type != NodeType::Any
-                            {
-                                bail!(
-                                    "Type mismatch: expected {}, got {}",
-                                    operator_value.input_type,
-                                    field.node_type
-                                );
+                                operator_value.name, arity, j
+                            ),
+                        )
+                    })?;
+                    // The count of a variadic operator isn't one of its inputs
+                    if j == 0 && op.is_variadic() {
+                        continue;
+                    }
+                    let input_type = operator_value.input_type;
+                    match top {
+                        // Real constants 0 and 1 are accepted as booleans, but nothing else
+                        // is, since boolean operators would silently give nonsense for them
+                        TypedValue::Constant(value, NodeType::Real)
+                            if input_type == NodeType::Bool =>
+                        {
+                            if value != 0.0 && value != 1.0 {
+                                return Err(error(
+                                    i,
+                                    format!(
+                                        "Operator {} expects a boolean, got constant {} \
+                                        (booleans are 0 or 1)",
+                                        op, value
+                                    ),
+                                ));
                             }
                         }
-                        Node::Constant(constant) => {
-                            if constant.node_type != operator_value.input_type
-                                && operator_value.input_type != NodeType::Any
-                            {
-                                bail!(
-                                    "Type mismatch: expected {}, got {}",
-                                    operator_value.input_type,
-                                    constant.node_type
-                                );
+                        TypedValue::Constant(_, node_type) | TypedValue::Value(node_type) => {
+                            if node_type != input_type && input_type != NodeType::Any {
+                                return Err(error(
+                                    i,
+                                    format!(
+                                        "Type mismatch: expected {}, got {}",
+                                        input_type, node_type
+                                    ),
+                                ));
                             }
                         }
                     };
                 }
-                let result_node = Node::Constant(Constant {
-                    value: 0.0,
-                    node_type: operator_value.output_type,
-                });
-                stack.push(result_node);
+                stack.push(TypedValue::Value(operator_value.output_type));
             }
         };
     }
     if stack.len() != 1 {
-        bail!("Invalid definition stack: {:?}", stack);
+        return Err(error(
+            nodes.len().saturating_sub(1),
+            format!("Invalid definition stack: {:?}", stack),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that constant bounds given to a clamp are ordered. Constants are always leaves, so
+/// when the last two nodes before the clamp are constants they are exactly its bounds.
+fn check_clamp_bounds(preceding: &[Node]) -> Result<()> {
+    if let [.., Node::Constant(lower), Node::Constant(upper)] = preceding {
+        if lower.value > upper.value {
+            bail!(
+                "Clamp lower bound {} is greater than upper bound {}",
+                lower.value,
+                upper.value
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Most bins a quantize can have, since each bin boundary is stored
+pub const MAX_QUANTIZE_BINS: f32 = 1000.0;
+
+/// Check that the number of bins given to a quantize is a positive integer constant of at
+/// most `MAX_QUANTIZE_BINS`. It is the second operand, so it must be the node right before
+/// the quantize.
+fn check_quantize_bins(preceding: &[Node]) -> Result<()> {
+    match preceding.last() {
+        Some(Node::Constant(bins)) if bins.value > MAX_QUANTIZE_BINS => {
+            bail!(
+                "Quantize can have at most {} bins, got {}",
+                MAX_QUANTIZE_BINS,
+                bins.value
+            )
+        }
+        Some(Node::Constant(bins)) if bins.value >= 1.0 && bins.value.fract() == 0.0 => Ok(()),
+        Some(Node::Constant(bins)) => {
+            bail!(
+                "Quantize bins must be a positive integer, got {}",
+                bins.value
+            )
+        }
+        _ => bail!("Quantize bins must be a constant"),
+    }
+}
+
+/// Check that the cutoff given to a threshold is a constant, so that it can't compare two
+/// features. It is the second operand, so it must be the node right before the threshold.
+fn check_threshold_cutoff(preceding: &[Node]) -> Result<()> {
+    match preceding.last() {
+        Some(Node::Constant(_)) => Ok(()),
+        _ => bail!("Threshold cutoff must be a constant"),
+    }
+}
+
+/// Whether an operator is disabled. Operators are disabled by name, matched
+/// case-insensitively (e.g. `div` or `DIV`).
+pub fn operator_is_disabled(op: &Operators, disabled_operators: &[String]) -> bool {
+    let name = op.to_string();
+    disabled_operators
+        .iter()
+        .any(|disabled| disabled.eq_ignore_ascii_case(&name))
+}
+
+/// Check that a definition doesn't use any disabled operator
+pub fn check_disabled_operators(
+    nodes: &[ParsingNode],
+    disabled_operators: &[String],
+) -> Result<()> {
+    for node in nodes {
+        if let ParsingNode::Operator(op) = node {
+            if operator_is_disabled(op, disabled_operators) {
+                bail!("Operator {} is disabled on this server", op);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that a definition is at most `max_bytes` long. This is checked before parsing,
+/// so that huge definitions can't use up memory or time in the parser.
+pub fn check_definition_size(definition: &str, max_bytes: usize) -> Result<()> {
+    if definition.len() > max_bytes {
+        bail!(
+            "Phenotype definition is {} bytes, more than the limit of {}",
+            definition.len(),
+            max_bytes
+        );
     }
     Ok(())
 }
@@ -159,27 +489,261 @@ pub fn type_check_nodes(nodes: &[Node]) -> Result<()> {
 pub fn validate_phenotype_definition(
     cohort_id: i32,
     definition: &str,
+    syntax: DefinitionSyntax,
+    kb: &KnowledgeBase,
+    disabled_operators: &[String],
+) -> Result<Vec<Node>> {
+    let parsed = parse_checked_definition(definition, syntax, disabled_operators)?;
+    validate_parsed_definition(cohort_id, &parsed, kb)
+}
+
+/// Parse a definition and check its operators, which doesn't depend on the cohort, so a
+/// definition validated against many cohorts is only parsed once
+pub fn parse_checked_definition(
+    definition: &str,
+    syntax: DefinitionSyntax,
+    disabled_operators: &[String],
+) -> Result<ParsedDefinition> {
+    let parsed = match syntax {
+        DefinitionSyntax::Postfix => parse_definition(definition)?,
+        DefinitionSyntax::Infix => parse_infix_definition(definition)?,
+    };
+    check_disabled_operators(&parsed.nodes, disabled_operators)?;
+    check_arity(&parsed)?;
+    Ok(parsed)
+}
+
+/// Validate a definition from `parse_checked_definition` against one cohort
+pub fn validate_parsed_definition(
+    cohort_id: i32,
+    parsed: &ParsedDefinition,
     kb: &KnowledgeBase,
 ) -> Result<Vec<Node>> {
-    let nodes = parse_string_definition(definition)?;
-    let valid_nodes = validate_nodes(cohort_id, &nodes, kb).context("Error validating nodes")?;
-    type_check_nodes(&valid_nodes).context("Error type checking nodes")?;
+    let valid_nodes =
+        validate_nodes(cohort_id, &parsed.nodes, kb).context("Error validating nodes")?;
+    type_check_nodes(&valid_nodes, &parsed.spans).context("Error type checking nodes")?;
     Ok(valid_nodes)
 }
 
+/// Feature codes of a definition that the cohort doesn't have, each listed once in the
+/// order they first appear
+pub fn missing_features(cohort_id: i32, nodes: &[ParsingNode], kb: &KnowledgeBase) -> Vec<String> {
+    nodes
+        .iter()
+        .filter_map(|node| match node {
+            ParsingNode::Feature(code) if kb.find_field(cohort_id, code).is_none() => {
+                Some(code.clone())
+            }
+            _ => None,
+        })
+        .unique()
+        .collect()
+}
+
+/// Divide, or NaN rather than infinite when the denominator is zero, so ratios of features
+/// exclude those samples like any other missing value
+fn safe_div(x: f32, y: f32) -> f32 {
+    if y == 0.0 {
+        f32::NAN
+    } else {
+        x / y
+    }
+}
+
+/// Missing (NaN) values mark samples excluded from the phenotype, so any operation on
+/// one is also missing. Without this, comparisons would silently turn them into controls.
+fn propagate_missing(x: f32, y: f32, value: f32) -> f32 {
+    if x.is_nan() || y.is_nan() {
+        f32::NAN
+    } else {
+        value
+    }
+}
+
+/// Case (1) where `case` is true, control (0) where only `control` is true, and excluded
+/// (NaN) where neither is
+fn case_control(case: f32, control: f32) -> f32 {
+    if case == 1.0 {
+        1.0
+    } else if control == 1.0 {
+        0.0
+    } else {
+        f32::NAN
+    }
+}
+
+/// Rank-based inverse normal transformation, mapping the rank `r` of each of the `n`
+/// non-missing values to the standard normal quantile of `(r - 3/8) / (n + 1/4)` (Blom's
+/// offset). Tied values share their average rank, and missing values stay missing.
+/// Unlike the other operators this depends on the whole column, not just one sample.
+pub fn inverse_normal_transform(values: &[f32]) -> Vec<f32> {
+    let mut order = (0..values.len())
+        .filter(|&i| !values[i].is_nan())
+        .collect::<Vec<usize>>();
+    order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+    let n = order.len() as f64;
+    let normal = Normal::standard();
+    let mut result = vec![f32::NAN; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        // Ranks are 1-based, so the tied group spans ranks start + 1 through end
+        let rank = (start + end + 1) as f64 / 2.0;
+        let value = normal.inverse_cdf((rank - 0.375) / (n + 0.25)) as f32;
+        for &i in &order[start..end] {
+            result[i] = value;
+        }
+        start = end;
+    }
+    result
+}
+
+/// Assign each non-missing value the 0-based index of its quantile bin out of `n_bins`.
+/// Bin boundaries are the linearly interpolated quantiles at `1/n_bins, 2/n_bins, ...`, and
+/// a value equal to a boundary falls in the lower bin. Missing values stay missing. Like
+/// `inverse_normal_transform`, this depends on the whole column.
+pub fn quantize(values: &[f32], n_bins: usize) -> Vec<f32> {
+    let mut sorted = values
+        .iter()
+        .copied()
+        .filter(|x| !x.is_nan())
+        .collect::<Vec<f32>>();
+    if sorted.is_empty() {
+        return values.to_vec();
+    }
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let boundaries = (1..n_bins)
+        .map(|j| {
+            let position = j as f32 / n_bins as f32 * (sorted.len() - 1) as f32;
+            let (lower, upper) = (position.floor() as usize, position.ceil() as usize);
+            sorted[lower] + (sorted[upper] - sorted[lower]) * position.fract()
+        })
+        .collect::<Vec<f32>>();
+    values
+        .iter()
+        .map(|x| match x.is_nan() {
+            true => f32::NAN,
+            false => boundaries.partition_point(|boundary| x > boundary) as f32,
+        })
+        .collect()
+}
+
+/// Standardize to mean zero and unit (sample) standard deviation over the non-missing
+/// values. Missing values stay missing. A column with no variance (or fewer than two
+/// non-missing values) can't be standardized, so every value becomes missing rather than
+/// a constant 0. Like `inverse_normal_transform`, this depends on the whole column.
+pub fn z_score(values: &[f32]) -> Vec<f32> {
+    let present = values
+        .iter()
+        .filter(|x| !x.is_nan())
+        .map(|x| *x as f64)
+        .collect::<Vec<f64>>();
+    let n = present.len() as f64;
+    let mean = present.iter().sum::<f64>() / n;
+    let variance = present.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    let std = variance.sqrt();
+    if std.is_nan() || std == 0.0 {
+        return vec![f32::NAN; values.len()];
+    }
+    values
+        .iter()
+        .map(|x| ((*x as f64 - mean) / std) as f32)
+        .collect()
+}
+
+/// Sum columns sample by sample. Like the other arithmetic operators, a sample missing
+/// (NaN) in any column is missing in the sum rather than having the missing value skipped.
+fn sum_columns(columns: &[Vec<f32>], n_samples: usize) -> Vec<f32> {
+    columns
+        .iter()
+        .fold(vec![0.0; n_samples], |mut sum, column| {
+            sum.iter_mut()
+                .zip(column)
+                .for_each(|(total, x)| *total += x);
+            sum
+        })
+}
+
+/// Number of true values in each row of boolean columns. By default (`COUNT_TRUE`) missing
+/// values count as false, so a sample with some diagnoses unrecorded still gets a count
+/// from the rest. With `propagate_missing` (`COUNT_TRUE_STRICT`), a sample missing in any
+/// column is missing in the count, like `SUM_FEATURES`.
+fn count_true(columns: &[Vec<f32>], n_samples: usize, propagate_missing: bool) -> Vec<f32> {
+    columns
+        .iter()
+        .fold(vec![0.0; n_samples], |mut count, column| {
+            count.iter_mut().zip(column).for_each(|(total, x)| {
+                if x.is_nan() && propagate_missing {
+                    *total = f32::NAN;
+                } else if *x == 1.0 {
+                    *total += 1.0;
+                }
+            });
+            count
+        })
+}
+
+/// Column index of each distinct feature in a definition. Resolving a code scans every
+/// feature name, so it's done once per code rather than once per use.
+fn resolve_definition_features<'a>(
+    definition: &'a [Node],
+    names: &[String],
+    aliases: &HashMap<String, String>,
+) -> Result<HashMap<&'a str, usize>> {
+    let mut indices = HashMap::new();
+    for node in definition {
+        if let Node::Feature(field) = node {
+            if !indices.contains_key(field.code.as_str()) {
+                let idx = resolve_feature_index(&field.code, names, aliases)
+                    .ok_or(anyhow!("Unknown field {}", field.code))?;
+                indices.insert(field.code.as_str(), idx);
+            }
+        }
+    }
+    Ok(indices)
+}
+
+/// Evaluate a definition on every sample. Samples excluded from the phenotype (e.g. by
+/// `CASE_CONTROL`, or missing values) are NaN in the result.
 pub fn apply_phenotype_definition(
     definition: &[Node],
     names: &[String],
     phenotypes: &Mat<f32>,
+    aliases: &HashMap<String, String>,
 ) -> Result<Vec<f32>> {
+    let feature_indices = resolve_definition_features(definition, names, aliases)?;
+    let n_samples = phenotypes.nrows();
     let mut stack = Vec::new();
-    for node in definition {
+    for (i, node) in definition.iter().enumerate() {
         match node {
             Node::Feature(field) => {
-                let idx = names.iter().position(|x| *x == field.code).unwrap();
+                let idx = feature_indices[field.code.as_str()];
                 let column = phenotypes.col(idx).iter().copied().collect::<Vec<f32>>();
                 stack.push(column);
             }
+            Node::Operator(op) if op.is_variadic() => {
+                let arity = operand_count(op, previous_constant(definition, i))?;
+                if stack.len() < arity {
+                    bail!(
+                        "Operator {} expects {} arguments, got {}",
+                        op,
+                        arity,
+                        stack.len()
+                    );
+                }
+                // The last operand is the count, which isn't an input
+                let mut operands = stack.split_off(stack.len() - arity);
+                operands.pop();
+                let result = match op {
+                    Operators::CountTrue => count_true(&operands, n_samples, false),
+                    Operators::CountTrueStrict => count_true(&operands, n_samples, true),
+                    _ => sum_columns(&operands, n_samples),
+                };
+                stack.push(result);
+            }
             Node::Operator(op) => {
                 let operator_value = op.value();
                 match operator_value.arity {
@@ -199,6 +763,23 @@ pub fn apply_phenotype_definition(
                                     item.iter().map(|x| (1.0_f32 - x)).collect::<Vec<f32>>();
                                 stack.push(result);
                             }
+                            Operators::Neg => {
+                                let result = item.iter().map(|x| -x).collect::<Vec<f32>>();
+                                stack.push(result);
+                            }
+                            Operators::IsMissing => {
+                                let result = item
+                                    .iter()
+                                    .map(|x| if x.is_nan() { 1.0 } else { 0.0 })
+                                    .collect::<Vec<f32>>();
+                                stack.push(result);
+                            }
+                            Operators::InverseNormal => {
+                                stack.push(inverse_normal_transform(&item));
+                            }
+                            Operators::ZScore => {
+                                stack.push(z_score(&item));
+                            }
                             _ => {
                                 bail!("Unknown operator {} with arity 1", operator_value.name)
                             }
@@ -238,11 +819,19 @@ pub fn apply_phenotype_definition(
                                     item1.iter().zip(item2.iter()).map(|(x, y)| x / y).collect();
                                 stack.push(result);
                             }
+                            Operators::SafeDiv => {
+                                let result = item1
+                                    .iter()
+                                    .zip(item2.iter())
+                                    .map(|(x, y)| safe_div(*x, *y))
+                                    .collect();
+                                stack.push(result);
+                            }
                             Operators::And => {
                                 let result = item1
                                     .iter()
                                     .zip(item2.iter())
-                                    .map(|(x, y)| x.min(*y))
+                                    .map(|(x, y)| propagate_missing(*x, *y, x.min(*y)))
                                     .collect();
                                 stack.push(result);
                             }
@@ -250,7 +839,36 @@ pub fn apply_phenotype_definition(
                                 let result = item1
                                     .iter()
                                     .zip(item2.iter())
-                                    .map(|(x, y)| x.max(*y))
+                                    .map(|(x, y)| propagate_missing(*x, *y, x.max(*y)))
+                                    .collect();
+                                stack.push(result);
+                            }
+                            Operators::Xor => {
+                                let result = item1
+                                    .iter()
+                                    .zip(item2.iter())
+                                    .map(|(x, y)| (x - y).abs())
+                                    .collect();
+                                stack.push(result);
+                            }
+                            Operators::Nand => {
+                                let result = item1
+                                    .iter()
+                                    .zip(item2.iter())
+                                    .map(|(x, y)| propagate_missing(*x, *y, 1.0 - x.min(*y)))
+                                    .collect();
+                                stack.push(result);
+                            }
+                            Operators::Quantize => {
+                                // Bins are a constant, so every entry is the same
+                                let n_bins = item2.first().copied().unwrap_or(1.0);
+                                stack.push(quantize(&item1, n_bins.max(1.0) as usize));
+                            }
+                            Operators::CaseControl => {
+                                let result = item1
+                                    .iter()
+                                    .zip(item2.iter())
+                                    .map(|(case, control)| case_control(*case, *control))
                                     .collect();
                                 stack.push(result);
                             }
@@ -258,15 +876,16 @@ pub fn apply_phenotype_definition(
                                 let result = item1
                                     .iter()
                                     .zip(item2.iter())
-                                    .map(|(x, y)| if x > y { 1.0 } else { 0.0 })
+                                    .map(|(x, y)| propagate_missing(*x, *y, (x > y) as u8 as f32))
                                     .collect();
                                 stack.push(result);
                             }
-                            Operators::Ge => {
+                            // A threshold is a comparison to a constant cutoff
+                            Operators::Ge | Operators::Threshold => {
                                 let result = item1
                                     .iter()
                                     .zip(item2.iter())
-                                    .map(|(x, y)| if x >= y { 1.0 } else { 0.0 })
+                                    .map(|(x, y)| propagate_missing(*x, *y, (x >= y) as u8 as f32))
                                     .collect();
                                 stack.push(result);
                             }
@@ -274,7 +893,7 @@ pub fn apply_phenotype_definition(
                                 let result = item1
                                     .iter()
                                     .zip(item2.iter())
-                                    .map(|(x, y)| if x < y { 1.0 } else { 0.0 })
+                                    .map(|(x, y)| propagate_missing(*x, *y, (x < y) as u8 as f32))
                                     .collect();
                                 stack.push(result);
                             }
@@ -282,7 +901,7 @@ pub fn apply_phenotype_definition(
                                 let result = item1
                                     .iter()
                                     .zip(item2.iter())
-                                    .map(|(x, y)| if x <= y { 1.0 } else { 0.0 })
+                                    .map(|(x, y)| propagate_missing(*x, *y, (x <= y) as u8 as f32))
                                     .collect();
                                 stack.push(result);
                             }
@@ -290,7 +909,7 @@ pub fn apply_phenotype_definition(
                                 let result = item1
                                     .iter()
                                     .zip(item2.iter())
-                                    .map(|(x, y)| if x == y { 1.0 } else { 0.0 })
+                                    .map(|(x, y)| propagate_missing(*x, *y, (x == y) as u8 as f32))
                                     .collect();
                                 stack.push(result);
                             }
@@ -299,6 +918,43 @@ pub fn apply_phenotype_definition(
                             }
                         }
                     }
+                    3 => {
+                        let item3 = stack.pop().ok_or(anyhow::anyhow!(
+                            "Operator {} expects {} arguments, got {}",
+                            operator_value.name,
+                            operator_value.arity,
+                            stack.len()
+                        ))?;
+                        let item2 = stack.pop().ok_or(anyhow::anyhow!(
+                            "Operator {} expects {} arguments, got {}",
+                            operator_value.name,
+                            operator_value.arity,
+                            stack.len()
+                        ))?;
+                        let item1 = stack.pop().ok_or(anyhow::anyhow!(
+                            "Operator {} expects {} arguments, got {}",
+                            operator_value.name,
+                            operator_value.arity,
+                            stack.len()
+                        ))?;
+                        match op {
+                            Operators::Clamp => {
+                                let result = izip!(item1.iter(), item2.iter(), item3.iter())
+                                    .map(|(x, lower, upper)| {
+                                        if x.is_nan() {
+                                            *x
+                                        } else {
+                                            x.max(*lower).min(*upper)
+                                        }
+                                    })
+                                    .collect();
+                                stack.push(result);
+                            }
+                            _ => {
+                                bail!("Unknown operator {} with arity 3", operator_value.name)
+                            }
+                        }
+                    }
                     _ => {
                         bail!(
                             "Unknown operator {} with arity {}",
@@ -310,9 +966,7 @@ pub fn apply_phenotype_definition(
             }
             Node::Constant(constant) => {
                 let constant_value = constant.value;
-                let result = std::iter::repeat(constant_value)
-                    .take(phenotypes.nrows())
-                    .collect();
+                let result = std::iter::repeat(constant_value).take(n_samples).collect();
                 stack.push(result);
             }
         };
@@ -328,7 +982,7 @@ pub fn apply_phenotype_definition(
 /// e.g. ["age", 30, "gt" "sex" "male" "eq" "and"] -> "AND(GT('age', 30), EQ('sex', 'male'))"
 pub fn format_phenotype_definition(nodes: &[Node]) -> String {
     let mut stack: Vec<String> = Vec::new();
-    for node in nodes {
+    for (i, node) in nodes.iter().enumerate() {
         match node {
             Node::Feature(_) => {
                 let formatted_node = format_node(node);
@@ -339,12 +993,13 @@ pub fn format_phenotype_definition(nodes: &[Node]) -> String {
                 stack.push(formatted_node);
             }
             Node::Operator(op) => {
-                let operator_value = op.value();
                 let operator_string = format_node(node);
                 let mut this_string = format!("{}(", operator_string);
-                assert!(operator_value.arity > 0);
+                let arity =
+                    operand_count(op, previous_constant(nodes, i)).expect("Invalid operand count");
+                assert!(arity > 0);
                 let mut local_stack = Vec::new();
-                for _ in 0..operator_value.arity {
+                for _ in 0..arity {
                     let top = stack.pop().unwrap();
                     local_stack.push(top);
                 }
@@ -362,6 +1017,21 @@ pub fn format_phenotype_definition(nodes: &[Node]) -> String {
     stack.pop().expect("Stack is empty")
 }
 
+/// Hash a phenotype definition by the feature codes, operators, and constants it contains,
+/// so that definitions that parse to the same nodes share a hash
+pub fn hash_phenotype_definition(nodes: &[Node]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for node in nodes {
+        match node {
+            Node::Feature(field) => format!("\"{}\"", field.code),
+            Node::Operator(op) => format!("`{}`", op),
+            Node::Constant(constant) => format!("<{}:{}>", constant.node_type, constant.value),
+        }
+        .hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
 pub fn format_node(node: &Node) -> String {
     match node {
         Node::Feature(field) => format!("'{}' [{}]", field.name, field.code),
@@ -373,6 +1043,117 @@ pub fn format_node(node: &Node) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::testing::test_feature;
+    use faer::mat;
+
+    fn feature(code: &str, cohort_id: i32) -> Feature {
+        Feature {
+            id: 0,
+            code: code.to_string(),
+            name: code.to_string(),
+            node_type: NodeType::Real,
+            sample_size: 0,
+            cohort_id,
+        }
+    }
+
+    /// Parse a definition into nodes, with each feature taken as one of cohort 1 with
+    /// the given type, skipping validation against a knowledge base
+    fn to_nodes(definition: &str, node_type: NodeType) -> Vec<Node> {
+        parse_definition(definition)
+            .unwrap()
+            .nodes
+            .into_iter()
+            .map(|node| match node {
+                ParsingNode::Feature(code) => Node::Feature(Feature {
+                    node_type,
+                    ..feature(&code, 1)
+                }),
+                node => node.into(),
+            })
+            .collect()
+    }
+
+    /// Type check nodes that weren't parsed, so have no spans to point errors at
+    fn type_check(nodes: &[Node]) -> Result<(), PhenotypeError> {
+        type_check_nodes(nodes, &vec![TokenSpan::default(); nodes.len()])
+    }
+
+    #[test]
+    fn test_validate_cross_cohort_feature() {
+        let kb = KnowledgeBase::new(vec![feature("a", 1), feature("b", 2)]);
+        let validate = |definition: &str, syntax: DefinitionSyntax| {
+            validate_phenotype_definition(1, definition, syntax, &kb, &[])
+        };
+        assert!(validate(r#""a" `ROOT`"#, DefinitionSyntax::Postfix).is_ok());
+        let err = validate(r#""a" "b" `ADD`"#, DefinitionSyntax::Postfix).unwrap_err();
+        assert!(format!("{:#}", err).contains("Fields not in cohort 1: b"));
+        let err = validate(r#""c" `ROOT`"#, DefinitionSyntax::Postfix).unwrap_err();
+        assert!(format!("{:#}", err).contains("Unknown field c"));
+        // Infix definitions are validated the same way once they're parsed
+        let err = validate("a + b", DefinitionSyntax::Infix).unwrap_err();
+        assert!(format!("{:#}", err).contains("Fields not in cohort 1: b"));
+        assert!(validate("a + b", DefinitionSyntax::Postfix).is_err());
+    }
+
+    #[test]
+    fn test_missing_features() {
+        let kb = KnowledgeBase::new(vec![feature("a", 1), feature("b", 2)]);
+        let nodes = parse_checked_definition(
+            r#""a" "b" `ADD` "c" `ADD` "b" `ADD`"#,
+            DefinitionSyntax::Postfix,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(missing_features(1, &nodes.nodes, &kb), vec!["b", "c"]);
+        assert_eq!(missing_features(2, &nodes.nodes, &kb), vec!["a", "c"]);
+        let nodes =
+            parse_checked_definition(r#""a" `ROOT`"#, DefinitionSyntax::Postfix, &[]).unwrap();
+        assert!(missing_features(1, &nodes.nodes, &kb).is_empty());
+        assert!(validate_parsed_definition(1, &nodes, &kb).is_ok());
+    }
+
+    #[test]
+    fn test_check_definition_size() {
+        assert!(check_definition_size(r#""a" `ROOT`"#, 10).is_ok());
+        let oversized = r#""a" "#.repeat(1000) + &"`ADD` ".repeat(999);
+        let err = check_definition_size(&oversized, 1024).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Phenotype definition is 9994 bytes, more than the limit of 1024"
+        );
+    }
+
+    #[test]
+    fn test_disabled_operators() {
+        let kb = KnowledgeBase::new(vec![feature("a", 1), feature("b", 1)]);
+        let disabled = vec!["div".to_string()];
+        let err = validate_phenotype_definition(
+            1,
+            r#""a" "b" `DIV`"#,
+            DefinitionSyntax::Postfix,
+            &kb,
+            &disabled,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Operator DIV is disabled on this server");
+        assert!(validate_phenotype_definition(
+            1,
+            r#""a" "b" `MUL`"#,
+            DefinitionSyntax::Postfix,
+            &kb,
+            &disabled
+        )
+        .is_ok());
+        assert!(validate_phenotype_definition(
+            1,
+            r#""a" "b" `DIV`"#,
+            DefinitionSyntax::Postfix,
+            &kb,
+            &[]
+        )
+        .is_ok());
+    }
 
     #[test]
     fn test_format_phenotype_definition() {
@@ -415,4 +1196,668 @@ mod tests {
             "AND(GT('age' [1], `30`), EQ('sex' [2], 'male' [3]))"
         );
     }
+
+    #[test]
+    fn test_apply_phenotype_definition_alias() {
+        let names = vec!["a".to_string(), "b".to_string()];
+        let phenotypes: Mat<f32> = mat![[1.0, 2.0], [3.0, 4.0]];
+        let aliases = HashMap::from([("canonical_b".to_string(), "b".to_string())]);
+        let aliased = apply_phenotype_definition(
+            &[test_feature("canonical_b")],
+            &names,
+            &phenotypes,
+            &aliases,
+        )
+        .unwrap();
+        assert_eq!(aliased, vec![2.0, 4.0]);
+        let literal =
+            apply_phenotype_definition(&[test_feature("a")], &names, &phenotypes, &aliases)
+                .unwrap();
+        assert_eq!(literal, vec![1.0, 3.0]);
+        let unknown =
+            apply_phenotype_definition(&[test_feature("c")], &names, &phenotypes, &aliases);
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn test_parse_negative_constant() {
+        let nodes = parse_definition("<REAL:-1.5>").unwrap().nodes;
+        match &nodes[..] {
+            [ParsingNode::Constant(constant)] => assert_eq!(constant.value, -1.5),
+            _ => panic!("Expected a single constant"),
+        }
+    }
+
+    #[test]
+    fn test_parse_definition_error_positions() {
+        let error = |definition: &str| parse_definition(definition).unwrap_err();
+        assert_eq!(
+            error(r#""a" `FOO`"#),
+            PhenotypeError {
+                position: 5,
+                token: "`FOO`".to_string(),
+                message: "Unknown operator `FOO`".to_string(),
+            }
+        );
+        assert_eq!(
+            error(r#""a"  <REAL:x> `ADD`"#).to_string(),
+            "invalid float literal at position 6"
+        );
+        // Positions count characters, not bytes
+        let err = error(r#""é" "b" `ADD` oops"#);
+        assert_eq!((err.position, err.token.as_str()), (15, "oops"));
+        let err = error("\t\"unterminated");
+        assert_eq!(
+            (err.position, err.message.as_str()),
+            (2, "Invalid field name \"unterminated")
+        );
+        assert_eq!(error(r#"`"#).position, 1);
+        assert!(parse_definition("").unwrap().nodes.is_empty());
+    }
+
+    #[test]
+    fn test_apply_neg() {
+        let names = vec!["a".to_string()];
+        let phenotypes: Mat<f32> = mat![[1.0], [-2.0]];
+        let a = Node::Feature(feature("a", 1));
+        let aliases = HashMap::new();
+        let negated = apply_phenotype_definition(
+            &[a.clone(), Node::Operator(Operators::Neg)],
+            &names,
+            &phenotypes,
+            &aliases,
+        )
+        .unwrap();
+        let zero = Node::Constant(Constant {
+            value: 0.0,
+            node_type: NodeType::Real,
+        });
+        let subtracted = apply_phenotype_definition(
+            &[zero, a, Node::Operator(Operators::Sub)],
+            &names,
+            &phenotypes,
+            &aliases,
+        )
+        .unwrap();
+        assert_eq!(negated, vec![-1.0, 2.0]);
+        assert_eq!(negated, subtracted);
+    }
+
+    #[test]
+    fn test_apply_inverse_normal() {
+        // A heavily skewed feature
+        let n = 1000;
+        let names = vec!["a".to_string()];
+        let phenotypes = Mat::from_fn(n, 1, |i, _| (((i * 7919) % n) as f32 / 100.0).exp());
+        let aliases = HashMap::new();
+        let result = apply_phenotype_definition(
+            &[
+                Node::Feature(feature("a", 1)),
+                Node::Operator(Operators::InverseNormal),
+            ],
+            &names,
+            &phenotypes,
+            &aliases,
+        )
+        .unwrap();
+        let mean = result.iter().sum::<f32>() / n as f32;
+        let variance = result.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / n as f32;
+        assert!(mean.abs() < 1e-3);
+        assert!((variance - 1.0).abs() < 0.01);
+        let skewness = result.iter().map(|x| (x - mean).powi(3)).sum::<f32>() / n as f32;
+        assert!(skewness.abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_inverse_normal_ties_and_missing() {
+        let result = inverse_normal_transform(&[2.0, f32::NAN, 1.0, 2.0]);
+        assert!(result[1].is_nan());
+        assert_eq!(result[0], result[3]);
+        assert!(result[2] < 0.0 && result[0] > 0.0);
+        // The tied pair shares rank 2.5 of 3
+        let expected = Normal::standard().inverse_cdf((2.5 - 0.375) / 3.25) as f32;
+        assert!((result[0] - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_apply_z_score() {
+        let names = vec!["a".to_string(), "b".to_string()];
+        let phenotypes: Mat<f32> = mat![[1.0, 5.0], [f32::NAN, 5.0], [2.0, 5.0], [3.0, 5.0]];
+        let aliases = HashMap::new();
+        let z_score_of = |code: &str| {
+            apply_phenotype_definition(
+                &[
+                    Node::Feature(feature(code, 1)),
+                    Node::Operator(Operators::ZScore),
+                ],
+                &names,
+                &phenotypes,
+                &aliases,
+            )
+            .unwrap()
+        };
+        let result = z_score_of("a");
+        assert!(result[1].is_nan());
+        assert_eq!([result[0], result[2], result[3]], [-1.0, 0.0, 1.0]);
+        // Constant columns can't be standardized
+        assert!(z_score_of("b").iter().all(|x| x.is_nan()));
+    }
+
+    #[test]
+    fn test_boolean_operator_constants() {
+        let bool_feature = Feature {
+            node_type: NodeType::Bool,
+            ..feature("a", 1)
+        };
+        let check = |value: f32, op: Operators| {
+            type_check(&[
+                Node::Feature(bool_feature.clone()),
+                Node::Constant(Constant {
+                    value,
+                    node_type: NodeType::Real,
+                }),
+                Node::Operator(op),
+            ])
+        };
+        let err = check(2.0, Operators::And).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Operator AND expects a boolean, got constant 2 (booleans are 0 or 1)"
+        );
+        assert!(check(0.5, Operators::Xor).is_err());
+        assert!(check(1.0, Operators::And).is_ok());
+        assert!(check(0.0, Operators::Or).is_ok());
+    }
+
+    #[test]
+    fn test_apply_repeated_feature() {
+        let names = (0..100).map(|i| i.to_string()).collect::<Vec<String>>();
+        let phenotypes = Mat::from_fn(3, 100, |i, j| (i * 100 + j) as f32);
+        // The sum of one feature repeated 50 times
+        let mut nodes = vec![Node::Feature(feature("99", 1))];
+        for _ in 1..50 {
+            nodes.push(Node::Feature(feature("99", 1)));
+            nodes.push(Node::Operator(Operators::Add));
+        }
+        let result =
+            apply_phenotype_definition(&nodes, &names, &phenotypes, &HashMap::new()).unwrap();
+        assert_eq!(result, vec![99.0 * 50.0, 199.0 * 50.0, 299.0 * 50.0]);
+        nodes.push(Node::Feature(feature("100", 1)));
+        nodes.push(Node::Operator(Operators::Add));
+        let err =
+            apply_phenotype_definition(&nodes, &names, &phenotypes, &HashMap::new()).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown field 100");
+    }
+
+    /// Compare resolving a feature once per use (as before `resolve_definition_features`)
+    /// with resolving each distinct feature once, for a definition that repeats the last
+    /// of many features 50 times. Run with
+    /// `cargo test --release bench_apply_repeated_feature -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_apply_repeated_feature() {
+        let n_repeats = 100;
+        let n_features = 5000;
+        let names = (0..n_features)
+            .map(|i| format!("feature_{}", i))
+            .collect::<Vec<String>>();
+        let aliases = HashMap::from([("alias".to_string(), names[n_features - 1].clone())]);
+        let phenotypes = Mat::from_fn(1000, n_features, |i, j| (i + j) as f32);
+        for code in [names[n_features - 1].as_str(), "alias"] {
+            let mut nodes = vec![Node::Feature(feature(code, 1))];
+            for _ in 1..50 {
+                nodes.push(Node::Feature(feature(code, 1)));
+                nodes.push(Node::Operator(Operators::Add));
+            }
+            let start = std::time::Instant::now();
+            for _ in 0..n_repeats {
+                let indices = nodes
+                    .iter()
+                    .filter_map(|node| match node {
+                        Node::Feature(field) => {
+                            resolve_feature_index(&field.code, &names, &aliases)
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<usize>>();
+                std::hint::black_box(indices);
+            }
+            let per_use = start.elapsed() / n_repeats;
+            let start = std::time::Instant::now();
+            for _ in 0..n_repeats {
+                std::hint::black_box(
+                    resolve_definition_features(&nodes, &names, &aliases).unwrap(),
+                );
+            }
+            let once = start.elapsed() / n_repeats;
+            let start = std::time::Instant::now();
+            for _ in 0..n_repeats {
+                std::hint::black_box(
+                    apply_phenotype_definition(&nodes, &names, &phenotypes, &aliases).unwrap(),
+                );
+            }
+            let apply = start.elapsed() / n_repeats;
+            println!(
+                "{}: resolve per use {:?}, resolve once {:?}, whole definition {:?}",
+                code, per_use, once, apply
+            );
+        }
+    }
+
+    #[test]
+    fn test_apply_safe_div() {
+        let names = vec!["ldl".to_string(), "hdl".to_string()];
+        let phenotypes: Mat<f32> = mat![[3.0, 1.5], [2.0, 0.0], [0.0, 0.0], [1.0, f32::NAN]];
+        let nodes = to_nodes(r#""ldl" "hdl" `SAFE_DIV`"#, NodeType::Real);
+        type_check(&nodes).unwrap();
+        let result =
+            apply_phenotype_definition(&nodes, &names, &phenotypes, &HashMap::new()).unwrap();
+        assert_eq!(result[0], 2.0);
+        assert!(result[1..].iter().all(|x| x.is_nan()));
+    }
+
+    #[test]
+    fn test_apply_threshold() {
+        let names = vec!["bmi".to_string(), "height".to_string()];
+        let phenotypes: Mat<f32> = mat![[29.9, 1.0], [30.0, 1.0], [30.1, 1.0], [f32::NAN, 1.0]];
+        let nodes = to_nodes(r#""bmi" <REAL:30> `THRESHOLD`"#, NodeType::Real);
+        type_check(&nodes).unwrap();
+        let result =
+            apply_phenotype_definition(&nodes, &names, &phenotypes, &HashMap::new()).unwrap();
+        assert_eq!(result[..3], [0.0, 1.0, 1.0]);
+        assert!(result[3].is_nan());
+        // The cutoff can't be another feature
+        let err =
+            type_check(&to_nodes(r#""bmi" "height" `THRESHOLD`"#, NodeType::Real)).unwrap_err();
+        assert_eq!(err.message, "Threshold cutoff must be a constant");
+    }
+
+    #[test]
+    fn test_apply_case_control() {
+        let names = vec!["case".to_string(), "control".to_string(), "x".to_string()];
+        let phenotypes: Mat<f32> = mat![
+            [1.0, 0.0, 1.0],
+            [0.0, 1.0, 1.0],
+            [0.0, 0.0, 1.0],
+            [1.0, 1.0, f32::NAN]
+        ];
+        let aliases = HashMap::new();
+        let case_control = apply_phenotype_definition(
+            &[
+                Node::Feature(feature("case", 1)),
+                Node::Feature(feature("control", 1)),
+                Node::Operator(Operators::CaseControl),
+            ],
+            &names,
+            &phenotypes,
+            &aliases,
+        )
+        .unwrap();
+        assert_eq!(case_control[..2], [1.0, 0.0]);
+        assert!(case_control[2].is_nan());
+        assert_eq!(case_control[3], 1.0);
+        // Exclusions carry through comparisons rather than becoming controls
+        let zero = Node::Constant(Constant {
+            value: 0.0,
+            node_type: NodeType::Real,
+        });
+        let positive = apply_phenotype_definition(
+            &[
+                Node::Feature(feature("x", 1)),
+                zero,
+                Node::Operator(Operators::Gt),
+            ],
+            &names,
+            &phenotypes,
+            &aliases,
+        )
+        .unwrap();
+        assert_eq!(positive[..3], [1.0, 1.0, 1.0]);
+        assert!(positive[3].is_nan());
+    }
+
+    #[test]
+    fn test_apply_is_missing() {
+        let names = vec!["a".to_string(), "b".to_string()];
+        let phenotypes: Mat<f32> = mat![[1.0, 1.0], [f32::NAN, 1.0], [3.0, 0.0], [f32::NAN, 0.0]];
+        let aliases = HashMap::new();
+        let missing = apply_phenotype_definition(
+            &[test_feature("a"), Node::Operator(Operators::IsMissing)],
+            &names,
+            &phenotypes,
+            &aliases,
+        )
+        .unwrap();
+        assert_eq!(missing, vec![0.0, 1.0, 0.0, 1.0]);
+        let missing_and_b = apply_phenotype_definition(
+            &[
+                test_feature("a"),
+                Node::Operator(Operators::IsMissing),
+                test_feature("b"),
+                Node::Operator(Operators::And),
+            ],
+            &names,
+            &phenotypes,
+            &aliases,
+        )
+        .unwrap();
+        assert_eq!(missing_and_b, vec![0.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_apply_xor_nand_truth_table() {
+        let names = vec!["a".to_string(), "b".to_string()];
+        let phenotypes: Mat<f32> = mat![[0.0, 0.0], [0.0, 1.0], [1.0, 0.0], [1.0, 1.0]];
+        let aliases = HashMap::new();
+        let xor = apply_phenotype_definition(
+            &[
+                test_feature("a"),
+                test_feature("b"),
+                Node::Operator(Operators::Xor),
+            ],
+            &names,
+            &phenotypes,
+            &aliases,
+        )
+        .unwrap();
+        assert_eq!(xor, vec![0.0, 1.0, 1.0, 0.0]);
+        let nand = apply_phenotype_definition(
+            &[
+                test_feature("a"),
+                test_feature("b"),
+                Node::Operator(Operators::Nand),
+            ],
+            &names,
+            &phenotypes,
+            &aliases,
+        )
+        .unwrap();
+        assert_eq!(nand, vec![1.0, 1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_apply_clamp() {
+        let names = vec!["a".to_string()];
+        let phenotypes: Mat<f32> = mat![[-5.0], [100.0], [250.0], [f32::NAN]];
+        let nodes = vec![
+            test_feature("a"),
+            Node::Constant(Constant {
+                value: 0.0,
+                node_type: NodeType::Real,
+            }),
+            Node::Constant(Constant {
+                value: 200.0,
+                node_type: NodeType::Real,
+            }),
+            Node::Operator(Operators::Clamp),
+        ];
+        type_check(&nodes).unwrap();
+        let result =
+            apply_phenotype_definition(&nodes, &names, &phenotypes, &HashMap::new()).unwrap();
+        assert_eq!(result[..3], [0.0, 100.0, 200.0]);
+        assert!(result[3].is_nan());
+    }
+
+    #[test]
+    fn test_unary_operator_with_two_operands() {
+        let nodes = parse_definition(r#""a" "b" `NOT`"#).unwrap();
+        let err = check_arity(&nodes).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Definition leaves 2 values unconsumed at position 9"
+        );
+    }
+
+    #[test]
+    fn test_leftover_operands() {
+        let nodes = parse_definition(r#""a" "b" `ADD` "c""#).unwrap();
+        let err = check_arity(&nodes).unwrap_err();
+        assert_eq!(
+            (err.message.as_str(), err.position, err.token.as_str()),
+            ("Definition leaves 2 values unconsumed", 15, "\"c\"")
+        );
+        let nodes = parse_definition(r#""a" "b" "c""#).unwrap();
+        let err = check_arity(&nodes).unwrap_err();
+        assert_eq!(err.message, "Definition leaves 3 values unconsumed");
+    }
+
+    #[test]
+    fn test_binary_operator_with_one_operand() {
+        let nodes = parse_definition(r#""a" `AND` "b" `OR`"#).unwrap();
+        let err = check_arity(&nodes).unwrap_err();
+        assert_eq!(
+            err,
+            PhenotypeError {
+                position: 5,
+                token: "`AND`".to_string(),
+                message: "Operator and expects 2 arguments, got 1".to_string(),
+            }
+        );
+        let nodes = parse_definition(r#""a" "b" `AND` "c" `OR`"#).unwrap();
+        assert!(check_arity(&nodes).is_ok());
+    }
+
+    #[test]
+    fn test_boolean_promotes_to_real_in_arithmetic() {
+        let names = vec!["flag".to_string()];
+        let phenotypes: Mat<f32> = mat![[1.0], [0.0]];
+        let mut flag = feature("flag", 1);
+        flag.node_type = NodeType::Bool;
+        let half = Node::Constant(Constant {
+            value: 0.5,
+            node_type: NodeType::Real,
+        });
+        let nodes = vec![Node::Feature(flag), half, Node::Operator(Operators::Add)];
+        type_check(&nodes).unwrap();
+        let result =
+            apply_phenotype_definition(&nodes, &names, &phenotypes, &HashMap::new()).unwrap();
+        assert_eq!(result, vec![1.5, 0.5]);
+        // The sum is real, so it can be used where a real is required but not a boolean
+        let mut negated = nodes.clone();
+        negated.push(Node::Operator(Operators::Neg));
+        type_check(&negated).unwrap();
+        let mut inverted = nodes;
+        inverted.push(Node::Operator(Operators::Not));
+        assert!(type_check(&inverted).is_err());
+    }
+
+    #[test]
+    fn test_apply_quantize() {
+        let names = vec!["a".to_string()];
+        let values = [
+            5.0,
+            1.0,
+            12.0,
+            3.0,
+            8.0,
+            f32::NAN,
+            10.0,
+            2.0,
+            7.0,
+            4.0,
+            11.0,
+            6.0,
+            9.0,
+        ];
+        let phenotypes = Mat::from_fn(values.len(), 1, |i, _| values[i]);
+        let nodes = vec![
+            Node::Feature(feature("a", 1)),
+            Node::Constant(Constant {
+                value: 4.0,
+                node_type: NodeType::Real,
+            }),
+            Node::Operator(Operators::Quantize),
+        ];
+        type_check(&nodes).unwrap();
+        let result =
+            apply_phenotype_definition(&nodes, &names, &phenotypes, &HashMap::new()).unwrap();
+        // Quartile boundaries of 1..=12 are 3.75, 6.5 and 9.25
+        let expected = [
+            1.0,
+            0.0,
+            3.0,
+            0.0,
+            2.0,
+            f32::NAN,
+            3.0,
+            0.0,
+            2.0,
+            1.0,
+            3.0,
+            1.0,
+            2.0,
+        ];
+        assert_eq!(result[..5], expected[..5]);
+        assert!(result[5].is_nan());
+        assert_eq!(result[6..], expected[6..]);
+    }
+
+    #[test]
+    fn test_apply_sum_features() {
+        let names = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let phenotypes = mat![[1.0, 2.0, 3.0], [4.0, f32::NAN, 6.0]];
+        let nodes = to_nodes(r#""a" "b" "c" <REAL:3> `SUM_FEATURES`"#, NodeType::Real);
+        type_check(&nodes).unwrap();
+        assert_eq!(
+            format_phenotype_definition(&nodes),
+            "SUM_FEATURES('a' [a], 'b' [b], 'c' [c], `3`)"
+        );
+        let result =
+            apply_phenotype_definition(&nodes, &names, &phenotypes, &HashMap::new()).unwrap();
+        assert_eq!(result[0], 6.0);
+        // A value missing in any summed feature makes the sum missing
+        assert!(result[1].is_nan());
+    }
+
+    #[test]
+    fn test_apply_count_true() {
+        let names = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let phenotypes = mat![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, f32::NAN, 1.0],
+            [1.0, 1.0, 1.0]
+        ];
+        let nodes = to_nodes(r#""a" "b" "c" <REAL:3> `COUNT_TRUE`"#, NodeType::Bool);
+        type_check(&nodes).unwrap();
+        let result =
+            apply_phenotype_definition(&nodes, &names, &phenotypes, &HashMap::new()).unwrap();
+        assert_eq!(result, vec![0.0, 1.0, 2.0, 3.0]);
+        // The strict count is missing wherever any of the features is
+        let nodes = to_nodes(
+            r#""a" "b" "c" <REAL:3> `COUNT_TRUE_STRICT`"#,
+            NodeType::Bool,
+        );
+        type_check(&nodes).unwrap();
+        let result =
+            apply_phenotype_definition(&nodes, &names, &phenotypes, &HashMap::new()).unwrap();
+        assert_eq!(result[..2], [0.0, 1.0]);
+        assert!(result[2].is_nan());
+        assert_eq!(result[3], 3.0);
+        // Only boolean features can be counted
+        let real_nodes = [
+            Node::Feature(feature("a", 1)),
+            Node::Constant(Constant {
+                value: 1.0,
+                node_type: NodeType::Real,
+            }),
+            Node::Operator(Operators::CountTrue),
+        ];
+        assert!(type_check(&real_nodes).is_err());
+    }
+
+    #[test]
+    fn test_sum_features_count() {
+        let arity = |definition: &str| check_arity(&parse_definition(definition).unwrap());
+        assert!(arity(r#""a" "b" <REAL:2> `SUM_FEATURES`"#).is_ok());
+        let err = arity(r#""a" "b" <REAL:3> `SUM_FEATURES`"#).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Operator sum_features expects 4 arguments, got 3"
+        );
+        assert_eq!((err.position, err.token.as_str()), (18, "`SUM_FEATURES`"));
+        assert!(arity(r#""a" "b" `SUM_FEATURES`"#).is_err());
+        assert!(arity(r#""a" "b" <REAL:1.5> `SUM_FEATURES`"#).is_err());
+    }
+
+    #[test]
+    fn test_quantize_bins_must_be_positive_integer() {
+        let quantize_with = |bins: f32| {
+            type_check(&[
+                Node::Feature(feature("a", 1)),
+                Node::Constant(Constant {
+                    value: bins,
+                    node_type: NodeType::Real,
+                }),
+                Node::Operator(Operators::Quantize),
+            ])
+        };
+        assert!(quantize_with(3.0).is_ok());
+        assert!(quantize_with(0.0).is_err());
+        assert!(quantize_with(2.5).is_err());
+        assert!(quantize_with(MAX_QUANTIZE_BINS).is_ok());
+        let err = quantize_with(1e9).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Quantize can have at most 1000 bins, got 1000000000"
+        );
+        let by_feature = type_check(&[
+            Node::Feature(feature("a", 1)),
+            Node::Feature(feature("a", 1)),
+            Node::Operator(Operators::Quantize),
+        ]);
+        assert!(by_feature.is_err());
+    }
+
+    #[test]
+    fn test_clamp_bounds_out_of_order() {
+        let nodes = vec![
+            Node::Feature(Feature {
+                id: 0,
+                code: "a".to_string(),
+                name: "a".to_string(),
+                node_type: NodeType::Real,
+                sample_size: 0,
+                cohort_id: 0,
+            }),
+            Node::Constant(Constant {
+                value: 200.0,
+                node_type: NodeType::Real,
+            }),
+            Node::Constant(Constant {
+                value: 0.0,
+                node_type: NodeType::Real,
+            }),
+            Node::Operator(Operators::Clamp),
+        ];
+        assert!(type_check(&nodes).is_err());
+    }
+
+    #[test]
+    fn test_type_error_positions() {
+        let kb = KnowledgeBase::new(vec![feature("a", 1), feature("b", 1)]);
+        let error = |definition: &str, syntax: DefinitionSyntax| {
+            let err = validate_phenotype_definition(1, definition, syntax, &kb, &[]).unwrap_err();
+            let err = err.downcast::<PhenotypeError>().unwrap();
+            (err.position, err.token, err.message)
+        };
+        assert_eq!(
+            error(r#""a" "b" `ADD` `NOT`"#, DefinitionSyntax::Postfix),
+            (
+                15,
+                "`NOT`".to_string(),
+                "Type mismatch: expected BOOL, got REAL".to_string()
+            )
+        );
+        assert_eq!(
+            error("a + clamp(b, 2, 1)", DefinitionSyntax::Infix),
+            (
+                5,
+                "clamp".to_string(),
+                "Clamp lower bound 2 is greater than upper bound 1".to_string()
+            )
+        );
+        let (position, token, _) = error("a & (b > 1)", DefinitionSyntax::Infix);
+        assert_eq!((position, token.as_str()), (3, "&"));
+    }
 }
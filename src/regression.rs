@@ -56,6 +56,24 @@ pub fn compute_weighted_ridge_pseudoinverse(
         * w_sqrt.column_vector_as_diagonal()
 }
 
+/// Ridge penalty of the weighted ridge pseudoinverse that cohorts' left inverses are
+/// computed with, so fits made at request time use the same estimator
+pub const RIDGE_LAMBDA: f32 = 1.0;
+
+/// Regress with the weighted ridge pseudoinverse of `exog` (see
+/// `compute_weighted_ridge_pseudoinverse`)
+pub fn regress_weighted_ridge_vec(
+    endog: &Col<f32>,
+    exog: &Mat<f32>,
+    weights: &Col<f32>,
+    lambda: f32,
+) -> Col<f32> {
+    regress_left_inverse_vec(
+        endog,
+        &compute_weighted_ridge_pseudoinverse(exog, weights, lambda),
+    )
+}
+
 pub fn regress_left_inverse_vec(endog: &Col<f32>, exog_left_inverse: &Mat<f32>) -> Col<f32> {
     exog_left_inverse * endog
 }
@@ -76,6 +94,144 @@ pub fn regress_mat(endog: &Mat<f32>, exog: &Mat<f32>) -> Result<Mat<f32>> {
     Ok(result)
 }
 
+/// Drop the rows where `endog` is missing (NaN) from both `endog` and `exog`
+pub fn drop_missing_rows(endog: &Col<f32>, exog: &Mat<f32>) -> (Col<f32>, Mat<f32>) {
+    let rows = (0..endog.nrows())
+        .filter(|&i| !endog.read(i).is_nan())
+        .collect::<Vec<usize>>();
+    let endog = Col::from_fn(rows.len(), |i| endog.read(rows[i]));
+    let exog = Mat::from_fn(rows.len(), exog.ncols(), |i, j| exog.read(rows[i], j));
+    (endog, exog)
+}
+
+/// Replace missing (NaN) values with the mean of the non-missing ones. Values are left
+/// missing if every one is.
+pub fn mean_impute(x: &mut [f32]) {
+    let present = x.iter().filter(|value| !value.is_nan());
+    let n = present.clone().count();
+    let mean = present.sum::<f32>() / n as f32;
+    x.iter_mut()
+        .filter(|value| value.is_nan())
+        .for_each(|value| *value = mean);
+}
+
+/// Regress `endog` on z-score standardized columns of `exog` plus an intercept with the
+/// weighted ridge pseudoinverse, then map the coefficients back to the original scale of
+/// `exog`. The ridge penalty depends on the scale of each column, so standardizing
+/// shrinks every feature equally and improves the pseudoinverse's conditioning. Means and
+/// standard deviations are weighted like the fit, since each row stands for `weights`
+/// samples.
+///
+/// With column means m_j and standard deviations s_j, the fit on standardized columns is
+/// y = g_0 + sum_j g_j (x_j - m_j) / s_j, which rearranges to
+/// y = (g_0 - sum_j g_j m_j / s_j) + sum_j (g_j / s_j) x_j.
+/// The original-scale coefficients are b_j = g_j / s_j, with intercept g_0 - sum_j b_j m_j.
+/// Since the returned coefficients are on the original scale, variances computed from the
+/// unstandardized covariance matrix (b' C b) need no further adjustment. Zero-variance
+/// columns are only centered (s_j = 1), so they contribute nothing to the fit.
+pub fn regress_standardized_vec(
+    endog: &Col<f32>,
+    exog: &Mat<f32>,
+    weights: &Col<f32>,
+    lambda: f32,
+) -> (Col<f32>, f32) {
+    let total_weight = weights.sum();
+    let weighted_mean = |values: &mut dyn Iterator<Item = f32>| {
+        values.zip(weights.iter()).map(|(x, w)| x * w).sum::<f32>() / total_weight
+    };
+    let mut standardized = exog.clone();
+    let mut means = Vec::with_capacity(exog.ncols());
+    let mut stds = Vec::with_capacity(exog.ncols());
+    for j in 0..exog.ncols() {
+        let mean = weighted_mean(&mut exog.col(j).iter().copied());
+        let variance = weighted_mean(&mut exog.col(j).iter().map(|x| (x - mean).powi(2)));
+        let std = if variance > 0.0 { variance.sqrt() } else { 1.0 };
+        standardized
+            .col_mut(j)
+            .iter_mut()
+            .for_each(|x| *x = (*x - mean) / std);
+        means.push(mean);
+        stds.push(std);
+    }
+    add_intercept(&mut standardized);
+    let gamma = regress_weighted_ridge_vec(endog, &standardized, weights, lambda);
+    let mut beta = Col::zeros(exog.ncols());
+    let mut intercept = gamma.read(exog.ncols());
+    for (j, (mean, std)) in means.iter().zip(stds.iter()).enumerate() {
+        let coefficient = gamma.read(j) / std;
+        beta.write(j, coefficient);
+        intercept -= coefficient * mean;
+    }
+    (beta, intercept)
+}
+
+/// Coefficient of determination of a fit. Samples excluded from the phenotype (NaN) don't
+/// count towards the fit, and a constant phenotype gives a non-finite value.
+pub fn compute_rsquared(observed: &Col<f32>, predicted: &Col<f32>) -> f32 {
+    let included = observed
+        .iter()
+        .zip(predicted.iter())
+        .filter(|(y, _)| !y.is_nan())
+        .collect::<Vec<(&f32, &f32)>>();
+    let rss = included
+        .iter()
+        .map(|(y, y_pred)| (*y_pred - *y).powi(2))
+        .sum::<f32>();
+    let mean = included.iter().map(|(y, _)| *y).sum::<f32>() / included.len() as f32;
+    let tss = included
+        .iter()
+        .map(|(y, _)| (*y - mean).powi(2))
+        .sum::<f32>();
+    1.0 - (rss / tss)
+}
+
+/// Cosine of the angle between two vectors, or `None` if either is zero, since a zero
+/// vector has no direction
+pub fn cosine_similarity(a: &Col<f32>, b: &Col<f32>) -> Option<f32> {
+    let norms = a.norm_l2() * b.norm_l2();
+    if norms == 0.0 {
+        return None;
+    }
+    Some((a.transpose() * b) / norms)
+}
+
+/// Fraction of nonzero coefficients below which `projection_variance` only reads their
+/// rows and columns. Above it, the scalar loop is slower than the dense product (see
+/// `bench_projection_variance`).
+const SPARSE_PROJECTION_DENSITY: f32 = 0.25;
+
+/// Variance of a projection, `beta' * covariance * beta`. Features with a zero coefficient
+/// contribute nothing, so for sparse projections (e.g. a single feature or a feature
+/// subset) only the rows and columns of the others are read, which is far less than the
+/// whole matrix. Denser projections take a single matrix-vector product.
+pub fn projection_variance(beta: &Col<f32>, covariance: &Mat<f32>) -> f32 {
+    let nonzero = (0..beta.nrows())
+        .filter(|&i| beta.read(i) != 0.0)
+        .collect::<Vec<usize>>();
+    if nonzero.len() as f32 >= SPARSE_PROJECTION_DENSITY * beta.nrows() as f32 {
+        return dense_projection_variance(beta, covariance);
+    }
+    sparse_projection_variance(beta, covariance, &nonzero)
+}
+
+fn dense_projection_variance(beta: &Col<f32>, covariance: &Mat<f32>) -> f32 {
+    beta.transpose() * (covariance * beta)
+}
+
+/// Projection variance reading only the rows and columns of the `nonzero` coefficients
+fn sparse_projection_variance(beta: &Col<f32>, covariance: &Mat<f32>, nonzero: &[usize]) -> f32 {
+    nonzero
+        .iter()
+        .map(|&i| {
+            let row = nonzero
+                .iter()
+                .map(|&j| covariance.read(i, j) * beta.read(j))
+                .sum::<f32>();
+            beta.read(i) * row
+        })
+        .sum()
+}
+
 pub fn compute_covariance(x: &Mat<f32>, ddof: usize) -> Mat<f32> {
     // Normalize each column to mean zero
     let mut x_norm = x.clone();
@@ -125,6 +281,129 @@ mod tests {
         assert!((result - expected).squared_norm_l2() < 1e-6);
     }
 
+    #[test]
+    fn test_drop_missing_rows() {
+        let x = mat![[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]];
+        let y = col![1.0, f32::NAN, 3.0];
+        let (y, x) = drop_missing_rows(&y, &x);
+        assert_eq!(y, col![1.0f32, 3.0]);
+        assert_eq!(x, mat![[1.0f32, 2.0], [5.0, 6.0]]);
+    }
+
+    #[test]
+    fn test_rsquared() {
+        let y: Col<f32> = col![1.0, 2.0, f32::NAN, 3.0];
+        let perfect: Col<f32> = col![1.0, 2.0, 10.0, 3.0];
+        assert_eq!(compute_rsquared(&y, &perfect), 1.0);
+        let mean: Col<f32> = col![2.0, 2.0, 2.0, 2.0];
+        assert_eq!(compute_rsquared(&y, &mean), 0.0);
+        let constant: Col<f32> = col![1.0, 1.0, 1.0, 1.0];
+        assert!(compute_rsquared(&constant, &constant).is_nan());
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        let a: Col<f32> = col![1.0, 0.0, 0.0];
+        assert_eq!(cosine_similarity(&a, &col![2.0, 0.0, 0.0]), Some(1.0));
+        assert_eq!(cosine_similarity(&a, &col![0.0, 3.0, 0.0]), Some(0.0));
+        assert_eq!(cosine_similarity(&a, &col![-1.0, 0.0, 0.0]), Some(-1.0));
+        assert_eq!(cosine_similarity(&a, &Col::zeros(3)), None);
+    }
+
+    /// A symmetric positive definite matrix and a projection with every `sparsity`-th
+    /// coefficient nonzero
+    fn projection_fixture(n: usize, sparsity: usize) -> (Col<f32>, Mat<f32>) {
+        let x = Mat::from_fn(n + 10, n, |i, j| ((i * 7 + j * 13) % 17) as f32 - 8.0);
+        let covariance = compute_covariance(&x, 1);
+        let beta = Col::from_fn(n, |i| match i % sparsity {
+            0 => (i % 5) as f32 - 1.5,
+            _ => 0.0,
+        });
+        (beta, covariance)
+    }
+
+    #[test]
+    fn test_projection_variance() {
+        // Dense, just above and below the density threshold, and very sparse
+        for sparsity in [1, 3, 5, 50] {
+            let (beta, covariance) = projection_fixture(50, sparsity);
+            let expected = beta.transpose() * &covariance * &beta;
+            let variance = projection_variance(&beta, &covariance);
+            assert!((variance - expected).abs() <= 1e-4 * expected.abs());
+        }
+        assert_eq!(projection_variance(&Col::zeros(3), &Mat::zeros(3, 3)), 0.0);
+    }
+
+    /// Compare the dense product and the sparse loop on a large matrix across densities,
+    /// to show where the sparse loop stops paying off. Run with
+    /// `cargo test --release bench_projection_variance -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_projection_variance() {
+        let n_repeats = 20;
+        for sparsity in [1, 2, 3, 4, 5, 10, 1000] {
+            let (beta, covariance) = projection_fixture(4000, sparsity);
+            let nonzero = (0..beta.nrows())
+                .filter(|&i| beta.read(i) != 0.0)
+                .collect::<Vec<usize>>();
+            let start = std::time::Instant::now();
+            for _ in 0..n_repeats {
+                std::hint::black_box(dense_projection_variance(&beta, &covariance));
+            }
+            let dense = start.elapsed() / n_repeats;
+            let start = std::time::Instant::now();
+            for _ in 0..n_repeats {
+                std::hint::black_box(sparse_projection_variance(&beta, &covariance, &nonzero));
+            }
+            let sparse = start.elapsed() / n_repeats;
+            println!(
+                "{:.1}% nonzero: dense {:?}, sparse {:?}",
+                100.0 * nonzero.len() as f32 / beta.nrows() as f32,
+                dense,
+                sparse
+            );
+        }
+    }
+
+    #[test]
+    fn test_regress_standardized() {
+        let x = mat![
+            [1.0, 20.0, -1.0],
+            [1.5, 33.0, -0.5],
+            [3.1, 7.0, 2.2],
+            [0.0, 3.0, -2.0],
+            [2.1, 10.0, 4.3],
+            [0.0, 55.0, 3.8]
+        ];
+        let y = col![0.0, 1.0, 5.3, -2.0, 6.3, 3.8];
+        let weights = col![1.0, 2.0, 1.0, 3.0, 1.0, 1.5];
+        // Without a penalty, weighted least squares is invariant to rescaling, so this
+        // matches the direct fit
+        let (beta, intercept) = regress_standardized_vec(&y, &x, &weights, 0.0);
+        let mut x_intercept = x.clone();
+        add_intercept(&mut x_intercept);
+        let expected = regress_weighted_ridge_vec(&y, &x_intercept, &weights, 0.0);
+        assert!((beta - expected.subrows(0, 3)).squared_norm_l2() < 1e-6);
+        assert!((intercept - expected.read(3)).abs() < 1e-3);
+
+        // The ridge penalty depends on scale, so the direct fit changes when a column is
+        // rescaled, but the standardized one only rescales that column's coefficient
+        let mut rescaled = x.clone();
+        rescaled.col_mut(1).iter_mut().for_each(|x| *x /= 100.0);
+        let (beta, intercept) = regress_standardized_vec(&y, &x, &weights, RIDGE_LAMBDA);
+        let (rescaled_beta, rescaled_intercept) =
+            regress_standardized_vec(&y, &rescaled, &weights, RIDGE_LAMBDA);
+        assert!((rescaled_beta.read(1) / 100.0 - beta.read(1)).abs() < 1e-5);
+        assert!((rescaled_beta.read(0) - beta.read(0)).abs() < 1e-4);
+        assert!((rescaled_intercept - intercept).abs() < 1e-3);
+        let mut rescaled_intercept = rescaled.clone();
+        add_intercept(&mut rescaled_intercept);
+        let direct = regress_weighted_ridge_vec(&y, &x_intercept, &weights, RIDGE_LAMBDA);
+        let direct_rescaled =
+            regress_weighted_ridge_vec(&y, &rescaled_intercept, &weights, RIDGE_LAMBDA);
+        assert!((direct_rescaled.read(1) / (100.0 * direct.read(1)) - 1.0).abs() > 0.1);
+    }
+
     #[test]
     fn test_covariance() {
         let x = mat![
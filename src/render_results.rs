@@ -3,9 +3,19 @@ use itertools::izip;
 use polars::prelude::*;
 use std::{path::PathBuf, sync::Arc};
 
-use crate::models::{ChromosomePosition, Pvalue, PvaluesResult};
+use crate::models::{ChromosomePosition, NodeType, Pvalue, PvaluesResult};
 
 fn read_pvalue_df(path: PathBuf) -> Result<DataFrame> {
+    if path.extension().and_then(|x| x.to_str()) == Some("arrow") {
+        let df = LazyFrame::scan_ipc(&path, ScanArgsIpc::default())?
+            .with_columns([
+                col("neg_log_p_value").cast(DataType::Float32),
+                col("chromosome").cast(DataType::String),
+                col("position").cast(DataType::Int32),
+            ])
+            .collect()?;
+        return Ok(df);
+    }
     let schema_override = Schema::from_iter(vec![
         ("neg_log_p_value".into(), DataType::Float32),
         ("chromosome".into(), DataType::String),
@@ -121,10 +131,66 @@ pub fn load_pvalues(path: PathBuf, min_neg_log_p: Option<f32>) -> Result<Pvalues
     })
 }
 
+/// Bin counts of feature values, with NaN values counted as missing rather than binned.
+/// Boolean features get false and true counts instead of bins.
+pub fn compute_histogram(
+    values: &[f32],
+    node_type: NodeType,
+    n_bins: usize,
+) -> (Option<Vec<f32>>, Vec<usize>, usize) {
+    let present = values
+        .iter()
+        .copied()
+        .filter(|x| !x.is_nan())
+        .collect::<Vec<f32>>();
+    let n_missing = values.len() - present.len();
+    if node_type == NodeType::Bool {
+        let n_true = present.iter().filter(|x| **x != 0.0).count();
+        return (None, vec![present.len() - n_true, n_true], n_missing);
+    }
+    if present.is_empty() {
+        return (Some(Vec::new()), Vec::new(), n_missing);
+    }
+    let min = present.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = present.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if min == max {
+        return (Some(vec![min, max]), vec![present.len()], n_missing);
+    }
+    let n_bins = n_bins.max(1);
+    let width = (max - min) / n_bins as f32;
+    let bin_edges = (0..=n_bins)
+        .map(|i| min + width * i as f32)
+        .collect::<Vec<f32>>();
+    let mut counts = vec![0; n_bins];
+    for x in present {
+        let bin = (((x - min) / width).floor() as usize).min(n_bins - 1);
+        counts[bin] += 1;
+    }
+    (Some(bin_edges), counts, n_missing)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_compute_histogram_real() {
+        let values = [0.0, 1.0, 2.0, 3.0, 4.0, f32::NAN];
+        let (bin_edges, counts, n_missing) = compute_histogram(&values, NodeType::Real, 2);
+        assert_eq!(bin_edges, Some(vec![0.0, 2.0, 4.0]));
+        assert_eq!(counts, vec![2, 3]);
+        assert_eq!(n_missing, 1);
+    }
+
+    #[test]
+    fn test_compute_histogram_bool() {
+        let values = [0.0, 1.0, 1.0, f32::NAN];
+        let (bin_edges, counts, n_missing) = compute_histogram(&values, NodeType::Bool, 50);
+        assert_eq!(bin_edges, None);
+        assert_eq!(counts, vec![1, 2]);
+        assert_eq!(n_missing, 1);
+    }
+
     #[test]
     fn test_chrom_to_index() {
         assert_eq!(chrom_to_index("X"), 22);
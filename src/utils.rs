@@ -1,7 +1,14 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use faer::Col;
 use num::cast::AsPrimitive;
 use polars::series::Series;
+use rand::{rngs::StdRng, SeedableRng};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::future::Future;
+use std::path::Path;
+use tokio::runtime::{Handle, RuntimeFlavor};
 
 /// Get everything up to and including the item
 pub fn slice_before<T: PartialEq + Clone>(vec: &[T], item: &T) -> Vec<T> {
@@ -46,3 +53,146 @@ pub fn vec_to_col<T: AsPrimitive<f32>>(vec: &[T]) -> Col<f32> {
     }
     result
 }
+
+/// Compute the hex-encoded SHA-256 of some bytes
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Compute the hex-encoded SHA-256 of a file, reading it in chunks rather than all at once
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Read an optional TOML file, which gives the type's defaults when it doesn't exist
+pub fn load_optional_toml<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let contents = std::fs::read_to_string(path)?;
+    toml::from_str(&contents).context(anyhow!("Invalid file {}", path.display()))
+}
+
+/// Randomly choose `n_samples` of `n_rows` row indices, returned in increasing order.
+/// All rows are returned when `n_samples` is at least `n_rows`.
+pub fn subsample_indices(n_rows: usize, n_samples: usize, seed: u64) -> Vec<usize> {
+    if n_samples >= n_rows {
+        return (0..n_rows).collect();
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut indices = rand::seq::index::sample(&mut rng, n_rows, n_samples).into_vec();
+    indices.sort_unstable();
+    indices
+}
+
+/// Run a future to completion on `runtime` from synchronous code, such as a worker thread.
+/// Blocking a thread that's driving async tasks would panic, so inside a multi-threaded
+/// runtime the thread is handed over with `block_in_place` first. A current-thread runtime
+/// can't hand its only thread over, so the future is run on a separate thread instead.
+/// That deadlocks when `runtime` is itself the caller's current-thread runtime and the
+/// future needs its IO or timers, since the only thread that could drive them is waiting.
+pub fn block_on<F>(runtime: &Handle, future: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    match Handle::try_current().map(|current| current.runtime_flavor()) {
+        Ok(RuntimeFlavor::CurrentThread) => std::thread::scope(|scope| {
+            scope
+                .spawn(|| runtime.block_on(future))
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        }),
+        Ok(_) => tokio::task::block_in_place(|| runtime.block_on(future)),
+        Err(_) => runtime.block_on(future),
+    }
+}
+
+/// Longest label kept by `sanitize_label`
+pub const MAX_LABEL_LENGTH: usize = 64;
+
+/// Make a label safe to use in a file name. Runs of anything other than ASCII letters,
+/// digits, `-` and `_` (including path separators and spaces) become a single `_`, and the
+/// result is truncated to `MAX_LABEL_LENGTH`. Returns `None` if nothing usable is left.
+pub fn sanitize_label(label: &str) -> Option<String> {
+    let mut sanitized = String::new();
+    for c in label.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+            sanitized.push(c);
+        } else if !sanitized.ends_with('_') {
+            sanitized.push('_');
+        }
+    }
+    let sanitized = sanitized
+        .trim_matches('_')
+        .chars()
+        .take(MAX_LABEL_LENGTH)
+        .collect::<String>();
+    let sanitized = sanitized.trim_end_matches('_');
+    match sanitized.is_empty() {
+        true => None,
+        false => Some(sanitized.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_on() {
+        let worker_runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let handle = worker_runtime.handle().clone();
+        let run = |handle: &Handle| {
+            block_on(handle, async {
+                tokio::task::yield_now().await;
+                1
+            })
+        };
+        // From threads outside any runtime, as the workers are
+        assert_eq!(run(&handle), 1);
+        let from_thread = std::thread::scope(|scope| scope.spawn(|| run(&handle)).join().unwrap());
+        assert_eq!(from_thread, 1);
+        // From inside the runtime itself and inside runtimes of both flavors, which would
+        // panic with `Handle::block_on` alone
+        assert_eq!(worker_runtime.block_on(async { run(&handle) }), 1);
+        let current_thread = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        assert_eq!(current_thread.block_on(async { run(&handle) }), 1);
+        let multi_thread = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        assert_eq!(multi_thread.block_on(async { run(&handle) }), 1);
+    }
+
+    #[test]
+    fn test_sanitize_label() {
+        assert_eq!(
+            sanitize_label("../my phenotype/v2").as_deref(),
+            Some("my_phenotype_v2")
+        );
+        assert_eq!(sanitize_label(" / "), None);
+        assert_eq!(
+            sanitize_label(&"a".repeat(100)).unwrap().len(),
+            MAX_LABEL_LENGTH
+        );
+    }
+
+    #[test]
+    fn test_subsample_indices() {
+        let indices = subsample_indices(100, 10, 0);
+        assert_eq!(indices.len(), 10);
+        assert!(indices.windows(2).all(|x| x[0] < x[1]));
+        assert_eq!(indices, subsample_indices(100, 10, 0));
+        assert_eq!(subsample_indices(5, 10, 0), vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -1,172 +1,667 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use aws_sdk_s3::presigning::PresigningConfig;
-use faer::Col;
+use faer::{Col, Mat};
+use itertools::Itertools;
 use log::info;
+use serde::Serialize;
+use std::fmt::Display;
 use std::fs::File;
 use std::io::{BufReader, Seek, Write};
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::thread;
+use std::time::Instant;
 use tokio::time::Duration;
 use tracing::info_span;
+use uuid::Uuid;
 use zip::write::SimpleFileOptions;
-use zip::CompressionMethod;
-
-use crate::igwas::{run_igwas_df_impl, Projection};
-use crate::models::{CohortData, Node, RequestMetadata};
-use crate::phenotype_definitions::format_phenotype_definition;
-use crate::regression::regress_left_inverse_vec;
-use crate::utils::vec_to_col;
-use crate::AppState;
+
+use crate::audit::AuditEntry;
+use crate::igwas::{run_igwas_df_impl, IgwasSummary, Projection, ResultsOutput};
+use crate::models::{
+    CohortData, MissingPhenotypeSummary, MissingPolicy, Node, ProjectionOptions, RequestMetadata,
+    ResultDestination,
+};
+use crate::regression::{
+    add_intercept, drop_missing_rows, mean_impute, projection_variance, regress_left_inverse_vec,
+    regress_standardized_vec, regress_weighted_ridge_vec, RIDGE_LAMBDA,
+};
+use crate::utils::{block_on, sanitize_label, sha256_file, vec_to_col};
+use crate::{ensure_results_directory, AppState, CachedProjection};
 use crate::{
     models::{WebGWASRequestId, WebGWASResultStatus},
-    phenotype_definitions::apply_phenotype_definition,
+    phenotype_definitions::{
+        apply_phenotype_definition, format_phenotype_definition, resolve_feature_index,
+    },
 };
 
 pub fn worker_loop(state: Arc<AppState>) {
     loop {
-        let task = {
-            let mut queue = state.queue.lock().unwrap();
-            queue.pop()
-        };
-        if let Some(request) = task {
-            let _span = info_span!("main_worker_loop", request_id = %request.id,
-            )
-            .entered();
-            let result = handle_webgwas_request(state.clone(), request);
-            if let Err(err) = result {
-                info!("Failed to handle request: {}", err);
-            }
-        } else {
-            thread::sleep(Duration::from_millis(10));
+        let request = state.queue.pop();
+        let request_id = request.id;
+        let _span = info_span!(
+            "main_worker_loop",
+            request_id = %request_id,
+            cohort_id = request.cohort_id
+        )
+        .entered();
+        let start = Instant::now();
+        let result = handle_webgwas_request(state.clone(), request);
+        state.audit_log.record(AuditEntry::finished(
+            request_id,
+            result.as_ref().err().map(|err| err.to_string()),
+        ));
+        match result {
+            Ok(()) => state.request_durations.record(start.elapsed()),
+            Err(err) => info!("Failed to handle request: {}", err),
         }
+        state.queue.finish(&request_id);
     }
 }
 
 pub fn handle_webgwas_request(state: Arc<AppState>, request: WebGWASRequestId) -> Result<()> {
+    if let Some(result) = state.results.lock().unwrap().get_mut(&request.id) {
+        result.timestamps.started_at = Some(chrono::Utc::now());
+    }
     // 0. Load the cohort info (relevant data for this request)
-    let cohort_info = {
-        let binding = state.cohort_id_to_data.lock().unwrap();
-        binding
-            .get(&request.cohort_id)
-            .context(format!(
-                "Failed to get cohort info for {}",
-                request.cohort_id
-            ))?
-            .clone()
-    };
+    let cohort_info = state.cohort_data(request.cohort_id)?.context(format!(
+        "Failed to get cohort info for {}",
+        request.cohort_id
+    ))?;
 
-    // 1. Apply the phenotype and compute the projection coefficents
-    let projection_result = compute_projection(&request.phenotype_definition, &cohort_info);
-    let mut projection = match projection_result {
-        Ok(projection) => projection,
+    // 1. Apply the phenotype and compute the projection coefficents and variance
+    let projection_result = get_or_compute_projection(
+        &state,
+        request.cohort_id,
+        &request.phenotype_definition,
+        &request.projection_options,
+        &cohort_info,
+    );
+    let cached_projection = match projection_result {
+        Ok(cached_projection) => cached_projection,
         Err(err) => {
-            let mut results = state.results.lock().unwrap();
-            let result = results
-                .get_mut(&request.id)
-                .context("Failed to get result")?;
-            result.status = WebGWASResultStatus::Error;
-            result.error_msg = Some(format!("Failed to compute projection: {}", err));
+            record_failure(
+                &state,
+                &request,
+                format!("Failed to compute projection: {}", err),
+            )?;
+            return Err(err);
+        }
+    };
+    let n_covariates = match resolve_num_covariates(
+        request.num_covar,
+        cohort_info.num_covar,
+        cohort_info.features.nrows(),
+    ) {
+        Ok(n_covariates) => n_covariates,
+        Err(err) => {
+            record_failure(
+                &state,
+                &request,
+                format!("Invalid number of covariates: {}", err),
+            )?;
             return Err(err);
         }
     };
 
-    // 2. Compute the projection variance
-    let beta = &projection.feature_coefficient;
-    let projection_variance = beta.transpose() * &cohort_info.covariance_matrix * beta;
+    let mut projection = cached_projection.projection.clone();
+    let projection_variance = cached_projection.projection_variance;
 
-    // 3. Compute GWAS
-    let output_path = state
-        .root_directory
-        .join(format!("results/{}.tsv", request.id));
-    {
-        let _span = info_span!("run_igwas_df_impl").entered();
-        run_igwas_df_impl(
-            &cohort_info.gwas_df,
+    // 2. Compute GWAS
+    // The directory is created at startup, but recreate it in case it was removed since
+    let results_directory = match ensure_results_directory(&state.root_directory) {
+        Ok(results_directory) => results_directory,
+        Err(err) => {
+            record_failure(&state, &request, format!("{:#}", err))?;
+            return Err(err);
+        }
+    };
+    let output_path = results_directory.join(format!(
+        "{}.{}",
+        request.id,
+        request.output_format.extension()
+    ));
+    let plot_data_path = request
+        .plot_data
+        .then(|| results_directory.join(format!("{}_plot_data.parquet", request.id)));
+    let igwas_summary = {
+        // Wait for threads before entering the span, so it only times the computation
+        let threads = state.thread_budget.acquire(state.settings.igwas_threads);
+        let _span = info_span!("run_igwas_df_impl", n_threads = threads.n).entered();
+        let output = ResultsOutput {
+            path: &output_path,
+            n_threads: threads.n,
+            max_bytes: state.settings.max_result_bytes,
+            pvalue_adjustment: request.pvalue_adjustment,
+            p_threshold: request.p_threshold,
+            annotations: cohort_info.annotations.as_ref(),
+            plot_data_path: plot_data_path.as_deref(),
+        };
+        let igwas_result = run_igwas_df_impl(
+            &cohort_info.gwas,
             &mut projection,
             projection_variance,
-            cohort_info.cohort.num_covar.expect("Num_covar is missing") as usize,
-            &output_path,
-            16,
-        )?;
-    }
+            n_covariates,
+            &output,
+            |progress| {
+                let mut results = state.results.lock().unwrap();
+                if let Some(result) = results.get_mut(&request.id) {
+                    result.progress = Some(progress);
+                }
+            },
+        );
+        match igwas_result {
+            Ok(igwas_summary) => igwas_summary,
+            Err(err) => {
+                record_failure(&state, &request, format!("Failed to compute GWAS: {}", err))?;
+                return Err(err);
+            }
+        }
+    };
     {
         let mut results = state.results.lock().unwrap();
         let result = results
             .get_mut(&request.id)
             .context("Failed to get result")?;
         result.status = WebGWASResultStatus::Uploading;
+        result.timestamps.uploading_at = Some(chrono::Utc::now());
         result.local_result_file = Some(output_path.clone());
     }
 
-    let metadata_file = create_metadata_file(&state, &request)?;
-    let output_zip_path = create_output_zip(&output_path, &metadata_file)?;
+    let missing_summary = MissingPhenotypeSummary {
+        policy: request.projection_options.missing_policy,
+        n_missing: cached_projection.n_missing,
+    };
+    let delivered = match deliver_results(
+        &state,
+        &request,
+        &cohort_info,
+        &output_path,
+        plot_data_path.as_deref(),
+        &igwas_summary,
+        missing_summary,
+    ) {
+        Ok(delivered) => delivered,
+        Err(err) => {
+            record_failure(
+                &state,
+                &request,
+                format!("Failed to deliver results: {:#}", err),
+            )?;
+            return Err(err);
+        }
+    };
+    {
+        let mut results = state.results.lock().unwrap();
+        let result = results.get_mut(&request.id).context("Result not found")?;
+        result.status = WebGWASResultStatus::Done;
+        result.timestamps.completed_at = Some(chrono::Utc::now());
+        result.url = delivered.url;
+        result.s3_key = delivered.s3_key;
+        result.destination = delivered.destination;
+        result.content_length = delivered.content_length;
+        result.checksum = Some(delivered.checksum);
+        result.lambda_gc = igwas_summary.lambda_gc;
+    }
+    Ok(())
+}
+
+/// Where and how a request's result zip was delivered
+struct DeliveredResults {
+    url: Option<String>,
+    s3_key: Option<String>,
+    destination: Option<ResultDestination>,
+    content_length: Option<i64>,
+    checksum: String,
+}
+
+/// Zip a request's results with their metadata, checksum the zip, and upload it (unless
+/// this is a dry run), removing the local files that are no longer needed
+fn deliver_results(
+    state: &AppState,
+    request: &WebGWASRequestId,
+    cohort_info: &CohortData,
+    output_path: &Path,
+    plot_data_path: Option<&Path>,
+    igwas_summary: &IgwasSummary,
+    missing_summary: MissingPhenotypeSummary,
+) -> Result<DeliveredResults> {
+    let metadata_file =
+        create_metadata_file(state, request, output_path, igwas_summary, missing_summary)?;
+    let output_zip_path = create_output_zip(
+        output_path,
+        &metadata_file,
+        plot_data_path,
+        request.label.as_deref(),
+        state.settings.zip_file_options()?,
+    )?;
     std::fs::remove_file(metadata_file)?;
+    if let Some(plot_data_path) = plot_data_path {
+        std::fs::remove_file(plot_data_path)?;
+    }
+    let checksum = sha256_file(&output_zip_path).context("Failed to checksum result zip")?;
 
-    let url = if state.settings.dry_run {
+    let (url, s3_key, destination, content_length) = if state.settings.dry_run {
         info!("Dry run, skipping S3 upload");
-        None
+        (None, None, None, None)
     } else {
         let _span = info_span!("upload_and_get_url").entered();
-        let key = format!("{}/{}.zip", state.settings.s3_result_path, request.id);
-        let url = upload_and_get_url(&state, &output_zip_path, &key)?;
+        let key = state
+            .settings
+            .result_key(&cohort_info.cohort.normalized_name, &request.id);
+        let uploaded = match &request.destination_bucket {
+            Some(bucket) => {
+                let content_length = upload_to_destination(state, &output_zip_path, bucket, &key)?;
+                let destination = ResultDestination {
+                    bucket: bucket.clone(),
+                    key,
+                };
+                (None, None, Some(destination), content_length)
+            }
+            None => {
+                let (url, content_length) = upload_and_get_url(state, &output_zip_path, &key)?;
+                (Some(url), Some(key), None, content_length)
+            }
+        };
         std::fs::remove_file(output_zip_path)?;
-        Some(url)
+        uploaded
     };
-    {
-        let mut results = state.results.lock().unwrap();
-        let result = results.get_mut(&request.id).context("Result not found")?;
-        result.status = WebGWASResultStatus::Done;
-        result.url = url;
-    }
+    Ok(DeliveredResults {
+        url,
+        s3_key,
+        destination,
+        content_length,
+        checksum,
+    })
+}
+
+/// Mark a request as failed with the given message
+fn record_failure(state: &AppState, request: &WebGWASRequestId, message: String) -> Result<()> {
+    let mut results = state.results.lock().unwrap();
+    let result = results
+        .get_mut(&request.id)
+        .context("Failed to get result")?;
+    result.status = WebGWASResultStatus::Error;
+    result.timestamps.completed_at = Some(chrono::Utc::now());
+    result.error_msg = Some(message);
     Ok(())
 }
 
+/// Choose the number of covariates for a request, preferring the request's override to
+/// the cohort's value. Either must be non-negative and less than the cohort size.
+pub fn resolve_num_covariates(
+    request_num_covar: Option<i32>,
+    cohort_num_covar: Option<i32>,
+    cohort_size: usize,
+) -> Result<usize> {
+    let num_covar = request_num_covar
+        .or(cohort_num_covar)
+        .context("Cohort has no number of covariates and the request gave none")?;
+    if num_covar < 0 {
+        bail!(
+            "Number of covariates must be non-negative, got {}",
+            num_covar
+        );
+    }
+    if num_covar as usize >= cohort_size {
+        bail!(
+            "Number of covariates ({}) must be less than the sample size ({})",
+            num_covar,
+            cohort_size
+        );
+    }
+    Ok(num_covar as usize)
+}
+
+/// Fetch a projection from the cache, or compute, standardize, and cache it
+pub fn get_or_compute_projection(
+    state: &AppState,
+    cohort_id: i32,
+    phenotype_definition: &[Node],
+    options: &ProjectionOptions,
+    cohort_info: &CohortData,
+) -> Result<Arc<CachedProjection>> {
+    if let Some(cached) = state.projections.lock().unwrap().get(
+        cohort_id,
+        cohort_info.generation,
+        phenotype_definition,
+        options,
+    ) {
+        return Ok(cached);
+    }
+    let (mut projection, intercept, n_missing) =
+        compute_projection(phenotype_definition, options, cohort_info)?;
+    projection.standardize(&cohort_info.feature_names);
+    let beta = &projection.feature_coefficient;
+    let projection_variance = projection_variance(beta, &cohort_info.covariance_matrix);
+    let cached = Arc::new(CachedProjection {
+        projection,
+        intercept,
+        projection_variance,
+        n_missing,
+    });
+    state.projections.lock().unwrap().insert(
+        cohort_id,
+        cohort_info.generation,
+        phenotype_definition,
+        options,
+        cached.clone(),
+    );
+    Ok(cached)
+}
+
+/// Why a feature of a definition can't be projected
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExclusionReason {
+    /// The cohort's data has no column for the feature
+    NotInCohort,
+    /// The feature has the same value in every sample that has one, so as the whole
+    /// phenotype it has nothing to project
+    ZeroVariance,
+}
+
+impl Display for ExclusionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExclusionReason::NotInCohort => write!(f, "not in the cohort's data"),
+            ExclusionReason::ZeroVariance => write!(f, "zero variance"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ExcludedFeature {
+    pub code: String,
+    pub reason: ExclusionReason,
+}
+
+/// Every feature of a definition that can't be projected
+#[derive(Debug, PartialEq)]
+pub struct ExcludedFeatures(pub Vec<ExcludedFeature>);
+
+impl Display for ExcludedFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let features = self
+            .0
+            .iter()
+            .map(|feature| format!("{} ({})", feature.code, feature.reason))
+            .join(", ");
+        write!(f, "Features can't be projected: {}", features)
+    }
+}
+
+impl std::error::Error for ExcludedFeatures {}
+
+/// Check that every feature of a definition can be projected, listing all that can't
+/// rather than stopping at the first. A lone feature is the whole phenotype, so it also
+/// needs some variance, while in larger definitions a constant feature is harmless.
+pub fn check_definition_features(
+    definition: &[Node],
+    cohort_info: &CohortData,
+) -> Result<(), ExcludedFeatures> {
+    let mut excluded: Vec<ExcludedFeature> = Vec::new();
+    for node in definition {
+        let Node::Feature(feature) = node else {
+            continue;
+        };
+        if excluded.iter().any(|other| other.code == feature.code) {
+            continue;
+        }
+        let idx = resolve_feature_index(
+            &feature.code,
+            &cohort_info.feature_names,
+            &cohort_info.aliases,
+        );
+        let reason = match idx {
+            None => Some(ExclusionReason::NotInCohort),
+            Some(idx)
+                if definition.len() == 1 && !has_variance(cohort_info.features.col(idx).iter()) =>
+            {
+                Some(ExclusionReason::ZeroVariance)
+            }
+            Some(_) => None,
+        };
+        if let Some(reason) = reason {
+            excluded.push(ExcludedFeature {
+                code: feature.code.clone(),
+                reason,
+            });
+        }
+    }
+    match excluded.is_empty() {
+        true => Ok(()),
+        false => Err(ExcludedFeatures(excluded)),
+    }
+}
+
+/// Whether the non-missing values aren't all the same
+fn has_variance<'a>(values: impl Iterator<Item = &'a f32>) -> bool {
+    let mut values = values.filter(|x| !x.is_nan());
+    match values.next() {
+        Some(first) => values.any(|x| x != first),
+        None => false,
+    }
+}
+
+/// Compute the projection coefficients of a phenotype onto the cohort features, along with
+/// the intercept of the fit and the number of samples missing the phenotype. With
+/// `standardize_features`, the weighted ridge regression is fit on z-scored features and
+/// the coefficients are mapped back to the original scale.
+///
+/// Missing samples are handled by the `missing_policy`. Dropping them fits the projection
+/// on the complete cases. Mean imputation keeps every sample, so the precomputed left
+/// inverse can still be used, but it shrinks the phenotype's variance and so its
+/// coefficients towards zero.
+///
+/// With a `feature_subset`, only those features are used in the regression and every other
+/// feature gets a zero coefficient once the projection is standardized. The subset is fit
+/// with the same weighted ridge pseudoinverse as the cohort's left inverse. The phenotype
+/// is still computed from any feature, so the fit can be worse than with every feature. If
+/// the subset is rank-deficient (e.g. it includes collinear features), the weight is split
+/// between the collinear features, so their individual coefficients shouldn't be
+/// interpreted.
+///
+/// With `residualize_covariates`, the phenotype is replaced by its residuals from a
+/// regression on the cohort's covariates before it's projected, so the projection only
+/// captures the part of the phenotype the covariates don't explain.
 pub fn compute_projection(
     phenotype_definition: &[Node],
+    options: &ProjectionOptions,
     cohort_info: &CohortData,
-) -> Result<Projection> {
-    if phenotype_definition.len() == 1 {
+) -> Result<(Projection, f32, usize)> {
+    let residualize = cohort_info
+        .defaults
+        .residualize_covariates(options.residualize_covariates);
+    check_definition_features(phenotype_definition, cohort_info)?;
+    // A single feature projects onto itself, unless it's left out of the subset or its
+    // residuals are projected instead
+    if phenotype_definition.len() == 1 && options.feature_subset.is_none() && !residualize {
         match &phenotype_definition[0] {
             Node::Feature(feature) => {
+                let index = cohort_info.feature_indices(std::slice::from_ref(&feature.code))?[0];
                 let mut beta = Col::zeros(1);
                 beta[0] = 1.0;
-                let phenotype_names = vec![feature.code.clone()];
+                let phenotype_names = vec![cohort_info.feature_names[index].clone()];
                 let mut projection = Projection::new(phenotype_names, beta)?;
                 // Standardize to the full feature names
                 projection.standardize(&cohort_info.feature_names);
-                if !projection.feature_id.contains(&feature.code) {
-                    bail!("Feature {} not found after standardization", feature.code);
-                }
-                Ok(projection)
+                let n_missing = cohort_info
+                    .features
+                    .col(index)
+                    .iter()
+                    .filter(|x| x.is_nan())
+                    .count();
+                Ok((projection, 0.0, n_missing))
             }
             Node::Operator(operator) => {
                 bail!("Operator {} is not supported", operator.value().name);
             }
+            // A constant has no variance for the features to explain, so every coefficient
+            // is zero and the constant is all intercept. This gives a projection variance of
+            // zero, which is handy for debugging.
             Node::Constant(constant) => {
-                bail!("Constant {} is not supported", constant.value);
+                let n_features = cohort_info.feature_names.len();
+                let projection =
+                    Projection::new(cohort_info.feature_names.clone(), Col::zeros(n_features))?;
+                Ok((projection, constant.value, 0))
             }
         }
     } else {
-        let phenotype = apply_phenotype_definition(
+        let mut phenotype = apply_phenotype_definition(
             phenotype_definition,
             &cohort_info.feature_names,
             &cohort_info.features,
+            &cohort_info.aliases,
         )
         .context("Failed to apply phenotype definition")?;
+        let n_missing = phenotype.iter().filter(|x| x.is_nan()).count();
+        if options.missing_policy == MissingPolicy::MeanImpute {
+            mean_impute(&mut phenotype);
+        }
+        if residualize {
+            let _span = info_span!("residualize_covariates").entered();
+            residualize_phenotype(&mut phenotype, cohort_info)?;
+        }
         let phenotype_mat = vec_to_col(&phenotype);
-        let beta = {
+        let subset = match &options.feature_subset {
+            Some(codes) => Some(resolve_feature_subset(codes, cohort_info)?),
+            None => None,
+        };
+        let subset_features = subset
+            .as_ref()
+            .map(|indices| select_columns(&cohort_info.features, indices));
+        let features = subset_features.as_ref().unwrap_or(&cohort_info.features);
+        let feature_names = match &subset {
+            Some(indices) => indices
+                .iter()
+                .map(|&i| cohort_info.feature_names[i].clone())
+                .collect(),
+            None => cohort_info.feature_names.clone(),
+        };
+        // Samples excluded from the phenotype (NaN) can't be fit, so the projection is fit
+        // on the included samples only, with a fresh weighted ridge pseudoinverse like the
+        // cohort's precomputed one, which covers every sample. Exclusions only shrink the
+        // fit. The feature GWAS still cover the whole cohort, so reported sample sizes are
+        // unchanged.
+        let n_excluded = phenotype.iter().filter(|x| x.is_nan()).count();
+        let (beta, intercept) = if n_excluded > 0 {
+            let _span = info_span!("regress_included_samples", n_excluded).entered();
+            let weights = included_weights(&phenotype, &cohort_info.sample_weights);
+            let (phenotype_mat, features) = drop_missing_rows(&phenotype_mat, features);
+            if phenotype_mat.nrows() == 0 {
+                bail!("Every sample is excluded from the phenotype");
+            }
+            if options.standardize_features {
+                regress_standardized_vec(&phenotype_mat, &features, &weights, RIDGE_LAMBDA)
+            } else {
+                regress_weighted_with_intercept(&phenotype_mat, features, &weights)
+            }
+        } else if options.standardize_features {
+            let _span = info_span!("regress_standardized_vec").entered();
+            regress_standardized_vec(
+                &phenotype_mat,
+                features,
+                &cohort_info.sample_weights,
+                RIDGE_LAMBDA,
+            )
+        } else if subset.is_some() {
+            // The precomputed left inverse covers every feature, so the subset needs a
+            // fresh pseudoinverse
+            let _span = info_span!("regress_feature_subset").entered();
+            regress_weighted_with_intercept(
+                &phenotype_mat,
+                features.clone(),
+                &cohort_info.sample_weights,
+            )
+        } else {
             let _span = info_span!("regress_left_inverse_vec").entered();
-            let mut beta = regress_left_inverse_vec(&phenotype_mat, &cohort_info.left_inverse);
-            beta.truncate(beta.nrows() - 1); // Drop the last element (the intercept)
-            beta
+            split_intercept(regress_left_inverse_vec(
+                &phenotype_mat,
+                &cohort_info.left_inverse,
+            ))
         };
-        let projection = Projection::new(cohort_info.feature_names.clone(), beta)?;
-        Ok(projection)
+        let projection = Projection::new(feature_names, beta)?;
+        Ok((projection, intercept, n_missing))
     }
 }
 
+/// Replace a phenotype with its residuals from a regression on the cohort's covariates and
+/// an intercept, weighted and regularized like the projection. Missing samples can't be
+/// fit, so they're left out and stay missing.
+fn residualize_phenotype(phenotype: &mut [f32], cohort_info: &CohortData) -> Result<()> {
+    let covariates = cohort_info.covariates.as_ref().context(anyhow!(
+        "Cohort {} has no covariates to residualize on",
+        cohort_info.cohort.name
+    ))?;
+    let included = (0..phenotype.len())
+        .filter(|&i| !phenotype[i].is_nan())
+        .collect::<Vec<usize>>();
+    if included.is_empty() {
+        bail!("Every sample is excluded from the phenotype");
+    }
+    let endog = Col::from_fn(included.len(), |i| phenotype[included[i]]);
+    let exog = Mat::from_fn(included.len(), covariates.ncols(), |i, j| {
+        covariates.read(included[i], j)
+    });
+    let weights = included_weights(phenotype, &cohort_info.sample_weights);
+    let (beta, intercept) = regress_weighted_with_intercept(&endog, exog, &weights);
+    for i in included {
+        let fitted = (0..covariates.ncols())
+            .map(|j| covariates.read(i, j) * beta.read(j))
+            .sum::<f32>();
+        phenotype[i] -= fitted + intercept;
+    }
+    Ok(())
+}
+
+/// Column indices of a feature subset, without duplicates, which would make the
+/// regression rank-deficient
+fn resolve_feature_subset(codes: &[String], cohort_info: &CohortData) -> Result<Vec<usize>> {
+    let mut indices = cohort_info.feature_indices(codes)?;
+    let mut seen = std::collections::HashSet::new();
+    indices.retain(|index| seen.insert(*index));
+    if indices.is_empty() {
+        bail!("Feature subset is empty");
+    }
+    Ok(indices)
+}
+
+fn select_columns(x: &Mat<f32>, indices: &[usize]) -> Mat<f32> {
+    Mat::from_fn(x.nrows(), indices.len(), |i, j| x.read(i, indices[j]))
+}
+
+/// Regress on the given features plus an intercept with the weighted ridge pseudoinverse,
+/// the same estimator as the cohort's left inverse, returning both separately
+fn regress_weighted_with_intercept(
+    endog: &Col<f32>,
+    mut exog: Mat<f32>,
+    weights: &Col<f32>,
+) -> (Col<f32>, f32) {
+    add_intercept(&mut exog);
+    split_intercept(regress_weighted_ridge_vec(
+        endog,
+        &exog,
+        weights,
+        RIDGE_LAMBDA,
+    ))
+}
+
+/// Weights of the samples that aren't excluded from the phenotype (NaN)
+fn included_weights(phenotype: &[f32], weights: &Col<f32>) -> Col<f32> {
+    let included = (0..phenotype.len())
+        .filter(|&i| !phenotype[i].is_nan())
+        .collect::<Vec<usize>>();
+    Col::from_fn(included.len(), |i| weights.read(included[i]))
+}
+
+/// Split regression coefficients into the feature coefficients and the intercept (last)
+fn split_intercept(mut beta: Col<f32>) -> (Col<f32>, f32) {
+    let intercept = beta.read(beta.nrows() - 1);
+    beta.truncate(beta.nrows() - 1);
+    (beta, intercept)
+}
+
 pub async fn upload_object(
     client: &aws_sdk_s3::Client,
     file_name: &Path,
@@ -184,25 +679,62 @@ pub async fn upload_object(
     Ok(result)
 }
 
-pub fn upload_and_get_url(state: &AppState, output_zip_path: &Path, key: &str) -> Result<String> {
-    let rt = tokio::runtime::Runtime::new()?;
-    let url = rt.block_on(async { upload_and_get_url_async(state, output_zip_path, key).await })?;
-    Ok(url)
+/// Upload the result and return a presigned URL along with the object's size in bytes
+pub fn upload_and_get_url(
+    state: &AppState,
+    output_zip_path: &Path,
+    key: &str,
+) -> Result<(String, Option<i64>)> {
+    block_on(
+        &state.runtime,
+        upload_and_get_url_async(state, output_zip_path, key),
+    )
+}
+
+/// Upload the result to a requested destination bucket rather than the results bucket,
+/// returning the object's size in bytes. No URL is made, since the bucket's owner reads
+/// it with their own credentials.
+pub fn upload_to_destination(
+    state: &AppState,
+    output_zip_path: &Path,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<i64>> {
+    block_on(
+        &state.runtime,
+        upload_and_get_size(state, output_zip_path, bucket, key),
+    )
+}
+
+async fn upload_and_get_size(
+    state: &AppState,
+    output_zip_path: &Path,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<i64>> {
+    upload_object(&state.s3_client, output_zip_path, bucket, key)
+        .await
+        .context(anyhow!("Failed to upload object to {}", bucket))?;
+    let content_length = state
+        .s3_client
+        .head_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .context("Failed to get object metadata")?
+        .content_length();
+    Ok(content_length)
 }
 
 async fn upload_and_get_url_async(
     state: &AppState,
     output_zip_path: &Path,
     key: &str,
-) -> Result<String> {
-    upload_object(
-        &state.s3_client,
-        output_zip_path,
-        &state.settings.s3_bucket,
-        key,
-    )
-    .await
-    .context("Failed to upload object")?;
+) -> Result<(String, Option<i64>)> {
+    let content_length =
+        upload_and_get_size(state, output_zip_path, &state.settings.s3_bucket, key).await?;
+    // Presigned GET URLs do not sign the Range header, so clients can request byte ranges
     const URL_EXPIRES_IN: Duration = Duration::from_secs(3600);
     let url = state
         .s3_client
@@ -214,29 +746,33 @@ async fn upload_and_get_url_async(
         .context("Failed to get presigned URL")?
         .uri()
         .to_string();
-    Ok(url)
-}
-
-pub fn create_metadata_file(state: &AppState, request: &WebGWASRequestId) -> Result<PathBuf> {
-    let cohort_info = {
-        let binding = state.cohort_id_to_data.lock().unwrap();
-        binding
-            .get(&request.cohort_id)
-            .context(format!(
-                "Failed to get cohort info for {}",
-                request.cohort_id
-            ))?
-            .clone()
-    };
+    Ok((url, content_length))
+}
+
+pub fn create_metadata_file(
+    state: &AppState,
+    request: &WebGWASRequestId,
+    output_path: &Path,
+    igwas_summary: &IgwasSummary,
+    missing_summary: MissingPhenotypeSummary,
+) -> Result<PathBuf> {
+    let cohort_info = state.cohort_data(request.cohort_id)?.context(format!(
+        "Failed to get cohort info for {}",
+        request.cohort_id
+    ))?;
     let metadata = RequestMetadata::new(
         request.id,
-        format_phenotype_definition(&request.phenotype_definition),
+        &request.phenotype_definition,
         cohort_info.cohort.name.clone(),
         cohort_info.features.nrows(),
+        igwas_summary,
+        missing_summary,
+        cohort_info
+            .defaults
+            .residualize_covariates(request.projection_options.residualize_covariates),
+        sha256_file(output_path).context("Failed to checksum results file")?,
     );
-    let output_metadata_path = state
-        .root_directory
-        .join(format!("results/{}.txt", request.id));
+    let output_metadata_path = output_path.with_extension("txt");
     let mut metadata_file = File::create(output_metadata_path.clone())?;
     write!(metadata_file, "{}", metadata)?;
     Ok(output_metadata_path)
@@ -246,13 +782,11 @@ pub fn add_file_to_zip<W>(
     zip_writer: &mut zip::ZipWriter<W>,
     file_path: &Path,
     name_in_zip: &str,
+    options: SimpleFileOptions,
 ) -> zip::result::ZipResult<()>
 where
     W: Write + Seek,
 {
-    let options = SimpleFileOptions::default()
-        .compression_method(CompressionMethod::Deflated)
-        .unix_permissions(0o644);
     let file = File::open(file_path)?;
     let mut buffered_reader = BufReader::new(file);
     zip_writer.start_file(name_in_zip, options)?;
@@ -260,11 +794,406 @@ where
     Ok(())
 }
 
-pub fn create_output_zip(output_path: &Path, metadata_path: &Path) -> Result<PathBuf> {
+/// Names of the results and metadata files inside the zip, prefixed by the label if any.
+/// The results keep the extension of their format.
+pub fn zip_file_names(label: Option<&str>, results_extension: &str) -> (String, String) {
+    match label {
+        Some(label) => (
+            format!("{}_results.{}", label, results_extension),
+            format!("{}_metadata.txt", label),
+        ),
+        None => (
+            format!("results.{}", results_extension),
+            "metadata.txt".to_string(),
+        ),
+    }
+}
+
+/// Name of the plot data file inside the zip, prefixed by the label if any
+pub fn plot_data_file_name(label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{}_plot_data.parquet", label),
+        None => "plot_data.parquet".to_string(),
+    }
+}
+
+/// Name for a downloaded result zip, made from the phenotype definition when it gives a
+/// usable file name
+pub fn download_file_name(request_id: &Uuid, definition: Option<&[Node]>) -> String {
+    match definition
+        .map(format_phenotype_definition)
+        .as_deref()
+        .and_then(sanitize_label)
+    {
+        Some(phenotype) => format!("{}-{}.zip", phenotype, request_id),
+        None => format!("{}.zip", request_id),
+    }
+}
+
+/// Zip the results, metadata, and plot data if any. `label` must already be sanitized with
+/// `sanitize_label`.
+pub fn create_output_zip(
+    output_path: &Path,
+    metadata_path: &Path,
+    plot_data_path: Option<&Path>,
+    label: Option<&str>,
+    options: SimpleFileOptions,
+) -> Result<PathBuf> {
     let output_zip_path = output_path.with_extension("").with_extension("zip");
     let mut zip_writer = zip::ZipWriter::new(File::create(output_zip_path.clone())?);
-    add_file_to_zip(&mut zip_writer, output_path, "results.tsv")?;
-    add_file_to_zip(&mut zip_writer, metadata_path, "metadata.txt")?;
+    let results_extension = output_path
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or("tsv");
+    let (results_name, metadata_name) = zip_file_names(label, results_extension);
+    add_file_to_zip(&mut zip_writer, output_path, &results_name, options)?;
+    add_file_to_zip(&mut zip_writer, metadata_path, &metadata_name, options)?;
+    if let Some(plot_data_path) = plot_data_path {
+        add_file_to_zip(
+            &mut zip_writer,
+            plot_data_path,
+            &plot_data_file_name(label),
+            options,
+        )?;
+    }
     zip_writer.finish()?;
     Ok(output_zip_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::testing::{test_cohort_data, test_feature};
+
+    /// Cohort with six rows of features a, b, and c, which stand for different numbers of
+    /// samples as anonymized rows do, and the weighted ridge left inverse they're
+    /// registered with
+    fn weighted_cohort_data() -> CohortData {
+        let features = faer::mat![
+            [1.0, 2.0, -1.0],
+            [1.5, 3.3, -0.5],
+            [3.1, 0.7, 2.2],
+            [0.0, 0.3, -2.0],
+            [2.1, 1.0, 4.3],
+            [0.0, 5.5, 3.8]
+        ];
+        let mut features_with_intercept = features.clone();
+        add_intercept(&mut features_with_intercept);
+        let weights = faer::col![1.0, 2.0, 1.0, 3.0, 1.0, 1.5];
+        CohortData {
+            left_inverse: crate::regression::compute_weighted_ridge_pseudoinverse(
+                &features_with_intercept,
+                &weights,
+                RIDGE_LAMBDA,
+            ),
+            sample_weights: weights,
+            ..test_cohort_data(&["a", "b", "c"], features)
+        }
+    }
+
+    #[test]
+    fn test_zip_file_names() {
+        assert_eq!(
+            zip_file_names(None, "tsv"),
+            ("results.tsv".to_string(), "metadata.txt".to_string())
+        );
+        assert_eq!(zip_file_names(Some("bmi"), "arrow").0, "bmi_results.arrow");
+        assert_eq!(plot_data_file_name(None), "plot_data.parquet");
+        assert_eq!(plot_data_file_name(Some("bmi")), "bmi_plot_data.parquet");
+    }
+
+    #[test]
+    fn test_zip_compression_level() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("results.tsv");
+        let mut file = File::create(&path).unwrap();
+        let mut x: u64 = 1;
+        for i in 0..20_000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            writeln!(
+                file,
+                "1:{}:A:G\t{:.4}\t{}",
+                i,
+                (x >> 40) as f32 / 1e6,
+                x % 7
+            )
+            .unwrap();
+        }
+        let zip_size = |level: i64| {
+            let mut zip_writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+            let options = SimpleFileOptions::default().compression_level(Some(level));
+            add_file_to_zip(&mut zip_writer, &path, "results.tsv", options).unwrap();
+            zip_writer.finish().unwrap().into_inner().len()
+        };
+        assert!(zip_size(9) < zip_size(1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_download_file_name() {
+        let id = Uuid::nil();
+        let definition = vec![test_feature("bmi")];
+        assert_eq!(
+            download_file_name(&id, Some(&definition)),
+            format!("bmi_bmi-{}.zip", id)
+        );
+        assert_eq!(download_file_name(&id, None), format!("{}.zip", id));
+    }
+
+    #[test]
+    fn test_projection_feature_subset() {
+        let cohort_info = weighted_cohort_data();
+        let features = &cohort_info.features;
+        let definition = vec![
+            test_feature("a"),
+            test_feature("b"),
+            Node::Operator(crate::models::Operators::Add),
+        ];
+        let project = |subset: Option<&[&str]>| {
+            let options = ProjectionOptions {
+                feature_subset: subset.map(|codes| codes.iter().map(|x| x.to_string()).collect()),
+                ..Default::default()
+            };
+            let (mut projection, intercept, _) =
+                compute_projection(&definition, &options, &cohort_info).unwrap();
+            projection.standardize(&cohort_info.feature_names);
+            (projection.feature_coefficient, intercept)
+        };
+        // A subset of every feature is fit the same way as the full projection
+        let (full, full_intercept) = project(None);
+        let (every, every_intercept) = project(Some(&["c", "a", "b"]));
+        assert!((every - &full).norm_max() < 1e-4);
+        assert!((every_intercept - full_intercept).abs() < 1e-4);
+
+        // Duplicates are dropped, and features outside the subset get zero
+        let (subset, intercept) = project(Some(&["b", "a", "a"]));
+        let phenotype = Col::from_fn(6, |i| features.read(i, 0) + features.read(i, 1));
+        let (expected, expected_intercept) = regress_weighted_with_intercept(
+            &phenotype,
+            select_columns(features, &[1, 0]),
+            &cohort_info.sample_weights,
+        );
+        assert!((subset[0] - expected[1]).abs() < 1e-4);
+        assert!((subset[1] - expected[0]).abs() < 1e-4);
+        assert_eq!(subset[2], 0.0);
+        assert!((intercept - expected_intercept).abs() < 1e-4);
+        let definition = vec![test_feature("a")];
+        let options = ProjectionOptions {
+            feature_subset: Some(vec!["d".to_string()]),
+            ..Default::default()
+        };
+        assert!(compute_projection(&definition, &options, &cohort_info).is_err());
+    }
+
+    #[test]
+    fn test_check_definition_features() {
+        let cohort_info = test_cohort_data(
+            &["a", "b"],
+            faer::mat![[1.0, 2.0], [1.5, 2.0], [f32::NAN, 2.0]],
+        );
+        let excluded = |code: &str, reason| ExcludedFeature {
+            code: code.to_string(),
+            reason,
+        };
+        assert!(check_definition_features(&[test_feature("a")], &cohort_info).is_ok());
+        // Every missing feature is listed, each once
+        let definition = vec![
+            test_feature("x"),
+            test_feature("a"),
+            Node::Operator(crate::models::Operators::Add),
+            test_feature("y"),
+            Node::Operator(crate::models::Operators::Add),
+            test_feature("x"),
+            Node::Operator(crate::models::Operators::Add),
+        ];
+        let err = check_definition_features(&definition, &cohort_info).unwrap_err();
+        assert_eq!(
+            err.0,
+            vec![
+                excluded("x", ExclusionReason::NotInCohort),
+                excluded("y", ExclusionReason::NotInCohort)
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            "Features can't be projected: x (not in the cohort's data), y (not in the cohort's \
+            data)"
+        );
+        // A constant feature is only a problem when it's the whole phenotype
+        let err = check_definition_features(&[test_feature("b")], &cohort_info).unwrap_err();
+        assert_eq!(err.0, vec![excluded("b", ExclusionReason::ZeroVariance)]);
+        let definition = vec![
+            test_feature("a"),
+            test_feature("b"),
+            Node::Operator(crate::models::Operators::Add),
+        ];
+        assert!(check_definition_features(&definition, &cohort_info).is_ok());
+        let err = compute_projection(&[test_feature("b")], &Default::default(), &cohort_info)
+            .unwrap_err()
+            .downcast::<ExcludedFeatures>()
+            .unwrap();
+        assert_eq!(err.0[0].reason, ExclusionReason::ZeroVariance);
+    }
+
+    #[test]
+    fn test_projection_constant() {
+        let cohort_info = CohortData {
+            covariance_matrix: faer::mat![[1.0, 0.5], [0.5, 2.0]],
+            ..test_cohort_data(
+                &["a", "b"],
+                faer::mat![[1.0, 2.0], [1.5, 3.3], [3.1, 0.7], [0.0, 0.3]],
+            )
+        };
+        let definition = vec![Node::Constant(crate::models::Constant {
+            value: 3.0,
+            node_type: crate::models::NodeType::Real,
+        })];
+        let (mut projection, intercept, n_missing) =
+            compute_projection(&definition, &Default::default(), &cohort_info).unwrap();
+        projection.standardize(&cohort_info.feature_names);
+        assert_eq!(projection.feature_id, cohort_info.feature_names);
+        assert_eq!(projection.feature_coefficient, Col::<f32>::zeros(2));
+        assert_eq!(intercept, 3.0);
+        assert_eq!(n_missing, 0);
+        let beta = &projection.feature_coefficient;
+        let projection_variance = beta.transpose() * &cohort_info.covariance_matrix * beta;
+        assert_eq!(projection_variance, 0.0);
+
+        // Fitting a constant on a subset of the features is a ridge fit like any other,
+        // which shrinks the intercept too
+        let options = ProjectionOptions {
+            feature_subset: Some(vec!["b".to_string()]),
+            ..Default::default()
+        };
+        let (mut projection, intercept, _) =
+            compute_projection(&definition, &options, &cohort_info).unwrap();
+        projection.standardize(&cohort_info.feature_names);
+        let (expected, expected_intercept) = regress_weighted_with_intercept(
+            &Col::from_fn(4, |_| 3.0),
+            select_columns(&cohort_info.features, &[1]),
+            &cohort_info.sample_weights,
+        );
+        assert!((projection.feature_coefficient[1] - expected[0]).abs() < 1e-4);
+        assert!((intercept - expected_intercept).abs() < 1e-4);
+        assert!(intercept < 3.0);
+    }
+
+    #[test]
+    fn test_projection_missing_policy() {
+        let cohort_info = weighted_cohort_data();
+        let features = &cohort_info.features;
+        let constant = |value| {
+            Node::Constant(crate::models::Constant {
+                value,
+                node_type: crate::models::NodeType::Real,
+            })
+        };
+        // Cases have a > 2 and controls b > 1, which leaves the fourth sample as neither,
+        // so the phenotype is [0, 0, 1, NaN, 1, 0]
+        let definition = vec![
+            test_feature("a"),
+            constant(2.0),
+            Node::Operator(crate::models::Operators::Gt),
+            test_feature("b"),
+            constant(1.0),
+            Node::Operator(crate::models::Operators::Gt),
+            Node::Operator(crate::models::Operators::CaseControl),
+        ];
+        let project = |missing_policy| {
+            let options = ProjectionOptions {
+                missing_policy,
+                ..Default::default()
+            };
+            compute_projection(&definition, &options, &cohort_info).unwrap()
+        };
+        let (dropped, dropped_intercept, n_missing) = project(MissingPolicy::DropMissing);
+        assert_eq!(n_missing, 1);
+        let phenotype = faer::col![0.0, 0.0, 1.0, f32::NAN, 1.0, 0.0];
+        let (complete_phenotype, complete_features) = drop_missing_rows(&phenotype, features);
+        let complete_weights = faer::col![1.0, 2.0, 1.0, 1.0, 1.5];
+        let (expected, expected_intercept) = regress_weighted_with_intercept(
+            &complete_phenotype,
+            complete_features,
+            &complete_weights,
+        );
+        assert!((dropped.feature_coefficient.clone() - expected).norm_max() < 1e-4);
+        assert!((dropped_intercept - expected_intercept).abs() < 1e-4);
+
+        // The missing sample is imputed with the mean of the others, 0.4. This uses the left
+        // inverse, which matches a fresh fit on every sample.
+        let (imputed, imputed_intercept, n_missing) = project(MissingPolicy::MeanImpute);
+        assert_eq!(n_missing, 1);
+        let imputed_phenotype = faer::col![0.0, 0.0, 1.0, 0.4, 1.0, 0.0];
+        let (expected, expected_intercept) = regress_weighted_with_intercept(
+            &imputed_phenotype,
+            features.clone(),
+            &cohort_info.sample_weights,
+        );
+        assert!((imputed.feature_coefficient.clone() - expected).norm_max() < 1e-4);
+        assert!((imputed_intercept - expected_intercept).abs() < 1e-4);
+        assert!((imputed.feature_coefficient - dropped.feature_coefficient).norm_max() > 0.01);
+    }
+
+    #[test]
+    fn test_projection_residualize_covariates() {
+        let mut cohort_info = weighted_cohort_data();
+        let features = cohort_info.features.clone();
+        let weights = cohort_info.sample_weights.clone();
+        // The only covariate is feature a itself
+        let covariates = Mat::from_fn(6, 1, |i, _| features.read(i, 0));
+        cohort_info.covariates = Some(covariates.clone());
+        cohort_info.num_covar = Some(1);
+        let definition = vec![
+            test_feature("a"),
+            test_feature("b"),
+            Node::Operator(crate::models::Operators::Add),
+        ];
+        let project = |residualize_covariates, cohort_info: &CohortData| {
+            let options = ProjectionOptions {
+                residualize_covariates,
+                ..Default::default()
+            };
+            compute_projection(&definition, &options, cohort_info)
+        };
+        let a_plus_b = Col::from_fn(6, |i| features.read(i, 0) + features.read(i, 1));
+        let (raw, _, _) = project(None, &cohort_info).unwrap();
+        let (expected, _) = regress_weighted_with_intercept(&a_plus_b, features.clone(), &weights);
+        assert!((raw.feature_coefficient.clone() - &expected).norm_max() < 1e-4);
+        // The residuals of a + b regressed on a, weighted like the projection, are
+        // projected instead, which takes weight off a's coefficient
+        let (residualized, _, _) = project(Some(true), &cohort_info).unwrap();
+        let (slope, intercept) = regress_weighted_with_intercept(&a_plus_b, covariates, &weights);
+        let residuals = Col::from_fn(6, |i| {
+            a_plus_b.read(i) - slope.read(0) * features.read(i, 0) - intercept
+        });
+        let (expected, _) = regress_weighted_with_intercept(&residuals, features, &weights);
+        assert!((residualized.feature_coefficient.clone() - expected).norm_max() < 1e-4);
+        assert!(residualized.feature_coefficient[0] < raw.feature_coefficient[0] - 0.1);
+
+        // A cohort can residualize by default, which requests can still opt out of
+        cohort_info.defaults.residualize_covariates = Some(true);
+        let (by_default, _, _) = project(None, &cohort_info).unwrap();
+        assert_eq!(
+            by_default.feature_coefficient,
+            residualized.feature_coefficient
+        );
+        let (opted_out, _, _) = project(Some(false), &cohort_info).unwrap();
+        assert_eq!(opted_out.feature_coefficient, raw.feature_coefficient);
+
+        cohort_info.covariates = None;
+        let err = project(Some(true), &cohort_info).unwrap_err();
+        assert!(err.to_string().contains("no covariates"));
+    }
+
+    #[test]
+    fn test_resolve_num_covariates() {
+        assert_eq!(resolve_num_covariates(None, Some(10), 100).unwrap(), 10);
+        assert_eq!(resolve_num_covariates(Some(3), Some(10), 100).unwrap(), 3);
+        assert!(resolve_num_covariates(None, None, 100).is_err());
+        assert!(resolve_num_covariates(Some(-1), Some(10), 100).is_err());
+        assert!(resolve_num_covariates(Some(100), Some(10), 100).is_err());
+    }
+}